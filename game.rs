@@ -1,12 +1,118 @@
 use anchor_lang::prelude::*;
 use anchor_lang::system_program;
 use anchor_lang::solana_program::hash::hash;
+use anchor_lang::solana_program::sysvar;
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::metadata::mpl_token_metadata::types::DataV2;
+use anchor_spl::metadata::{
+    create_master_edition_v3, create_metadata_accounts_v3, update_metadata_accounts_v2,
+    CreateMasterEditionV3, CreateMetadataAccountsV3, Metadata, UpdateMetadataAccountsV2,
+};
+use anchor_spl::token::{mint_to, Mint, MintTo, Token, TokenAccount};
+#[cfg(not(feature = "localnet"))]
+use switchboard_on_demand::{RandomnessAccountData, SWITCHBOARD_ON_DEMAND_PROGRAM_ID};
 
 declare_id!("4hmtAprg26SJgUKURwVMscyMv9mTtHnbvxaAXy6VJrr8");
 
+// GameConfig defaults, the live values are set by the config admin
 const TURN_TIMEOUT_SECONDS: i64 = 30;
+const REVEAL_TIMEOUT_SECONDS: i64 = 15; // Once both stances are committed
 const BATTLE_EXPIRY_SECONDS: i64 = 3600; // 1 hour
 const WILDCARD_DECISION_TIMEOUT: i64 = 10; // 10 seconds to decide
+const MIN_BATTLE_INTERVAL_SECONDS: i64 = 60; // 1 minute between battles
+const CHALLENGE_WINDOW_SECONDS: i64 = 24 * 3600; // Unanswered challenges lapse after a day
+const PVE_TIMEOUT_REFUNDS_STAKE: bool = false; // Otherwise a PvE forfeit stake goes to the Treasury
+const ABANDON_THRESHOLD: u16 = 3; // PvP forfeits within the window before a queue ban
+const ABANDON_WINDOW_SECONDS: i64 = 24 * 3600;
+const ABANDON_BAN_SECONDS: i64 = 3600;
+const QUEUE_TIMEOUT_SECONDS: i64 = 30 * 60; // Unmatched queue entries can be expired after this
+const MMR_BUCKET_SIZE: u64 = 100; // Matchmaking brackets
+const QUEUE_PRIORITY_STEP_SECONDS: i64 = 60; // Each minute waiting widens the search by a bucket
+const MAX_QUEUE_PRIORITY_BOOST: u16 = 5;
+const BASE_WILDCARD_CHANCE: u8 = 10; // Percent
+const TRICKSTER_WILDCARD_CHANCE: u8 = 25;
+const HEAL_PRICE_PER_HP_LAMPORTS: u64 = 10_000; // 0.001 SOL for 100 HP
+const REVIVE_COST_LAMPORTS: u64 = 5_000_000; // 0.005 SOL
+const REVIVE_DOWNTIME_SECONDS: i64 = 600; // 10 minutes dead before a revive
+const RESPEC_COST_LAMPORTS: u64 = 50_000_000; // 0.05 SOL
+const RESPEC_COOLDOWN_SECONDS: i64 = 7 * 24 * 3600; // One respec a week
+const DEFAULT_HOUSE_EDGE: u8 = 5; // Percent
+const MAX_HOUSE_EDGE: u8 = 10;
+const MMR_K_RANKED: u64 = 32; // Elo K-factor for Ranked, Tournament and Staked
+const MMR_K_PROVISIONAL: u64 = 64; // While a character has few games this season
+const MMR_K_CASUAL: u64 = 8;
+const MAX_MMR_GAP: u64 = 300; // Ranked only
+const XP_MULTIPLIER_BPS: u16 = 10_000; // 1x
+const LOSER_XP_PERCENT: u8 = 30; // Of the winner's base XP
+// Turns before a special can be used again, indexed like CharacterClass:
+// Warrior, Assassin, Mage, Tank, Trickster, Paladin
+const SPECIAL_COOLDOWNS: [u8; 6] = [3, 4, 3, 2, 5, 3];
+const CLASS_BASE_STATS: [ClassStats; 6] = [
+    ClassStats { max_hp: 120, damage_min: 8, damage_max: 15, crit_chance: 15, dodge_chance: 0 },
+    ClassStats { max_hp: 90, damage_min: 12, damage_max: 20, crit_chance: 35, dodge_chance: 20 },
+    ClassStats { max_hp: 80, damage_min: 10, damage_max: 18, crit_chance: 20, dodge_chance: 0 },
+    ClassStats { max_hp: 150, damage_min: 6, damage_max: 12, crit_chance: 10, dodge_chance: 0 },
+    ClassStats { max_hp: 100, damage_min: 9, damage_max: 16, crit_chance: 25, dodge_chance: 15 },
+    ClassStats { max_hp: 130, damage_min: 7, damage_max: 14, crit_chance: 12, dodge_chance: 5 },
+];
+const INSTANT_KILL_ENABLED: bool = true; // Casual matches only either way
+const PROVISIONAL_SEASON_GAMES: u32 = 10;
+const MAX_BATTLE_TURNS: u32 = 50; // Default turn limit, decided on HP percentage after it
+const TURN_RECORD_CAPACITY: usize = 50; // Battle::turn_records length, bounds max_battle_turns
+const COMBO_MASTER_THRESHOLD: u16 = 5;
+const MAX_HIT_COMBO: u16 = 10; // Landed hits stop building the combo here, wildcards can go past it
+const COUNTER_KEEPS_COMBO: bool = true; // Counter's own swing deals nothing by design
+const STREAK_XP_BONUS_START: u32 = 3; // +10% XP per win from here on
+const STREAK_XP_BONUS_CAP: u64 = 50; // Percent
+const STREAK_MMR_BONUS_START: u32 = 5;
+const STREAK_MMR_BONUS: u64 = 3;
+const STREAK_MASTER_THRESHOLD: u32 = 10;
+const STREAK_MASTER_MMR_BONUS: u64 = 6; // Replaces STREAK_MMR_BONUS from here on
+const MAX_LEVEL: u16 = 50;
+const PRESTIGE_DAMAGE_BONUS: u16 = 1; // Per prestige, min and max damage
+const PRESTIGE_HP_BONUS: u64 = 3; // Per prestige
+const LEADERBOARD_SIZE: usize = 100;
+const MAX_GUILD_MEMBERS: u32 = 50;
+const DAILY_CLAIM_INTERVAL_SECONDS: i64 = 24 * 3600;
+const DAILY_STREAK_GRACE_SECONDS: i64 = 48 * 3600; // Streak resets after this
+const DAILY_REWARD_BASE_XP: u64 = 20;
+const DAILY_REWARD_XP_PER_STREAK_DAY: u64 = 10;
+const DAILY_REWARD_MAX_STREAK_BONUS_DAYS: u16 = 6; // Caps the reward at day 7
+const FIRST_WIN_REWARD_XP: u64 = 100;
+const HUNDRED_WINS_CRIT_BONUS: u16 = 1; // Percent, permanent
+const CHAMPION_TITLE: &str = "Champion";
+const COSMETIC_COMBO_AURA: u8 = 1 << 0; // cosmetic_flags bit from ComboMaster
+const RANK_DECAY_INACTIVITY_SECONDS: i64 = 14 * 24 * 3600; // 2 weeks without a battle
+const RANK_DECAY_PERIOD_SECONDS: i64 = 7 * 24 * 3600; // then every week
+const RANK_DECAY_AMOUNT: u64 = 25;
+const RANK_DECAY_FLOOR_MMR: u64 = 1000; // Bottom of Silver
+const BATTLE_LOG_CAPACITY: usize = 64;
+const VENOM_DOT_PER_STACK: u64 = 2;
+const VENOM_DOT_CAP: u64 = 10; // Five stacks
+const VENOM_DOT_TURNS: u8 = 3;
+const TEAM_TURN_ORDER: [u8; 4] = [0, 2, 1, 3]; // Sides alternate, so do teammates
+const WILDCARD_UNDECIDED: u8 = 0;
+const WILDCARD_DECLINED: u8 = 1;
+const WILDCARD_ACCEPTED: u8 = 2;
+const HEALTH_POTION_LAMPORTS: u64 = 2_000_000;
+const ADRENALINE_SHOT_LAMPORTS: u64 = 1_500_000;
+const SMOKE_BOMB_LAMPORTS: u64 = 1_500_000;
+const HEALTH_POTION_HP: u64 = 30;
+const MAX_CRIT_CHANCE: u16 = 100; // Percent, caps class stats plus gear
+const MAX_DODGE_CHANCE: u16 = 75;
+const SMOKE_BOMB_DODGE: u8 = 20; // Percent, for the next incoming attack
+const EVASION_DODGE: u8 = 40; // Percent, Trickster special on the next incoming attack
+const MAX_ITEMS_PER_BATTLE: u8 = 3;
+const MIN_BET_LAMPORTS: u64 = 10_000_000; // 0.01 SOL
+const MAX_BET_PER_WALLET_LAMPORTS: u64 = 10_000_000_000; // 10 SOL across all of a wallet's bets on a pool
+const MAX_POOL_SIZE_LAMPORTS: u64 = 200_000_000_000; // 200 SOL
+const MAX_SIDE_BETS_LAMPORTS: u64 = 100_000_000_000; // 100 SOL per side
+const MAX_LOCKED_ODDS: u64 = 90; // A bet locks at most a 10x payout
+const MAX_FIXED_ODDS_EXPOSURE_LAMPORTS: u64 = 10_000_000_000; // Most a side's locked payouts may exceed the pool by
+const BETTING_CLOSES_AT_TURN: u32 = 3; // Bets are only taken in the uncertain early game
+const BET_CLAIM_WINDOW_SECONDS: i64 = 30 * 24 * 3600; // Unclaimed pool funds go to the Treasury after this
+const SEASON_CLAIM_WINDOW_SECONDS: i64 = 30 * 24 * 3600; // Unclaimed season rewards too, counted from the season's end
+const BETTING_START_WINDOW_SECONDS: i64 = 15 * 60; // Pools on a battle that never starts can be voided after this
 
 #[program]
 pub mod my_program {
@@ -25,6 +131,8 @@ pub mod my_program {
 
         character.owner = ctx.accounts.owner.key();
         character.character_class = character_class;
+        character.seed_name = name.clone();
+        character.seed_owner = ctx.accounts.owner.key();
         character.name = name;
         character.level = 1;
         character.xp = 0;
@@ -33,59 +141,40 @@ pub mod my_program {
         character.max_combo = 0;
         character.created_at = clock.unix_timestamp;
         character.last_battle = 0;
+        character.last_decay = 0;
         character.rank_tier = RankTier::Bronze;
         character.season_wins = 0;
         character.season_losses = 0;
+        character.season_index = ctx.accounts.config.current_season;
         character.achievements = vec![];
 
-        // Set base stats based on class
-        match character_class {
-            CharacterClass::Warrior => {
-                character.max_hp = 120;
-                character.current_hp = 120;
-                character.base_damage_min = 8;
-                character.base_damage_max = 15;
-                character.crit_chance = 15;
-                character.dodge_chance = 0;
-            }
-            CharacterClass::Assassin => {
-                character.max_hp = 90;
-                character.current_hp = 90;
-                character.base_damage_min = 12;
-                character.base_damage_max = 20;
-                character.crit_chance = 35;
-                character.dodge_chance = 20;
-            }
-            CharacterClass::Mage => {
-                character.max_hp = 80;
-                character.current_hp = 80;
-                character.base_damage_min = 10;
-                character.base_damage_max = 18;
-                character.crit_chance = 20;
-                character.dodge_chance = 0;
-            }
-            CharacterClass::Tank => {
-                character.max_hp = 150;
-                character.current_hp = 150;
-                character.base_damage_min = 6;
-                character.base_damage_max = 12;
-                character.crit_chance = 10;
-                character.dodge_chance = 0;
-            }
-            CharacterClass::Trickster => {
-                character.max_hp = 100;
-                character.current_hp = 100;
-                character.base_damage_min = 9;
-                character.base_damage_max = 16;
-                character.crit_chance = 25;
-                character.dodge_chance = 15;
-            }
-        }
+        set_class_base_stats(character, &ctx.accounts.config.settings);
 
-        character.defense = 0;
-        character.special_cooldown = 0;
-        character.mmr = 1000; // Starting MMR
+        character.ranked_mmr = 1000; // Starting MMR
+        character.casual_mmr = 1000;
+        character.current_streak = 0;
+        character.best_streak = 0;
         character.metadata_uri = String::new();
+        character.metadata_version = 0;
+        character.guild = None;
+        character.in_battle = None;
+        character.tournament = None;
+        character.battle_nonce = 0;
+        character.death_count = 0;
+        character.prestige = 0;
+        character.last_daily_claim = 0;
+        character.daily_streak = 0;
+        character.equipment = [None; 3];
+        character.last_respec = 0;
+        character.nft_mint = None;
+        character.metadata_dirty = false;
+        character.claimed_achievement_rewards = 0;
+        character.title = String::new();
+        character.free_heals = 0;
+        character.abandons = 0;
+        character.abandon_window_start = 0;
+        character.ban_until = 0;
+        character.cosmetic_flags = 0;
 
         emit!(CharacterCreated {
             character: character.key(),
@@ -103,12 +192,21 @@ pub mod my_program {
         ctx: Context<JoinQueue>,
         match_type: MatchType,
         stake_amount: u64,
+        region: u8,
     ) -> Result<()> {
         let queue_entry = &mut ctx.accounts.queue_entry;
         let character = &ctx.accounts.character;
         let clock = Clock::get()?;
 
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
         require!(character.current_hp > 0, GameError::CharacterDead);
+        require!(character.in_battle.is_none(), GameError::CharacterBusy);
+        check_battle_cooldown(character, &ctx.accounts.config.settings, &clock)?;
+        check_not_banned(character, &clock)?;
+        require!(
+            match_type != MatchType::Practice || stake_amount == 0,
+            GameError::PracticeIsUnstaked
+        );
 
         // If staked match, lock the SOL
         if stake_amount > 0 {
@@ -124,156 +222,334 @@ pub mod my_program {
 
         queue_entry.player = character.owner;
         queue_entry.character = character.key();
-        queue_entry.mmr = character.mmr;
+        queue_entry.mmr = if match_type == MatchType::Casual { character.casual_mmr } else { character.ranked_mmr };
         queue_entry.match_type = match_type;
         queue_entry.stake_amount = stake_amount;
         queue_entry.joined_at = clock.unix_timestamp;
         queue_entry.matched = false;
+        queue_entry.mmr_bucket = mmr_bucket(queue_entry.mmr);
+        queue_entry.region = region;
 
         emit!(QueueJoined {
             player: character.owner,
             character: character.key(),
-            mmr: character.mmr,
+            mmr: queue_entry.mmr,
             match_type,
+            mmr_bucket: queue_entry.mmr_bucket,
+            region,
         });
 
-        msg!("{} joined queue (MMR: {})", character.name, character.mmr);
+        msg!("{} joined queue (MMR: {})", character.name, queue_entry.mmr);
+        Ok(())
+    }
+
+    // Crank: close a queue entry nobody matched within queue_timeout_seconds.
+    // Closing it returns the locked stake and the rent to the player.
+    pub fn expire_queue_entry(ctx: Context<ExpireQueueEntry>) -> Result<()> {
+        let queue_entry = &ctx.accounts.queue_entry;
+        let clock = Clock::get()?;
+
+        require!(
+            queue_entry.has_expired(&ctx.accounts.config.settings, &clock),
+            GameError::QueueEntryNotExpired
+        );
+
+        emit!(QueueExpired {
+            player: queue_entry.player,
+            character: queue_entry.character,
+            refunded: queue_entry.to_account_info().lamports(),
+        });
         Ok(())
     }
 
-    // Create battle from queue match or direct challenge
+    // Start a battle against the AI character. PvP battles need both owners'
+    // consent and go through send_challenge/accept_challenge instead.
     pub fn create_battle(
         ctx: Context<CreateBattle>,
         match_type: MatchType,
         stake_amount: u64,
-        is_vs_ai: bool,
     ) -> Result<()> {
-        let battle = &mut ctx.accounts.battle;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_init()?;
         let clock = Clock::get()?;
 
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
         require!(
             ctx.accounts.player1_character.current_hp > 0,
             GameError::CharacterDead
         );
 
-        if !is_vs_ai {
-            require!(
-                ctx.accounts.player2_character.current_hp > 0,
-                GameError::CharacterDead
+        require!(
+            ctx.accounts.player1_character.in_battle.is_none(),
+            GameError::CharacterBusy
+        );
+
+        let settings = &ctx.accounts.config.settings;
+        check_battle_cooldown(&ctx.accounts.player1_character, settings, &clock)?;
+        require!(
+            match_type != MatchType::Practice || stake_amount == 0,
+            GameError::PracticeIsUnstaked
+        );
+
+        // Lock stakes in the battle's escrow, apart from any account rent
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.battle = battle_key;
+        escrow.bump = ctx.bumps.escrow;
+
+        if stake_amount > 0 {
+            let cpi_context = CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.player1_owner.to_account_info(),
+                    to: escrow.to_account_info(),
+                },
             );
+            system_program::transfer(cpi_context, stake_amount)?;
         }
 
-        // Lock stakes if applicable
+        init_battle(
+            battle,
+            &ctx.accounts.player1_character,
+            &ctx.accounts.player2_character,
+            match_type,
+            stake_amount,
+            true,
+            &clock,
+        );
+
+        // The AI side is a shared character, only the player gets a cooldown and lock
+        ctx.accounts.player1_character.last_battle = clock.unix_timestamp;
+        ctx.accounts.player1_character.in_battle = Some(battle_key);
+        ctx.accounts.player1_character.battle_nonce += 1;
+
+        emit!(BattleCreated {
+            battle: battle_key,
+            player1: battle.player1,
+            player2: battle.player2,
+            match_type,
+            is_vs_ai: true,
+        });
+
+        msg!("Battle created between {} and AI", ctx.accounts.player1_character.name);
+        Ok(())
+    }
+
+    // Challenge a specific opponent instead of going through the queue. The
+    // challenger's stake is locked in the challenge until it's answered.
+    pub fn send_challenge(
+        ctx: Context<SendChallenge>,
+        match_type: MatchType,
+        stake_amount: u64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(
+            ctx.accounts.challenger_character.key() != ctx.accounts.challenged_character.key(),
+            GameError::CannotChallengeSelf
+        );
+        require!(
+            ctx.accounts.challenger_character.current_hp > 0,
+            GameError::CharacterDead
+        );
+        require!(
+            match_type != MatchType::Practice || stake_amount == 0,
+            GameError::PracticeIsUnstaked
+        );
+
+        let clock = Clock::get()?;
+        check_not_banned(&ctx.accounts.challenger_character, &clock)?;
+        let challenge = &mut ctx.accounts.challenge;
+        challenge.challenger = ctx.accounts.challenger_character.key();
+        challenge.challenged = ctx.accounts.challenged_character.key();
+        challenge.challenger_owner = ctx.accounts.owner.key();
+        challenge.match_type = match_type;
+        challenge.stake_amount = stake_amount;
+        challenge.expires_at = clock.unix_timestamp + ctx.accounts.config.settings.challenge_window_seconds;
+        challenge.bump = ctx.bumps.challenge;
+
         if stake_amount > 0 {
             let cpi_context = CpiContext::new(
                 ctx.accounts.system_program.to_account_info(),
                 system_program::Transfer {
-                    from: ctx.accounts.player1_owner.to_account_info(),
-                    to: battle.to_account_info(),
+                    from: ctx.accounts.owner.to_account_info(),
+                    to: challenge.to_account_info(),
                 },
             );
             system_program::transfer(cpi_context, stake_amount)?;
+        }
 
-            if !is_vs_ai {
-                let cpi_context2 = CpiContext::new(
-                    ctx.accounts.system_program.to_account_info(),
-                    system_program::Transfer {
-                        from: ctx.accounts.player2_owner.to_account_info(),
-                        to: battle.to_account_info(),
-                    },
-                );
-                system_program::transfer(cpi_context2, stake_amount)?;
-            }
+        emit!(ChallengeSent {
+            challenge: challenge.key(),
+            challenger: challenge.challenger,
+            challenged: challenge.challenged,
+            match_type,
+            stake_amount,
+            expires_at: challenge.expires_at,
+        });
+        Ok(())
+    }
+
+    // The challenged player accepts and matches the stake. The battle is set
+    // up like create_battle's, with the challenger as player1.
+    pub fn accept_challenge(ctx: Context<AcceptChallenge>) -> Result<()> {
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_init()?;
+        let clock = Clock::get()?;
+
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(
+            clock.unix_timestamp < ctx.accounts.challenge.expires_at,
+            GameError::ChallengeExpired
+        );
+        require!(
+            ctx.accounts.challenger_character.current_hp > 0
+                && ctx.accounts.challenged_character.current_hp > 0,
+            GameError::CharacterDead
+        );
+        require!(
+            ctx.accounts.challenger_character.in_battle.is_none()
+                && ctx.accounts.challenged_character.in_battle.is_none(),
+            GameError::CharacterBusy
+        );
+
+        check_not_banned(&ctx.accounts.challenger_character, &clock)?;
+        check_not_banned(&ctx.accounts.challenged_character, &clock)?;
+
+        let settings = &ctx.accounts.config.settings;
+        check_battle_cooldown(&ctx.accounts.challenger_character, settings, &clock)?;
+        check_battle_cooldown(&ctx.accounts.challenged_character, settings, &clock)?;
+
+        let match_type = ctx.accounts.challenge.match_type;
+        check_mmr_gap(
+            match_type,
+            &ctx.accounts.challenger_character,
+            &ctx.accounts.challenged_character,
+            settings,
+        )?;
+
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.battle = battle_key;
+        escrow.bump = ctx.bumps.escrow;
+
+        let stake_amount = ctx.accounts.challenge.stake_amount;
+        if stake_amount > 0 {
+            // The challenger's half moves over from the challenge account
+            **ctx.accounts.challenge.to_account_info().try_borrow_mut_lamports()? -= stake_amount;
+            **escrow.to_account_info().try_borrow_mut_lamports()? += stake_amount;
+
+            let cpi_context = CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.owner.to_account_info(),
+                    to: escrow.to_account_info(),
+                },
+            );
+            system_program::transfer(cpi_context, stake_amount)?;
         }
 
-        battle.player1 = ctx.accounts.player1_character.key();
-        battle.player2 = ctx.accounts.player2_character.key();
-        battle.match_type = match_type;
-        battle.stake_amount = stake_amount;
-        battle.created_at = clock.unix_timestamp;
-        battle.turn_number = 0;
-        battle.current_turn = 1;
-        battle.is_finished = false;
-        battle.winner = None;
-        battle.is_vs_ai = is_vs_ai;
-        battle.abandoned = false;
-        battle.last_action_time = clock.unix_timestamp;
+        init_battle(
+            battle,
+            &ctx.accounts.challenger_character,
+            &ctx.accounts.challenged_character,
+            match_type,
+            stake_amount,
+            false,
+            &clock,
+        );
 
-        battle.player1_hp = ctx.accounts.player1_character.max_hp;
-        battle.player2_hp = ctx.accounts.player2_character.max_hp;
-        battle.player1_combo = 0;
-        battle.player2_combo = 0;
-        battle.player1_stance = BattleStance::Balanced;
-        battle.player2_stance = BattleStance::Balanced;
-        battle.player1_stance_committed = false;
-        battle.player2_stance_committed = false;
-        battle.player1_stance_hash = [0u8; 32];
-        battle.player2_stance_hash = [0u8; 32];
-        battle.player1_dot_damage = 0;
-        battle.player2_dot_damage = 0;
-        battle.player1_dot_turns = 0;
-        battle.player2_dot_turns = 0;
-        battle.player1_reflection = 0;
-        battle.player2_reflection = 0;
-        battle.player1_miss_count = 0;
-        battle.player2_miss_count = 0;
-        battle.player1_special_cooldown = 0;
-        battle.player2_special_cooldown = 0;
-        battle.last_damage_roll = 0;
-        battle.wildcard_active = false;
-        battle.wildcard_type = None;
-        battle.wildcard_decision_deadline = 0;
-        battle.wildcard_player1_decision = None;
-        battle.wildcard_player2_decision = None;
-        battle.battle_log = vec![];
+        ctx.accounts.challenger_character.last_battle = clock.unix_timestamp;
+        ctx.accounts.challenged_character.last_battle = clock.unix_timestamp;
+        ctx.accounts.challenger_character.in_battle = Some(battle_key);
+        ctx.accounts.challenged_character.in_battle = Some(battle_key);
+        ctx.accounts.challenger_character.battle_nonce += 1;
 
+        emit!(ChallengeAccepted {
+            challenge: ctx.accounts.challenge.key(),
+            battle: battle_key,
+        });
         emit!(BattleCreated {
-            battle: battle.key(),
+            battle: battle_key,
             player1: battle.player1,
             player2: battle.player2,
             match_type,
-            is_vs_ai,
+            is_vs_ai: false,
         });
+        Ok(())
+    }
 
-        msg!("Battle created between {} and {}", 
-            ctx.accounts.player1_character.name,
-            if is_vs_ai { "AI" } else { &ctx.accounts.player2_character.name }
-        );
+    // The challenged player declines, or anyone clears the challenge once it
+    // has expired. Closing it returns the locked stake to the challenger.
+    pub fn decline_challenge(ctx: Context<DeclineChallenge>) -> Result<()> {
+        let challenge = &ctx.accounts.challenge;
+        let clock = Clock::get()?;
+
+        let expired = check_can_clear_challenge(
+            challenge,
+            &ctx.accounts.challenged_character,
+            &ctx.accounts.signer.key(),
+            &clock,
+        )?;
+
+        emit!(ChallengeDeclined {
+            challenge: challenge.key(),
+            challenger: challenge.challenger,
+            challenged: challenge.challenged,
+            refunded: challenge.stake_amount,
+            expired,
+        });
         Ok(())
     }
 
     // Commit stance (hidden commitment phase)
+    // stance_hash = hash(stance ++ item ++ use_special ++ salt ++ battle ++ turn_number ++ player),
+    // see stance_commitment(). Binding the battle, turn and player means a
+    // commitment can't be replayed on another turn or battle. item is the
+    // BattleItem::code() the player will use this turn, 0 for none, and
+    // use_special is 1 if the special is used, so it can't be decided after
+    // seeing the opponent's reveal.
     pub fn commit_stance(
         ctx: Context<CommitStance>,
         stance_hash: [u8; 32],
     ) -> Result<()> {
-        let battle = &mut ctx.accounts.battle;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_mut()?;
         let character = &ctx.accounts.character;
         let clock = Clock::get()?;
 
-        require!(!battle.is_finished, GameError::BattleAlreadyFinished);
-        check_battle_timeout(battle, &clock)?;
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(battle.is_finished == 0, GameError::BattleAlreadyFinished);
+        check_battle_timeout(battle, &ctx.accounts.config.settings, &clock)?;
 
         let is_player1 = battle.player1 == character.key();
         require!(
-            (is_player1 && battle.current_turn == 1) || (!is_player1 && battle.current_turn == 2),
-            GameError::NotYourTurn
+            is_player1 || battle.player2 == character.key(),
+            GameError::NotBattleParticipant
         );
+        require_keys_eq!(character.owner, ctx.accounts.player.key(), GameError::NotCharacterOwner);
+
+        // Both players commit every turn. The AI never commits, so in PvE the
+        // human only commits on their own turn.
+        if battle.is_vs_ai != 0 {
+            require!(is_player1 && battle.current_turn == 1, GameError::NotYourTurn);
+        }
 
         if is_player1 {
-            require!(!battle.player1_stance_committed, GameError::AlreadyCommitted);
+            require!(battle.player1_stance_committed == 0, GameError::AlreadyCommitted);
             battle.player1_stance_hash = stance_hash;
-            battle.player1_stance_committed = true;
+            battle.player1_stance_committed = 1;
         } else {
-            require!(!battle.player2_stance_committed, GameError::AlreadyCommitted);
+            require!(battle.player2_stance_committed == 0, GameError::AlreadyCommitted);
             battle.player2_stance_hash = stance_hash;
-            battle.player2_stance_committed = true;
+            battle.player2_stance_committed = 1;
         }
 
         battle.last_action_time = clock.unix_timestamp;
+        // The reveal window starts once neither side can still change its stance
+        if battle.player1_stance_committed != 0 && battle.player2_stance_committed != 0 {
+            battle.both_committed_at = clock.unix_timestamp;
+        }
 
         emit!(StanceCommitted {
-            battle: battle.key(),
+            battle: battle_key,
             player: character.owner,
             turn: battle.turn_number,
         });
@@ -282,29 +558,101 @@ pub mod my_program {
         Ok(())
     }
 
+    // Bind a committed-but-unrevealed Switchboard randomness account to the
+    // battle. Stances must already be locked in so nobody can pick one after
+    // seeing the roll.
+    pub fn request_turn_randomness(ctx: Context<RequestTurnRandomness>) -> Result<()> {
+        let battle = &mut ctx.accounts.battle.load_mut()?;
+        let clock = Clock::get()?;
+
+        require!(battle.is_finished == 0, GameError::BattleAlreadyFinished);
+        require!(battle.randomness_pending == 0, GameError::RandomnessAlreadyRequested);
+        // An unused seed can't be swapped for a better one
+        require!(battle.seed_consumed != 0, GameError::RandomnessAlreadyRequested);
+        require!(
+            battle.wildcard_active == 0
+                || !WildcardEvent::from_code(battle.wildcard_type).is_some_and(requires_decision)
+                || (battle.wildcard_player1_decision != WILDCARD_UNDECIDED
+                    && battle.wildcard_player2_decision != WILDCARD_UNDECIDED),
+            GameError::WildcardDecisionPending
+        );
+
+        let stances_locked = if battle.is_vs_ai != 0 {
+            battle.current_turn == 2 || battle.player1_stance_committed != 0
+        } else {
+            battle.player1_stance_committed != 0 && battle.player2_stance_committed != 0
+        };
+        require!(stances_locked, GameError::OpponentNotCommitted);
+
+        check_randomness_committed(&ctx.accounts.randomness_account, &clock)?;
+
+        battle.randomness_account = ctx.accounts.randomness_account.key();
+        battle.randomness_pending = 1;
+
+        msg!("Randomness requested for turn {}", battle.turn_number);
+        Ok(())
+    }
+
+    // Store the revealed randomness as the seed for the next turn resolution.
+    // Must run in the same slot as the Switchboard reveal.
+    pub fn consume_randomness(ctx: Context<ConsumeRandomness>) -> Result<()> {
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_mut()?;
+        let clock = Clock::get()?;
+
+        require!(battle.randomness_pending != 0, GameError::RandomnessNotRequested);
+
+        battle.random_seed = read_randomness(
+            &ctx.accounts.randomness_account,
+            &clock,
+            &battle_key,
+            battle.turn_number,
+        )?;
+        battle.randomness_pending = 0;
+        battle.seed_turn = battle.turn_number;
+        battle.seed_consumed = 0;
+
+        msg!("Randomness fulfilled for turn {}", battle.turn_number);
+        Ok(())
+    }
+
     // Reveal stance and execute turn
+    // Either player may reveal once both have committed. The acting player's
+    // attack (current_turn) resolves when the second stance is revealed.
+    // attacker_character is the revealing player, defender_character the other.
     pub fn reveal_and_execute_turn(
         ctx: Context<ExecuteTurn>,
         stance: BattleStance,
         salt: u64,
         use_special: bool,
     ) -> Result<()> {
-        let battle = &mut ctx.accounts.battle;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_mut()?;
         let attacker_char = &ctx.accounts.attacker_character;
         let defender_char = &ctx.accounts.defender_character;
         let clock = Clock::get()?;
 
-        require!(!battle.is_finished, GameError::BattleAlreadyFinished);
-        check_battle_timeout(battle, &clock)?;
-
-        let is_player1 = battle.player1 == attacker_char.key();
-        require!(
-            (is_player1 && battle.current_turn == 1) || (!is_player1 && battle.current_turn == 2),
-            GameError::NotYourTurn
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(battle.is_finished == 0, GameError::BattleAlreadyFinished);
+        check_battle_timeout(battle, &ctx.accounts.config.settings, &clock)?;
+
+        let is_player1 = turn_sides(battle, &attacker_char.key(), &defender_char.key())?;
+        require_keys_eq!(attacker_char.owner, ctx.accounts.attacker.key(), GameError::NotCharacterOwner);
+        let is_acting = (is_player1 && battle.current_turn == 1) || (!is_player1 && battle.current_turn == 2);
+
+        check_can_reveal(battle, is_acting)?;
+
+        // Verify stance commitment, including any item and special used this turn
+        let item = if is_player1 { battle.player1_item_this_turn } else { battle.player2_item_this_turn };
+        let computed_hash = stance_commitment(
+            stance,
+            item,
+            use_special,
+            salt,
+            &battle_key,
+            battle.turn_number,
+            &ctx.accounts.attacker.key(),
         );
-
-        // Verify stance commitment
-        let computed_hash = hash(&[&stance.to_bytes()[..], &salt.to_le_bytes()].concat()).to_bytes();
         if is_player1 {
             require!(
                 battle.player1_stance_hash == computed_hash,
@@ -319,6 +667,7 @@ pub mod my_program {
 
         // Check special cooldown
         if use_special {
+            require!(is_acting, GameError::NotYourTurn);
             let cooldown = if is_player1 {
                 battle.player1_special_cooldown
             } else {
@@ -327,36 +676,68 @@ pub mod my_program {
             require!(cooldown == 0, GameError::SpecialOnCooldown);
         }
 
-        // Set stance
+        // Set stance, the salt also feeds this turn's random seed
         if is_player1 {
-            battle.player1_stance = stance;
+            battle.player1_stance = stance.code();
+            battle.player1_stance_revealed = 1;
+            battle.player1_salt = salt;
         } else {
-            battle.player2_stance = stance;
+            battle.player2_stance = stance.code();
+            battle.player2_stance_revealed = 1;
+            battle.player2_salt = salt;
+        }
+        if is_acting {
+            battle.pending_use_special = use_special as u8;
+        }
+        battle.last_action_time = clock.unix_timestamp;
+
+        let both_revealed = battle.is_vs_ai != 0
+            || (battle.player1_stance_revealed != 0 && battle.player2_stance_revealed != 0);
+        if !both_revealed {
+            emit!(StanceRevealed {
+                battle: battle_key,
+                player: ctx.accounts.attacker.key(),
+                stance,
+                turn: battle.turn_number,
+            });
+            return Ok(());
         }
 
-        // Apply DOT damage at start of turn
-        if is_player1 && battle.player1_dot_turns > 0 {
-            battle.player1_hp = battle.player1_hp.saturating_sub(battle.player1_dot_damage);
-            battle.player1_dot_turns -= 1;
-            log_battle_event(battle, format!("Player 1 takes {} DOT damage", battle.player1_dot_damage));
-        } else if !is_player1 && battle.player2_dot_turns > 0 {
-            battle.player2_hp = battle.player2_hp.saturating_sub(battle.player2_dot_damage);
-            battle.player2_dot_turns -= 1;
-            log_battle_event(battle, format!("Player 2 takes {} DOT damage", battle.player2_dot_damage));
+        // Both stances are public, resolve the acting player's attack
+        let acting_is_player1 = battle.current_turn == 1;
+        let (acting_char, target_char) = if is_acting {
+            (attacker_char, defender_char)
+        } else {
+            (defender_char, attacker_char)
+        };
+        let use_special = battle.pending_use_special != 0;
+
+        let slot_hash = recent_slot_hash(&ctx.accounts.recent_slothashes)?;
+        take_turn_seed(battle, &slot_hash)?;
+
+        // A risky wildcard is only rolled now, with randomness requested after
+        // both players made their call
+        if battle.wildcard_active != 0
+            && battle.wildcard_player1_decision != WILDCARD_UNDECIDED
+            && battle.wildcard_player2_decision != WILDCARD_UNDECIDED
+        {
+            resolve_wildcard_with_decisions(battle, battle_key)?;
         }
 
         // Trickster ability: Manipulate wildcard chance
-        let mut wildcard_chance = 10u8; // Base 10%
-        if attacker_char.character_class == CharacterClass::Trickster {
-            wildcard_chance = 25; // Tricksters have 25% wildcard chance
-            log_battle_event(battle, "Trickster's wildcard manipulation active!".to_string());
+        let settings = &ctx.accounts.config.settings;
+        let mut wildcard_chance = settings.base_wildcard_chance;
+        if acting_char.character_class == CharacterClass::Trickster {
+            wildcard_chance = settings.trickster_wildcard_chance;
+            let player = battle.current_turn;
+            log_battle_event(battle, battle_key, BattleLogEvent::WildcardBoost { player });
         }
 
         // Check for wildcard event
-        let wildcard_roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 1) % 100;
-        if wildcard_roll < wildcard_chance && !battle.wildcard_active {
-            let wildcard_type_roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 2) % 8;
-            battle.wildcard_type = Some(match wildcard_type_roll {
+        let wildcard_roll = roll_range(battle, 0, 99);
+        if wildcard_roll < wildcard_chance as u64 && battle.wildcard_active == 0 {
+            let wildcard_type_roll = roll_range(battle, 0, 7);
+            let kind = match wildcard_type_roll {
                 0 => WildcardEvent::DoubleOrNothing,
                 1 => WildcardEvent::ReverseRoles,
                 2 => WildcardEvent::MysteryBox,
@@ -365,39 +746,97 @@ pub mod my_program {
                 5 => WildcardEvent::TimeWarp,
                 6 => WildcardEvent::LuckySeven,
                 _ => WildcardEvent::GamblersFallacy,
-            });
+            };
+            battle.wildcard_type = kind.code();
+            battle.wildcards_triggered = battle.wildcards_triggered.saturating_add(1);
 
             // Check if wildcard requires decision
-            if requires_decision(battle.wildcard_type.unwrap()) {
-                battle.wildcard_active = true;
-                battle.wildcard_decision_deadline = clock.unix_timestamp + WILDCARD_DECISION_TIMEOUT;
-                log_battle_event(battle, format!("Wildcard event triggered: {:?} - Decision required!", battle.wildcard_type.unwrap()));
+            if requires_decision(kind) {
+                battle.wildcard_active = 1;
+                battle.wildcard_decision_deadline = clock.unix_timestamp + settings.wildcard_decision_timeout;
+                log_battle_event(battle, battle_key, BattleLogEvent::WildcardTriggered { kind, needs_decision: true });
                 
                 emit!(WildcardTriggered {
-                    battle: battle.key(),
-                    wildcard_type: battle.wildcard_type.unwrap(),
+                    battle: battle_key,
+                    wildcard_type: kind,
                     decision_deadline: battle.wildcard_decision_deadline,
                 });
                 
                 // Don't execute turn yet, wait for decisions
                 return Ok(());
             } else {
-                battle.wildcard_active = true;
-                log_battle_event(battle, format!("Wildcard event triggered: {:?}", battle.wildcard_type.unwrap()));
+                battle.wildcard_active = 1;
+                log_battle_event(battle, battle_key, BattleLogEvent::WildcardTriggered { kind, needs_decision: false });
             }
         }
 
         // Execute the actual turn
-        execute_battle_turn(battle, attacker_char, defender_char, is_player1, use_special, &clock)?;
+        execute_battle_turn(
+            battle,
+            battle_key,
+            acting_char,
+            target_char,
+            acting_is_player1,
+            use_special,
+            &ctx.accounts.config.settings,
+        )?;
 
         battle.last_action_time = clock.unix_timestamp;
 
         // Reset commitments for next turn
-        battle.player1_stance_committed = false;
-        battle.player2_stance_committed = false;
+        battle.player1_stance_committed = 0;
+        battle.player2_stance_committed = 0;
         battle.player1_stance_hash = [0u8; 32];
         battle.player2_stance_hash = [0u8; 32];
+        battle.player1_stance_revealed = 0;
+        battle.player2_stance_revealed = 0;
+        battle.both_committed_at = 0;
+        battle.pending_use_special = 0;
+        battle.player1_item_this_turn = 0;
+        battle.player2_item_this_turn = 0;
+
+        Ok(())
+    }
+
+    // Use a consumable on your own turn, after committing and before your
+    // reveal. The commitment covers the item, so it can't be decided after
+    // seeing the opponent's stance.
+    pub fn use_item(ctx: Context<UseItem>, item: BattleItem) -> Result<()> {
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_mut()?;
+        let character_key = ctx.accounts.character.key();
+        let clock = Clock::get()?;
+
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(battle.is_finished == 0, GameError::BattleAlreadyFinished);
+        check_battle_timeout(battle, &ctx.accounts.config.settings, &clock)?;
+
+        let is_player1 = battle.player1 == character_key;
+        require!(
+            is_player1 || battle.player2 == character_key,
+            GameError::NotBattleParticipant
+        );
+        let player = if is_player1 { 1 } else { 2 };
+        require!(battle.current_turn == player, GameError::NotYourTurn);
 
+        check_item_use(battle, is_player1, item)?;
+
+        let inventory = &mut ctx.accounts.inventory;
+        require!(inventory.counts[item.index()] > 0, GameError::ItemNotOwned);
+        inventory.counts[item.index()] -= 1;
+
+        let amount = apply_battle_item(battle, is_player1, item);
+        battle.last_action_time = clock.unix_timestamp;
+
+        log_battle_event(battle, battle_key, BattleLogEvent::ItemUsed { player, item, amount: log_amount(amount) });
+
+        emit!(ItemUsed {
+            battle: battle_key,
+            character: character_key,
+            item,
+            amount,
+            items_used: if is_player1 { battle.player1_items_used } else { battle.player2_items_used },
+        });
         Ok(())
     }
 
@@ -406,33 +845,40 @@ pub mod my_program {
         ctx: Context<DecideWildcard>,
         accept: bool,
     ) -> Result<()> {
-        let battle = &mut ctx.accounts.battle;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_mut()?;
         let character = &ctx.accounts.character;
         let clock = Clock::get()?;
 
-        require!(battle.wildcard_active, GameError::NoActiveWildcard);
+        require!(battle.wildcard_active != 0, GameError::NoActiveWildcard);
         require!(
             clock.unix_timestamp <= battle.wildcard_decision_deadline,
             GameError::DecisionTimeout
         );
 
         let is_player1 = battle.player1 == character.key();
+        require!(
+            is_player1 || battle.player2 == character.key(),
+            GameError::NotBattleParticipant
+        );
 
+        let decision = if accept { WILDCARD_ACCEPTED } else { WILDCARD_DECLINED };
         if is_player1 {
-            battle.wildcard_player1_decision = Some(accept);
+            battle.wildcard_player1_decision = decision;
         } else {
-            battle.wildcard_player2_decision = Some(accept);
+            battle.wildcard_player2_decision = decision;
         }
 
         emit!(WildcardDecision {
-            battle: battle.key(),
+            battle: battle_key,
             player: character.owner,
             accepted: accept,
         });
 
-        // If both decided, resolve wildcard and continue turn
-        if battle.wildcard_player1_decision.is_some() && battle.wildcard_player2_decision.is_some() {
-            resolve_wildcard_with_decisions(battle, &clock)?;
+        // Once both decided, the wildcard resolves on the next reveal with
+        // fresh randomness, so the outcome can't be known while deciding
+        if battle.wildcard_player1_decision != WILDCARD_UNDECIDED && battle.wildcard_player2_decision != WILDCARD_UNDECIDED {
+            log_battle_event(battle, battle_key, BattleLogEvent::WildcardDecisionsLocked);
         }
 
         Ok(())
@@ -440,63 +886,96 @@ pub mod my_program {
 
     // Auto-resolve if timeout on wildcard decision
     pub fn resolve_wildcard_timeout(ctx: Context<ResolveWildcardTimeout>) -> Result<()> {
-        let battle = &mut ctx.accounts.battle;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_mut()?;
         let clock = Clock::get()?;
 
-        require!(battle.wildcard_active, GameError::NoActiveWildcard);
+        require!(battle.wildcard_active != 0, GameError::NoActiveWildcard);
         require!(
             clock.unix_timestamp > battle.wildcard_decision_deadline,
             GameError::DecisionNotExpired
         );
 
         // Auto-decline for players who didn't respond
-        if battle.wildcard_player1_decision.is_none() {
-            battle.wildcard_player1_decision = Some(false);
-            log_battle_event(battle, "Player 1 auto-declined wildcard (timeout)".to_string());
+        if battle.wildcard_player1_decision == WILDCARD_UNDECIDED {
+            battle.wildcard_player1_decision = WILDCARD_DECLINED;
+            log_battle_event(battle, battle_key, BattleLogEvent::WildcardAutoDeclined { player: 1 });
         }
-        if battle.wildcard_player2_decision.is_none() {
-            battle.wildcard_player2_decision = Some(false);
-            log_battle_event(battle, "Player 2 auto-declined wildcard (timeout)".to_string());
+        if battle.wildcard_player2_decision == WILDCARD_UNDECIDED {
+            battle.wildcard_player2_decision = WILDCARD_DECLINED;
+            log_battle_event(battle, battle_key, BattleLogEvent::WildcardAutoDeclined { player: 2 });
         }
 
-        resolve_wildcard_with_decisions(battle, &clock)?;
-
         Ok(())
     }
 
     // Check and handle battle timeout/abandonment
     pub fn check_timeout(ctx: Context<CheckTimeout>) -> Result<()> {
-        let battle = &mut ctx.accounts.battle;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_mut()?;
         let clock = Clock::get()?;
 
-        require!(!battle.is_finished, GameError::BattleAlreadyFinished);
+        require!(battle.is_finished == 0, GameError::BattleAlreadyFinished);
+
+        let settings = &ctx.accounts.config.settings;
+        if let Some(forfeited_by) = timed_out_player(battle, settings, &clock) {
+            battle.is_finished = 1;
+            battle.abandoned = 1;
+            battle.winner = if forfeited_by == 1 { 2 } else { 1 };
 
-        let time_since_last_action = clock.unix_timestamp - battle.last_action_time;
+            log_battle_event(battle, battle_key, BattleLogEvent::Forfeit { player: forfeited_by });
 
-        if time_since_last_action > TURN_TIMEOUT_SECONDS {
-            // Current player forfeits
-            battle.is_finished = true;
-            battle.abandoned = true;
-            battle.winner = Some(if battle.current_turn == 1 { 2 } else { 1 });
+            ctx.accounts.player1_character.last_battle = clock.unix_timestamp;
+            if battle.is_vs_ai == 0 {
+                ctx.accounts.player2_character.last_battle = clock.unix_timestamp;
+            }
+            release_character(&mut ctx.accounts.player1_character, battle_key);
+            release_character(&mut ctx.accounts.player2_character, battle_key);
 
-            log_battle_event(battle, format!("Player {} forfeited (timeout)", battle.current_turn));
+            // Quitting on the AI or a practice match doesn't cost anyone else a match
+            if battle.is_vs_ai == 0 && MatchType::from_code(battle.match_type) != MatchType::Practice {
+                let quitter = if forfeited_by == 1 {
+                    &mut ctx.accounts.player1_character
+                } else {
+                    &mut ctx.accounts.player2_character
+                };
+                record_abandon(quitter, &ctx.accounts.config.settings, &clock);
+            }
 
             emit!(BattleAbandoned {
-                battle: battle.key(),
-                abandoned_by: battle.current_turn,
-                winner: battle.winner.unwrap(),
+                battle: battle_key,
+                abandoned_by: forfeited_by,
+                winner: battle.winner,
             });
 
-            // Return stakes to winner
+            // A forfeit settles the pool in refund mode
+            if let Some(pool) = ctx.accounts.betting_pool.as_mut() {
+                settle_pool(pool, battle, &mut ctx.accounts.treasury, &clock)?;
+            }
+
+            // Return stakes to winner, finalize_battle only closes the escrow after this
             if battle.stake_amount > 0 {
-                let winner_key = if battle.winner.unwrap() == 1 {
-                    battle.player1
+                let stake = escrowed_stake(battle);
+                let recipient = timeout_stake_recipient(
+                    battle,
+                    settings,
+                    ctx.accounts.player1_character.owner,
+                    ctx.accounts.player2_character.owner,
+                );
+                if let Some(recipient) = recipient {
+                    require_keys_eq!(ctx.accounts.winner.key(), recipient, GameError::InvalidStakeRecipient);
+                }
+                if battle.is_vs_ai != 0 && battle.winner == 2 {
+                    pay_ai_win_stake(
+                        &ctx.accounts.escrow,
+                        &mut ctx.accounts.treasury,
+                        &ctx.accounts.winner,
+                        settings,
+                        stake,
+                    )?;
                 } else {
-                    battle.player2
-                };
-                
-                **battle.to_account_info().try_borrow_mut_lamports()? -= battle.stake_amount * 2;
-                **ctx.accounts.winner.to_account_info().try_borrow_mut_lamports()? += battle.stake_amount * 2;
+                    pay_from_escrow(&ctx.accounts.escrow, &ctx.accounts.winner, stake)?;
+                }
             }
         }
 
@@ -505,989 +984,5695 @@ pub mod my_program {
 
     // Execute AI turn (for PvE battles)
     pub fn execute_ai_turn(ctx: Context<ExecuteAiTurn>) -> Result<()> {
-        let battle = &mut ctx.accounts.battle;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &mut ctx.accounts.battle.load_mut()?;
         let player_char = &ctx.accounts.player_character;
         let ai_char = &ctx.accounts.ai_character;
         let clock = Clock::get()?;
 
-        require!(battle.is_vs_ai, GameError::NotAiBattle);
-        require!(!battle.is_finished, GameError::BattleAlreadyFinished);
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(battle.is_vs_ai != 0, GameError::NotAiBattle);
+        require!(battle.is_finished == 0, GameError::BattleAlreadyFinished);
         require!(battle.current_turn == 2, GameError::NotAiTurn);
+        require_keys_eq!(player_char.key(), battle.player1, GameError::NotBattleParticipant);
+        require_keys_eq!(ai_char.key(), battle.player2, GameError::WrongDefender);
+
+        let slot_hash = recent_slot_hash(&ctx.accounts.recent_slothashes)?;
+        take_turn_seed(battle, &slot_hash)?;
 
         // Simple AI logic
-        let ai_stance = choose_ai_stance(battle, ai_char, player_char, &clock);
+        let ai_stance = choose_ai_stance(battle, ai_char, player_char);
         let ai_use_special = battle.player2_special_cooldown == 0 && battle.player2_hp < (ai_char.max_hp / 2);
 
-        battle.player2_stance = ai_stance;
+        battle.player2_stance = ai_stance.code();
 
-        execute_battle_turn(battle, ai_char, player_char, false, ai_use_special, &clock)?;
+        execute_battle_turn(battle, battle_key, ai_char, player_char, false, ai_use_special, &ctx.accounts.config.settings)?;
 
         battle.last_action_time = clock.unix_timestamp;
 
         Ok(())
     }
 
+    // Read-only damage preview for UIs, returns ranges instead of rolling
+    pub fn simulate_turn(
+        ctx: Context<SimulateTurn>,
+        attacker_stance: BattleStance,
+        defender_stance: BattleStance,
+        use_special: bool,
+    ) -> Result<TurnSimulation> {
+        let battle = &ctx.accounts.battle.load()?;
+        let attacker_char = &ctx.accounts.attacker_character;
+        let defender_char = &ctx.accounts.defender_character;
+
+        let is_player1 = turn_sides(battle, &attacker_char.key(), &defender_char.key())?;
+
+        Ok(simulate_damage(
+            attacker_char,
+            defender_char,
+            battle,
+            is_player1,
+            attacker_stance,
+            defender_stance,
+            use_special,
+        ))
+    }
+
+    // Read-only XP needed to advance from the given level, for progress bars
+    pub fn required_xp(_ctx: Context<RequiredXp>, level: u16) -> Result<u64> {
+        Ok(get_required_xp(level))
+    }
+
+    // Read-only summary of a battle, so light clients needn't fetch the
+    // whole account. Simulate the transaction to read the return data.
+    pub fn battle_snapshot(ctx: Context<GetBattleSnapshot>) -> Result<BattleSnapshot> {
+        let battle = ctx.accounts.battle.load()?;
+        Ok(BattleSnapshot {
+            turn_number: battle.turn_number,
+            current_turn: battle.current_turn,
+            player1_hp: battle.player1_hp,
+            player2_hp: battle.player2_hp,
+            player1_combo: battle.player1_combo,
+            player2_combo: battle.player2_combo,
+            wildcard_type: WildcardEvent::from_code(battle.wildcard_type),
+            is_finished: battle.is_finished != 0,
+            winner: battle.winner,
+        })
+    }
+
+    // Create a 2v2 battle, side A is slots 0 and 1, side B slots 2 and 3.
+    // Team battles have no stances, specials or wildcards yet. All four owners
+    // sign, and their characters are locked until finalize_team_battle.
+    pub fn create_team_battle(ctx: Context<CreateTeamBattle>) -> Result<()> {
+        let clock = Clock::get()?;
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        let settings = &ctx.accounts.config.settings;
+        let members = [
+            &ctx.accounts.side_a_character1,
+            &ctx.accounts.side_a_character2,
+            &ctx.accounts.side_b_character1,
+            &ctx.accounts.side_b_character2,
+        ];
+
+        let mut characters = [Pubkey::default(); 4];
+        let mut hp = [0u64; 4];
+        let mut stats = Vec::with_capacity(4);
+        for (slot, member) in members.iter().enumerate() {
+            require!(member.current_hp > 0, GameError::CharacterDead);
+            require!(member.in_battle.is_none(), GameError::CharacterBusy);
+            check_battle_cooldown(member, settings, &clock)?;
+            check_not_banned(member, &clock)?;
+            require!(!characters[..slot].contains(&member.key()), GameError::DuplicateTeamMember);
+            characters[slot] = member.key();
+            hp[slot] = member.max_hp;
+            // Like 1v1 battles, gear or level changes mid-battle don't count
+            stats.push(CombatStats::from(&***member));
+        }
+
+        let team_key = ctx.accounts.team_battle.key();
+        let team_battle = &mut ctx.accounts.team_battle;
+        team_battle.characters = characters;
+        team_battle.hp = hp;
+        team_battle.stats = stats.try_into().unwrap();
+        team_battle.created_at = clock.unix_timestamp;
+        team_battle.last_action_time = clock.unix_timestamp;
+        team_battle.turn_number = 0;
+        team_battle.current_slot = TEAM_TURN_ORDER[0];
+        team_battle.is_finished = false;
+        team_battle.winning_side = 0;
+        team_battle.randomness_account = Pubkey::default();
+        team_battle.randomness_pending = false;
+        team_battle.pending_target = 0;
+
+        for member in [
+            &mut ctx.accounts.side_a_character1,
+            &mut ctx.accounts.side_a_character2,
+            &mut ctx.accounts.side_b_character1,
+            &mut ctx.accounts.side_b_character2,
+        ] {
+            member.last_battle = clock.unix_timestamp;
+            member.in_battle = Some(team_key);
+        }
+
+        emit!(TeamBattleCreated {
+            team_battle: team_key,
+            characters,
+        });
+
+        Ok(())
+    }
+
+    // The acting character's owner picks a living enemy and binds a committed
+    // Switchboard randomness account. The target is locked in before the roll
+    // can be known.
+    pub fn request_team_turn(ctx: Context<RequestTeamTurn>, target: u8) -> Result<()> {
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        let team_battle = &mut ctx.accounts.team_battle;
+        let clock = Clock::get()?;
+
+        require!(!team_battle.is_finished, GameError::BattleAlreadyFinished);
+        require!(!team_battle.randomness_pending, GameError::RandomnessAlreadyRequested);
+
+        let attacker_slot = team_battle.current_slot;
+        require_keys_eq!(
+            ctx.accounts.attacker_character.key(),
+            team_battle.characters[attacker_slot as usize],
+            GameError::NotYourTurn
+        );
+        require!(
+            target < 4
+                && team_side(target) != team_side(attacker_slot)
+                && team_battle.hp[target as usize] > 0,
+            GameError::InvalidTeamTarget
+        );
+
+        check_randomness_committed(&ctx.accounts.randomness_account, &clock)?;
+
+        team_battle.randomness_account = ctx.accounts.randomness_account.key();
+        team_battle.randomness_pending = true;
+        team_battle.pending_target = target;
+        team_battle.last_action_time = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    // Resolve the requested attack with the revealed randomness. Must run in
+    // the same slot as the Switchboard reveal.
+    pub fn team_execute_turn(ctx: Context<TeamExecuteTurn>) -> Result<()> {
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        let team_key = ctx.accounts.team_battle.key();
+        let team_battle = &mut ctx.accounts.team_battle;
+        let clock = Clock::get()?;
+
+        require!(!team_battle.is_finished, GameError::BattleAlreadyFinished);
+        require!(team_battle.randomness_pending, GameError::RandomnessNotRequested);
+
+        let seed = read_randomness(
+            &ctx.accounts.randomness_account,
+            &clock,
+            &team_key,
+            team_battle.turn_number,
+        )?;
+        team_battle.randomness_pending = false;
+
+        let attacker_slot = team_battle.current_slot;
+        let target = team_battle.pending_target;
+        let context = AttackContext {
+            combo: 0,
+            miss_count: 0,
+            defender_hp: team_battle.hp[target as usize],
+            gamblers_fallacy: false,
+            dodge_bonus: 0,
+            instant_kill: false,
+        };
+        let mut counter = 0;
+        let outcome = roll_attack(
+            &team_battle.stats[attacker_slot as usize],
+            &team_battle.stats[target as usize],
+            &seed,
+            &mut counter,
+            &context,
+            false,
+        )?;
+
+        let target_hp = team_battle.hp[target as usize].saturating_sub(outcome.amount);
+        team_battle.hp[target as usize] = target_hp;
+        team_battle.turn_number += 1;
+        team_battle.last_action_time = clock.unix_timestamp;
+
+        emit!(TeamTurnExecuted {
+            team_battle: team_key,
+            turn: team_battle.turn_number,
+            attacker_slot,
+            target,
+            damage: outcome.amount,
+            was_crit: outcome.was_crit,
+            was_dodged: outcome.was_dodged,
+            target_hp,
+        });
+
+        // A side is only out once both of its characters are down
+        let side_a_down = team_battle.hp[0] == 0 && team_battle.hp[1] == 0;
+        let side_b_down = team_battle.hp[2] == 0 && team_battle.hp[3] == 0;
+        if side_a_down || side_b_down {
+            end_team_battle(team_battle, team_key, if side_b_down { 1 } else { 2 });
+        } else {
+            team_battle.current_slot = next_team_slot(team_battle);
+        }
+
+        Ok(())
+    }
+
+    // Anyone can call it: the side whose character didn't act (request and
+    // execute) within turn_timeout_seconds forfeits
+    pub fn check_team_timeout(ctx: Context<CheckTeamTimeout>) -> Result<()> {
+        let team_key = ctx.accounts.team_battle.key();
+        let team_battle = &mut ctx.accounts.team_battle;
+        let clock = Clock::get()?;
+
+        require!(!team_battle.is_finished, GameError::BattleAlreadyFinished);
+
+        if team_turn_timed_out(team_battle, &ctx.accounts.config.settings, &clock) {
+            let stalled_side = team_side(team_battle.current_slot);
+            end_team_battle(team_battle, team_key, if stalled_side == 1 { 2 } else { 1 });
+        }
+        Ok(())
+    }
+
+    // Frees all four characters once the team battle is over. Anyone can call it.
+    pub fn finalize_team_battle(ctx: Context<FinalizeTeamBattle>) -> Result<()> {
+        let team_key = ctx.accounts.team_battle.key();
+        require!(ctx.accounts.team_battle.is_finished, GameError::BattleNotFinished);
+
+        for member in [
+            &mut ctx.accounts.side_a_character1,
+            &mut ctx.accounts.side_a_character2,
+            &mut ctx.accounts.side_b_character1,
+            &mut ctx.accounts.side_b_character2,
+        ] {
+            release_character(member, team_key);
+        }
+        Ok(())
+    }
+
     // Finalize battle and distribute rewards
     pub fn finalize_battle(ctx: Context<FinalizeBattle>) -> Result<()> {
-        let battle = &ctx.accounts.battle;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = &ctx.accounts.battle.load()?;
         let player1_char = &mut ctx.accounts.player1_character;
         let player2_char = &mut ctx.accounts.player2_character;
+        let settings = &ctx.accounts.config.settings;
+        let clock = Clock::get()?;
+
+        require!(battle.is_finished != 0, GameError::BattleNotFinished);
+
+        player1_char.last_battle = clock.unix_timestamp;
+        if battle.is_vs_ai == 0 {
+            player2_char.last_battle = clock.unix_timestamp;
+        }
+        release_character(player1_char, battle_key);
+        release_character(player2_char, battle_key);
+
+        if finish_practice_battle(battle, player1_char, player2_char) {
+            msg!("Practice battle finished, no stats recorded");
+            return Ok(());
+        }
+
+        // First battle of a new season clears the last one's record
+        for character in [&mut *player1_char, &mut *player2_char] {
+            if roll_over_season(character, ctx.accounts.config.current_season) {
+                update_rank_tier(character);
+            }
+        }
+
+        update_combo_stats(player1_char, battle.player1_peak_combo);
+        update_combo_stats(player2_char, battle.player2_peak_combo);
+
+        // Settle the battle's betting pool inline when it's passed in and
+        // nobody has settled it yet
+        if let Some(pool) = ctx.accounts.betting_pool.as_mut() {
+            settle_pool_if_open(pool, battle, &mut ctx.accounts.treasury, &clock)?;
+        }
+
+        // Stakes of a forfeited battle were already paid out by check_timeout
+        let pay_stakes = battle.stake_amount > 0 && battle.abandoned == 0;
+        let escrow = &ctx.accounts.escrow;
+
+        // A draw gives no XP, leaves win streaks as they were and hands each
+        // side its own stake back
+        if battle.is_draw != 0 {
+            if pay_stakes {
+                pay_from_escrow(escrow, &ctx.accounts.player1_owner, battle.stake_amount)?;
+
+                if battle.is_vs_ai == 0 {
+                    pay_from_escrow(escrow, &ctx.accounts.player2_owner, battle.stake_amount)?;
+                }
+            }
+
+            msg!("Battle ended in a draw, stakes returned");
+            return Ok(());
+        }
 
-        require!(battle.is_finished, GameError::BattleNotFinished);
-        require!(battle.winner.is_some(), GameError::NoWinner);
+        require!(battle.winner != 0, GameError::NoWinner);
 
-        let winner_is_player1 = battle.winner.unwrap() == 1;
+        let winner_is_player1 = battle.winner == 1;
+        let match_type = MatchType::from_code(battle.match_type);
+        let pool = mmr_pool(match_type, battle.is_vs_ai != 0);
+        let (winner_mmr, loser_mmr) = match pool {
+            Some(pool) if winner_is_player1 => (pool.rating(player1_char), pool.rating(player2_char)),
+            Some(pool) => (pool.rating(player2_char), pool.rating(player1_char)),
+            None => (0, 0),
+        };
+        let (winner_k, loser_k) = if winner_is_player1 {
+            (mmr_k_factor(player1_char, match_type, settings), mmr_k_factor(player2_char, match_type, settings))
+        } else {
+            (mmr_k_factor(player2_char, match_type, settings), mmr_k_factor(player1_char, match_type, settings))
+        };
+        let (mmr_gain, mmr_loss) = match pool {
+            Some(_) => (
+                compute_mmr_delta(winner_mmr, loser_mmr, winner_k),
+                compute_mmr_delta(winner_mmr, loser_mmr, loser_k),
+            ),
+            None => (0, 0), // AI battles move no rating
+        };
+        let levels_before = [player1_char.level, player2_char.level];
+        let tiers_before = [player1_char.rank_tier, player2_char.rank_tier];
 
         // Calculate XP reward
-        let level_diff = (player1_char.level as i32 - player2_char.level as i32).abs() as u64;
-        let base_xp = match battle.match_type {
+        let level_diff = player1_char.level.abs_diff(player2_char.level) as u64;
+        let base_xp = match match_type {
             MatchType::Casual => 50,
             MatchType::Ranked => 100,
             MatchType::Tournament => 200,
             MatchType::Staked => 150,
+            MatchType::Practice => 0,
         };
 
-        let xp_bonus = if level_diff > 5 { 50 } else { level_diff * 10 };
-        let total_xp = base_xp + xp_bonus;
+        let total_xp = winner_xp(base_xp, level_diff, settings);
+
+        let loser_xp = consolation_xp(battle, base_xp, settings);
 
         // Update winner stats
         if winner_is_player1 {
-            update_winner_stats(player1_char, total_xp, level_diff)?;
-            update_loser_stats(player2_char, level_diff)?;
+            let flawless = battle.player1_hp == battle.player1_max_hp;
+            let xp_gained = update_winner_stats(player1_char, total_xp, pool, mmr_gain, flawless, battle.abandoned == 0)?;
+            update_loser_stats(player2_char, loser_xp, pool, mmr_loss)?;
 
             // Transfer stakes to winner
-            if battle.stake_amount > 0 {
-                **battle.to_account_info().try_borrow_mut_lamports()? -= battle.stake_amount * 2;
-                **ctx.accounts.player1_owner.to_account_info().try_borrow_mut_lamports()? += battle.stake_amount * 2;
+            if pay_stakes {
+                pay_from_escrow(escrow, &ctx.accounts.player1_owner, escrowed_stake(battle))?;
             }
 
             emit!(BattleFinalized {
-                battle: battle.key(),
+                battle: battle_key,
                 winner: battle.player1,
                 loser: battle.player2,
-                xp_gained: total_xp,
+                xp_gained,
+                loser_xp,
             });
         } else {
-            update_winner_stats(player2_char, total_xp, level_diff)?;
-            update_loser_stats(player1_char, level_diff)?;
-
-            if battle.stake_amount > 0 && !battle.is_vs_ai {
-                **battle.to_account_info().try_borrow_mut_lamports()? -= battle.stake_amount * 2;
-                **ctx.accounts.player2_owner.to_account_info().try_borrow_mut_lamports()? += battle.stake_amount * 2;
-            }
+            let flawless = battle.player2_hp == battle.player2_max_hp;
+            let xp_gained = update_winner_stats(player2_char, total_xp, pool, mmr_gain, flawless, battle.abandoned == 0)?;
+            update_loser_stats(player1_char, loser_xp, pool, mmr_loss)?;
+
+            if pay_stakes {
+                if battle.is_vs_ai != 0 {
+                    pay_ai_win_stake(
+                        escrow,
+                        &mut ctx.accounts.treasury,
+                        &ctx.accounts.player1_owner,
+                        settings,
+                        escrowed_stake(battle),
+                    )?;
+                } else {
+                    pay_from_escrow(escrow, &ctx.accounts.player2_owner, escrowed_stake(battle))?;
+                }
+            }
 
             emit!(BattleFinalized {
-                battle: battle.key(),
+                battle: battle_key,
                 winner: battle.player2,
                 loser: battle.player1,
-                xp_gained: total_xp,
+                xp_gained,
+                loser_xp,
             });
         }
 
+        // A tier change already emitted the update from update_rank_tier
+        for (i, character) in [&mut *player1_char, &mut *player2_char].into_iter().enumerate() {
+            if character.level != levels_before[i] && character.rank_tier == tiers_before[i] {
+                emit_metadata_update(character);
+            }
+        }
+
+        // Ranked PvP wins count for the winner's guild when it's passed in
+        if let Some(guild) = ctx.accounts.winner_guild.as_mut() {
+            let winner_guild = if winner_is_player1 { player1_char.guild } else { player2_char.guild };
+            require!(winner_guild == Some(guild.key()), GameError::NotGuildMember);
+
+            if MatchType::from_code(battle.match_type) == MatchType::Ranked && battle.is_vs_ai == 0 {
+                guild.total_guild_wins += 1;
+            }
+        }
+
+        let leaderboard = &mut ctx.accounts.leaderboard;
+        update_leaderboard(leaderboard, player1_char);
+        if battle.is_vs_ai == 0 {
+            update_leaderboard(leaderboard, player2_char);
+        }
+
         Ok(())
     }
 
-    // Heal character (costs SOL)
-    pub fn heal_character(ctx: Context<HealCharacter>) -> Result<()> {
+    // Create a betting pool for one market on a battle, each market is its own
+    // two-sided pool. house_edge and limits default to the config's, the
+    // creator may pick a house edge up to MAX_HOUSE_EDGE
+    pub fn create_betting_pool(
+        ctx: Context<CreateBettingPool>,
+        house_edge: Option<u8>,
+        odds_mode: OddsMode,
+        limits: Option<BetLimits>,
+        market: BetMarket,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.betting_pool;
+        let battle = &ctx.accounts.battle.load()?;
+        let clock = Clock::get()?;
+
+        require!(battle.is_finished == 0, GameError::BattleAlreadyFinished);
         require!(
-            ctx.accounts.character.current_hp < ctx.accounts.character.max_hp,
-            GameError::AlreadyFullHealth
+            MatchType::from_code(battle.match_type) != MatchType::Practice,
+            GameError::NoBettingOnPractice
         );
+        let house_edge = pool_house_edge(house_edge, &ctx.accounts.config.settings)?;
+        let limits = limits.unwrap_or(ctx.accounts.config.settings.bet_limits);
+        require!(limits.is_valid(), GameError::InvalidBetLimits);
+
+        pool.battle = ctx.accounts.battle.key();
+        pool.total_pool = 0;
+        pool.player1_bets = 0;
+        pool.player2_bets = 0;
+        pool.house_edge = house_edge;
+        pool.odds_mode = odds_mode;
+        pool.market = market;
+        pool.player1_liability = 0;
+        pool.player2_liability = 0;
+        pool.is_settled = false;
+        pool.winner = None;
+        pool.created_at = clock.unix_timestamp;
+        pool.total_claimed = 0;
+        pool.claimed_winning_bets = 0;
+        pool.refund_mode = false;
+        pool.min_bet = limits.min_bet;
+        pool.max_bet_per_wallet = limits.max_bet_per_wallet;
+        pool.max_pool_size = limits.max_pool_size;
+        pool.max_side_total = MAX_SIDE_BETS_LAMPORTS;
+        pool.max_exposure = MAX_FIXED_ODDS_EXPOSURE_LAMPORTS;
+        pool.betting_closes_at_turn = ctx.accounts.config.settings.betting_closes_at_turn;
+        pool.betting_closed_announced = false;
+        // A line the battle has already reached by the cutoff is settled while bets are open
+        if let BetMarket::TotalTurns { line } = market {
+            require!(line >= pool.betting_closes_at_turn, GameError::InvalidMarketLine);
+        }
+        pool.settled_at = 0;
+
+        // Calculate initial odds based on character stats, propositions start even
+        let (player1_score, player2_score) = if market == BetMarket::Winner {
+            (
+                calculate_betting_score(&ctx.accounts.player1_character, battle.player1_hp),
+                calculate_betting_score(&ctx.accounts.player2_character, battle.player2_hp),
+            )
+        } else {
+            (0, 0)
+        };
+        (pool.player1_odds, pool.player2_odds) = opening_odds(player1_score, player2_score);
+
+        emit!(BettingPoolCreated {
+            betting_pool: pool.key(),
+            battle: pool.battle,
+            house_edge,
+            odds_mode,
+            market,
+            min_bet: pool.min_bet,
+            max_bet_per_wallet: pool.max_bet_per_wallet,
+            max_pool_size: pool.max_pool_size,
+        });
+
+        msg!("Betting pool created. Odds - P1: {}%, P2: {}%", pool.player1_odds, pool.player2_odds);
+        Ok(())
+    }
+
+    // Place a bet on a battle
+    // bet_index is any index the bettor hasn't used on this pool yet, so one
+    // wallet can hold several bets (adding to a side, or hedging the other)
+    pub fn place_bet(
+        ctx: Context<PlaceBet>,
+        amount: u64,
+        bet_on_player: u8,
+        bet_index: u32,
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(bet_on_player == 1 || bet_on_player == 2, GameError::InvalidBetTarget);
+        require!(amount > 0, GameError::InvalidBetAmount);
+
+        {
+            let battle = ctx.accounts.battle.load()?;
+            require!(battle.is_finished == 0, GameError::BattleAlreadyFinished);
+            require!(!betting_has_closed(&battle, &ctx.accounts.betting_pool), GameError::BettingClosed);
+        }
+        require!(!ctx.accounts.betting_pool.is_settled, GameError::PoolAlreadySettled);
 
-        let heal_cost = 1_000_000; // 0.001 SOL per heal
+        check_bet_limits(&ctx.accounts.betting_pool, ctx.accounts.bet_tally.total, amount, bet_on_player)?;
 
         let cpi_context = CpiContext::new(
             ctx.accounts.system_program.to_account_info(),
             system_program::Transfer {
-                from: ctx.accounts.owner.to_account_info(),
-                to: ctx.accounts.game_treasury.to_account_info(),
+                from: ctx.accounts.bettor.to_account_info(),
+                to: ctx.accounts.betting_pool.to_account_info(),
             },
         );
-        system_program::transfer(cpi_context, heal_cost)?;
-
-        let character = &mut ctx.accounts.character;
-        character.current_hp = character.max_hp;
-
-        emit!(CharacterHealed {
-            character: character.key(),
-            owner: character.owner,
+        system_program::transfer(cpi_context, amount)?;
+
+        ctx.accounts.bet_tally.total += amount;
+
+        let pool = &mut ctx.accounts.betting_pool;
+        let bet = &mut ctx.accounts.bet;
+
+        bet.bettor = ctx.accounts.bettor.key();
+        bet.betting_pool = pool.key();
+        bet.amount = amount;
+        bet.bet_on_player = bet_on_player;
+        bet.is_claimed = false;
+        bet.index = bet_index;
+        bet.locked_odds = book_bet(pool, amount, bet_on_player)?;
+
+        emit!(OddsLocked {
+            betting_pool: pool.key(),
+            bettor: bet.bettor,
+            amount,
+            bet_on_player,
+            odds: bet.locked_odds,
+            odds_mode: pool.odds_mode,
         });
 
-        msg!("{} fully healed!", character.name);
+        msg!("Bet placed: {} lamports on Player {}", amount, bet_on_player);
         Ok(())
     }
 
-    // Create tournament
-    pub fn create_tournament(
-        ctx: Context<CreateTournament>,
-        entry_fee: u64,
-        prize_pool: u64,
-        max_players: u8,
-    ) -> Result<()> {
-        let tournament = &mut ctx.accounts.tournament;
-        let clock = Clock::get()?;
-
-        tournament.creator = ctx.accounts.creator.key();
-        tournament.entry_fee = entry_fee;
-        tournament.prize_pool = prize_pool;
-        tournament.max_players = max_players;
-        tournament.current_players = 0;
-        tournament.status = TournamentStatus::Registration;
-        tournament.created_at = clock.unix_timestamp;
-        tournament.participants = vec![];
-        tournament.current_round = 0;
-        tournament.winner = None;
+    // One per wallet per pool, tracks the wallet's total across its bets for
+    // max_bet_per_wallet. Bundle it ahead of the first place_bet.
+    pub fn open_bet_tally(ctx: Context<OpenBetTally>) -> Result<()> {
+        require!(!ctx.accounts.betting_pool.is_settled, GameError::PoolAlreadySettled);
 
-        emit!(TournamentCreated {
-            tournament: tournament.key(),
-            creator: tournament.creator,
-            prize_pool,
-            max_players,
-        });
+        let tally = &mut ctx.accounts.bet_tally;
+        tally.bettor = ctx.accounts.bettor.key();
+        tally.betting_pool = ctx.accounts.betting_pool.key();
+        tally.total = 0;
+        tally.bump = ctx.bumps.bet_tally;
+        Ok(())
+    }
 
+    // Reclaim a tally's rent once its pool has settled. Only place_bet reads
+    // the tally, claims and refunds don't need it.
+    pub fn close_bet_tally(ctx: Context<CloseBetTally>) -> Result<()> {
+        // collect_house_edge closes the pool, which is only possible once settled
+        let pool_info = &ctx.accounts.betting_pool;
+        if !pool_info.data_is_empty() {
+            let pool = BettingPool::try_deserialize(&mut &pool_info.try_borrow_data()?[..])?;
+            require!(pool.is_settled, GameError::PoolNotSettled);
+        }
         Ok(())
     }
-}
 
-// Helper functions
-fn simple_random(timestamp: i64, seed1: u64, seed2: u64) -> u8 {
-    let combined = timestamp as u64 ^ seed1 ^ seed2;
-    ((combined >> 8) ^ (combined >> 16) ^ (combined >> 24)) as u8
-}
+    // Cancel a parimutuel bet and get the stake back before the battle starts
+    pub fn cancel_bet(ctx: Context<CancelBet>) -> Result<()> {
+        let battle = &ctx.accounts.battle.load()?;
+        let pool = &mut ctx.accounts.betting_pool;
+        let bet = &ctx.accounts.bet;
+
+        require!(battle.turn_number == 0, GameError::BettingClosed);
+        require!(!pool.is_settled, GameError::PoolAlreadySettled);
+        // Locked odds were quoted against the pool as it stood, pulling a bet
+        // back out would let a whale skew them and walk away
+        require!(pool.odds_mode != OddsMode::FixedAtBet, GameError::FixedOddsBetFinal);
+
+        let amount = bet.amount;
+        ctx.accounts.bet_tally.total -= amount;
+        pool.total_pool -= amount;
+        if bet.bet_on_player == 1 {
+            pool.player1_bets -= amount;
+        } else {
+            pool.player2_bets -= amount;
+        }
+        recalculate_pool_odds(pool);
 
-fn check_battle_timeout(battle: &Battle, clock: &Clock) -> Result<()> {
-    let time_since_creation = clock.unix_timestamp - battle.created_at;
-    require!(
-        time_since_creation < BATTLE_EXPIRY_SECONDS,
-        GameError::BattleExpired
-    );
-    Ok(())
-}
+        **pool.to_account_info().try_borrow_mut_lamports()? -= amount;
+        **ctx.accounts.bettor.to_account_info().try_borrow_mut_lamports()? += amount;
 
-fn requires_decision(wildcard: WildcardEvent) -> bool {
-    matches!(
-        wildcard,
-        WildcardEvent::DoubleOrNothing | WildcardEvent::DeathRoulette
-    )
-}
+        emit!(BetCancelled {
+            betting_pool: pool.key(),
+            bettor: bet.bettor,
+            amount,
+            player1_odds: pool.player1_odds,
+            player2_odds: pool.player2_odds,
+        });
 
-fn log_battle_event(battle: &mut Battle, event: String) {
-    if battle.battle_log.len() < 50 {
-        battle.battle_log.push(event);
+        msg!("Bet cancelled: {} lamports refunded", amount);
+        Ok(())
     }
-}
-
-fn execute_battle_turn(
-    battle: &mut Battle,
-    attacker: &Character,
-    defender: &Character,
-    is_player1: bool,
-    use_special: bool,
-    clock: &Clock,
-) -> Result<()> {
-    let mut damage = calculate_damage(
-        attacker,
-        defender,
-        battle,
-        is_player1,
-        use_special,
-        clock.unix_timestamp,
-    )?;
 
-    let (attacker_stance, defender_stance) = if is_player1 {
-        (battle.player1_stance, battle.player2_stance)
-    } else {
-        (battle.player2_stance, battle.player1_stance)
-    };
+    // Crank: announce that a pool has stopped taking bets. Each pool keeps the
+    // cutoff turn it was created with, so the announcement is per pool.
+    pub fn announce_betting_closed(ctx: Context<AnnounceBettingClosed>) -> Result<()> {
+        let battle = ctx.accounts.battle.load()?;
+        let pool = &mut ctx.accounts.betting_pool;
 
-    damage = apply_stance_modifiers(damage, attacker_stance, defender_stance, is_player1, battle);
+        require!(!pool.betting_closed_announced, GameError::BettingClosed);
+        require!(betting_has_closed(&battle, pool), GameError::BettingStillOpen);
+        pool.betting_closed_announced = true;
 
-    if battle.wildcard_active && battle.wildcard_type.is_some() {
-        damage = apply_wildcard_effects(damage, battle, is_player1, clock.unix_timestamp)?;
+        emit!(BettingClosed {
+            battle: pool.battle,
+            betting_pool: pool.key(),
+            turn_number: battle.turn_number,
+        });
+        Ok(())
     }
 
-    // Apply damage
-    if is_player1 {
-        battle.player2_hp = battle.player2_hp.saturating_sub(damage);
-        
-        if battle.player1_reflection > 0 {
-            let reflected = (damage * battle.player1_reflection as u64) / 100;
-            battle.player1_hp = battle.player1_hp.saturating_sub(reflected);
-            log_battle_event(battle, format!("Player 1 takes {} reflected damage", reflected));
-        }
-    } else {
-        battle.player1_hp = battle.player1_hp.saturating_sub(damage);
-        
-        if battle.player2_reflection > 0 {
-            let reflected = (damage * battle.player2_reflection as u64) / 100;
-            battle.player2_hp = battle.player2_hp.saturating_sub(reflected);
-            log_battle_event(battle, format!("Player 2 takes {} reflected damage", reflected));
-        }
+    // Settle betting pool after battle. Permissionless, the result comes from
+    // the battle alone. finalize_battle and check_timeout settle inline when
+    // the pool is passed to them.
+    pub fn settle_betting_pool(ctx: Context<SettleBettingPool>) -> Result<()> {
+        let battle = &ctx.accounts.battle.load()?;
+        let clock = Clock::get()?;
+        settle_pool(&mut ctx.accounts.betting_pool, battle, &mut ctx.accounts.treasury, &clock)
     }
 
-    log_battle_event(battle, format!("Damage dealt: {}", damage));
+    // Void a pool whose battle can never produce a winner: it expired unfinished,
+    // or never started within BETTING_START_WINDOW_SECONDS. Anyone can call it.
+    // Bettors then recover their stakes through claim_bet_refund.
+    pub fn void_betting_pool(ctx: Context<VoidBettingPool>) -> Result<()> {
+        let pool = &mut ctx.accounts.betting_pool;
+        let battle = &ctx.accounts.battle.load()?;
+        let clock = Clock::get()?;
 
-    // Set special cooldown
-    if use_special {
-        if is_player1 {
-            battle.player1_special_cooldown = 3; // 3 turn cooldown
-        } else {
-            battle.player2_special_cooldown = 3;
-        }
-    }
+        require!(!pool.is_settled, GameError::PoolAlreadySettled);
+        require!(battle.is_finished == 0, GameError::BattleAlreadyFinished); // settle_betting_pool covers these
 
-    // Reduce cooldowns
-    if is_player1 {
-        battle.player1_special_cooldown = battle.player1_special_cooldown.saturating_sub(1);
-    } else {
-        battle.player2_special_cooldown = battle.player2_special_cooldown.saturating_sub(1);
-    }
+        let reason = void_reason(battle, &ctx.accounts.config.settings, &clock)?;
 
-    // Check for battle end
-    if battle.player1_hp == 0 || battle.player2_hp == 0 {
-        battle.is_finished = true;
-        battle.winner = if battle.player1_hp > 0 { Some(1) } else { Some(2) };
-        log_battle_event(battle, format!("Battle finished! Winner: Player {}", battle.winner.unwrap()));
+        pool.is_settled = true;
+        pool.settled_at = clock.unix_timestamp;
+        pool.refund_mode = true;
 
-        emit!(BattleEnded {
-            battle: battle.key(),
-            winner: battle.winner.unwrap(),
-            total_turns: battle.turn_number,
+        emit!(PoolVoided {
+            betting_pool: pool.key(),
+            battle: pool.battle,
+            reason,
         });
+
+        msg!("Betting pool voided ({:?}), bets refundable", reason);
+        Ok(())
     }
 
-    // Switch turns
-    battle.current_turn = if battle.current_turn == 1 { 2 } else { 1 };
-    battle.turn_number += 1;
-    battle.wildcard_active = false;
+    // Permissionless once bet_claim_window_seconds have passed since settlement:
+    // whatever nobody claimed, rounding dust included, goes to the Treasury and
+    // the pool is closed. Bets still unclaimed at that point are forfeit.
+    pub fn collect_house_edge(ctx: Context<CollectHouseEdge>) -> Result<()> {
+        let pool = &ctx.accounts.betting_pool;
+        let clock = Clock::get()?;
 
-    Ok(())
-}
+        require!(pool.is_settled, GameError::PoolNotSettled);
+        require!(
+            clock.unix_timestamp >= pool.settled_at + ctx.accounts.config.settings.bet_claim_window_seconds,
+            GameError::ClaimWindowOpen
+        );
 
-// Continuation of the smart contract - Part 2
+        let pool_info = pool.to_account_info();
+        let rent = Rent::get()?.minimum_balance(pool_info.data_len());
+        let amount = pool_info.lamports().saturating_sub(rent);
+        ctx.accounts.treasury.lifetime_fees_collected += amount;
 
-fn resolve_wildcard_with_decisions(battle: &mut Battle, clock: &Clock) -> Result<()> {
-    let p1_accepts = battle.wildcard_player1_decision.unwrap_or(false);
-    let p2_accepts = battle.wildcard_player2_decision.unwrap_or(false);
+        emit!(HouseEdgeCollected {
+            betting_pool: pool.key(),
+            amount,
+        });
 
-    if let Some(wildcard) = battle.wildcard_type {
-        match wildcard {
-            WildcardEvent::DoubleOrNothing => {
-                if p1_accepts && p2_accepts {
-                    let roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 7) % 2;
-                    if roll == 0 {
-                        // Both miss next attack
-                        log_battle_event(battle, "Double or Nothing: Both MISS next turn!".to_string());
-                    } else {
-                        // Both get double damage next turn
-                        battle.player1_combo += 2;
-                        battle.player2_combo += 2;
-                        log_battle_event(battle, "Double or Nothing: Both get DOUBLE damage!".to_string());
-                    }
-                } else if p1_accepts {
-                    // Only P1 risks
-                    let roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 7) % 2;
-                    if roll == 0 {
-                        battle.player1_miss_count += 1;
-                        log_battle_event(battle, "P1 Double or Nothing: MISS!".to_string());
-                    } else {
-                        battle.player1_combo += 3;
-                        log_battle_event(battle, "P1 Double or Nothing: Triple damage!".to_string());
-                    }
-                } else if p2_accepts {
-                    // Only P2 risks
-                    let roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 8) % 2;
-                    if roll == 0 {
-                        battle.player2_miss_count += 1;
-                        log_battle_event(battle, "P2 Double or Nothing: MISS!".to_string());
-                    } else {
-                        battle.player2_combo += 3;
-                        log_battle_event(battle, "P2 Double or Nothing: Triple damage!".to_string());
-                    }
-                }
-            }
-            WildcardEvent::DeathRoulette => {
-                if p1_accepts && p2_accepts {
-                    let roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 9) % 2;
-                    if roll == 0 {
-                        battle.player1_hp = 1; // Nearly dead
-                        battle.player2_hp = battle.player2_hp.saturating_add(100); // Healed
-                        log_battle_event(battle, "Death Roulette: P1 nearly killed, P2 healed!".to_string());
-                    } else {
-                        battle.player2_hp = 1;
-                        battle.player1_hp = battle.player1_hp.saturating_add(100);
-                        log_battle_event(battle, "Death Roulette: P2 nearly killed, P1 healed!".to_string());
-                    }
-                } else if p1_accepts {
-                    let roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 9) % 2;
-                    if roll == 0 {
-                        battle.player1_hp = 1;
-                        log_battle_event(battle, "P1 Death Roulette: Nearly killed!".to_string());
-                    } else {
-                        battle.player1_hp = 999;
-                        log_battle_event(battle, "P1 Death Roulette: Massive heal!".to_string());
-                    }
-                } else if p2_accepts {
-                    let roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 10) % 2;
-                    if roll == 0 {
-                        battle.player2_hp = 1;
-                        log_battle_event(battle, "P2 Death Roulette: Nearly killed!".to_string());
-                    } else {
-                        battle.player2_hp = 999;
-                        log_battle_event(battle, "P2 Death Roulette: Massive heal!".to_string());
-                    }
-                }
-            }
-            _ => {}
-        }
+        msg!("Collected {} lamports from a settled betting pool", amount);
+        Ok(())
     }
 
-    // Reset wildcard state
-    battle.wildcard_active = false;
-    battle.wildcard_player1_decision = None;
-    battle.wildcard_player2_decision = None;
+    // Claim betting winnings
+    pub fn claim_bet_winnings(ctx: Context<ClaimBetWinnings>) -> Result<()> {
+        let pool = &mut ctx.accounts.betting_pool;
+        let bet = &mut ctx.accounts.bet;
 
-    Ok(())
-}
+        require!(pool.is_settled, GameError::PoolNotSettled);
+        require!(!pool.refund_mode, GameError::PoolInRefundMode);
+        require!(!bet.is_claimed, GameError::AlreadyClaimed);
+        require!(bet.bettor == ctx.accounts.bettor.key(), GameError::NotBetOwner);
 
-fn choose_ai_stance(
-    battle: &Battle,
-    ai_char: &Character,
-    player_char: &Character,
-    clock: &Clock,
-) -> BattleStance {
-    let ai_hp_percent = (battle.player2_hp * 100) / ai_char.max_hp as u64;
-    let player_hp_percent = (battle.player1_hp * 100) / player_char.max_hp as u64;
+        let won = pool.winner == Some(bet.bet_on_player);
+        require!(won, GameError::BetLost);
 
-    // Strategic AI decision making
-    if ai_hp_percent < 30 {
-        // Low HP - play defensive or berserker for desperation
-        if simple_random(clock.unix_timestamp, battle.turn_number as u64, 20) % 2 == 0 {
-            BattleStance::Defensive
+        let winning_pool = if bet.bet_on_player == 1 {
+            pool.player1_bets
         } else {
-            BattleStance::Berserker // All-in
-        }
-    } else if player_hp_percent < 30 {
-        // Player low HP - go aggressive
-        BattleStance::Aggressive
-    } else if battle.player1_stance == BattleStance::Aggressive {
-        // Counter aggressive plays
-        BattleStance::Counter
-    } else {
-        // Default balanced with some randomness
-        let roll = simple_random(clock.unix_timestamp, battle.turn_number as u64, 21) % 5;
-        match roll {
-            0 => BattleStance::Aggressive,
-            1 => BattleStance::Defensive,
-            2 => BattleStance::Counter,
-            3 => BattleStance::Berserker,
-            _ => BattleStance::Balanced,
-        }
-    }
-}
+            pool.player2_bets
+        };
+        require!(winning_pool > 0, GameError::NoWinningBets);
 
-fn update_winner_stats(character: &mut Character, xp: u64, level_diff: u64) -> Result<()> {
-    character.xp += xp;
-    character.total_wins += 1;
-    character.season_wins += 1;
-    character.current_hp = character.max_hp;
+        let winnings = bet_winnings(pool, bet);
+        pool.total_claimed += winnings;
+        pool.claimed_winning_bets += bet.amount;
+        bet.is_claimed = true;
 
-    // Check for achievements
-    check_achievements(character);
+        **pool.to_account_info().try_borrow_mut_lamports()? -= winnings;
+        **ctx.accounts.bettor.to_account_info().try_borrow_mut_lamports()? += winnings;
 
-    // Check for level up
-    let required_xp = get_required_xp(character.level);
-    if character.xp >= required_xp && character.level < 50 {
-        character.level += 1;
-        character.xp -= required_xp;
-        character.max_hp += 5;
-        character.current_hp = character.max_hp;
-        character.base_damage_min += 2;
-        character.base_damage_max += 2;
-        character.crit_chance += 1;
-        character.defense += 1;
-        msg!("{} leveled up to level {}!", character.name, character.level);
+        msg!("Winnings claimed: {} lamports", winnings);
+        Ok(())
     }
 
-    // Update MMR
-    let mmr_gain = 25 + (level_diff * 5);
-    character.mmr += mmr_gain;
-
-    // Update rank tier
-    update_rank_tier(character);
+    // Refund a bet in full when the pool settled in refund mode or was voided.
+    // The Bet account is closed and its rent returned too.
+    pub fn claim_bet_refund(ctx: Context<ClaimBetRefund>) -> Result<()> {
+        let pool = &mut ctx.accounts.betting_pool;
+        let bet = &mut ctx.accounts.bet;
+
+        require!(pool.is_settled, GameError::PoolNotSettled);
+        require!(pool.refund_mode, GameError::PoolNotInRefundMode);
+        require!(!bet.is_claimed, GameError::AlreadyClaimed);
+        require!(bet.bettor == ctx.accounts.bettor.key(), GameError::NotBetOwner);
+
+        // No house cut on a refund, regardless of which side the bet was on
+        let refund = bet.amount;
+        pool.total_claimed += refund;
+        bet.is_claimed = true;
+
+        **pool.to_account_info().try_borrow_mut_lamports()? -= refund;
+        **ctx.accounts.bettor.to_account_info().try_borrow_mut_lamports()? += refund;
+
+        emit!(BetRefunded {
+            betting_pool: pool.key(),
+            bettor: bet.bettor,
+            amount: refund,
+        });
 
-    Ok(())
-}
+        msg!("Bet refunded: {} lamports", refund);
+        Ok(())
+    }
 
-fn update_loser_stats(character: &mut Character, level_diff: u64) -> Result<()> {
-    character.total_losses += 1;
-    character.season_losses += 1;
-    character.current_hp = character.max_hp;
+    // Heal character (costs SOL per HP restored)
+    // amount is capped at the missing HP, 0 heals to full.
+    pub fn heal_character(ctx: Context<HealCharacter>, amount: u64) -> Result<()> {
+        let character = &ctx.accounts.character;
+        let (hp_restored, heal_cost) = heal_quote(character, amount, &ctx.accounts.config.settings)?;
+        let use_credit = character.free_heals > 0;
 
-    // Lose MMR
-    let mmr_loss = 15 + (level_diff * 3);
-    character.mmr = character.mmr.saturating_sub(mmr_loss);
+        let cpi_context = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.owner.to_account_info(),
+                to: ctx.accounts.game_treasury.to_account_info(),
+            },
+        );
+        system_program::transfer(cpi_context, heal_cost)?;
+        ctx.accounts.game_treasury.lifetime_fees_collected += heal_cost;
 
-    // Update rank tier
-    update_rank_tier(character);
+        let character = &mut ctx.accounts.character;
+        character.current_hp += hp_restored;
+        if use_credit {
+            character.free_heals -= 1;
+        }
 
-    Ok(())
-}
+        emit!(CharacterHealed {
+            character: character.key(),
+            owner: character.owner,
+            hp_restored,
+            lamports_paid: heal_cost,
+        });
 
-fn check_achievements(character: &mut Character) {
-    // First win
-    if character.total_wins == 1 && !character.achievements.contains(&Achievement::FirstWin) {
-        character.achievements.push(Achievement::FirstWin);
+        msg!("{} healed for {} HP", character.name, hp_restored);
+        Ok(())
     }
-    
-    // 10 wins
-    if character.total_wins == 10 && !character.achievements.contains(&Achievement::TenWins) {
-        character.achievements.push(Achievement::TenWins);
-    }
-    
-    // 100 wins
-    if character.total_wins == 100 && !character.achievements.contains(&Achievement::HundredWins) {
-        character.achievements.push(Achievement::HundredWins);
-    }
-    
-    // Flawless (if max HP still)
-    if character.current_hp == character.max_hp && !character.achievements.contains(&Achievement::Flawless) {
-        character.achievements.push(Achievement::Flawless);
-    }
-}
-
-fn update_rank_tier(character: &mut Character) {
-    character.rank_tier = match character.mmr {
-        0..=999 => RankTier::Bronze,
-        1000..=1499 => RankTier::Silver,
-        1500..=1999 => RankTier::Gold,
-        2000..=2499 => RankTier::Platinum,
-        2500..=2999 => RankTier::Diamond,
-        _ => RankTier::Master,
-    };
-}
 
-fn calculate_damage(
-    attacker: &Character,
-    defender: &Character,
-    battle: &Battle,
-    is_player1: bool,
-    use_special: bool,
-    timestamp: i64,
-) -> Result<u64> {
-    let mut damage: u64;
+    // Once per 24h, a small XP reward that grows with a daily streak. Missing
+    // more than 48h since the last claim starts the streak over.
+    pub fn claim_daily_reward(ctx: Context<ClaimDailyReward>) -> Result<()> {
+        let character = &mut ctx.accounts.character;
+        let clock = Clock::get()?;
 
-    let damage_range = attacker.base_damage_max - attacker.base_damage_min;
-    let roll = simple_random(timestamp, battle.turn_number as u64, 3) as u64;
-    let base_damage = attacker.base_damage_min as u64 + (roll % (damage_range as u64 + 1));
+        let xp = record_daily_claim(character, &clock)?;
+        let level_before = character.level;
+        grant_xp(character, xp);
+        if character.level != level_before {
+            emit_metadata_update(character);
+        }
 
-    let level_bonus = (attacker.level as u64 - 1) * 2;
-    damage = base_damage + level_bonus;
+        emit!(DailyRewardClaimed {
+            character: character.key(),
+            streak: character.daily_streak,
+            xp_gained: xp,
+        });
+        Ok(())
+    }
 
-    // Check for critical hit
-    let crit_roll = simple_random(timestamp, battle.turn_number as u64, 4) % 100;
-    let mut crit_chance = attacker.crit_chance as u64;
+    // One-time reward for an earned achievement. Only some achievements have
+    // one, claimed_achievement_rewards keeps each from paying out twice.
+    pub fn claim_achievement_reward(ctx: Context<ClaimAchievementReward>, achievement: Achievement) -> Result<()> {
+        let character = &mut ctx.accounts.character;
 
-    // Gambler's Fallacy effect
-    if battle.wildcard_type == Some(WildcardEvent::GamblersFallacy) {
-        let miss_count = if is_player1 { battle.player1_miss_count } else { battle.player2_miss_count };
-        crit_chance += miss_count as u64 * 5;
-    }
+        require!(character.achievements.contains(&achievement), GameError::AchievementNotEarned);
+        require!(
+            character.claimed_achievement_rewards & achievement.bit() == 0,
+            GameError::RewardAlreadyClaimed
+        );
 
-    let is_crit = (crit_roll as u64) < crit_chance;
-    if is_crit {
-        damage = match attacker.character_class {
-            CharacterClass::Warrior => damage * 2,
-            CharacterClass::Assassin => damage * 3,
-            CharacterClass::Mage => damage * 2,
-            CharacterClass::Tank => damage * 2,
-            CharacterClass::Trickster => {
-                // Trickster crits can trigger additional effects
-                damage * 2 + 20 // Extra flat damage
+        match achievement {
+            Achievement::FirstWin => {
+                let level_before = character.level;
+                grant_xp(character, FIRST_WIN_REWARD_XP);
+                if character.level != level_before {
+                    emit_metadata_update(character);
+                }
             }
-        };
-        
-        // Instant kill check
-        let defender_hp = if is_player1 { battle.player2_hp } else { battle.player1_hp };
-        let defender_max_hp = defender.max_hp as u64;
-        if defender_hp < (defender_max_hp * 20) / 100 {
-            let instant_kill_roll = simple_random(timestamp, battle.turn_number as u64, 5) % 100;
-            if instant_kill_roll < 5 {
-                damage = defender_hp;
-                msg!("INSTANT KILL!");
+            Achievement::TenWins => character.free_heals += 1,
+            Achievement::HundredWins => {
+                character.crit_chance = character.crit_chance.saturating_add(HUNDRED_WINS_CRIT_BONUS);
+            }
+            Achievement::TournamentWinner => character.title = CHAMPION_TITLE.to_string(),
+            Achievement::ComboMaster => character.cosmetic_flags |= COSMETIC_COMBO_AURA,
+            Achievement::Flawless
+            | Achievement::Prestiged
+            | Achievement::StreakMaster
+            | Achievement::StreakFive => {
+                return err!(GameError::NoAchievementReward);
             }
         }
-    }
+        character.claimed_achievement_rewards |= achievement.bit();
 
-    // Apply combo bonus
-    let combo = if is_player1 { battle.player1_combo } else { battle.player2_combo };
-    if combo > 0 {
-        let combo_bonus = (damage * 15 * combo as u64) / 100;
-        damage += combo_bonus;
+        emit!(AchievementRewardClaimed {
+            character: character.key(),
+            achievement,
+        });
+        Ok(())
     }
 
-    // Special moves
-    if use_special {
-        damage = match attacker.character_class {
-            CharacterClass::Warrior => damage * 2, // Berserker Rage
-            CharacterClass::Assassin => damage * 3, // Shadow Strike
-            CharacterClass::Mage => {
-                // Arcane Burst - apply DOT
-                if is_player1 {
-                    battle.player2_dot_damage = 15;
-                    battle.player2_dot_turns = 3;
-                } else {
-                    battle.player1_dot_damage = 15;
-                    battle.player1_dot_turns = 3;
-                }
-                damage * 2
-            }
-            CharacterClass::Tank => {
-                // Fortress Stance - massive defense boost
-                if is_player1 {
-                    battle.player1_reflection = 50;
-                } else {
-                    battle.player2_reflection = 50;
-                }
-                damage
-            }
-            CharacterClass::Trickster => {
-                // Wild Card special: Random powerful effect
-                let effect_roll = simple_random(timestamp, battle.turn_number as u64, 11) % 4;
-                match effect_roll {
-                    0 => {
-                        // Steal combo
-                        if is_player1 {
-                            let stolen = battle.player2_combo;
-                            battle.player1_combo += stolen;
-                            battle.player2_combo = 0;
-                        } else {
-                            let stolen = battle.player1_combo;
-                            battle.player2_combo += stolen;
-                            battle.player1_combo = 0;
-                        }
-                        damage * 2
-                    }
-                    1 => {
-                        // Confusion: swap stances
-                        let temp = battle.player1_stance;
-                        battle.player1_stance = battle.player2_stance;
-                        battle.player2_stance = temp;
-                        damage * 2
-                    }
-                    2 => {
-                        // Evasion: high dodge chance next turn
-                        damage * 3
-                    }
-                    _ => {
-                        // Trigger extra wildcard
-                        battle.wildcard_active = true;
-                        damage * 2
-                    }
-                }
-            }
-        };
-        msg!("Special move used!");
+    // Admin mint or drop of a piece of gear to a player
+    pub fn mint_equipment(
+        ctx: Context<MintEquipment>,
+        recipient: Pubkey,
+        slot: EquipmentSlot,
+        stats: EquipmentStats,
+    ) -> Result<()> {
+        require!(stats.is_valid(), GameError::InvalidEquipmentStats);
+
+        let equipment = &mut ctx.accounts.equipment;
+        equipment.owner = recipient;
+        equipment.slot = slot;
+        equipment.stats = stats;
+        equipment.equipped_to = None;
+
+        emit!(EquipmentMinted {
+            equipment: equipment.key(),
+            owner: recipient,
+            slot,
+        });
+        Ok(())
     }
 
-    // Apply defense
-    let defense_reduction = defender.defense as u64;
-    damage = damage.saturating_sub(defense_reduction);
+    // Battles use the stats snapshotted at creation, so gear changes only
+    // count from the next battle
+    pub fn equip_item(ctx: Context<EquipItem>) -> Result<()> {
+        let equipment_key = ctx.accounts.equipment.key();
+        let character_key = ctx.accounts.character.key();
+        let equipment = &mut ctx.accounts.equipment;
+        let character = &mut ctx.accounts.character;
 
-    // Check for dodge
-    let dodge_roll = simple_random(timestamp, battle.turn_number as u64, 6) % 100;
-    if (dodge_roll as u64) < defender.dodge_chance as u64 {
-        damage = 0;
-        msg!("Attack dodged!");
+        equip_gear(character, character_key, equipment, equipment_key)?;
+
+        emit!(EquipmentChanged {
+            character: character.key(),
+            equipment: equipment.key(),
+            slot: equipment.slot,
+            equipped: true,
+        });
+        Ok(())
     }
 
-    Ok(damage)
-}
+    pub fn unequip_item(ctx: Context<UnequipItem>) -> Result<()> {
+        let equipment_key = ctx.accounts.equipment.key();
+        let equipment = &mut ctx.accounts.equipment;
+        let character = &mut ctx.accounts.character;
 
-fn apply_stance_modifiers(
-    mut damage: u64,
-    attacker_stance: BattleStance,
-    defender_stance: BattleStance,
-    is_player1: bool,
-    battle: &mut Battle,
-) -> u64 {
-    match attacker_stance {
-        BattleStance::Aggressive => {
-            damage = (damage * 130) / 100;
-        }
-        BattleStance::Defensive => {
-            damage = (damage * 70) / 100;
-        }
-        BattleStance::Berserker => {
-            damage = damage * 2;
-            let self_damage = (damage * 25) / 100;
-            if is_player1 {
-                battle.player1_hp = battle.player1_hp.saturating_sub(self_damage);
-            } else {
-                battle.player2_hp = battle.player2_hp.saturating_sub(self_damage);
-            }
-        }
-        BattleStance::Counter => {
-            if defender_stance == BattleStance::Aggressive {
-                damage = (damage * 150) / 100;
-            } else {
-                damage = 0;
-            }
-        }
-        BattleStance::Balanced => {}
+        unequip_gear(character, equipment, equipment_key)?;
+
+        emit!(EquipmentChanged {
+            character: character.key(),
+            equipment: equipment.key(),
+            slot: equipment.slot,
+            equipped: false,
+        });
+        Ok(())
     }
 
-    match defender_stance {
-        BattleStance::Defensive => {
-            damage = (damage * 50) / 100;
-        }
-        BattleStance::Aggressive => {
-            damage = (damage * 150) / 100;
-        }
-        _ => {}
+    // One-time setup of a character's consumable inventory
+    pub fn create_item_inventory(ctx: Context<CreateItemInventory>) -> Result<()> {
+        let inventory = &mut ctx.accounts.inventory;
+        inventory.character = ctx.accounts.character.key();
+        inventory.counts = [0; 3];
+        inventory.bump = ctx.bumps.inventory;
+        Ok(())
     }
 
-    damage
-}
+    // Buy consumables, paid into the treasury
+    pub fn buy_item(ctx: Context<BuyItem>, item: BattleItem, quantity: u16) -> Result<()> {
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(quantity > 0, GameError::InvalidItemQuantity);
 
-fn apply_wildcard_effects(
-    mut damage: u64,
-    battle: &mut Battle,
-    is_player1: bool,
-    timestamp: i64,
-) -> Result<u64> {
-    if let Some(wildcard) = battle.wildcard_type {
-        match wildcard {
-            WildcardEvent::ReverseRoles => {
-                let p1_percent = (battle.player1_hp * 100) / battle.player1_hp.max(1);
-                let p2_percent = (battle.player2_hp * 100) / battle.player2_hp.max(1);
-                
-                let temp = battle.player1_hp;
-                battle.player1_hp = (battle.player1_hp * p2_percent) / 100;
-                battle.player2_hp = (temp * p1_percent) / 100;
-                msg!("Reverse Roles: HP swapped!");
-            }
-            WildcardEvent::MysteryBox => {
-                let buff_roll = simple_random(timestamp, battle.turn_number as u64, 8) % 4;
-                match buff_roll {
-                    0 => {
-                        damage *= 3;
-                        msg!("Mystery Box: Triple damage!");
-                    }
-                    1 => {
-                        if is_player1 {
-                            battle.player1_reflection = 50;
-                        } else {
-                            battle.player2_reflection = 50;
-                        }
-                        msg!("Mystery Box: 50% reflection!");
-                    }
-                    2 => {
-                        if is_player1 {
-                            battle.player1_hp += 50;
-                        } else {
-                            battle.player2_hp += 50;
-                        }
-                        msg!("Mystery Box: +50 HP!");
-                    }
-                    _ => {
-                        if is_player1 {
-                            battle.player1_combo += 3;
-                        } else {
-                            battle.player2_combo += 3;
-                        }
-                        msg!("Mystery Box: +3 combo!");
-                    }
-                }
-            }
-            WildcardEvent::ComboBreaker => {
-                if is_player1 {
-                    let stolen = battle.player2_combo;
-                    battle.player1_combo += stolen;
-                    battle.player2_combo = 0;
-                } else {
-                    let stolen = battle.player1_combo;
-                    battle.player2_combo += stolen;
-                    battle.player1_combo = 0;
-                }
-            }
-            WildcardEvent::TimeWarp => {
-                if is_player1 {
-                    battle.player2_hp += damage.min(50);
-                } else {
-                    battle.player1_hp += damage.min(50);
-                }
-                damage = 0;
-            }
-            WildcardEvent::LuckySeven => {
-                if battle.last_damage_roll == 7 {
-                    damage *= 7;
-                    msg!("Lucky Seven: 7x damage!");
-                }
-            }
-            _ => {}
-        }
+        let inventory = &mut ctx.accounts.inventory;
+        let count = &mut inventory.counts[item.index()];
+        *count = count.checked_add(quantity).ok_or(GameError::InvalidItemQuantity)?;
+
+        let cost = item.price() * quantity as u64;
+        let cpi_context = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.owner.to_account_info(),
+                to: ctx.accounts.game_treasury.to_account_info(),
+            },
+        );
+        system_program::transfer(cpi_context, cost)?;
+        ctx.accounts.game_treasury.lifetime_fees_collected += cost;
+
+        emit!(ItemPurchased {
+            character: ctx.accounts.character.key(),
+            item,
+            quantity,
+            lamports_paid: cost,
+        });
+        Ok(())
     }
 
-    Ok(damage)
-}
+    // At max level with a full XP bar, start over from level 1 with class base
+    // stats plus a small permanent bonus per prestige
+    pub fn prestige_character(ctx: Context<PrestigeCharacter>) -> Result<()> {
+        let character = &mut ctx.accounts.character;
 
-fn get_required_xp(level: u16) -> u64 {
-    let xp_curve: [u64; 11] = [0, 100, 250, 450, 700, 1000, 1400, 1900, 2500, 3200, 4000];
-    
-    if level < 11 {
-        xp_curve[level as usize]
-    } else {
-        4000 + ((level as u64 - 10) * 500)
-    }
-}
+        // Gear bonuses sit on top of the stats a prestige resets
+        require!(
+            character.equipment.iter().all(Option::is_none),
+            GameError::EquipmentStillEquipped
+        );
 
-// Account contexts
-#[derive(Accounts)]
-pub struct JoinQueue<'info> {
-    #[account(
-        init,
-        payer = player,
-        space = 8 + QueueEntry::INIT_SPACE,
-        seeds = [b"queue", character.key().as_ref()],
-        bump
-    )]
-    pub queue_entry: Account<'info, QueueEntry>,
-    pub character: Account<'info, Character>,
-    #[account(mut)]
-    pub player: Signer<'info>,
-    pub system_program: Program<'info, System>,
-}
+        let required_xp = get_required_xp(MAX_LEVEL);
+        require!(
+            character.level == MAX_LEVEL && character.xp >= required_xp && character.prestige < u8::MAX,
+            GameError::PrestigeNotReady
+        );
+        require!(character.in_battle.is_none(), GameError::CharacterBusy);
 
-#[derive(Accounts)]
-pub struct CommitStance<'info> {
-    #[account(mut)]
-    pub battle: Account<'info, Battle>,
-    pub character: Account<'info, Character>,
-    pub player: Signer<'info>,
-}
+        character.prestige += 1;
+        character.level = 1;
+        character.xp -= required_xp;
 
-#[derive(Accounts)]
-pub struct DecideWildcard<'info> {
-    #[account(mut)]
-    pub battle: Account<'info, Battle>,
-    pub character: Account<'info, Character>,
-    pub player: Signer<'info>,
-}
+        set_class_base_stats(character, &ctx.accounts.config.settings);
+        apply_permanent_bonuses(character);
+        character.current_hp = character.max_hp;
 
-#[derive(Accounts)]
-pub struct ResolveWildcardTimeout<'info> {
-    #[account(mut)]
-    pub battle: Account<'info, Battle>,
-}
+        grant_achievement(character, Achievement::Prestiged);
+        emit_metadata_update(character);
 
-#[derive(Accounts)]
-pub struct CheckTimeout<'info> {
-    #[account(mut)]
-    pub battle: Account<'info, Battle>,
-    /// CHECK: Winner account to receive stakes
-    #[account(mut)]
-    pub winner: AccountInfo<'info>,
-}
+        emit!(CharacterPrestiged {
+            character: character.key(),
+            prestige: character.prestige,
+        });
 
-#[derive(Accounts)]
-pub struct ExecuteAiTurn<'info> {
-    #[account(mut)]
-    pub battle: Account<'info, Battle>,
-    pub player_character: Account<'info, Character>,
-    pub ai_character: Account<'info, Character>,
-}
+        msg!("{} reached prestige {}!", character.name, character.prestige);
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct CreateTournament<'info> {
-    #[account(
-        init,
-        payer = creator,
-        space = 8 + Tournament::INIT_SPACE
-    )]
-    pub tournament: Account<'info, Tournament>,
-    #[account(mut)]
-    pub creator: Signer<'info>,
-    pub system_program: Program<'info, System>,
-}
+    // Hand a character to another wallet. Its address doesn't change, the
+    // creation seeds are kept in seed_name and seed_owner.
+    pub fn transfer_character(ctx: Context<TransferCharacter>, new_owner: Pubkey) -> Result<()> {
+        let character = &mut ctx.accounts.character;
 
-// Additional state accounts
-#[account]
-#[derive(InitSpace)]
-pub struct QueueEntry {
-    pub player: Pubkey,
-    pub character: Pubkey,
-    pub mmr: u64,
-    pub match_type: MatchType,
-    pub stake_amount: u64,
-    pub joined_at: i64,
-    pub matched: bool,
-}
+        // Once minted, whoever holds the token owns the character
+        require!(character.nft_mint.is_none(), GameError::CharacterIsNft);
+        require!(character.in_battle.is_none(), GameError::CharacterBusy);
+        require!(ctx.accounts.queue_entry.data_is_empty(), GameError::CharacterBusy);
+        require!(character.tournament.is_none(), GameError::CharacterInTournament);
+        // Gear is owner-checked, the new owner couldn't take it off
+        require!(
+            character.equipment.iter().all(Option::is_none),
+            GameError::EquipmentStillEquipped
+        );
 
-#[account]
-#[derive(InitSpace)]
-pub struct Tournament {
-    pub creator: Pubkey,
-    pub entry_fee: u64,
-    pub prize_pool: u64,
-    pub max_players: u8,
-    pub current_players: u8,
-    pub status: TournamentStatus,
-    pub created_at: i64,
-    #[max_len(64)]
-    pub participants: Vec<Pubkey>,
-    pub current_round: u8,
-    pub winner: Option<Pubkey>,
-}
+        let from = character.owner;
+        character.owner = new_owner;
 
-// Additional enums
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
-pub enum RankTier {
-    Bronze,
-    Silver,
-    Gold,
-    Platinum,
-    Diamond,
-    Master,
-}
+        emit!(CharacterTransferred {
+            character: character.key(),
+            from,
+            to: new_owner,
+        });
+        Ok(())
+    }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
-pub enum Achievement {
-    FirstWin,
-    TenWins,
-    HundredWins,
-    Flawless,
-    ComboMaster,
-    TournamentWinner,
-}
+    // Mint the character as a Metaplex NFT with supply 1, sent to the owner.
+    // The mint PDA is its own mint and update authority, the master edition
+    // locks the supply. Characters created before this can mint at any time.
+    pub fn mint_character_nft(ctx: Context<MintCharacterNft>) -> Result<()> {
+        let character = &ctx.accounts.character;
+        require!(character.nft_mint.is_none(), GameError::CharacterIsNft);
+
+        let character_key = character.key();
+        let mint_seeds: &[&[u8]] = &[b"character_mint", character_key.as_ref(), &[ctx.bumps.mint]];
+        let signer = &[mint_seeds];
+
+        mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: ctx.accounts.mint.to_account_info(),
+                    to: ctx.accounts.token_account.to_account_info(),
+                    authority: ctx.accounts.mint.to_account_info(),
+                },
+                signer,
+            ),
+            1,
+        )?;
+
+        create_metadata_accounts_v3(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                CreateMetadataAccountsV3 {
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    mint_authority: ctx.accounts.mint.to_account_info(),
+                    payer: ctx.accounts.owner.to_account_info(),
+                    update_authority: ctx.accounts.mint.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                signer,
+            ),
+            nft_data(character),
+            true,
+            true,
+            None,
+        )?;
+
+        create_master_edition_v3(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                CreateMasterEditionV3 {
+                    edition: ctx.accounts.master_edition.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    update_authority: ctx.accounts.mint.to_account_info(),
+                    mint_authority: ctx.accounts.mint.to_account_info(),
+                    payer: ctx.accounts.owner.to_account_info(),
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                signer,
+            ),
+            Some(0),
+        )?;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
-pub enum TournamentStatus {
-    Registration,
-    InProgress,
-    Completed,
-    Cancelled,
-}
+        let character = &mut ctx.accounts.character;
+        character.nft_mint = Some(ctx.accounts.mint.key());
+        character.metadata_dirty = false;
 
-impl BattleStance {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        match self {
-            BattleStance::Aggressive => vec![0],
-            BattleStance::Defensive => vec![1],
-            BattleStance::Balanced => vec![2],
-            BattleStance::Berserker => vec![3],
-            BattleStance::Counter => vec![4],
+        emit!(CharacterNftMinted {
+            character: character_key,
+            mint: ctx.accounts.mint.key(),
+            owner: character.owner,
+        });
+        Ok(())
+    }
+
+    // The holder of a character's NFT claims it. Selling the token moves the
+    // character, the buyer calls this so owner checks elsewhere pass for them.
+    // The previous owner can't hold the sync off: battles and queue entries
+    // end through permissionless cranks (check_timeout, finalize_battle,
+    // expire_queue_entry), and equipped gear, which stays with its owner, is
+    // taken off here. remaining_accounts must hold every equipped Equipment.
+    pub fn sync_character_owner<'info>(ctx: Context<'_, '_, 'info, 'info, SyncCharacterOwner<'info>>) -> Result<()> {
+        let character = &mut ctx.accounts.character;
+        let token_account = &ctx.accounts.token_account;
+        let holder = ctx.accounts.holder.key();
+
+        require!(
+            holds_character_nft(character, token_account.mint, token_account.owner, token_account.amount, &holder),
+            GameError::NotNftHolder
+        );
+        require!(character.in_battle.is_none(), GameError::CharacterBusy);
+        require!(ctx.accounts.queue_entry.data_is_empty(), GameError::CharacterBusy);
+
+        for info in ctx.remaining_accounts.iter() {
+            let mut equipment = Account::<Equipment>::try_from(info)?;
+            unequip_gear(character, &mut equipment, info.key())?;
+            equipment.exit(&crate::ID)?;
+
+            emit!(EquipmentChanged {
+                character: character.key(),
+                equipment: info.key(),
+                slot: equipment.slot,
+                equipped: false,
+            });
         }
+        require!(
+            character.equipment.iter().all(Option::is_none),
+            GameError::EquipmentStillEquipped
+        );
+
+        let from = character.owner;
+        character.owner = holder;
+
+        emit!(CharacterTransferred {
+            character: character.key(),
+            from,
+            to: holder,
+        });
+        Ok(())
     }
-}
 
-// Events
-#[event]
-pub struct CharacterCreated {
-    pub character: Pubkey,
-    pub owner: Pubkey,
-    pub class: CharacterClass,
-    pub name: String,
-}
+    // Push name, tier and URI into the Metaplex metadata after they changed.
+    // Anyone can call it, a crank watches MetadataShouldUpdate for this.
+    pub fn update_character_metadata(ctx: Context<UpdateCharacterMetadata>) -> Result<()> {
+        let character = &ctx.accounts.character;
+        require!(character.nft_mint.is_some(), GameError::CharacterNotNft);
+        require!(character.metadata_dirty, GameError::MetadataUpToDate);
+
+        let character_key = character.key();
+        let mint_seeds: &[&[u8]] = &[b"character_mint", character_key.as_ref(), &[ctx.bumps.mint]];
+        update_metadata_accounts_v2(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                UpdateMetadataAccountsV2 {
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    update_authority: ctx.accounts.mint.to_account_info(),
+                },
+                &[mint_seeds],
+            ),
+            None,
+            Some(nft_data(character)),
+            None,
+            None,
+        )?;
+
+        ctx.accounts.character.metadata_dirty = false;
+        Ok(())
+    }
 
-#[event]
-pub struct QueueJoined {
-    pub player: Pubkey,
-    pub character: Pubkey,
-    pub mmr: u64,
-    pub match_type: MatchType,
-}
+    // Delete a character and reclaim its rent. confirm_name must match the
+    // current name so a maxed character isn't closed by a slip.
+    pub fn close_character(ctx: Context<CloseCharacter>, confirm_name: String) -> Result<()> {
+        let character = &ctx.accounts.character;
 
-#[event]
-pub struct BattleCreated {
-    pub battle: Pubkey,
-    pub player1: Pubkey,
-    pub player2: Pubkey,
-    pub match_type: MatchType,
-    pub is_vs_ai: bool,
-}
+        require!(confirm_name == character.name, GameError::NameMismatch);
+        require!(character.in_battle.is_none(), GameError::CharacterBusy);
+        require!(ctx.accounts.queue_entry.data_is_empty(), GameError::CharacterBusy);
+        require!(character.tournament.is_none(), GameError::CharacterInTournament);
+        require!(character.guild.is_none(), GameError::LeaveGuildFirst);
+        require!(
+            character.equipment.iter().all(Option::is_none),
+            GameError::EquipmentStillEquipped
+        );
+        // The token would outlive the character
+        require!(character.nft_mint.is_none(), GameError::CharacterIsNft);
 
-#[event]
-pub struct StanceCommitted {
-    pub battle: Pubkey,
-    pub player: Pubkey,
-    pub turn: u32,
-}
+        emit!(CharacterDeleted {
+            character: character.key(),
+            owner: character.owner,
+            name: character.name.clone(),
+        });
+        Ok(())
+    }
 
-#[event]
-pub struct WildcardTriggered {
-    pub battle: Pubkey,
-    pub wildcard_type: WildcardEvent,
-    pub decision_deadline: i64,
-}
+    // Owner sets the art URI, normally after a MetadataShouldUpdate event.
+    // The config admin may also set it, to take down offensive art.
+    pub fn set_metadata_uri(ctx: Context<SetMetadataUri>, metadata_uri: String) -> Result<()> {
+        let authority = ctx.accounts.authority.key();
+        require!(
+            authority == ctx.accounts.character.owner || authority == ctx.accounts.config.admin,
+            GameError::NotCharacterOwner
+        );
+        require!(metadata_uri.len() <= 100, GameError::MetadataUriTooLong);
+        require!(
+            metadata_uri.starts_with("https://") || metadata_uri.starts_with("ipfs://"),
+            GameError::InvalidMetadataUri
+        );
 
-#[event]
-pub struct WildcardDecision {
-    pub battle: Pubkey,
-    pub player: Pubkey,
-    pub accepted: bool,
-}
+        let character = &mut ctx.accounts.character;
+        character.metadata_uri = metadata_uri;
+        character.metadata_version = character.metadata_version.wrapping_add(1);
+        character.metadata_dirty = true;
 
-#[event]
-pub struct BattleEnded {
-    pub battle: Pubkey,
-    pub winner: u8,
-    pub total_turns: u32,
-}
+        emit!(MetadataUpdated {
+            character: character.key(),
+            uri: character.metadata_uri.clone(),
+            version: character.metadata_version,
+        });
+        Ok(())
+    }
 
-#[event]
-pub struct BattleAbandoned {
-    pub battle: Pubkey,
-    pub abandoned_by: u8,
-    pub winner: u8,
-}
+    // Change the display name. The character's address is still derived from
+    // seed_name and seed_owner, the name and owner it was created with.
+    pub fn rename_character(ctx: Context<RenameCharacter>, new_name: String) -> Result<()> {
+        require!(new_name.len() <= 32, GameError::NameTooLong);
 
-#[event]
-pub struct BattleFinalized {
-    pub battle: Pubkey,
-    pub winner: Pubkey,
-    pub loser: Pubkey,
-    pub xp_gained: u64,
-}
+        let character = &mut ctx.accounts.character;
+        let old_name = std::mem::replace(&mut character.name, new_name);
+        character.metadata_dirty = true;
 
-#[event]
-pub struct CharacterHealed {
-    pub character: Pubkey,
-    pub owner: Pubkey,
-}
+        emit!(CharacterRenamed {
+            character: character.key(),
+            old_name,
+            new_name: character.name.clone(),
+        });
+        Ok(())
+    }
 
-#[event]
-pub struct TournamentCreated {
-    pub tournament: Pubkey,
-    pub creator: Pubkey,
-    pub prize_pool: u64,
-    pub max_players: u8,
-}
+    // Switch class, paid into the treasury. Stats become what a character of
+    // the new class at the same level and prestige would have; XP, record,
+    // MMR and achievements carry over. HP is kept, capped at the new max.
+    pub fn respec_character(ctx: Context<RespecCharacter>, new_class: CharacterClass) -> Result<()> {
+        let character = &ctx.accounts.character;
+        let old_class = character.character_class;
+        let clock = Clock::get()?;
 
-// Additional error codes
-#[error_code]
-pub enum GameError {
-    #[msg("Name is too long (max 32 characters)")]
-    NameTooLong,
-    #[msg("Battle has already finished")]
-    BattleAlreadyFinished,
-    #[msg("Not your turn")]
-    NotYourTurn,
-    #[msg("Battle is not finished yet")]
-    BattleNotFinished,
-    #[msg("No winner determined")]
-    NoWinner,
-    #[msg("Invalid bet target (must be 1 or 2)")]
-    InvalidBetTarget,
-    #[msg("Invalid bet amount")]
-    InvalidBetAmount,
-    #[msg("Pool already settled")]
-    PoolAlreadySettled,
-    #[msg("Pool not settled yet")]
-    PoolNotSettled,
-    #[msg("Bet already claimed")]
-    AlreadyClaimed,
-    #[msg("Not the bet owner")]
+        require!(new_class != old_class, GameError::SameClass);
+        require!(character.in_battle.is_none(), GameError::CharacterBusy);
+        require!(ctx.accounts.queue_entry.data_is_empty(), GameError::CharacterBusy);
+        require!(character.tournament.is_none(), GameError::CharacterInTournament);
+        require!(
+            clock.unix_timestamp - character.last_respec >= RESPEC_COOLDOWN_SECONDS,
+            GameError::RespecCooldownActive
+        );
+        require!(
+            character.equipment.iter().all(Option::is_none),
+            GameError::EquipmentStillEquipped
+        );
+
+        let respec_cost = ctx.accounts.config.settings.respec_cost;
+        let cpi_context = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.owner.to_account_info(),
+                to: ctx.accounts.game_treasury.to_account_info(),
+            },
+        );
+        system_program::transfer(cpi_context, respec_cost)?;
+        ctx.accounts.game_treasury.lifetime_fees_collected += respec_cost;
+
+        let character = &mut ctx.accounts.character;
+        respec_stats(character, new_class, &ctx.accounts.config.settings);
+        character.last_respec = clock.unix_timestamp;
+        character.metadata_dirty = true; // NFT symbol is the class
+
+        emit!(ClassChanged {
+            character: character.key(),
+            old_class,
+            new_class,
+            lamports_paid: respec_cost,
+        });
+
+        msg!("{} is now a {}", character.name, new_class.to_string());
+        Ok(())
+    }
+
+    // Bring a character at 0 HP back to full. Costs more than a heal and only
+    // works once the downtime since its last battle has passed.
+    pub fn revive_character(ctx: Context<ReviveCharacter>) -> Result<()> {
+        let settings = &ctx.accounts.config.settings;
+        let clock = Clock::get()?;
+
+        check_can_revive(&ctx.accounts.character, settings, &clock)?;
+
+        let revive_cost = settings.revive_cost;
+        let cpi_context = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.owner.to_account_info(),
+                to: ctx.accounts.game_treasury.to_account_info(),
+            },
+        );
+        system_program::transfer(cpi_context, revive_cost)?;
+        ctx.accounts.game_treasury.lifetime_fees_collected += revive_cost;
+
+        let character = &mut ctx.accounts.character;
+        character.current_hp = character.max_hp;
+        character.death_count += 1;
+
+        emit!(CharacterRevived {
+            character: character.key(),
+            owner: character.owner,
+            lamports_paid: revive_cost,
+            death_count: character.death_count,
+        });
+
+        msg!("{} revived", character.name);
+        Ok(())
+    }
+
+    // Create tournament
+    pub fn create_tournament(
+        ctx: Context<CreateTournament>,
+        entry_fee: u64,
+        prize_pool: u64,
+        max_players: u8,
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+
+        let tournament = &mut ctx.accounts.tournament;
+        let clock = Clock::get()?;
+
+        // The creator funds the prize pool up front, the champion takes it
+        // along with the entry fees
+        if prize_pool > 0 {
+            let cpi_context = CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.creator.to_account_info(),
+                    to: tournament.to_account_info(),
+                },
+            );
+            system_program::transfer(cpi_context, prize_pool)?;
+        }
+
+        tournament.creator = ctx.accounts.creator.key();
+        tournament.entry_fee = entry_fee;
+        tournament.prize_pool = prize_pool;
+        tournament.max_players = max_players;
+        tournament.current_players = 0;
+        tournament.status = TournamentStatus::Registration;
+        tournament.created_at = clock.unix_timestamp;
+        tournament.participants = vec![];
+        tournament.current_round = 0;
+        tournament.winner = None;
+        tournament.started_at = 0;
+        tournament.eliminated = 0;
+
+        emit!(TournamentCreated {
+            tournament: tournament.key(),
+            creator: tournament.creator,
+            prize_pool,
+            max_players,
+        });
+
+        Ok(())
+    }
+
+    // Register a character for a tournament, paying the entry fee into it
+    pub fn join_tournament(ctx: Context<JoinTournament>) -> Result<()> {
+        let tournament = &mut ctx.accounts.tournament;
+        let character_key = ctx.accounts.character.key();
+
+        require!(!ctx.accounts.config.paused, GameError::GamePaused);
+        require!(
+            tournament.status == TournamentStatus::Registration,
+            GameError::TournamentNotOpen
+        );
+        require!(
+            tournament.current_players < tournament.max_players && tournament.participants.len() < 64,
+            GameError::TournamentFull
+        );
+        require!(
+            !tournament.participants.contains(&character_key),
+            GameError::AlreadyRegistered
+        );
+        require!(ctx.accounts.character.tournament.is_none(), GameError::CharacterInTournament);
+
+        if tournament.entry_fee > 0 {
+            let cpi_context = CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.owner.to_account_info(),
+                    to: tournament.to_account_info(),
+                },
+            );
+            system_program::transfer(cpi_context, tournament.entry_fee)?;
+        }
+
+        tournament.participants.push(character_key);
+        tournament.current_players += 1;
+        ctx.accounts.character.tournament = Some(tournament.key());
+
+        msg!("{} joined the tournament", ctx.accounts.character.name);
+        Ok(())
+    }
+
+    // Creator closes registration and seeds the bracket by ranked MMR.
+    // remaining_accounts must hold every participant's Character, in any order.
+    pub fn start_tournament<'info>(ctx: Context<'_, '_, 'info, 'info, StartTournament<'info>>) -> Result<()> {
+        let tournament = &mut ctx.accounts.tournament;
+
+        require!(
+            tournament.status == TournamentStatus::Registration,
+            GameError::TournamentNotOpen
+        );
+        require!(tournament.current_players >= 2, GameError::NotEnoughPlayers);
+        require!(
+            ctx.remaining_accounts.len() == tournament.participants.len(),
+            GameError::ParticipantMismatch
+        );
+
+        let mut seeded = Vec::with_capacity(ctx.remaining_accounts.len());
+        for info in ctx.remaining_accounts.iter() {
+            let character = Account::<Character>::try_from(info)?;
+            require!(
+                tournament.participants.contains(&character.key()),
+                GameError::NotTournamentParticipant
+            );
+            require!(
+                !seeded.iter().any(|(_, key)| *key == character.key()),
+                GameError::ParticipantMismatch
+            );
+            seeded.push((character.ranked_mmr, character.key()));
+        }
+
+        tournament.participants = seed_bracket(seeded);
+        tournament.status = TournamentStatus::InProgress;
+        tournament.current_round = 1;
+        tournament.started_at = Clock::get()?.unix_timestamp;
+
+        // Round one pairs neighbours in the bracket, an odd last entry has a bye
+        for pair in tournament.participants.chunks(2) {
+            emit!(TournamentPairing {
+                tournament: tournament.key(),
+                round: 1,
+                player1: pair[0],
+                player2: pair.get(1).copied(),
+            });
+        }
+
+        msg!("Tournament started with {} players", tournament.current_players);
+        Ok(())
+    }
+
+    // Knock the loser of a finished Tournament battle between two remaining
+    // participants out of the tournament. Permissionless, the result comes
+    // from the battle alone.
+    pub fn record_tournament_result(ctx: Context<RecordTournamentResult>) -> Result<()> {
+        let tournament = &mut ctx.accounts.tournament;
+        let battle_key = ctx.accounts.battle.key();
+        let battle = ctx.accounts.battle.load()?;
+
+        require!(
+            tournament.status == TournamentStatus::InProgress,
+            GameError::TournamentNotInProgress
+        );
+        require!(battle.is_finished != 0, GameError::BattleNotFinished);
+        require!(battle.is_draw == 0 && battle.winner != 0, GameError::NoWinner);
+        // Only battles played for this tournament, after it started, count
+        require!(
+            battle.is_vs_ai == 0
+                && MatchType::from_code(battle.match_type) == MatchType::Tournament
+                && battle.created_at >= tournament.started_at,
+            GameError::NotTournamentBattle
+        );
+
+        let (winner, loser) = if battle.winner == 1 {
+            (battle.player1, battle.player2)
+        } else {
+            (battle.player2, battle.player1)
+        };
+        require_keys_eq!(ctx.accounts.loser.key(), loser, GameError::NotBattleParticipant);
+        let winner_index = tournament_index(tournament, &winner)?;
+        let loser_index = tournament_index(tournament, &loser)?;
+        require!(
+            tournament.eliminated & (1 << winner_index) == 0
+                && tournament.eliminated & (1 << loser_index) == 0,
+            GameError::ParticipantEliminated
+        );
+
+        tournament.eliminated |= 1 << loser_index;
+        release_from_tournament(&mut ctx.accounts.loser, &tournament.key());
+
+        emit!(TournamentMatchRecorded {
+            tournament: tournament.key(),
+            battle: battle_key,
+            winner,
+            loser,
+        });
+        Ok(())
+    }
+
+    // Once every other participant has been knocked out, the last one
+    // standing is champion and takes the entry fees and the prize pool.
+    // Anyone can call it.
+    pub fn complete_tournament(ctx: Context<CompleteTournament>) -> Result<()> {
+        let tournament = &mut ctx.accounts.tournament;
+        let champion = &mut ctx.accounts.champion;
+
+        require!(
+            tournament.status == TournamentStatus::InProgress,
+            GameError::TournamentNotInProgress
+        );
+        let champion_index = tournament_index(tournament, &champion.key())?;
+        require!(
+            tournament.eliminated & (1 << champion_index) == 0
+                && tournament.eliminated.count_ones() as usize == tournament.participants.len() - 1,
+            GameError::TournamentUndecided
+        );
+
+        tournament.status = TournamentStatus::Completed;
+        tournament.winner = Some(champion.key());
+        release_from_tournament(champion, &tournament.key());
+
+        grant_achievement(champion, Achievement::TournamentWinner);
+
+        let prize = tournament.entry_fee * tournament.current_players as u64 + tournament.prize_pool;
+        if prize > 0 {
+            **tournament.to_account_info().try_borrow_mut_lamports()? -= prize;
+            **ctx.accounts.champion_owner.to_account_info().try_borrow_mut_lamports()? += prize;
+        }
+
+        emit!(TournamentCompleted {
+            tournament: tournament.key(),
+            champion: champion.key(),
+            prize,
+        });
+
+        Ok(())
+    }
+
+    // Creator calls the tournament off before it's decided. The prize pool
+    // goes back to the creator, entrants reclaim their fees with
+    // claim_tournament_refund.
+    pub fn cancel_tournament(ctx: Context<CancelTournament>) -> Result<()> {
+        let tournament = &mut ctx.accounts.tournament;
+
+        require!(
+            tournament.status == TournamentStatus::Registration
+                || tournament.status == TournamentStatus::InProgress,
+            GameError::TournamentAlreadyFinished
+        );
+
+        tournament.status = TournamentStatus::Cancelled;
+
+        let prize_pool = tournament.prize_pool;
+        if prize_pool > 0 {
+            tournament.prize_pool = 0;
+            **tournament.to_account_info().try_borrow_mut_lamports()? -= prize_pool;
+            **ctx.accounts.creator.to_account_info().try_borrow_mut_lamports()? += prize_pool;
+        }
+
+        emit!(TournamentCancelled {
+            tournament: tournament.key(),
+            refunded_prize_pool: prize_pool,
+        });
+        Ok(())
+    }
+
+    // An entrant of a cancelled tournament takes their entry fee back. Also
+    // needed without a fee, it's what frees the character from the tournament.
+    pub fn claim_tournament_refund(ctx: Context<ClaimTournamentRefund>) -> Result<()> {
+        let tournament = &mut ctx.accounts.tournament;
+        let character_key = ctx.accounts.character.key();
+
+        require!(
+            tournament.status == TournamentStatus::Cancelled,
+            GameError::TournamentNotCancelled
+        );
+        let index = tournament_index(tournament, &character_key)?;
+
+        // Order no longer matters once cancelled, removing the entry makes
+        // the refund one-shot
+        tournament.participants.swap_remove(index);
+        tournament.current_players -= 1;
+        release_from_tournament(&mut ctx.accounts.character, &tournament.key());
+
+        let refund = tournament.entry_fee;
+        if refund > 0 {
+            **tournament.to_account_info().try_borrow_mut_lamports()? -= refund;
+            **ctx.accounts.owner.to_account_info().try_borrow_mut_lamports()? += refund;
+        }
+
+        emit!(TournamentRefunded {
+            tournament: tournament.key(),
+            character: character_key,
+            amount: refund,
+        });
+        Ok(())
+    }
+
+    // Found a guild, the founding character becomes its leader and first member
+    pub fn create_guild(ctx: Context<CreateGuild>, name: String) -> Result<()> {
+        require!(name.len() <= 32, GameError::NameTooLong);
+        require!(ctx.accounts.character.guild.is_none(), GameError::AlreadyInGuild);
+
+        let guild = &mut ctx.accounts.guild;
+        guild.name = name;
+        guild.leader = ctx.accounts.character.key();
+        guild.member_count = 1;
+        guild.treasury_balance = 0;
+        guild.total_guild_wins = 0;
+        guild.bump = ctx.bumps.guild;
+
+        ctx.accounts.character.guild = Some(guild.key());
+
+        emit!(GuildCreated {
+            guild: guild.key(),
+            leader: guild.leader,
+            name: guild.name.clone(),
+        });
+        Ok(())
+    }
+
+    pub fn join_guild(ctx: Context<JoinGuild>) -> Result<()> {
+        let guild = &mut ctx.accounts.guild;
+        let character = &mut ctx.accounts.character;
+
+        require!(character.guild.is_none(), GameError::AlreadyInGuild);
+        require!(guild.member_count < MAX_GUILD_MEMBERS, GameError::GuildFull);
+
+        character.guild = Some(guild.key());
+        add_guild_member(guild, character.key());
+
+        emit!(GuildMembershipChanged {
+            guild: guild.key(),
+            character: character.key(),
+            joined: true,
+            member_count: guild.member_count,
+        });
+        Ok(())
+    }
+
+    // The leader can only leave once everyone else has
+    pub fn leave_guild(ctx: Context<LeaveGuild>) -> Result<()> {
+        let guild = &mut ctx.accounts.guild;
+        let character = &mut ctx.accounts.character;
+
+        require!(character.guild == Some(guild.key()), GameError::NotGuildMember);
+        require!(
+            character.key() != guild.leader || guild.member_count == 1,
+            GameError::GuildLeaderCannotLeave
+        );
+
+        character.guild = None;
+        remove_guild_member(guild);
+
+        emit!(GuildMembershipChanged {
+            guild: guild.key(),
+            character: character.key(),
+            joined: false,
+            member_count: guild.member_count,
+        });
+        Ok(())
+    }
+
+    // Anyone can send lamports to a guild's treasury
+    pub fn donate_to_guild(ctx: Context<DonateToGuild>, amount: u64) -> Result<()> {
+        require!(amount > 0, GameError::InvalidDonationAmount);
+
+        let cpi_context = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.donor.to_account_info(),
+                to: ctx.accounts.guild.to_account_info(),
+            },
+        );
+        system_program::transfer(cpi_context, amount)?;
+
+        let guild = &mut ctx.accounts.guild;
+        guild.treasury_balance += amount;
+
+        emit!(GuildDonation {
+            guild: guild.key(),
+            donor: ctx.accounts.donor.key(),
+            amount,
+            treasury_balance: guild.treasury_balance,
+        });
+        Ok(())
+    }
+
+    // Crank: take MMR off characters that stopped playing, one step per
+    // elapsed period. Decay never drops anyone below Silver.
+    pub fn decay_rank(ctx: Context<DecayRank>) -> Result<()> {
+        let character = &mut ctx.accounts.character;
+        let clock = Clock::get()?;
+
+        let mmr_lost = apply_rank_decay(character, &clock)?;
+        update_rank_tier(character);
+
+        update_leaderboard(&mut ctx.accounts.leaderboard, character);
+
+        emit!(RankDecayed {
+            character: character.key(),
+            mmr_lost,
+            new_mmr: character.ranked_mmr,
+            rank_tier: character.rank_tier,
+        });
+
+        Ok(())
+    }
+
+    // Open the next season, funding its reward pool from the treasury.
+    // tier_rewards are lamports per RankTier, Bronze first.
+    pub fn start_season(
+        ctx: Context<StartSeason>,
+        index: u32,
+        end_time: i64,
+        tier_rewards: [u64; 6],
+        reward_pool: u64,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let clock = Clock::get()?;
+
+        require!(!config.season_active, GameError::SeasonAlreadyActive);
+        require!(index == config.current_season + 1, GameError::InvalidSeason);
+        require!(end_time > clock.unix_timestamp, GameError::InvalidSeason);
+
+        let treasury_info = ctx.accounts.treasury.to_account_info();
+        let rent_floor = Rent::get()?.minimum_balance(treasury_info.data_len());
+        let available = treasury_info.lamports().saturating_sub(rent_floor);
+        require!(reward_pool <= available, GameError::InsufficientTreasuryFunds);
+
+        **treasury_info.try_borrow_mut_lamports()? -= reward_pool;
+        **ctx.accounts.season.to_account_info().try_borrow_mut_lamports()? += reward_pool;
+
+        config.current_season = index;
+        config.season_active = true;
+
+        let season = &mut ctx.accounts.season;
+        season.index = index;
+        season.start_time = clock.unix_timestamp;
+        season.end_time = end_time;
+        season.ended = false;
+        season.tier_rewards = tier_rewards;
+        season.reward_pool = reward_pool;
+        season.claims = 0;
+        season.bump = ctx.bumps.season;
+
+        emit!(SeasonStarted {
+            index,
+            start_time: season.start_time,
+            end_time,
+            reward_pool,
+        });
+        Ok(())
+    }
+
+    // Close the current season once its end_time has passed, opening claims
+    pub fn end_season(ctx: Context<EndSeason>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let season = &mut ctx.accounts.season;
+        let clock = Clock::get()?;
+
+        require!(config.season_active && season.index == config.current_season, GameError::InvalidSeason);
+        require!(clock.unix_timestamp >= season.end_time, GameError::SeasonNotOver);
+
+        season.ended = true;
+        config.season_active = false;
+
+        emit!(SeasonEnded {
+            index: season.index,
+            reward_pool: season.reward_pool,
+        });
+        Ok(())
+    }
+
+    // Reward for the latest ended season by the character's rank tier, within
+    // season_claim_window_seconds of its end. The tier only counts if the
+    // character hasn't battled since the season ended. The soft MMR reset
+    // happens on the character's first battle of the next season.
+    pub fn claim_season_reward(ctx: Context<ClaimSeasonReward>) -> Result<()> {
+        let season = &mut ctx.accounts.season;
+        let character = &ctx.accounts.character;
+        let clock = Clock::get()?;
+
+        require!(season.ended, GameError::SeasonNotEnded);
+        require!(season.index == ctx.accounts.config.current_season, GameError::InvalidSeason);
+        require!(
+            !season_claim_window_closed(season, &ctx.accounts.config.settings, &clock),
+            GameError::ClaimWindowClosed
+        );
+        require!(
+            character.season_index == season.index && character.season_wins + character.season_losses > 0,
+            GameError::NoSeasonActivity
+        );
+        require!(character.last_battle <= season.end_time, GameError::PlayedSinceSeasonEnd);
+
+        let rank_tier = character.rank_tier;
+        let amount = season.tier_rewards[rank_tier as usize].min(season.reward_pool);
+        if amount > 0 {
+            season.reward_pool -= amount;
+            **season.to_account_info().try_borrow_mut_lamports()? -= amount;
+            **ctx.accounts.owner.to_account_info().try_borrow_mut_lamports()? += amount;
+        }
+        season.claims += 1;
+
+        let claim = &mut ctx.accounts.season_claim;
+        claim.character = character.key();
+        claim.season = season.index;
+        claim.rank_tier = rank_tier;
+        claim.amount = amount;
+
+        emit!(SeasonRewardClaimed {
+            season: season.index,
+            character: character.key(),
+            rank_tier,
+            amount,
+        });
+        Ok(())
+    }
+
+    // Anyone can call it once the claim window is over: whatever is left of
+    // the reward pool goes back to the treasury
+    pub fn sweep_season_rewards(ctx: Context<SweepSeasonRewards>) -> Result<()> {
+        let season = &mut ctx.accounts.season;
+        let clock = Clock::get()?;
+
+        require!(season.ended, GameError::SeasonNotEnded);
+        require!(
+            season_claim_window_closed(season, &ctx.accounts.config.settings, &clock),
+            GameError::ClaimWindowOpen
+        );
+
+        let amount = season.reward_pool;
+        if amount > 0 {
+            season.reward_pool = 0;
+            **season.to_account_info().try_borrow_mut_lamports()? -= amount;
+            **ctx.accounts.treasury.to_account_info().try_borrow_mut_lamports()? += amount;
+        }
+
+        emit!(SeasonRewardsSwept {
+            season: season.index,
+            amount,
+        });
+        Ok(())
+    }
+
+    // Create the GameConfig PDA with default settings. Only the program's
+    // upgrade authority can call it, and becomes admin
+    pub fn initialize_config(ctx: Context<InitializeConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.admin.key();
+        config.bump = ctx.bumps.config;
+        config.paused = false;
+        config.current_season = 0;
+        config.season_active = false;
+        config.ai_character = Pubkey::default();
+
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.bump = ctx.bumps.treasury;
+        treasury.lifetime_fees_collected = 0;
+        config.settings = GameSettings {
+            turn_timeout_seconds: TURN_TIMEOUT_SECONDS,
+            battle_expiry_seconds: BATTLE_EXPIRY_SECONDS,
+            wildcard_decision_timeout: WILDCARD_DECISION_TIMEOUT,
+            min_battle_interval_seconds: MIN_BATTLE_INTERVAL_SECONDS,
+            challenge_window_seconds: CHALLENGE_WINDOW_SECONDS,
+            revive_downtime_seconds: REVIVE_DOWNTIME_SECONDS,
+            base_wildcard_chance: BASE_WILDCARD_CHANCE,
+            trickster_wildcard_chance: TRICKSTER_WILDCARD_CHANCE,
+            heal_price_per_hp: HEAL_PRICE_PER_HP_LAMPORTS,
+            revive_cost: REVIVE_COST_LAMPORTS,
+            respec_cost: RESPEC_COST_LAMPORTS,
+            default_house_edge: DEFAULT_HOUSE_EDGE,
+            mmr_k_ranked: MMR_K_RANKED,
+            mmr_k_provisional: MMR_K_PROVISIONAL,
+            mmr_k_casual: MMR_K_CASUAL,
+            max_mmr_gap: MAX_MMR_GAP,
+            xp_multiplier: XP_MULTIPLIER_BPS,
+            loser_xp_percent: LOSER_XP_PERCENT,
+            special_cooldowns: SPECIAL_COOLDOWNS,
+            class_stats: CLASS_BASE_STATS,
+            bet_limits: BetLimits {
+                min_bet: MIN_BET_LAMPORTS,
+                max_bet_per_wallet: MAX_BET_PER_WALLET_LAMPORTS,
+                max_pool_size: MAX_POOL_SIZE_LAMPORTS,
+            },
+            instant_kill_enabled: INSTANT_KILL_ENABLED,
+            queue_timeout_seconds: QUEUE_TIMEOUT_SECONDS,
+            betting_closes_at_turn: BETTING_CLOSES_AT_TURN,
+            bet_claim_window_seconds: BET_CLAIM_WINDOW_SECONDS,
+            counter_keeps_combo: COUNTER_KEEPS_COMBO,
+            pve_timeout_refunds_stake: PVE_TIMEOUT_REFUNDS_STAKE,
+            abandon_threshold: ABANDON_THRESHOLD,
+            abandon_window_seconds: ABANDON_WINDOW_SECONDS,
+            abandon_ban_seconds: ABANDON_BAN_SECONDS,
+            reveal_timeout_seconds: REVEAL_TIMEOUT_SECONDS,
+            max_battle_turns: MAX_BATTLE_TURNS,
+            season_claim_window_seconds: SEASON_CLAIM_WINDOW_SECONDS,
+        };
+        Ok(())
+    }
+
+    // Admin-only balance changes, takes effect on the next instruction
+    pub fn update_config(ctx: Context<UpdateConfig>, settings: GameSettings) -> Result<()> {
+        validate_settings(&settings)?;
+
+        let config = &mut ctx.accounts.config;
+        let old = config.settings;
+        config.settings = settings;
+
+        emit!(ConfigUpdated {
+            admin: config.admin,
+            old,
+            new: settings,
+        });
+
+        Ok(())
+    }
+
+    // Admin-only payout from the treasury, which always keeps its rent
+    pub fn withdraw_treasury(ctx: Context<WithdrawTreasury>, amount: u64) -> Result<()> {
+        let treasury_info = ctx.accounts.treasury.to_account_info();
+        let rent_floor = Rent::get()?.minimum_balance(treasury_info.data_len());
+        let available = treasury_info.lamports().saturating_sub(rent_floor);
+        require!(amount <= available, GameError::InsufficientTreasuryFunds);
+
+        **treasury_info.try_borrow_mut_lamports()? -= amount;
+        **ctx.accounts.recipient.try_borrow_mut_lamports()? += amount;
+
+        emit!(TreasuryWithdrawal {
+            admin: ctx.accounts.admin.key(),
+            recipient: ctx.accounts.recipient.key(),
+            amount,
+            remaining: available - amount,
+        });
+
+        Ok(())
+    }
+
+    // Emergency stop. Only new gameplay is blocked, claims and refunds still
+    // go through so nobody's funds are stuck.
+    pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.paused = paused;
+
+        if paused {
+            emit!(GamePaused { admin: config.admin });
+        } else {
+            emit!(GameUnpaused { admin: config.admin });
+        }
+
+        Ok(())
+    }
+
+    // Admin-only, picks the character create_battle pits players against.
+    // The admin owns it, so its stats are never anyone else's.
+    pub fn set_ai_character(ctx: Context<SetAiCharacter>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.ai_character = ctx.accounts.ai_character.key();
+
+        emit!(AiCharacterSet {
+            admin: config.admin,
+            ai_character: config.ai_character,
+        });
+        Ok(())
+    }
+
+    // Create the global top-MMR leaderboard, once
+    pub fn initialize_leaderboard(ctx: Context<InitializeLeaderboard>) -> Result<()> {
+        ctx.accounts.leaderboard.entries = vec![];
+        Ok(())
+    }
+}
+
+// Helper functions
+
+fn rand_from_seed(seed: &[u8; 32], counter: u64) -> u64 {
+    let digest = hash(&[&seed[..], &counter.to_le_bytes()].concat()).to_bytes();
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+// Uniform value in [min, max]. Draws from the top partial bucket are
+// rejected so the modulo carries no bias.
+fn gen_range(seed: &[u8; 32], counter: &mut u64, min: u64, max: u64) -> u64 {
+    let span = max - min + 1;
+    let zone = u64::MAX - (u64::MAX % span);
+    loop {
+        let value = rand_from_seed(seed, *counter);
+        *counter += 1;
+        if value < zone {
+            return min + value % span;
+        }
+    }
+}
+
+// Every roll in a turn advances the battle's counter, so no two share a value
+fn roll_range(battle: &mut Battle, min: u64, max: u64) -> u64 {
+    let seed = battle.random_seed;
+    gen_range(&seed, &mut battle.roll_counter, min, max)
+}
+
+// Every turn resolution needs a seed fulfilled for this turn that hasn't
+// been used yet. Both reveal salts and the latest slot hash are mixed in, so
+// neither player alone can predict or grind the rolls.
+fn take_turn_seed(battle: &mut Battle, slot_hash: &[u8; 32]) -> Result<()> {
+    require!(
+        battle.seed_consumed == 0 && battle.seed_turn == battle.turn_number,
+        GameError::RandomnessNotReady
+    );
+    battle.seed_consumed = 1;
+    battle.roll_counter = 0;
+    battle.random_seed = hash(&[
+        &battle.random_seed[..],
+        &battle.player1_salt.to_le_bytes(),
+        &battle.player2_salt.to_le_bytes(),
+        &slot_hash[..],
+        &battle.turn_number.to_le_bytes(),
+    ].concat()).to_bytes();
+    Ok(())
+}
+
+// SlotHashes is a u64 length followed by (slot, hash) pairs, newest first
+fn recent_slot_hash(slot_hashes: &AccountInfo) -> Result<[u8; 32]> {
+    let data = slot_hashes.try_borrow_data()?;
+    require!(data.len() >= 48, GameError::InvalidSlotHashes);
+    let mut slot_hash = [0u8; 32];
+    slot_hash.copy_from_slice(&data[16..48]);
+    Ok(slot_hash)
+}
+
+#[cfg(not(feature = "localnet"))]
+fn check_randomness_committed(randomness_account: &AccountInfo, clock: &Clock) -> Result<()> {
+    require_keys_eq!(
+        *randomness_account.owner,
+        SWITCHBOARD_ON_DEMAND_PROGRAM_ID,
+        GameError::InvalidRandomnessAccount
+    );
+    let randomness_data = RandomnessAccountData::parse(randomness_account.data.borrow())
+        .map_err(|_| GameError::InvalidRandomnessAccount)?;
+    // Committed in the previous slot, so its value can't be known yet
+    require!(
+        randomness_data.seed_slot == clock.slot - 1,
+        GameError::RandomnessAlreadyRevealed
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "localnet"))]
+fn read_randomness(
+    randomness_account: &AccountInfo,
+    clock: &Clock,
+    _battle_key: &Pubkey,
+    _turn_number: u32,
+) -> Result<[u8; 32]> {
+    let randomness_data = RandomnessAccountData::parse(randomness_account.data.borrow())
+        .map_err(|_| GameError::InvalidRandomnessAccount)?;
+    let value = randomness_data
+        .get_value(clock)
+        .map_err(|_| GameError::RandomnessNotReady)?;
+    Ok(value)
+}
+
+// Deterministic stand-in for localnet tests, where no oracle is running
+#[cfg(feature = "localnet")]
+fn check_randomness_committed(_randomness_account: &AccountInfo, _clock: &Clock) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(feature = "localnet")]
+fn read_randomness(
+    _randomness_account: &AccountInfo,
+    _clock: &Clock,
+    battle_key: &Pubkey,
+    turn_number: u32,
+) -> Result<[u8; 32]> {
+    Ok(hash(&[battle_key.as_ref(), &turn_number.to_le_bytes()].concat()).to_bytes())
+}
+
+fn check_battle_timeout(battle: &Battle, settings: &GameSettings, clock: &Clock) -> Result<()> {
+    let time_since_creation = clock.unix_timestamp - battle.created_at;
+    require!(
+        time_since_creation < settings.battle_expiry_seconds,
+        GameError::BattleExpired
+    );
+    Ok(())
+}
+
+fn check_can_reveal(battle: &Battle, is_acting: bool) -> Result<()> {
+    if battle.is_vs_ai != 0 {
+        // The AI never commits, the human reveals and attacks on their own turn
+        require!(is_acting, GameError::NotYourTurn);
+    } else {
+        // No stance is revealed until both are locked in
+        require!(
+            battle.player1_stance_committed != 0 && battle.player2_stance_committed != 0,
+            GameError::OpponentNotCommitted
+        );
+    }
+    Ok(())
+}
+
+fn stance_commitment(
+    stance: BattleStance,
+    item: u8,
+    use_special: bool,
+    salt: u64,
+    battle: &Pubkey,
+    turn_number: u32,
+    player: &Pubkey,
+) -> [u8; 32] {
+    hash(&[
+        &stance.to_bytes()[..],
+        &[item],
+        &[use_special as u8],
+        &salt.to_le_bytes(),
+        battle.as_ref(),
+        &turn_number.to_le_bytes(),
+        player.as_ref(),
+    ].concat()).to_bytes()
+}
+
+// Total lamports locked for a battle, the AI never stakes
+fn escrowed_stake(battle: &Battle) -> u64 {
+    if battle.is_vs_ai != 0 {
+        battle.stake_amount
+    } else {
+        battle.stake_amount * 2
+    }
+}
+
+// Who check_timeout has to pay the escrowed stake to. None when the AI won
+// and the stake goes to the Treasury, the winner account isn't paid then.
+fn timeout_stake_recipient(
+    battle: &Battle,
+    settings: &GameSettings,
+    player1_owner: Pubkey,
+    player2_owner: Pubkey,
+) -> Option<Pubkey> {
+    if battle.is_vs_ai != 0 && battle.winner == 2 {
+        settings.pve_timeout_refunds_stake.then_some(player1_owner)
+    } else if battle.winner == 1 {
+        Some(player1_owner)
+    } else {
+        Some(player2_owner)
+    }
+}
+
+fn pay_from_escrow<'info>(
+    escrow: &Account<'info, BattleEscrow>,
+    recipient: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    **escrow.to_account_info().try_borrow_mut_lamports()? -= amount;
+    **recipient.try_borrow_mut_lamports()? += amount;
+    Ok(())
+}
+
+// The AI has no owner to pay. Only the human's single stake is escrowed,
+// it's refunded to them or kept by the house per config.
+fn pay_ai_win_stake<'info>(
+    escrow: &Account<'info, BattleEscrow>,
+    treasury: &mut Account<'info, Treasury>,
+    player_owner: &AccountInfo<'info>,
+    settings: &GameSettings,
+    stake: u64,
+) -> Result<()> {
+    if settings.pve_timeout_refunds_stake {
+        pay_from_escrow(escrow, player_owner, stake)
+    } else {
+        pay_from_escrow(escrow, &treasury.to_account_info(), stake)?;
+        treasury.lifetime_fees_collected += stake;
+        Ok(())
+    }
+}
+
+// Bounds keep a typo in an update from bricking battles or the economy
+fn validate_settings(settings: &GameSettings) -> Result<()> {
+    require!(
+        (10..=600).contains(&settings.turn_timeout_seconds),
+        GameError::InvalidConfig
+    );
+    require!(
+        (600..=86_400).contains(&settings.battle_expiry_seconds)
+            && settings.battle_expiry_seconds > settings.turn_timeout_seconds,
+        GameError::InvalidConfig
+    );
+    require!(
+        (5..=120).contains(&settings.wildcard_decision_timeout),
+        GameError::InvalidConfig
+    );
+    require!(
+        (0..=3600).contains(&settings.min_battle_interval_seconds),
+        GameError::InvalidConfig
+    );
+    require!(
+        (300..=7 * 86_400).contains(&settings.challenge_window_seconds),
+        GameError::InvalidConfig
+    );
+    require!(
+        (60..=86_400).contains(&settings.queue_timeout_seconds),
+        GameError::InvalidConfig
+    );
+    require!(
+        (1..=TURN_RECORD_CAPACITY as u32).contains(&settings.max_battle_turns),
+        GameError::InvalidConfig
+    );
+    require!(
+        (1..=settings.max_battle_turns).contains(&settings.betting_closes_at_turn),
+        GameError::InvalidConfig
+    );
+    require!(
+        (86_400..=90 * 86_400).contains(&settings.bet_claim_window_seconds)
+            && (86_400..=90 * 86_400).contains(&settings.season_claim_window_seconds),
+        GameError::InvalidConfig
+    );
+    require!(
+        settings.base_wildcard_chance <= 100 && settings.trickster_wildcard_chance <= 100,
+        GameError::InvalidConfig
+    );
+    require!(settings.heal_price_per_hp <= 10_000_000, GameError::InvalidConfig); // 0.01 SOL
+    require!(settings.revive_cost <= 1_000_000_000, GameError::InvalidConfig); // 1 SOL
+    require!(settings.respec_cost <= 10_000_000_000, GameError::InvalidConfig); // 10 SOL
+    require!(
+        (0..=86_400).contains(&settings.revive_downtime_seconds),
+        GameError::InvalidConfig
+    );
+    require!(settings.default_house_edge <= MAX_HOUSE_EDGE, GameError::InvalidConfig);
+    require!(
+        settings.mmr_k_ranked <= 128
+            && settings.mmr_k_provisional <= 128
+            && settings.mmr_k_casual <= 128,
+        GameError::InvalidConfig
+    );
+    require!(
+        (1..=50_000).contains(&settings.xp_multiplier),
+        GameError::InvalidConfig
+    ); // Up to 5x for events
+    require!(
+        (50..=5000).contains(&settings.max_mmr_gap),
+        GameError::InvalidConfig
+    );
+    require!(settings.loser_xp_percent <= 100, GameError::InvalidConfig);
+    require!(
+        settings.special_cooldowns.iter().all(|cooldown| (1..=10).contains(cooldown)),
+        GameError::InvalidConfig
+    );
+    require!(
+        settings.class_stats.iter().all(|stats| {
+            (1..=1000).contains(&stats.max_hp)
+                && stats.damage_min >= 1
+                && stats.damage_min <= stats.damage_max
+                && stats.damage_max <= 100
+                && stats.crit_chance <= MAX_CRIT_CHANCE
+                && stats.dodge_chance <= MAX_DODGE_CHANCE
+        }),
+        GameError::InvalidConfig
+    );
+    require!(settings.bet_limits.is_valid(), GameError::InvalidConfig);
+    require!(
+        settings.abandon_threshold >= 1
+            && (3600..=30 * 86_400).contains(&settings.abandon_window_seconds)
+            && (60..=30 * 86_400).contains(&settings.abandon_ban_seconds),
+        GameError::InvalidConfig
+    );
+    require!(
+        (5..=settings.turn_timeout_seconds).contains(&settings.reveal_timeout_seconds),
+        GameError::InvalidConfig
+    );
+    Ok(())
+}
+
+fn special_cooldown_for(class: CharacterClass, settings: &GameSettings) -> u8 {
+    settings.special_cooldowns[class as usize]
+}
+
+fn mmr_bucket(mmr: u64) -> u16 {
+    (mmr / MMR_BUCKET_SIZE).min(u16::MAX as u64) as u16
+}
+
+// Ranked PvP needs comparable MMRs so nobody farms much weaker players
+fn check_mmr_gap(
+    match_type: MatchType,
+    player1: &Character,
+    player2: &Character,
+    settings: &GameSettings,
+) -> Result<()> {
+    if match_type == MatchType::Ranked {
+        require!(
+            player1.ranked_mmr.abs_diff(player2.ranked_mmr) <= settings.max_mmr_gap,
+            GameError::MmrGapTooLarge
+        );
+    }
+    Ok(())
+}
+
+// Orders (mmr, character) pairs into a bracket where each adjacent pair meets
+// in round one: highest against lowest, second against second lowest, and so
+// on. With an odd count the top seed takes the bye at the end.
+fn seed_bracket(mut seeded: Vec<(u64, Pubkey)>) -> Vec<Pubkey> {
+    seeded.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let bye = if seeded.len() % 2 == 1 { Some(seeded.remove(0).1) } else { None };
+
+    let mut bracket = Vec::with_capacity(seeded.len() + 1);
+    for i in 0..seeded.len() / 2 {
+        bracket.push(seeded[i].1);
+        bracket.push(seeded[seeded.len() - 1 - i].1);
+    }
+    bracket.extend(bye);
+    bracket
+}
+
+// Position in participants, which is also the character's eliminated bit
+fn tournament_index(tournament: &Tournament, character: &Pubkey) -> Result<usize> {
+    tournament
+        .participants
+        .iter()
+        .position(|key| key == character)
+        .ok_or_else(|| error!(GameError::NotTournamentParticipant))
+}
+
+// A guild emptied by its last member has no leader, whoever joins next takes
+// over, treasury included, so donations are never stranded
+fn add_guild_member(guild: &mut Guild, character: Pubkey) {
+    if guild.leader == Pubkey::default() {
+        guild.leader = character;
+    }
+    guild.member_count += 1;
+}
+
+fn remove_guild_member(guild: &mut Guild) {
+    guild.member_count -= 1;
+    if guild.member_count == 0 {
+        guild.leader = Pubkey::default();
+    }
+}
+
+// Bets stop at the pool's own cutoff turn, when the battle ends first, or
+// once the market's outcome is already known
+fn betting_has_closed(battle: &Battle, pool: &BettingPool) -> bool {
+    battle.is_finished != 0 || battle.turn_number >= pool.betting_closes_at_turn || pool.market.is_decided(battle)
+}
+
+// Only a wallet holding the character's one NFT token
+fn holds_character_nft(character: &Character, mint: Pubkey, token_owner: Pubkey, amount: u64, holder: &Pubkey) -> bool {
+    character.nft_mint == Some(mint) && token_owner == *holder && amount == 1
+}
+
+// Adds the gear's bonuses to the character's stats and takes its slot
+fn equip_gear(character: &mut Character, character_key: Pubkey, equipment: &mut Equipment, equipment_key: Pubkey) -> Result<()> {
+    require!(equipment.equipped_to.is_none(), GameError::EquipmentAlreadyEquipped);
+    let slot = equipment.slot.index();
+    require!(character.equipment[slot].is_none(), GameError::EquipmentSlotOccupied);
+
+    let stats = &equipment.stats;
+    character.base_damage_min = character.base_damage_min.checked_add(stats.damage_min).ok_or(GameError::StatOutOfRange)?;
+    character.base_damage_max = character.base_damage_max.checked_add(stats.damage_max).ok_or(GameError::StatOutOfRange)?;
+    character.crit_chance = character.crit_chance.checked_add(stats.crit_chance).ok_or(GameError::StatOutOfRange)?;
+    character.dodge_chance = character.dodge_chance.checked_add(stats.dodge_chance).ok_or(GameError::StatOutOfRange)?;
+    character.defense = character.defense.checked_add(stats.defense).ok_or(GameError::StatOutOfRange)?;
+    character.max_hp = character.max_hp.checked_add(stats.max_hp).ok_or(GameError::StatOutOfRange)?;
+
+    character.equipment[slot] = Some(equipment_key);
+    equipment.equipped_to = Some(character_key);
+    Ok(())
+}
+
+fn unequip_gear(character: &mut Character, equipment: &mut Equipment, equipment_key: Pubkey) -> Result<()> {
+    let slot = equipment.slot.index();
+    require!(character.equipment[slot] == Some(equipment_key), GameError::EquipmentNotEquipped);
+
+    let stats = &equipment.stats;
+    character.base_damage_min = character.base_damage_min.checked_sub(stats.damage_min).ok_or(GameError::StatOutOfRange)?;
+    character.base_damage_max = character.base_damage_max.checked_sub(stats.damage_max).ok_or(GameError::StatOutOfRange)?;
+    character.crit_chance = character.crit_chance.checked_sub(stats.crit_chance).ok_or(GameError::StatOutOfRange)?;
+    character.dodge_chance = character.dodge_chance.checked_sub(stats.dodge_chance).ok_or(GameError::StatOutOfRange)?;
+    character.defense = character.defense.checked_sub(stats.defense).ok_or(GameError::StatOutOfRange)?;
+    character.max_hp = character.max_hp.checked_sub(stats.max_hp).ok_or(GameError::StatOutOfRange)?;
+    character.current_hp = character.current_hp.min(character.max_hp);
+
+    character.equipment[slot] = None;
+    equipment.equipped_to = None;
+    Ok(())
+}
+
+// Only clears the marker this tournament set, a character that has since
+// joined another tournament stays entered there
+fn release_from_tournament(character: &mut Character, tournament: &Pubkey) {
+    if character.tournament == Some(*tournament) {
+        character.tournament = None;
+    }
+}
+
+// Pays out nothing itself: sets the winner (or refund mode), moves the house
+// cut and any fixed-odds surplus to the Treasury, claims do the rest
+// Only the challenged player may decline before expires_at, anyone after it.
+// Returns whether the challenge has expired.
+fn check_can_clear_challenge(
+    challenge: &Challenge,
+    challenged_character: &Character,
+    signer: &Pubkey,
+    clock: &Clock,
+) -> Result<bool> {
+    let expired = clock.unix_timestamp >= challenge.expires_at;
+    require!(expired || *signer == challenged_character.owner, GameError::NotChallengedPlayer);
+    Ok(expired)
+}
+
+// Once both stances are in, whoever hasn't revealed within the shorter
+// reveal window forfeits. Otherwise the current player does on the turn timeout.
+fn timed_out_player(battle: &Battle, settings: &GameSettings, clock: &Clock) -> Option<u8> {
+    let reveal_expired = battle.both_committed_at != 0
+        && clock.unix_timestamp - battle.both_committed_at > settings.reveal_timeout_seconds;
+    if reveal_expired {
+        match (battle.player1_stance_revealed != 0, battle.player2_stance_revealed != 0) {
+            (false, true) => Some(1),
+            (true, false) => Some(2),
+            _ => Some(battle.current_turn),
+        }
+    } else if clock.unix_timestamp - battle.last_action_time > settings.turn_timeout_seconds {
+        Some(battle.current_turn)
+    } else {
+        None
+    }
+}
+
+// The attacker has to be in the battle and the defender its opponent.
+// Returns whether the attacker is player 1.
+fn turn_sides(battle: &Battle, attacker: &Pubkey, defender: &Pubkey) -> Result<bool> {
+    let is_player1 = battle.player1 == *attacker;
+    require!(is_player1 || battle.player2 == *attacker, GameError::NotBattleParticipant);
+    let opponent = if is_player1 { battle.player2 } else { battle.player1 };
+    require_keys_eq!(*defender, opponent, GameError::WrongDefender);
+    Ok(is_player1)
+}
+
+// Level gap bonus capped at 50, then the event multiplier
+fn winner_xp(base_xp: u64, level_diff: u64, settings: &GameSettings) -> u64 {
+    let xp_bonus = if level_diff > 5 { 50 } else { level_diff * 10 };
+    (base_xp + xp_bonus) * settings.xp_multiplier as u64 / 10_000
+}
+
+// Consolation XP for the loser, none for a forfeit or the AI
+fn consolation_xp(battle: &Battle, base_xp: u64, settings: &GameSettings) -> u64 {
+    let ai_lost = battle.is_vs_ai != 0 && battle.winner == 1;
+    if battle.abandoned != 0 || ai_lost {
+        return 0;
+    }
+    base_xp * settings.loser_xp_percent as u64 / 100 * settings.xp_multiplier as u64 / 10_000
+}
+
+// Practice leaves both records exactly as they were, apart from the heal.
+// Returns false for every other match type, which finalize_battle records.
+fn finish_practice_battle(battle: &Battle, player1: &mut Character, player2: &mut Character) -> bool {
+    if MatchType::from_code(battle.match_type) != MatchType::Practice {
+        return false;
+    }
+    player1.current_hp = player1.max_hp;
+    player2.current_hp = player2.max_hp;
+    true
+}
+
+fn pool_house_edge(requested: Option<u8>, settings: &GameSettings) -> Result<u8> {
+    let house_edge = requested.unwrap_or(settings.default_house_edge);
+    require!(house_edge <= MAX_HOUSE_EDGE, GameError::InvalidHouseEdge);
+    Ok(house_edge)
+}
+
+// Why an unfinished battle's pool can be voided, PoolNotVoidable while it may still finish
+fn void_reason(battle: &Battle, settings: &GameSettings, clock: &Clock) -> Result<VoidReason> {
+    let age = clock.unix_timestamp - battle.created_at;
+    if age >= settings.battle_expiry_seconds {
+        Ok(VoidReason::Expired)
+    } else if battle.turn_number == 0 && age >= BETTING_START_WINDOW_SECONDS {
+        Ok(VoidReason::NeverStarted)
+    } else {
+        err!(GameError::PoolNotVoidable)
+    }
+}
+
+// finalize_battle's inline settlement, a pool someone already settled
+// through settle_betting_pool is left alone
+fn settle_pool_if_open<'info>(
+    pool: &mut Account<'info, BettingPool>,
+    battle: &Battle,
+    treasury: &mut Account<'info, Treasury>,
+    clock: &Clock,
+) -> Result<()> {
+    if pool.is_settled {
+        return Ok(());
+    }
+    settle_pool(pool, battle, treasury, clock)
+}
+
+fn settle_pool<'info>(
+    pool: &mut Account<'info, BettingPool>,
+    battle: &Battle,
+    treasury: &mut Account<'info, Treasury>,
+    clock: &Clock,
+) -> Result<()> {
+    require!(battle.is_finished != 0, GameError::BattleNotFinished);
+    require!(!pool.is_settled, GameError::PoolAlreadySettled);
+
+    pool.is_settled = true;
+    pool.settled_at = clock.unix_timestamp;
+
+    // A forfeit is not a real result, so nobody wins on it
+    if battle.abandoned != 0 {
+        pool.refund_mode = true;
+        emit!(PoolVoided {
+            betting_pool: pool.key(),
+            battle: pool.battle,
+            reason: VoidReason::Abandoned,
+        });
+        msg!("Betting pool settled in refund mode (battle abandoned)");
+        return Ok(());
+    }
+
+    // A draw only voids the moneyline, propositions still resolve
+    if battle.is_draw != 0 && pool.market == BetMarket::Winner {
+        pool.refund_mode = true;
+        emit!(PoolVoided {
+            betting_pool: pool.key(),
+            battle: pool.battle,
+            reason: VoidReason::Draw,
+        });
+        msg!("Betting pool settled in refund mode (draw)");
+        return Ok(());
+    }
+
+    let Some(winner) = pool.market.resolve(battle) else {
+        pool.refund_mode = true;
+        emit!(PoolVoided {
+            betting_pool: pool.key(),
+            battle: pool.battle,
+            reason: VoidReason::Push,
+        });
+        msg!("Betting pool settled in refund mode (push)");
+        return Ok(());
+    };
+
+    // Nobody backed the winner, so it's a no-contest: everyone gets their
+    // stake back and the house takes nothing
+    let winning_side = if winner == 1 { pool.player1_bets } else { pool.player2_bets };
+    if winning_side == 0 {
+        pool.refund_mode = true;
+        emit!(PoolVoided {
+            betting_pool: pool.key(),
+            battle: pool.battle,
+            reason: VoidReason::NoWinningBets,
+        });
+        msg!("Betting pool settled in refund mode (no bets on the winner)");
+        return Ok(());
+    }
+
+    pool.winner = Some(winner);
+
+    // The house cut leaves the pool now, claims split what's left
+    let house_cut = (pool.total_pool * pool.house_edge as u64) / 100;
+    if house_cut > 0 {
+        **pool.to_account_info().try_borrow_mut_lamports()? -= house_cut;
+        **treasury.to_account_info().try_borrow_mut_lamports()? += house_cut;
+        treasury.lifetime_fees_collected += house_cut;
+    }
+
+    // A fixed-odds book that owes winners less than it holds keeps the rest
+    if pool.odds_mode == OddsMode::FixedAtBet {
+        let distributable = pool.total_pool - house_cut;
+        let liability = if winner == 1 { pool.player1_liability } else { pool.player2_liability };
+        let surplus = distributable.saturating_sub(liability);
+        if surplus > 0 {
+            **pool.to_account_info().try_borrow_mut_lamports()? -= surplus;
+            **treasury.to_account_info().try_borrow_mut_lamports()? += surplus;
+            treasury.lifetime_fees_collected += surplus;
+        }
+    }
+
+    emit!(PoolSettled {
+        betting_pool: pool.key(),
+        battle: pool.battle,
+        winner,
+        house_cut,
+    });
+
+    msg!("Betting pool settled. Winning side: {}", winner);
+    Ok(())
+}
+
+// Items go between committing and revealing, one per turn up to
+// MAX_ITEMS_PER_BATTLE, and a potion needs missing HP
+fn check_item_use(battle: &Battle, is_player1: bool, item: BattleItem) -> Result<()> {
+    let (committed, revealed, item_this_turn, items_used) = if is_player1 {
+        (
+            battle.player1_stance_committed,
+            battle.player1_stance_revealed,
+            battle.player1_item_this_turn,
+            battle.player1_items_used,
+        )
+    } else {
+        (
+            battle.player2_stance_committed,
+            battle.player2_stance_revealed,
+            battle.player2_item_this_turn,
+            battle.player2_items_used,
+        )
+    };
+    require!(committed != 0 && revealed == 0, GameError::ItemUseWindowClosed);
+    require!(item_this_turn == 0, GameError::ItemAlreadyUsedThisTurn);
+    require!(items_used < MAX_ITEMS_PER_BATTLE, GameError::BattleItemLimitReached);
+    if item == BattleItem::HealthPotion {
+        let (hp, max_hp) = if is_player1 {
+            (battle.player1_hp, battle.player1_max_hp)
+        } else {
+            (battle.player2_hp, battle.player2_max_hp)
+        };
+        require!(hp < max_hp, GameError::AlreadyFullHealth);
+    }
+    Ok(())
+}
+
+// Applies the item's effect and counts it, returns the amount for the log
+fn apply_battle_item(battle: &mut Battle, is_player1: bool, item: BattleItem) -> u64 {
+    let player = if is_player1 { 1 } else { 2 };
+    let amount = match item {
+        BattleItem::HealthPotion => {
+            let hp_before = if is_player1 { battle.player1_hp } else { battle.player2_hp };
+            heal_player(battle, player, HEALTH_POTION_HP);
+            let hp_after = if is_player1 { battle.player1_hp } else { battle.player2_hp };
+            hp_after - hp_before
+        }
+        BattleItem::AdrenalineShot => {
+            if is_player1 {
+                battle.player1_combo += 1;
+            } else {
+                battle.player2_combo += 1;
+            }
+            record_peak_combos(battle);
+            1
+        }
+        BattleItem::SmokeBomb => {
+            if is_player1 {
+                battle.player1_smoke_dodge = SMOKE_BOMB_DODGE;
+            } else {
+                battle.player2_smoke_dodge = SMOKE_BOMB_DODGE;
+            }
+            SMOKE_BOMB_DODGE as u64
+        }
+    };
+
+    if is_player1 {
+        battle.player1_item_this_turn = item.code();
+        battle.player1_items_used += 1;
+    } else {
+        battle.player2_item_this_turn = item.code();
+        battle.player2_items_used += 1;
+    }
+    amount
+}
+
+// Only a dead character outside any battle, revive_downtime_seconds after its last one
+fn check_can_revive(character: &Character, settings: &GameSettings, clock: &Clock) -> Result<()> {
+    require!(character.current_hp == 0, GameError::CharacterNotDead);
+    require!(character.in_battle.is_none(), GameError::CharacterBusy);
+    require!(
+        clock.unix_timestamp - character.last_battle >= settings.revive_downtime_seconds,
+        GameError::ReviveCooldownActive
+    );
+    Ok(())
+}
+
+// HP a heal restores and its price, amount is capped at the missing HP and 0
+// heals to full
+fn heal_quote(character: &Character, amount: u64, settings: &GameSettings) -> Result<(u64, u64)> {
+    require!(character.current_hp > 0, GameError::CharacterDead); // See revive_character
+    require!(
+        character.current_hp < character.max_hp,
+        GameError::AlreadyFullHealth
+    );
+    require!(character.in_battle.is_none(), GameError::CharacterBusy);
+
+    let missing_hp = character.max_hp - character.current_hp;
+    let hp_restored = if amount == 0 { missing_hp } else { amount.min(missing_hp) };
+    // A free heal credit (TenWins reward) covers the whole heal
+    let heal_cost = if character.free_heals > 0 { 0 } else { hp_restored * settings.heal_price_per_hp };
+    Ok((hp_restored, heal_cost))
+}
+
+// Only clears the lock this battle set, a stale battle can't free a character
+fn release_character(character: &mut Character, battle_key: Pubkey) {
+    if character.in_battle == Some(battle_key) {
+        character.in_battle = None;
+    }
+}
+
+fn check_battle_cooldown(character: &Character, settings: &GameSettings, clock: &Clock) -> Result<()> {
+    let time_since_last_battle = clock.unix_timestamp - character.last_battle;
+    require!(
+        time_since_last_battle >= settings.min_battle_interval_seconds,
+        GameError::BattleCooldownActive
+    );
+    Ok(())
+}
+
+fn check_not_banned(character: &Character, clock: &Clock) -> Result<()> {
+    require!(clock.unix_timestamp >= character.ban_until, GameError::TooManyAbandons);
+    Ok(())
+}
+
+// Counts a PvP forfeit, abandon_threshold of them inside abandon_window_seconds
+// bans the character from the queue and challenges for abandon_ban_seconds
+fn record_abandon(character: &mut Account<Character>, settings: &GameSettings, clock: &Clock) {
+    if clock.unix_timestamp - character.abandon_window_start > settings.abandon_window_seconds {
+        character.abandon_window_start = clock.unix_timestamp;
+        character.abandons = 0;
+    }
+    character.abandons = character.abandons.saturating_add(1);
+
+    if character.abandons >= settings.abandon_threshold {
+        character.ban_until = clock.unix_timestamp + settings.abandon_ban_seconds;
+        character.abandons = 0;
+        character.abandon_window_start = clock.unix_timestamp;
+
+        emit!(QueueBanned {
+            character: character.key(),
+            ban_until: character.ban_until,
+        });
+    }
+}
+
+// Fresh battle state, shared by create_battle and accept_challenge. Every
+// battle, rematches included, gets a new account (battle_nonce is in the
+// seeds). Transient effects (DoT, reflection, cooldowns, items) are still
+// reset explicitly rather than relying on the account starting zeroed.
+fn init_battle(
+    battle: &mut Battle,
+    player1: &Account<Character>,
+    player2: &Account<Character>,
+    match_type: MatchType,
+    stake_amount: u64,
+    is_vs_ai: bool,
+    clock: &Clock,
+) {
+    battle.player1 = player1.key();
+    battle.player2 = player2.key();
+    battle.match_type = match_type.code();
+    battle.stake_amount = stake_amount;
+    battle.created_at = clock.unix_timestamp;
+    battle.turn_number = 0;
+    battle.current_turn = 1;
+    battle.is_finished = 0;
+    battle.winner = 0;
+    battle.is_draw = 0;
+    battle.is_vs_ai = is_vs_ai as u8;
+    battle.abandoned = 0;
+    battle.last_action_time = clock.unix_timestamp;
+
+    battle.player1_hp = player1.max_hp;
+    battle.player2_hp = player2.max_hp;
+    battle.player1_max_hp = player1.max_hp;
+    battle.player2_max_hp = player2.max_hp;
+    let (stats1, stats2) = (CombatStats::from(&**player1), CombatStats::from(&**player2));
+    battle.levels = [stats1.level, stats2.level];
+    battle.damage_min = [stats1.base_damage_min, stats2.base_damage_min];
+    battle.damage_max = [stats1.base_damage_max, stats2.base_damage_max];
+    battle.crit_chance = [stats1.crit_chance, stats2.crit_chance];
+    battle.dodge_chance = [stats1.dodge_chance, stats2.dodge_chance];
+    battle.defense = [stats1.defense, stats2.defense];
+    battle.player1_combo = 0;
+    battle.player2_combo = 0;
+    battle.player1_peak_combo = 0;
+    battle.player2_peak_combo = 0;
+    battle.player1_stance = BattleStance::Balanced.code();
+    battle.player2_stance = BattleStance::Balanced.code();
+    battle.player1_stance_committed = 0;
+    battle.player2_stance_committed = 0;
+    battle.player1_stance_hash = [0u8; 32];
+    battle.player2_stance_hash = [0u8; 32];
+    battle.player1_stance_revealed = 0;
+    battle.player2_stance_revealed = 0;
+    battle.pending_use_special = 0;
+    battle.player1_salt = 0;
+    battle.player2_salt = 0;
+    battle.player1_dot_damage = 0;
+    battle.player2_dot_damage = 0;
+    battle.player1_dot_turns = 0;
+    battle.player2_dot_turns = 0;
+    battle.player1_reflection = 0;
+    battle.player2_reflection = 0;
+    battle.player1_miss_count = 0;
+    battle.player2_miss_count = 0;
+    battle.player1_pending_miss = 0;
+    battle.player2_pending_miss = 0;
+    battle.player1_special_cooldown = 0;
+    battle.player2_special_cooldown = 0;
+    battle.random_seed = [0u8; 32];
+    battle.randomness_account = Pubkey::default();
+    battle.randomness_pending = 0;
+    battle.seed_turn = 0;
+    battle.seed_consumed = 1;
+    battle.roll_counter = 0;
+    battle.last_damage_roll = 0;
+    battle.wildcard_active = 0;
+    battle.wildcard_type = 0;
+    battle.wildcard_decision_deadline = 0;
+    battle.both_committed_at = 0;
+    battle.wildcard_player1_decision = WILDCARD_UNDECIDED;
+    battle.wildcard_player2_decision = WILDCARD_UNDECIDED;
+    battle.player1_item_this_turn = 0;
+    battle.player2_item_this_turn = 0;
+    battle.player1_items_used = 0;
+    battle.player2_items_used = 0;
+    battle.player1_smoke_dodge = 0;
+    battle.player2_smoke_dodge = 0;
+    battle.battle_log_len = 0;
+    battle.battle_log_next = 0;
+    battle.first_blood = 0;
+    battle.wildcards_triggered = 0;
+    battle.log_offset = 0;
+    battle.turn_records = [TurnRecord::default(); TURN_RECORD_CAPACITY];
+}
+
+fn requires_decision(wildcard: WildcardEvent) -> bool {
+    matches!(
+        wildcard,
+        WildcardEvent::DoubleOrNothing | WildcardEvent::DeathRoulette
+    )
+}
+
+// The log is a ring buffer: once full, the oldest entry (at battle_log_next)
+// is overwritten. Every entry is also emitted with its sequence index, so
+// indexers can rebuild the full history.
+fn log_battle_event(battle: &mut Battle, battle_key: Pubkey, event: BattleLogEvent) {
+    let next = battle.battle_log_next as usize;
+    battle.battle_log[next] = event.into();
+    battle.battle_log_next = ((next + 1) % BATTLE_LOG_CAPACITY) as u8;
+    battle.battle_log_len = (battle.battle_log_len as usize + 1).min(BATTLE_LOG_CAPACITY) as u8;
+
+    emit!(BattleLogAppended {
+        battle: battle_key,
+        turn: battle.turn_number,
+        index: battle.log_offset,
+        entry: event,
+    });
+    battle.log_offset += 1;
+}
+
+// Log amounts are stored as u32, HP values never come close
+fn log_amount(amount: u64) -> u32 {
+    amount.min(u32::MAX as u64) as u32
+}
+
+fn execute_battle_turn(
+    battle: &mut Battle,
+    battle_key: Pubkey,
+    attacker: &Character,
+    defender: &Character,
+    is_player1: bool,
+    use_special: bool,
+    settings: &GameSettings,
+) -> Result<()> {
+    // Every damage source this turn is collected here and applied at once,
+    // so nobody is knocked out halfway through resolving the turn
+    let mut turn_damage = TurnDamage::default();
+    let turn_number = battle.turn_number;
+
+    // The attacker's turn starts here, after the reveal already checked its
+    // cooldown. A special with cooldown N sits out the caster's next N turns.
+    if is_player1 {
+        battle.player1_special_cooldown = battle.player1_special_cooldown.saturating_sub(1);
+    } else {
+        battle.player2_special_cooldown = battle.player2_special_cooldown.saturating_sub(1);
+    }
+
+    // DOT ticks for both players once per round (two turns), before the
+    // round's first attack, no matter who is acting
+    if battle.turn_number.is_multiple_of(2) {
+        process_start_of_round(battle, battle_key, &mut turn_damage);
+    }
+
+    let outcome = calculate_damage(
+        attacker,
+        defender,
+        battle,
+        is_player1,
+        use_special,
+        settings,
+    )?;
+
+    // Divine Light heals the caster, so a dodge on the attack doesn't stop it
+    if outcome.special_effect == Some(SpecialEffect::DivineLight) {
+        apply_special_effect(battle, battle_key, is_player1, SpecialEffect::DivineLight);
+    }
+
+    // A dodge fully negates the attack, special effects included
+    if outcome.was_dodged {
+        if is_player1 {
+            battle.player1_miss_count += 1;
+        } else {
+            battle.player2_miss_count += 1;
+        }
+        log_battle_event(battle, battle_key, BattleLogEvent::Dodged { by: if is_player1 { 2 } else { 1 } });
+    } else {
+        // A crit ends the attacker's cold streak
+        if outcome.was_crit {
+            if is_player1 {
+                battle.player1_miss_count = 0;
+            } else {
+                battle.player2_miss_count = 0;
+            }
+        }
+
+        if let Some(effect) = outcome.special_effect.filter(|effect| *effect != SpecialEffect::DivineLight) {
+            apply_special_effect(battle, battle_key, is_player1, effect);
+        }
+    }
+
+    // Ones digit of this roll is what LuckySeven looks at
+    battle.last_damage_roll = (outcome.amount % 10) as u8;
+
+    let mut damage = outcome.amount;
+
+    let (attacker_stance, defender_stance) = if is_player1 {
+        (BattleStance::from_code(battle.player1_stance), BattleStance::from_code(battle.player2_stance))
+    } else {
+        (BattleStance::from_code(battle.player2_stance), BattleStance::from_code(battle.player1_stance))
+    };
+
+    let (stance_damage, riposte) = apply_stance_modifiers(damage, attacker_stance, defender_stance);
+    damage = stance_damage;
+
+    let (attacker_player, defender_player) = if is_player1 { (1, 2) } else { (2, 1) };
+
+    let wildcard_applied = if battle.wildcard_active != 0 { WildcardEvent::from_code(battle.wildcard_type) } else { None };
+    if let Some(wildcard_type) = wildcard_applied {
+        damage = apply_wildcard_effects(damage, battle, battle_key, is_player1)?;
+
+        emit!(WildcardApplied {
+            battle: battle_key,
+            wildcard_type,
+            player: if is_player1 { 1 } else { 2 },
+            damage,
+        });
+    }
+
+    // A lost Double or Nothing makes this attack whiff
+    let pending_miss = if is_player1 { battle.player1_pending_miss } else { battle.player2_pending_miss };
+    if pending_miss != 0 {
+        damage = 0;
+        // Forced, not rolled, so Gambler's Fallacy doesn't count it
+        if is_player1 {
+            battle.player1_pending_miss = 0;
+        } else {
+            battle.player2_pending_miss = 0;
+        }
+        log_battle_event(battle, battle_key, BattleLogEvent::PendingMissConsumed { player: if is_player1 { 1 } else { 2 } });
+    }
+
+    // Venomous poisons on every hit that lands
+    let venom_applied = attacker_stance == BattleStance::Venomous && !outcome.was_dodged && damage > 0;
+    if venom_applied {
+        stack_venom(battle, battle_key, defender_player);
+    }
+
+    turn_damage.add(defender_player, damage);
+    if damage > 0 && battle.first_blood == 0 {
+        battle.first_blood = attacker_player;
+    }
+    log_battle_event(battle, battle_key, BattleLogEvent::DamageDealt {
+        by: attacker_player,
+        amount: log_amount(damage),
+        crit: outcome.was_crit && !outcome.was_dodged,
+    });
+
+    // Berserker pays 25% of what actually landed, so a whiff costs nothing
+    let mut self_damage = 0;
+    if attacker_stance == BattleStance::Berserker {
+        self_damage = (damage * 25) / 100;
+        if self_damage > 0 {
+            turn_damage.add(attacker_player, self_damage);
+            log_battle_event(battle, battle_key, BattleLogEvent::SelfDamage { player: attacker_player, amount: log_amount(self_damage) });
+        }
+    }
+
+    // Reflection belongs to the defender and bounces part of the hit back
+    let reflection = if is_player1 { battle.player2_reflection } else { battle.player1_reflection };
+    let mut reflected = 0;
+    if reflection > 0 && !outcome.was_dodged {
+        reflected = (damage * reflection as u64) / 100;
+        turn_damage.add(attacker_player, reflected);
+        log_battle_event(battle, battle_key, BattleLogEvent::Reflected { player: attacker_player, amount: log_amount(reflected) });
+    }
+
+    // A Counter defender strikes back at an all-in attacker
+    if riposte > 0 {
+        turn_damage.add(attacker_player, riposte);
+        log_battle_event(battle, battle_key, BattleLogEvent::Riposte { player: attacker_player, amount: log_amount(riposte) });
+    }
+
+    battle.player1_hp = battle.player1_hp.saturating_sub(turn_damage.player1);
+    battle.player2_hp = battle.player2_hp.saturating_sub(turn_damage.player2);
+
+    update_attack_combo(battle, is_player1, damage, attacker_stance, settings);
+    record_peak_combos(battle);
+
+    // Set special cooldown
+    if use_special {
+        let cooldown = special_cooldown_for(attacker.character_class, settings);
+        if is_player1 {
+            battle.player1_special_cooldown = cooldown;
+        } else {
+            battle.player2_special_cooldown = cooldown;
+        }
+    }
+
+    let attacker_combo = if is_player1 { battle.player1_combo } else { battle.player2_combo };
+    if let Some(record) = battle.turn_records.get_mut(turn_number as usize) {
+        *record = TurnRecord {
+            damage,
+            turn: turn_number as u16,
+            attacker: attacker_player,
+            was_crit: outcome.was_crit as u8,
+            was_dodged: outcome.was_dodged as u8,
+            stance: attacker_stance.code(),
+            wildcard: wildcard_applied.map_or(0, WildcardEvent::code),
+            combo: attacker_combo.min(u8::MAX as u16) as u8,
+            seed: battle.random_seed,
+        };
+    }
+
+    emit!(TurnExecuted {
+        battle: battle_key,
+        turn: turn_number,
+        player: attacker_player,
+        stance: attacker_stance,
+        base_damage: outcome.base_damage,
+        was_crit: outcome.was_crit,
+        was_dodged: outcome.was_dodged,
+        combo_multiplier: outcome.combo_multiplier,
+        combo: attacker_combo,
+        stance_modifier: stance_adjusted_damage(100, attacker_stance, defender_stance) as u16,
+        wildcard: wildcard_applied,
+        applied_dot: venom_applied
+            || (outcome.special_effect == Some(SpecialEffect::ArcaneBurst) && !outcome.was_dodged),
+        final_damage: damage,
+        self_damage,
+        reflected_damage: reflected,
+        riposte_damage: riposte,
+        player1_hp: battle.player1_hp,
+        player2_hp: battle.player2_hp,
+    });
+
+    // Switch turns
+    battle.current_turn = if battle.current_turn == 1 { 2 } else { 1 };
+    battle.turn_number += 1;
+    battle.wildcard_active = 0;
+
+    resolve_battle_end(battle, battle_key, settings.max_battle_turns);
+
+    Ok(())
+}
+
+// Result of a single attack roll. Side effects are only applied by
+// execute_battle_turn, and only when the attack wasn't dodged.
+struct DamageOutcome {
+    amount: u64,
+    base_damage: u64,
+    combo_multiplier: u16, // Percent, 100 = no combo bonus
+    was_crit: bool,
+    was_dodged: bool,
+    special_effect: Option<SpecialEffect>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SpecialEffect {
+    ArcaneBurst,
+    FortressStance,
+    StealCombo,
+    SwapStances,
+    Evasion,
+    ExtraWildcard,
+    DivineLight,
+}
+
+fn apply_special_effect(battle: &mut Battle, battle_key: Pubkey, is_player1: bool, effect: SpecialEffect) {
+    match effect {
+        SpecialEffect::ArcaneBurst => {
+            if is_player1 {
+                battle.player2_dot_damage = 15;
+                battle.player2_dot_turns = 3;
+            } else {
+                battle.player1_dot_damage = 15;
+                battle.player1_dot_turns = 3;
+            }
+        }
+        SpecialEffect::FortressStance => {
+            if is_player1 {
+                battle.player1_reflection = 50;
+            } else {
+                battle.player2_reflection = 50;
+            }
+        }
+        SpecialEffect::StealCombo => {
+            if is_player1 {
+                let stolen = battle.player2_combo;
+                battle.player1_combo += stolen;
+                battle.player2_combo = 0;
+            } else {
+                let stolen = battle.player1_combo;
+                battle.player2_combo += stolen;
+                battle.player1_combo = 0;
+            }
+        }
+        SpecialEffect::SwapStances => {
+            std::mem::swap(&mut battle.player1_stance, &mut battle.player2_stance);
+        }
+        SpecialEffect::Evasion => {
+            // Rides the smoke bomb slot, so it covers the next incoming attack only
+            let dodge = if is_player1 { &mut battle.player1_smoke_dodge } else { &mut battle.player2_smoke_dodge };
+            *dodge = (*dodge).max(EVASION_DODGE);
+        }
+        SpecialEffect::ExtraWildcard => {
+            battle.wildcard_active = 1;
+        }
+        SpecialEffect::DivineLight => {
+            let player = if is_player1 { 1 } else { 2 };
+            let (hp_before, max_hp) = if is_player1 {
+                (battle.player1_hp, battle.player1_max_hp)
+            } else {
+                (battle.player2_hp, battle.player2_max_hp)
+            };
+            heal_player(battle, player, (max_hp * 30) / 100);
+            let healed = if is_player1 { battle.player1_hp } else { battle.player2_hp } - hp_before;
+            log_battle_event(battle, battle_key, BattleLogEvent::Healed { player, amount: log_amount(healed) });
+        }
+    }
+}
+
+// Damage owed to each player for the turn being resolved
+#[derive(Default)]
+struct TurnDamage {
+    player1: u64,
+    player2: u64,
+}
+
+impl TurnDamage {
+    fn add(&mut self, player: u8, amount: u64) {
+        if player == 1 {
+            self.player1 = self.player1.saturating_add(amount);
+        } else {
+            self.player2 = self.player2.saturating_add(amount);
+        }
+    }
+}
+
+fn process_start_of_round(battle: &mut Battle, battle_key: Pubkey, turn_damage: &mut TurnDamage) {
+    if battle.player1_dot_turns > 0 {
+        let amount = battle.player1_dot_damage;
+        turn_damage.add(1, amount);
+        battle.player1_dot_turns -= 1;
+        log_battle_event(battle, battle_key, BattleLogEvent::DotTick { victim: 1, amount: amount.min(u16::MAX as u64) as u16 });
+
+        emit!(DotTick {
+            battle: battle_key,
+            victim: 1,
+            amount,
+            remaining_turns: battle.player1_dot_turns,
+        });
+    }
+
+    if battle.player2_dot_turns > 0 {
+        let amount = battle.player2_dot_damage;
+        turn_damage.add(2, amount);
+        battle.player2_dot_turns -= 1;
+        log_battle_event(battle, battle_key, BattleLogEvent::DotTick { victim: 2, amount: amount.min(u16::MAX as u64) as u16 });
+
+        emit!(DotTick {
+            battle: battle_key,
+            victim: 2,
+            amount,
+            remaining_turns: battle.player2_dot_turns,
+        });
+    }
+}
+
+// Each Venomous hit adds a stack to the victim's DOT, up to VENOM_DOT_CAP,
+// and refreshes its duration. A stronger DOT already running is left alone.
+fn stack_venom(battle: &mut Battle, battle_key: Pubkey, victim: u8) {
+    let (dot_damage, dot_turns) = if victim == 1 {
+        (&mut battle.player1_dot_damage, &mut battle.player1_dot_turns)
+    } else {
+        (&mut battle.player2_dot_damage, &mut battle.player2_dot_turns)
+    };
+    *dot_damage = (*dot_damage).max((*dot_damage + VENOM_DOT_PER_STACK).min(VENOM_DOT_CAP));
+    *dot_turns = (*dot_turns).max(VENOM_DOT_TURNS);
+    let amount = *dot_damage;
+    log_battle_event(battle, battle_key, BattleLogEvent::VenomStacked { victim, amount: amount.min(u16::MAX as u64) as u16 });
+}
+
+// All in-battle healing goes through here so HP never exceeds the
+// max HP snapshot taken when the battle was created
+fn heal_player(battle: &mut Battle, player: u8, amount: u64) {
+    if player == 1 {
+        battle.player1_hp = battle.player1_hp.saturating_add(amount).min(battle.player1_max_hp);
+    } else {
+        battle.player2_hp = battle.player2_hp.saturating_add(amount).min(battle.player2_max_hp);
+    }
+}
+
+fn heal_player_to_full(battle: &mut Battle, player: u8) {
+    heal_player(battle, player, u64::MAX);
+}
+
+// Single place a battle ends after a turn: a knockout wins (both down is a
+// draw), otherwise the turn limit decides on remaining HP percentage.
+// Returns true if the battle is now finished.
+fn resolve_battle_end(battle: &mut Battle, battle_key: Pubkey, max_battle_turns: u32) -> bool {
+    let player1_down = battle.player1_hp == 0;
+    let player2_down = battle.player2_hp == 0;
+
+    let reason = if player1_down || player2_down {
+        battle.winner = match (player1_down, player2_down) {
+            (true, true) => 0,
+            (true, false) => 2,
+            _ => 1,
+        };
+        BattleEndReason::Knockout
+    } else if battle.turn_number >= max_battle_turns {
+        // Cross-multiply instead of dividing so small differences aren't rounded away
+        let player1_share = battle.player1_hp as u128 * battle.player2_max_hp as u128;
+        let player2_share = battle.player2_hp as u128 * battle.player1_max_hp as u128;
+
+        battle.winner = if player1_share > player2_share {
+            1
+        } else if player2_share > player1_share {
+            2
+        } else {
+            0
+        };
+        BattleEndReason::TurnLimit
+    } else {
+        return false;
+    };
+
+    battle.is_finished = 1;
+    battle.is_draw = (battle.winner == 0) as u8;
+
+    let winner = battle.winner;
+    log_battle_event(battle, battle_key, BattleLogEvent::BattleFinished { winner });
+
+    emit!(BattleEnded {
+        battle: battle_key,
+        winner,
+        total_turns: battle.turn_number,
+        reason,
+    });
+
+    true
+}
+
+// Team battle sides are 1 (slots 0, 1) and 2 (slots 2, 3)
+fn team_side(slot: u8) -> u8 {
+    if slot < 2 { 1 } else { 2 }
+}
+
+fn team_turn_timed_out(team_battle: &TeamBattle, settings: &GameSettings, clock: &Clock) -> bool {
+    clock.unix_timestamp - team_battle.last_action_time > settings.turn_timeout_seconds
+}
+
+fn end_team_battle(team_battle: &mut TeamBattle, team_key: Pubkey, winning_side: u8) {
+    team_battle.is_finished = true;
+    team_battle.randomness_pending = false;
+    team_battle.winning_side = winning_side;
+
+    emit!(TeamBattleEnded {
+        team_battle: team_key,
+        winning_side,
+        total_turns: team_battle.turn_number,
+    });
+}
+
+// Next living character in TEAM_TURN_ORDER after the one that just acted
+fn next_team_slot(team_battle: &TeamBattle) -> u8 {
+    let current = TEAM_TURN_ORDER
+        .iter()
+        .position(|slot| *slot == team_battle.current_slot)
+        .unwrap_or(0);
+    (1..=TEAM_TURN_ORDER.len())
+        .map(|offset| TEAM_TURN_ORDER[(current + offset) % TEAM_TURN_ORDER.len()])
+        .find(|slot| team_battle.hp[*slot as usize] > 0)
+        .unwrap_or(team_battle.current_slot)
+}
+
+// Continuation of the smart contract - Part 2
+
+fn resolve_wildcard_with_decisions(battle: &mut Battle, battle_key: Pubkey) -> Result<()> {
+    let p1_accepts = battle.wildcard_player1_decision == WILDCARD_ACCEPTED;
+    let p2_accepts = battle.wildcard_player2_decision == WILDCARD_ACCEPTED;
+
+    if let Some(wildcard) = WildcardEvent::from_code(battle.wildcard_type) {
+        match wildcard {
+            WildcardEvent::DoubleOrNothing => {
+                if p1_accepts && p2_accepts {
+                    let roll = roll_range(battle, 0, 1);
+                    if roll == 0 {
+                        // Both miss next attack
+                        battle.player1_pending_miss = 1;
+                        battle.player2_pending_miss = 1;
+                        log_battle_event(battle, battle_key, BattleLogEvent::DoubleOrNothing { player: 0, won: false });
+                    } else {
+                        // Both get double damage next turn
+                        battle.player1_combo += 2;
+                        battle.player2_combo += 2;
+                        log_battle_event(battle, battle_key, BattleLogEvent::DoubleOrNothing { player: 0, won: true });
+                    }
+                } else if p1_accepts {
+                    // Only P1 risks
+                    let roll = roll_range(battle, 0, 1);
+                    if roll == 0 {
+                        battle.player1_pending_miss = 1;
+                        log_battle_event(battle, battle_key, BattleLogEvent::DoubleOrNothing { player: 1, won: false });
+                    } else {
+                        battle.player1_combo += 3;
+                        log_battle_event(battle, battle_key, BattleLogEvent::DoubleOrNothing { player: 1, won: true });
+                    }
+                } else if p2_accepts {
+                    // Only P2 risks
+                    let roll = roll_range(battle, 0, 1);
+                    if roll == 0 {
+                        battle.player2_pending_miss = 1;
+                        log_battle_event(battle, battle_key, BattleLogEvent::DoubleOrNothing { player: 2, won: false });
+                    } else {
+                        battle.player2_combo += 3;
+                        log_battle_event(battle, battle_key, BattleLogEvent::DoubleOrNothing { player: 2, won: true });
+                    }
+                }
+            }
+            WildcardEvent::DeathRoulette => {
+                if p1_accepts && p2_accepts {
+                    let roll = roll_range(battle, 0, 1);
+                    if roll == 0 {
+                        battle.player1_hp = 1; // Nearly dead
+                        heal_player(battle, 2, 100); // Healed
+                        log_battle_event(battle, battle_key, BattleLogEvent::DeathRoulette { player: 1, nearly_killed: true, both_accepted: true });
+                    } else {
+                        battle.player2_hp = 1;
+                        heal_player(battle, 1, 100);
+                        log_battle_event(battle, battle_key, BattleLogEvent::DeathRoulette { player: 2, nearly_killed: true, both_accepted: true });
+                    }
+                } else if p1_accepts {
+                    let roll = roll_range(battle, 0, 1);
+                    if roll == 0 {
+                        battle.player1_hp = 1;
+                        log_battle_event(battle, battle_key, BattleLogEvent::DeathRoulette { player: 1, nearly_killed: true, both_accepted: false });
+                    } else {
+                        heal_player_to_full(battle, 1);
+                        log_battle_event(battle, battle_key, BattleLogEvent::DeathRoulette { player: 1, nearly_killed: false, both_accepted: false });
+                    }
+                } else if p2_accepts {
+                    let roll = roll_range(battle, 0, 1);
+                    if roll == 0 {
+                        battle.player2_hp = 1;
+                        log_battle_event(battle, battle_key, BattleLogEvent::DeathRoulette { player: 2, nearly_killed: true, both_accepted: false });
+                    } else {
+                        heal_player_to_full(battle, 2);
+                        log_battle_event(battle, battle_key, BattleLogEvent::DeathRoulette { player: 2, nearly_killed: false, both_accepted: false });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    record_peak_combos(battle);
+
+    // Reset wildcard state
+    battle.wildcard_active = 0;
+    battle.wildcard_player1_decision = WILDCARD_UNDECIDED;
+    battle.wildcard_player2_decision = WILDCARD_UNDECIDED;
+
+    Ok(())
+}
+
+// A landed hit builds the attacker's combo up to MAX_HIT_COMBO, a whiff
+// (dodge, lost Double or Nothing, a swing soaked to nothing) halves it
+fn update_attack_combo(
+    battle: &mut Battle,
+    is_player1: bool,
+    damage: u64,
+    attacker_stance: BattleStance,
+    settings: &GameSettings,
+) {
+    let combo = if is_player1 { &mut battle.player1_combo } else { &mut battle.player2_combo };
+    if damage > 0 {
+        if *combo < MAX_HIT_COMBO {
+            *combo += 1;
+        }
+    } else if !(attacker_stance == BattleStance::Counter && settings.counter_keeps_combo) {
+        *combo /= 2;
+    }
+}
+
+// Combos only live on the battle, so keep the highest one reached for finalize_battle
+fn record_peak_combos(battle: &mut Battle) {
+    battle.player1_peak_combo = battle.player1_peak_combo.max(battle.player1_combo);
+    battle.player2_peak_combo = battle.player2_peak_combo.max(battle.player2_combo);
+}
+
+fn choose_ai_stance(
+    battle: &mut Battle,
+    ai_char: &Character,
+    player_char: &Character,
+) -> BattleStance {
+    let ai_hp_percent = (battle.player2_hp * 100) / ai_char.max_hp;
+    let player_hp_percent = (battle.player1_hp * 100) / player_char.max_hp;
+
+    // Strategic AI decision making
+    if ai_hp_percent < 30 {
+        // Low HP - play defensive or berserker for desperation
+        if roll_range(battle, 0, 1) == 0 {
+            BattleStance::Defensive
+        } else {
+            BattleStance::Berserker // All-in
+        }
+    } else if player_hp_percent < 30 {
+        // Player low HP - go aggressive
+        BattleStance::Aggressive
+    } else if BattleStance::from_code(battle.player1_stance) == BattleStance::Aggressive {
+        // Counter aggressive plays
+        BattleStance::Counter
+    } else {
+        // Default balanced with some randomness
+        let roll = roll_range(battle, 0, 4);
+        match roll {
+            0 => BattleStance::Aggressive,
+            1 => BattleStance::Defensive,
+            2 => BattleStance::Counter,
+            3 => BattleStance::Berserker,
+            _ => BattleStance::Balanced,
+        }
+    }
+}
+
+// flawless: the winner ended the battle at its starting HP, checked before
+// the post-battle heal
+fn update_winner_stats(
+    character: &mut Account<Character>,
+    xp: u64,
+    pool: Option<MmrPool>,
+    mmr_gain: u64,
+    flawless: bool,
+    extends_streak: bool, // False for a forfeit win, so timeouts can't farm streaks
+) -> Result<u64> {
+    character.total_wins += 1;
+    character.season_wins += 1;
+
+    if extends_streak {
+        character.current_streak += 1;
+        character.best_streak = character.best_streak.max(character.current_streak);
+        emit!(StreakUpdated {
+            character: character.key(),
+            streak: character.current_streak,
+        });
+    }
+
+    // Check for achievements
+    check_achievements(character, flawless);
+
+    character.current_hp = character.max_hp;
+
+    let streak = character.current_streak;
+    let xp = if streak >= STREAK_XP_BONUS_START {
+        let bonus_percent = ((streak - STREAK_XP_BONUS_START + 1) as u64 * 10).min(STREAK_XP_BONUS_CAP);
+        xp + xp * bonus_percent / 100
+    } else {
+        xp
+    };
+    grant_xp(character, xp);
+
+    if let Some(pool) = pool {
+        *pool.rating_mut(character) += mmr_gain + streak_mmr_bonus(streak);
+    }
+
+    // Update rank tier
+    update_rank_tier(character);
+
+    Ok(xp)
+}
+
+// Extra MMR on top of the Elo gain for a win that extends a long streak
+fn streak_mmr_bonus(streak: u32) -> u64 {
+    if streak >= STREAK_MASTER_THRESHOLD {
+        STREAK_MASTER_MMR_BONUS
+    } else if streak >= STREAK_MMR_BONUS_START {
+        STREAK_MMR_BONUS
+    } else {
+        0
+    }
+}
+
+// Elo expected score of the winner in per mille, for an MMR lead of 0, 50,
+// 100, ... 800 points. A deficit mirrors it: E(-d) = 1000 - E(d).
+const ELO_EXPECTED_PER_MILLE: [u64; 17] = [
+    500, 571, 640, 703, 760, 808, 849, 882, 909, 930, 947, 960, 969, 977, 983, 987, 990,
+];
+
+// Elo rating change for a decided match: K times how unexpected the result
+// was. The same amount is won by the winner and lost by the loser at equal K.
+// Linear interpolation between table steps keeps it float-free.
+fn compute_mmr_delta(winner_mmr: u64, loser_mmr: u64, k: u64) -> u64 {
+    let lead = (winner_mmr.abs_diff(loser_mmr)).min(800);
+    let step = (lead / 50) as usize;
+    let low = ELO_EXPECTED_PER_MILLE[step];
+    let high = ELO_EXPECTED_PER_MILLE[(step + 1).min(16)];
+    let expected_favorite = low + (high - low) * (lead % 50) / 50;
+    let expected_winner = if winner_mmr >= loser_mmr { expected_favorite } else { 1000 - expected_favorite };
+
+    let delta = (k * (1000 - expected_winner) + 500) / 1000;
+    if k > 0 { delta.max(1) } else { 0 }
+}
+
+// Which rating a match moves. Casual games have their own hidden rating for
+// matchmaking, only the ranked one drives rank_tier and the leaderboard.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MmrPool {
+    Ranked,
+    Casual,
+}
+
+impl MmrPool {
+    fn rating(self, character: &Character) -> u64 {
+        match self {
+            MmrPool::Ranked => character.ranked_mmr,
+            MmrPool::Casual => character.casual_mmr,
+        }
+    }
+
+    fn rating_mut(self, character: &mut Character) -> &mut u64 {
+        match self {
+            MmrPool::Ranked => &mut character.ranked_mmr,
+            MmrPool::Casual => &mut character.casual_mmr,
+        }
+    }
+}
+
+// AI and Practice battles never move a rating
+fn mmr_pool(match_type: MatchType, is_vs_ai: bool) -> Option<MmrPool> {
+    if is_vs_ai || match_type == MatchType::Practice {
+        None
+    } else if match_type == MatchType::Casual {
+        Some(MmrPool::Casual)
+    } else {
+        Some(MmrPool::Ranked)
+    }
+}
+
+fn mmr_k_factor(character: &Character, match_type: MatchType, settings: &GameSettings) -> u64 {
+    if match_type == MatchType::Casual {
+        settings.mmr_k_casual
+    } else if character.season_wins + character.season_losses < PROVISIONAL_SEASON_GAMES {
+        settings.mmr_k_provisional
+    } else {
+        settings.mmr_k_ranked
+    }
+}
+
+// Adds XP and levels up as many times as it covers, the level cap ends the loop
+// Once per DAILY_CLAIM_INTERVAL_SECONDS, the streak survives a claim within
+// DAILY_STREAK_GRACE_SECONDS of the last one. Returns the day's XP.
+fn record_daily_claim(character: &mut Character, clock: &Clock) -> Result<u64> {
+    let since_last_claim = clock.unix_timestamp - character.last_daily_claim;
+    require!(
+        since_last_claim >= DAILY_CLAIM_INTERVAL_SECONDS,
+        GameError::DailyAlreadyClaimed
+    );
+
+    if since_last_claim > DAILY_STREAK_GRACE_SECONDS {
+        character.daily_streak = 1;
+    } else {
+        character.daily_streak = character.daily_streak.saturating_add(1);
+    }
+    character.last_daily_claim = clock.unix_timestamp;
+
+    let bonus_days = (character.daily_streak - 1).min(DAILY_REWARD_MAX_STREAK_BONUS_DAYS);
+    Ok(DAILY_REWARD_BASE_XP + bonus_days as u64 * DAILY_REWARD_XP_PER_STREAK_DAY)
+}
+
+fn grant_xp(character: &mut Account<Character>, xp: u64) {
+    character.xp += xp;
+
+    while character.level < MAX_LEVEL {
+        let required_xp = get_required_xp(character.level);
+        if character.xp < required_xp {
+            break;
+        }
+
+        character.level += 1;
+        character.xp -= required_xp;
+        apply_level_up_gains(character);
+        character.current_hp = character.max_hp;
+
+        emit!(CharacterLeveledUp {
+            character: character.key(),
+            level: character.level,
+        });
+        msg!("{} leveled up to level {}!", character.name, character.level);
+    }
+}
+
+// Rebuilds the stats as if the character had always been new_class: base
+// stats, every level-up since level 1 and the permanent bonuses. Damage taken
+// carries over, capped at the new max_hp.
+fn respec_stats(character: &mut Character, new_class: CharacterClass, settings: &GameSettings) {
+    let current_hp = character.current_hp;
+    character.character_class = new_class;
+    set_class_base_stats(character, settings);
+    for _ in 1..character.level {
+        apply_level_up_gains(character);
+    }
+    apply_permanent_bonuses(character);
+    character.current_hp = current_hp.min(character.max_hp);
+}
+
+fn apply_level_up_gains(character: &mut Character) {
+    character.max_hp += 5;
+    character.base_damage_min += 2;
+    character.base_damage_max += 2;
+    character.crit_chance += 1;
+    character.defense += 1;
+}
+
+// Bonuses that survive a stat rebuild: prestige and claimed achievement rewards
+fn apply_permanent_bonuses(character: &mut Character) {
+    let bonus_damage = character.prestige as u16 * PRESTIGE_DAMAGE_BONUS;
+    character.base_damage_min = character.base_damage_min.saturating_add(bonus_damage);
+    character.base_damage_max = character.base_damage_max.saturating_add(bonus_damage);
+    character.max_hp += character.prestige as u64 * PRESTIGE_HP_BONUS;
+
+    if character.claimed_achievement_rewards & Achievement::HundredWins.bit() != 0 {
+        character.crit_chance = character.crit_chance.saturating_add(HUNDRED_WINS_CRIT_BONUS);
+    }
+}
+
+fn update_loser_stats(
+    character: &mut Account<Character>,
+    xp: u64,
+    pool: Option<MmrPool>,
+    mmr_loss: u64,
+) -> Result<()> {
+    character.total_losses += 1;
+    character.season_losses += 1;
+    character.current_hp = character.max_hp;
+
+    if character.current_streak > 0 {
+        character.current_streak = 0;
+        emit!(StreakUpdated {
+            character: character.key(),
+            streak: 0,
+        });
+    }
+
+    grant_xp(character, xp);
+
+    if let Some(pool) = pool {
+        let rating = pool.rating_mut(character);
+        *rating = rating.saturating_sub(mmr_loss);
+    }
+
+    // Update rank tier
+    update_rank_tier(character);
+
+    Ok(())
+}
+
+fn check_achievements(character: &mut Account<Character>, flawless: bool) {
+    // First win
+    if character.total_wins == 1 {
+        grant_achievement(character, Achievement::FirstWin);
+    }
+    
+    // 10 wins
+    if character.total_wins == 10 {
+        grant_achievement(character, Achievement::TenWins);
+    }
+    
+    // 100 wins
+    if character.total_wins == 100 {
+        grant_achievement(character, Achievement::HundredWins);
+    }
+    
+    // Flawless (won without losing any battle HP)
+    if flawless {
+        grant_achievement(character, Achievement::Flawless);
+    }
+
+    if character.best_streak >= STREAK_MMR_BONUS_START {
+        grant_achievement(character, Achievement::StreakFive);
+    }
+
+    if character.best_streak >= STREAK_MASTER_THRESHOLD {
+        grant_achievement(character, Achievement::StreakMaster);
+    }
+}
+
+// Achievements are granted at most once, and never past the account's max_len(20)
+fn grant_achievement(character: &mut Account<Character>, achievement: Achievement) {
+    if !character.achievements.contains(&achievement) && character.achievements.len() < 20 {
+        character.achievements.push(achievement);
+        emit!(AchievementUnlocked {
+            character: character.key(),
+            achievement,
+        });
+    }
+}
+
+fn update_combo_stats(character: &mut Account<Character>, peak_combo: u16) {
+    if peak_combo > character.max_combo {
+        character.max_combo = peak_combo;
+    }
+
+    if character.max_combo >= COMBO_MASTER_THRESHOLD {
+        grant_achievement(character, Achievement::ComboMaster);
+    }
+}
+
+// Keeps entries sorted by MMR, highest first. A character not already on the
+// board is only inserted if it would place.
+fn update_leaderboard(leaderboard: &mut Leaderboard, character: &Account<Character>) {
+    let (key, mmr) = (character.key(), character.ranked_mmr);
+    if let Some(rank) = place_on_leaderboard(leaderboard, key, mmr, character.owner) {
+        emit!(LeaderboardChanged { character: key, mmr, rank });
+    }
+}
+
+// Returns the character's new rank, None when it didn't make the board or
+// an MMR change kept it in the same spot
+fn place_on_leaderboard(leaderboard: &mut Leaderboard, key: Pubkey, mmr: u64, owner: Pubkey) -> Option<u16> {
+    let existing = leaderboard.entries.iter().position(|entry| entry.character == key);
+
+    if existing.is_none() && leaderboard.entries.len() >= LEADERBOARD_SIZE {
+        let lowest = leaderboard.entries.last().map(|entry| entry.mmr).unwrap_or(0);
+        if mmr <= lowest {
+            return None;
+        }
+        leaderboard.entries.pop();
+    }
+
+    if let Some(index) = existing {
+        leaderboard.entries.remove(index);
+    }
+
+    let position = leaderboard.entries.iter().position(|entry| entry.mmr < mmr).unwrap_or(leaderboard.entries.len());
+    leaderboard.entries.insert(position, LeaderboardEntry { character: key, mmr, owner });
+
+    (existing != Some(position)).then_some(position as u16 + 1)
+}
+
+// Season stats belong to season_index. The first finalized battle after a
+// new season starts clears them and soft-resets MMR toward 1000; returns whether
+// it did.
+fn roll_over_season(character: &mut Character, current_season: u32) -> bool {
+    if character.season_index == current_season {
+        return false;
+    }
+
+    character.season_index = current_season;
+    character.season_wins = 0;
+    character.season_losses = 0;
+    character.ranked_mmr = (character.ranked_mmr + 1000) / 2;
+    true
+}
+
+fn season_claim_window_closed(season: &Season, settings: &GameSettings, clock: &Clock) -> bool {
+    clock.unix_timestamp >= season.end_time + settings.season_claim_window_seconds
+}
+
+// Charges every full RANK_DECAY_PERIOD_SECONDS since decay started and
+// returns the MMR taken. Periods already decayed are never charged twice.
+fn apply_rank_decay(character: &mut Character, clock: &Clock) -> Result<u64> {
+    let decay_start = (character.last_battle + RANK_DECAY_INACTIVITY_SECONDS).max(character.last_decay);
+    require!(clock.unix_timestamp >= decay_start, GameError::NoRankDecayDue);
+
+    let periods = (clock.unix_timestamp - decay_start) / RANK_DECAY_PERIOD_SECONDS;
+    require!(periods > 0, GameError::NoRankDecayDue);
+
+    character.last_decay = decay_start + periods * RANK_DECAY_PERIOD_SECONDS;
+
+    let floor = character.ranked_mmr.min(RANK_DECAY_FLOOR_MMR);
+    let old_mmr = character.ranked_mmr;
+    character.ranked_mmr = character
+        .ranked_mmr
+        .saturating_sub(periods as u64 * RANK_DECAY_AMOUNT)
+        .max(floor);
+    Ok(old_mmr - character.ranked_mmr)
+}
+
+fn update_rank_tier(character: &mut Account<Character>) {
+    let rank_tier = match character.ranked_mmr {
+        0..=999 => RankTier::Bronze,
+        1000..=1499 => RankTier::Silver,
+        1500..=1999 => RankTier::Gold,
+        2000..=2499 => RankTier::Platinum,
+        2500..=2999 => RankTier::Diamond,
+        _ => RankTier::Master,
+    };
+
+    if rank_tier != character.rank_tier {
+        character.rank_tier = rank_tier;
+        emit_metadata_update(character);
+    }
+}
+
+// Level and rank tier drive the NFT art, an off-chain service regenerates it
+// on this event and calls set_metadata_uri. Minted characters also need
+// update_character_metadata, metadata_dirty tracks that.
+fn emit_metadata_update(character: &mut Account<Character>) {
+    character.metadata_dirty = true;
+    emit!(MetadataShouldUpdate {
+        character: character.key(),
+        level: character.level,
+        rank_tier: character.rank_tier,
+    });
+}
+
+// Metaplex data for a character's NFT, the name carries the rank tier
+fn nft_data(character: &Character) -> DataV2 {
+    const MAX_NFT_NAME_LEN: usize = 32; // Metaplex limit
+    let suffix = format!(" [{:?}]", character.rank_tier);
+    let mut name_len = character.name.len().min(MAX_NFT_NAME_LEN - suffix.len());
+    while !character.name.is_char_boundary(name_len) {
+        name_len -= 1;
+    }
+
+    DataV2 {
+        name: format!("{}{}", &character.name[..name_len], suffix),
+        symbol: character.character_class.to_string().to_uppercase(),
+        uri: character.metadata_uri.clone(),
+        seller_fee_basis_points: 0,
+        creators: None,
+        collection: None,
+        uses: None,
+    }
+}
+
+fn calculate_damage(
+    attacker: &Character,
+    defender: &Character,
+    battle: &mut Battle,
+    is_player1: bool,
+    use_special: bool,
+    settings: &GameSettings,
+) -> Result<DamageOutcome> {
+    let context = AttackContext {
+        combo: if is_player1 { battle.player1_combo } else { battle.player2_combo },
+        miss_count: if is_player1 { battle.player1_miss_count } else { battle.player2_miss_count },
+        defender_hp: if is_player1 { battle.player2_hp } else { battle.player1_hp },
+        gamblers_fallacy: WildcardEvent::from_code(battle.wildcard_type) == Some(WildcardEvent::GamblersFallacy),
+        dodge_bonus: if is_player1 { battle.player2_smoke_dodge } else { battle.player1_smoke_dodge },
+        instant_kill: settings.instant_kill_enabled
+            && MatchType::from_code(battle.match_type) == MatchType::Casual,
+    };
+    let attacker = battle_stats(battle, attacker, is_player1);
+    let defender = battle_stats(battle, defender, !is_player1);
+    let seed = battle.random_seed;
+    let outcome = roll_attack(&attacker, &defender, &seed, &mut battle.roll_counter, &context, use_special)?;
+
+    // A smoke bomb only covers one incoming attack
+    if is_player1 {
+        battle.player2_smoke_dodge = 0;
+    } else {
+        battle.player1_smoke_dodge = 0;
+    }
+    Ok(outcome)
+}
+
+// What an attack roll reads about each side, so 1v1 and team battles share
+// the same damage math. For 1v1 battles it comes from the snapshot taken at
+// battle creation, see battle_stats().
+// Team battles store it per slot.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub struct CombatStats {
+    pub character_class: CharacterClass,
+    pub level: u16,
+    pub base_damage_min: u16,
+    pub base_damage_max: u16,
+    pub crit_chance: u16,
+    pub dodge_chance: u16,
+    pub defense: u16,
+    pub max_hp: u64,
+}
+
+// Gear can push the raw stats past what classes are allowed, the chances
+// are capped here so no source makes a character untouchable
+impl From<&Character> for CombatStats {
+    fn from(character: &Character) -> Self {
+        CombatStats {
+            character_class: character.character_class,
+            level: character.level,
+            base_damage_min: character.base_damage_min,
+            base_damage_max: character.base_damage_max,
+            crit_chance: character.crit_chance.min(MAX_CRIT_CHANCE),
+            dodge_chance: character.dodge_chance.min(MAX_DODGE_CHANCE),
+            defense: character.defense,
+            max_hp: character.max_hp,
+        }
+    }
+}
+
+// Stats as of create_battle, so gear or level changes mid-battle don't count
+fn battle_stats(battle: &Battle, character: &Character, is_player1: bool) -> CombatStats {
+    let i = if is_player1 { 0 } else { 1 };
+    CombatStats {
+        character_class: character.character_class,
+        level: battle.levels[i],
+        base_damage_min: battle.damage_min[i],
+        base_damage_max: battle.damage_max[i],
+        crit_chance: battle.crit_chance[i],
+        dodge_chance: battle.dodge_chance[i],
+        defense: battle.defense[i],
+        max_hp: if is_player1 { battle.player1_max_hp } else { battle.player2_max_hp },
+    }
+}
+
+struct AttackContext {
+    combo: u16,
+    miss_count: u16,
+    defender_hp: u64,
+    gamblers_fallacy: bool,
+    dodge_bonus: u8,
+    instant_kill: bool, // Crits may finish a target under 20% HP
+}
+
+fn roll_attack(
+    attacker: &CombatStats,
+    defender: &CombatStats,
+    seed: &[u8; 32],
+    counter: &mut u64,
+    context: &AttackContext,
+    use_special: bool,
+) -> Result<DamageOutcome> {
+    let mut damage: u64;
+    let mut special_effect = None;
+
+    let base_damage = gen_range(seed, counter, attacker.base_damage_min as u64, attacker.base_damage_max as u64);
+
+    let level_bonus = (attacker.level as u64 - 1) * 2;
+    damage = base_damage + level_bonus;
+    let rolled_damage = damage;
+
+    // Check for critical hit
+    let crit_roll = gen_range(seed, counter, 0, 99);
+    let mut crit_chance = attacker.crit_chance as u64;
+
+    // Gambler's Fallacy effect
+    if context.gamblers_fallacy {
+        crit_chance += context.miss_count as u64 * 5;
+    }
+
+    let is_crit = crit_roll < crit_chance;
+    if is_crit {
+        damage = crit_damage(attacker.character_class, damage);
+        
+        // Instant kill check, rolled from the same turn seed as everything else
+        let defender_hp = context.defender_hp;
+        let defender_max_hp = defender.max_hp;
+        if context.instant_kill && defender_hp < (defender_max_hp * 20) / 100 {
+            let instant_kill_roll = gen_range(seed, counter, 0, 99);
+            if instant_kill_roll < 5 {
+                damage = defender_hp;
+                msg!("INSTANT KILL!");
+            }
+        }
+    }
+
+    // Apply combo bonus
+    let combo = context.combo;
+    damage = combo_damage(damage, combo);
+
+    // Special moves
+    if use_special {
+        damage = match attacker.character_class {
+            CharacterClass::Warrior => damage * 2, // Berserker Rage
+            CharacterClass::Assassin => damage * 3, // Shadow Strike
+            CharacterClass::Mage => {
+                // Arcane Burst - apply DOT
+                special_effect = Some(SpecialEffect::ArcaneBurst);
+                damage * 2
+            }
+            CharacterClass::Tank => {
+                // Fortress Stance - massive defense boost
+                special_effect = Some(SpecialEffect::FortressStance);
+                damage
+            }
+            CharacterClass::Paladin => {
+                // Divine Light - heals instead of hitting harder
+                special_effect = Some(SpecialEffect::DivineLight);
+                damage
+            }
+            CharacterClass::Trickster => {
+                // Wild Card special: Random powerful effect
+                let effect_roll = gen_range(seed, counter, 0, 3);
+                match effect_roll {
+                    0 => {
+                        special_effect = Some(SpecialEffect::StealCombo);
+                        damage * 2
+                    }
+                    1 => {
+                        // Confusion: swap stances
+                        special_effect = Some(SpecialEffect::SwapStances);
+                        damage * 2
+                    }
+                    2 => {
+                        special_effect = Some(SpecialEffect::Evasion);
+                        damage * 3
+                    }
+                    _ => {
+                        special_effect = Some(SpecialEffect::ExtraWildcard);
+                        damage * 2
+                    }
+                }
+            }
+        };
+        msg!("Special move used!");
+    }
+
+    // Apply defense
+    let defense_reduction = defender.defense as u64;
+    damage = damage.saturating_sub(defense_reduction);
+
+    // Check for dodge
+    let dodge_roll = gen_range(seed, counter, 0, 99);
+    let was_dodged = dodge_roll < defender.dodge_chance as u64 + context.dodge_bonus as u64;
+    if was_dodged {
+        damage = 0;
+    }
+
+    Ok(DamageOutcome {
+        amount: damage,
+        base_damage: rolled_damage,
+        combo_multiplier: combo.saturating_mul(15).saturating_add(100),
+        was_crit: is_crit,
+        was_dodged,
+        special_effect,
+    })
+}
+
+fn crit_damage(class: CharacterClass, damage: u64) -> u64 {
+    match class {
+        CharacterClass::Warrior => damage * 2,
+        CharacterClass::Assassin => damage * 3,
+        CharacterClass::Mage => damage * 2,
+        CharacterClass::Tank => damage * 2,
+        CharacterClass::Paladin => damage * 2,
+        CharacterClass::Trickster => {
+            // Trickster crits can trigger additional effects
+            damage * 2 + 20 // Extra flat damage
+        }
+    }
+}
+
+fn combo_damage(damage: u64, combo: u16) -> u64 {
+    damage + (damage * 15 * combo as u64) / 100
+}
+
+// Returns (damage, riposte). The riposte is dealt back to the attacker when
+// they swing Aggressive or Berserker into a Counter stance. Berserker
+// self-damage is settled by the caller once the final damage is known.
+fn apply_stance_modifiers(
+    damage: u64,
+    attacker_stance: BattleStance,
+    defender_stance: BattleStance,
+) -> (u64, u64) {
+    let swing = attacker_stance_damage(damage, attacker_stance);
+    let riposte = if counter_triggered(attacker_stance, defender_stance) {
+        (swing * 60) / 100
+    } else {
+        0
+    };
+
+    (defender_stance_damage(swing, attacker_stance, defender_stance), riposte)
+}
+
+// Pure stance math shared by the real turn and simulate_turn
+fn stance_adjusted_damage(
+    damage: u64,
+    attacker_stance: BattleStance,
+    defender_stance: BattleStance,
+) -> u64 {
+    let swing = attacker_stance_damage(damage, attacker_stance);
+    defender_stance_damage(swing, attacker_stance, defender_stance)
+}
+
+fn counter_triggered(attacker_stance: BattleStance, defender_stance: BattleStance) -> bool {
+    defender_stance == BattleStance::Counter
+        && matches!(attacker_stance, BattleStance::Aggressive | BattleStance::Berserker)
+}
+
+fn attacker_stance_damage(mut damage: u64, attacker_stance: BattleStance) -> u64 {
+    match attacker_stance {
+        BattleStance::Aggressive => {
+            damage = (damage * 130) / 100;
+        }
+        BattleStance::Defensive => {
+            damage = (damage * 70) / 100;
+        }
+        BattleStance::Berserker => {
+            damage *= 2;
+        }
+        BattleStance::Counter => {
+            // Counter waits for an attack, its own swing does nothing
+            damage = 0;
+        }
+        BattleStance::Venomous => {
+            // Weaker hit, the poison does the rest
+            damage = (damage * 80) / 100;
+        }
+        BattleStance::Balanced => {}
+    }
+
+    damage
+}
+
+fn defender_stance_damage(
+    mut damage: u64,
+    attacker_stance: BattleStance,
+    defender_stance: BattleStance,
+) -> u64 {
+    match defender_stance {
+        BattleStance::Defensive => {
+            damage = (damage * 50) / 100;
+        }
+        BattleStance::Aggressive => {
+            damage = (damage * 150) / 100;
+        }
+        BattleStance::Counter if counter_triggered(attacker_stance, defender_stance) => {
+            damage = (damage * 50) / 100;
+        }
+        _ => {}
+    }
+
+    damage
+}
+
+// Damage preview without randomness or side effects. Every base roll is
+// treated as equally likely. Wildcards, instant kills and self-damage are
+// not modelled.
+fn simulate_damage(
+    attacker: &Character,
+    defender: &Character,
+    battle: &Battle,
+    is_player1: bool,
+    attacker_stance: BattleStance,
+    defender_stance: BattleStance,
+    use_special: bool,
+) -> TurnSimulation {
+    let attacker = &battle_stats(battle, attacker, is_player1);
+    let defender = &battle_stats(battle, defender, !is_player1);
+    let level_bonus = (attacker.level as u64 - 1) * 2;
+    let combo = if is_player1 { battle.player1_combo } else { battle.player2_combo };
+
+    let mut crit_chance = attacker.crit_chance as u64;
+    if WildcardEvent::from_code(battle.wildcard_type) == Some(WildcardEvent::GamblersFallacy) {
+        let miss_count = if is_player1 { battle.player1_miss_count } else { battle.player2_miss_count };
+        crit_chance += miss_count as u64 * 5;
+    }
+    let crit_chance = crit_chance.min(100);
+    let smoke_dodge = if is_player1 { battle.player2_smoke_dodge } else { battle.player1_smoke_dodge };
+    let dodge_chance = (defender.dodge_chance as u64 + smoke_dodge as u64).min(100);
+
+    // (damage multiplier, weight), mirrors the special block in calculate_damage
+    let special_outcomes: &[(u64, u64)] = if !use_special {
+        &[(1, 1)]
+    } else {
+        match attacker.character_class {
+            CharacterClass::Warrior => &[(2, 1)],
+            CharacterClass::Assassin => &[(3, 1)],
+            CharacterClass::Mage => &[(2, 1)],
+            CharacterClass::Tank => &[(1, 1)],
+            CharacterClass::Paladin => &[(1, 1)],
+            // Wild Card triples on one of its four effects
+            CharacterClass::Trickster => &[(2, 3), (3, 1)],
+        }
+    };
+
+    let resolve = |base: u64, is_crit: bool, special_multiplier: u64| -> u64 {
+        let mut damage = base + level_bonus;
+        if is_crit {
+            damage = crit_damage(attacker.character_class, damage);
+        }
+        damage = combo_damage(damage, combo) * special_multiplier;
+        damage = damage.saturating_sub(defender.defense as u64);
+        stance_adjusted_damage(damage, attacker_stance, defender_stance)
+    };
+
+    let min_base = attacker.base_damage_min as u64;
+    let max_base = attacker.base_damage_max as u64;
+    let lowest_special = special_outcomes.iter().map(|o| o.0).min().unwrap_or(1);
+    let highest_special = special_outcomes.iter().map(|o| o.0).max().unwrap_or(1);
+
+    let mut normal_sum: u128 = 0;
+    let mut crit_sum: u128 = 0;
+    let mut total_weight: u128 = 0;
+    for base in min_base..=max_base {
+        for &(multiplier, weight) in special_outcomes {
+            normal_sum += resolve(base, false, multiplier) as u128 * weight as u128;
+            crit_sum += resolve(base, true, multiplier) as u128 * weight as u128;
+            total_weight += weight as u128;
+        }
+    }
+
+    let hit_chance = 100 - dodge_chance;
+    let expected_damage = (hit_chance as u128
+        * ((100 - crit_chance) as u128 * normal_sum + crit_chance as u128 * crit_sum)
+        / (100 * 100 * total_weight.max(1))) as u64;
+
+    TurnSimulation {
+        min_damage: resolve(min_base, false, lowest_special),
+        expected_damage,
+        max_damage: resolve(max_base, crit_chance > 0, highest_special),
+        hit_chance: hit_chance as u8,
+        crit_chance: crit_chance as u8,
+        dodge_chance: dodge_chance as u8,
+    }
+}
+
+fn apply_wildcard_effects(
+    mut damage: u64,
+    battle: &mut Battle,
+    battle_key: Pubkey,
+    is_player1: bool,
+) -> Result<u64> {
+    if let Some(wildcard) = WildcardEvent::from_code(battle.wildcard_type) {
+        match wildcard {
+            WildcardEvent::ReverseRoles => {
+                // Each player gets the other's HP percentage, applied to their own max
+                let (p1_before, p2_before) = (battle.player1_hp, battle.player2_hp);
+                battle.player1_hp = swap_hp_fraction(p2_before, battle.player2_max_hp, battle.player1_max_hp);
+                battle.player2_hp = swap_hp_fraction(p1_before, battle.player1_max_hp, battle.player2_max_hp);
+                let entry = BattleLogEvent::ReverseRoles {
+                    player1_before: log_amount(p1_before),
+                    player1_after: log_amount(battle.player1_hp),
+                    player2_before: log_amount(p2_before),
+                    player2_after: log_amount(battle.player2_hp),
+                };
+                log_battle_event(battle, battle_key, entry);
+            }
+            WildcardEvent::MysteryBox => {
+                let buff_roll = roll_range(battle, 0, 3);
+                log_battle_event(battle, battle_key, BattleLogEvent::MysteryBox {
+                    player: if is_player1 { 1 } else { 2 },
+                    prize: buff_roll as u8,
+                });
+                match buff_roll {
+                    0 => {
+                        damage *= 3;
+                    }
+                    1 => {
+                        if is_player1 {
+                            battle.player1_reflection = 50;
+                        } else {
+                            battle.player2_reflection = 50;
+                        }
+                    }
+                    2 => {
+                        heal_player(battle, if is_player1 { 1 } else { 2 }, 50);
+                    }
+                    _ => {
+                        if is_player1 {
+                            battle.player1_combo += 3;
+                        } else {
+                            battle.player2_combo += 3;
+                        }
+                    }
+                }
+            }
+            WildcardEvent::ComboBreaker => {
+                if is_player1 {
+                    let stolen = battle.player2_combo;
+                    battle.player1_combo += stolen;
+                    battle.player2_combo = 0;
+                } else {
+                    let stolen = battle.player1_combo;
+                    battle.player2_combo += stolen;
+                    battle.player1_combo = 0;
+                }
+            }
+            WildcardEvent::TimeWarp => {
+                heal_player(battle, if is_player1 { 2 } else { 1 }, damage.min(50));
+                damage = 0;
+            }
+            // last_damage_roll holds the ones digit of this turn's damage roll
+            WildcardEvent::LuckySeven if battle.last_damage_roll == 7 => {
+                damage *= 7;
+                log_battle_event(battle, battle_key, BattleLogEvent::LuckySeven { player: if is_player1 { 1 } else { 2 } });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(damage)
+}
+
+// Odds are the other side's share of the pool in percent, so a bet returns
+// 100 / (100 - odds) times its stake
+fn fixed_odds_payout(amount: u64, odds: u64) -> u64 {
+    let divisor = 100 - odds.min(99);
+    (amount as u128 * 100 / divisor as u128) as u64
+}
+
+// Per-bet minimum, the wallet's running total (BetTally), the pool size and
+// the side's total
+fn check_bet_limits(pool: &BettingPool, wallet_total: u64, amount: u64, bet_on_player: u8) -> Result<()> {
+    require!(amount >= pool.min_bet, GameError::BetTooSmall);
+    require!(wallet_total + amount <= pool.max_bet_per_wallet, GameError::BetTooLarge);
+    require!(pool.total_pool + amount <= pool.max_pool_size, GameError::PoolFull);
+    let side_total = if bet_on_player == 1 { pool.player1_bets } else { pool.player2_bets };
+    require!(side_total + amount <= pool.max_side_total, GameError::BetSideFull);
+    Ok(())
+}
+
+// Adds a bet to the pool and returns the odds it locks. Odds are quoted
+// before this bet moves them and capped at MAX_LOCKED_ODDS. Parimutuel pools
+// keep the snapshot for display only, fixed odds pay on it.
+fn book_bet(pool: &mut BettingPool, amount: u64, bet_on_player: u8) -> Result<u64> {
+    let quoted = if bet_on_player == 1 { pool.player1_odds } else { pool.player2_odds };
+    let locked_odds = quoted.min(MAX_LOCKED_ODDS);
+    if pool.odds_mode == OddsMode::FixedAtBet {
+        // Short books pay pro rata, the exposure cap bounds how short they can get
+        let total_after = pool.total_pool + amount;
+        let distributable = total_after - (total_after * pool.house_edge as u64) / 100;
+        let max_exposure = pool.max_exposure;
+
+        let liability = if bet_on_player == 1 { &mut pool.player1_liability } else { &mut pool.player2_liability };
+        *liability += fixed_odds_payout(amount, locked_odds);
+        require!(
+            liability.saturating_sub(distributable) <= max_exposure,
+            GameError::BetExceedsExposure
+        );
+    }
+
+    pool.total_pool += amount;
+    if bet_on_player == 1 {
+        pool.player1_bets += amount;
+    } else {
+        pool.player2_bets += amount;
+    }
+    recalculate_pool_odds(pool);
+    Ok(locked_odds)
+}
+
+// What a winning bet is paid, given the claims made so far. Parimutuel splits
+// the pool by stake. Fixed odds pay each bet's locked payout, scaled down pro
+// rata when the pool can't cover them all.
+fn bet_winnings(pool: &BettingPool, bet: &Bet) -> u64 {
+    let winning_pool = if bet.bet_on_player == 1 { pool.player1_bets } else { pool.player2_bets };
+    let house_cut = (pool.total_pool * pool.house_edge as u64) / 100;
+    let distributable = pool.total_pool - house_cut;
+
+    let (bet_share, total_shares, payable) = match pool.odds_mode {
+        OddsMode::Parimutuel => (bet.amount, winning_pool, distributable),
+        OddsMode::FixedAtBet => {
+            let liability = if bet.bet_on_player == 1 { pool.player1_liability } else { pool.player2_liability };
+            (fixed_odds_payout(bet.amount, bet.locked_odds), liability, distributable.min(liability))
+        }
+    };
+
+    // The last winning claimant takes whatever is left of the payable
+    // amount, so rounding dust never gets stranded in the pool
+    let is_last_claim = pool.claimed_winning_bets + bet.amount == winning_pool;
+    if is_last_claim {
+        payable - pool.total_claimed
+    } else {
+        ((bet_share as u128 * payable as u128) / total_shares as u128) as u64
+    }
+}
+
+// Odds follow betting volume. While only one side has money on it the volume
+// says nothing about the other side, so the odds keep their previous values
+// (the stat-based opening odds) until both sides have at least one bet.
+fn recalculate_pool_odds(pool: &mut BettingPool) {
+    if pool.player1_bets > 0 && pool.player2_bets > 0 {
+        let total = pool.player1_bets + pool.player2_bets;
+        pool.player1_odds = (pool.player2_bets * 100) / total;
+        pool.player2_odds = (pool.player1_bets * 100) / total;
+    }
+}
+
+// Each side's odds are the other side's share of the scores. Nothing tells the
+// sides apart when both scores are zero, so they start even.
+fn opening_odds(player1_score: u64, player2_score: u64) -> (u64, u64) {
+    let total_score = player1_score + player2_score;
+    (
+        (player2_score * 100).checked_div(total_score).unwrap_or(50),
+        (player1_score * 100).checked_div(total_score).unwrap_or(50),
+    )
+}
+
+fn calculate_betting_score(character: &Character, current_hp: u64) -> u64 {
+    let hp_percent = (current_hp * 100) / character.max_hp.max(1);
+    let win_rate = (character.total_wins * 100)
+        .checked_div(character.total_wins + character.total_losses)
+        .unwrap_or(50);
+
+    let level_score = character.level as u64 * 10;
+    let mmr_score = character.ranked_mmr / 10;
+
+    level_score + hp_percent + win_rate as u64 + mmr_score
+}
+
+// Scales hp (out of from_max) onto to_max, rounding down. A living player never
+// rounds down to 0 HP, so the swap alone can't kill anyone.
+fn swap_hp_fraction(hp: u64, from_max: u64, to_max: u64) -> u64 {
+    let scaled = (hp as u128 * to_max as u128 / from_max.max(1) as u128) as u64;
+    if hp > 0 {
+        scaled.min(to_max).max(1)
+    } else {
+        0
+    }
+}
+
+// Class starting stats from GameSettings::class_stats, also what a prestige resets to
+fn set_class_base_stats(character: &mut Character, settings: &GameSettings) {
+    let stats = settings.class_stats[character.character_class as usize];
+    character.max_hp = stats.max_hp;
+    character.current_hp = stats.max_hp;
+    character.base_damage_min = stats.damage_min;
+    character.base_damage_max = stats.damage_max;
+    character.crit_chance = stats.crit_chance;
+    character.dodge_chance = stats.dodge_chance;
+    character.defense = 0;
+}
+
+fn get_required_xp(level: u16) -> u64 {
+    let xp_curve: [u64; 11] = [0, 100, 250, 450, 700, 1000, 1400, 1900, 2500, 3200, 4000];
+    
+    if level < 11 {
+        xp_curve[level as usize]
+    } else {
+        4000 + ((level as u64 - 10) * 500)
+    }
+}
+
+// Account contexts
+#[derive(Accounts)]
+pub struct JoinQueue<'info> {
+    #[account(
+        init,
+        payer = player,
+        space = 8 + QueueEntry::INIT_SPACE,
+        seeds = [b"queue", character.key().as_ref()],
+        bump
+    )]
+    pub queue_entry: Account<'info, QueueEntry>,
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub player: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireQueueEntry<'info> {
+    #[account(mut, close = player, has_one = player)]
+    pub queue_entry: Account<'info, QueueEntry>,
+    /// CHECK: The player who queued, gets the stake and rent back
+    #[account(mut)]
+    pub player: AccountInfo<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct CommitStance<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    pub character: Account<'info, Character>,
+    pub player: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct RequestTurnRandomness<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    /// CHECK: Switchboard randomness account, owner and layout checked on use
+    pub randomness_account: AccountInfo<'info>,
+    pub requester: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ConsumeRandomness<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    /// CHECK: Must be the account bound by request_turn_randomness
+    #[account(address = battle.load()?.randomness_account)]
+    pub randomness_account: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DecideWildcard<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(constraint = character.owner == player.key() @ GameError::NotCharacterOwner)]
+    pub character: Account<'info, Character>,
+    pub player: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ResolveWildcardTimeout<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+}
+
+#[derive(Accounts)]
+pub struct CheckTimeout<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(mut, address = battle.load()?.player1)]
+    pub player1_character: Account<'info, Character>,
+    #[account(mut, address = battle.load()?.player2)]
+    pub player2_character: Account<'info, Character>,
+    #[account(mut, seeds = [b"escrow", battle.key().as_ref()], bump = escrow.bump)]
+    pub escrow: Account<'info, BattleEscrow>,
+    /// CHECK: Winner account to receive stakes, checked against the winning character's owner
+    /// (the human's owner when a PvE forfeit stake is refunded)
+    #[account(mut)]
+    pub winner: AccountInfo<'info>,
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    // Pass the battle's betting pool to settle it in the same transaction
+    #[account(mut, has_one = battle)]
+    pub betting_pool: Option<Account<'info, BettingPool>>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteAiTurn<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    pub player_character: Account<'info, Character>,
+    pub ai_character: Account<'info, Character>,
+    /// CHECK: SlotHashes sysvar, read raw since it's too large to deserialize
+    #[account(address = sysvar::slot_hashes::ID)]
+    pub recent_slothashes: AccountInfo<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct SimulateTurn<'info> {
+    pub battle: AccountLoader<'info, Battle>,
+    pub attacker_character: Account<'info, Character>,
+    pub defender_character: Account<'info, Character>,
+}
+
+#[derive(Accounts)]
+pub struct RequiredXp {}
+
+#[derive(Accounts)]
+pub struct GetBattleSnapshot<'info> {
+    pub battle: AccountLoader<'info, Battle>,
+}
+
+// Return value of battle_snapshot. winner is 0 until the battle is decided.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct BattleSnapshot {
+    pub turn_number: u32,
+    pub current_turn: u8,
+    pub player1_hp: u64,
+    pub player2_hp: u64,
+    pub player1_combo: u16,
+    pub player2_combo: u16,
+    pub wildcard_type: Option<WildcardEvent>,
+    pub is_finished: bool,
+    pub winner: u8,
+}
+
+#[derive(Accounts)]
+pub struct CreateTournament<'info> {
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + Tournament::INIT_SPACE
+    )]
+    pub tournament: Account<'info, Tournament>,
+    #[account(mut)]
+    pub creator: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct JoinTournament<'info> {
+    #[account(mut)]
+    pub tournament: Account<'info, Tournament>,
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct StartTournament<'info> {
+    #[account(mut, has_one = creator)]
+    pub tournament: Account<'info, Tournament>,
+    pub creator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RecordTournamentResult<'info> {
+    #[account(mut)]
+    pub tournament: Account<'info, Tournament>,
+    pub battle: AccountLoader<'info, Battle>,
+    // Freed to close, transfer or respec once knocked out
+    #[account(mut)]
+    pub loser: Account<'info, Character>,
+}
+
+#[derive(Accounts)]
+pub struct CompleteTournament<'info> {
+    #[account(mut)]
+    pub tournament: Account<'info, Tournament>,
+    #[account(mut)]
+    pub champion: Account<'info, Character>,
+    /// CHECK: Receives the prize, must own the champion
+    #[account(mut, address = champion.owner)]
+    pub champion_owner: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelTournament<'info> {
+    #[account(mut, has_one = creator)]
+    pub tournament: Account<'info, Tournament>,
+    #[account(mut)]
+    pub creator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimTournamentRefund<'info> {
+    #[account(mut)]
+    pub tournament: Account<'info, Tournament>,
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CreateGuild<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Guild::INIT_SPACE,
+        seeds = [b"guild", name.as_bytes()],
+        bump
+    )]
+    pub guild: Account<'info, Guild>,
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct JoinGuild<'info> {
+    #[account(mut, seeds = [b"guild", guild.name.as_bytes()], bump = guild.bump)]
+    pub guild: Account<'info, Guild>,
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct LeaveGuild<'info> {
+    #[account(mut, seeds = [b"guild", guild.name.as_bytes()], bump = guild.bump)]
+    pub guild: Account<'info, Guild>,
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DonateToGuild<'info> {
+    #[account(mut, seeds = [b"guild", guild.name.as_bytes()], bump = guild.bump)]
+    pub guild: Account<'info, Guild>,
+    #[account(mut)]
+    pub donor: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+// Additional state accounts
+#[account]
+#[derive(InitSpace)]
+pub struct QueueEntry {
+    pub player: Pubkey,
+    pub character: Pubkey,
+    pub mmr: u64,
+    pub match_type: MatchType,
+    pub stake_amount: u64,
+    pub joined_at: i64,
+    pub matched: bool,
+    pub mmr_bucket: u16, // mmr / MMR_BUCKET_SIZE, lets the matcher filter cheaply
+    pub region: u8,      // Player supplied, opaque on-chain
+}
+
+impl QueueEntry {
+    // How many buckets either side of its own this entry may be matched into.
+    // Grows with time in the queue so long waits don't go on forever.
+    pub fn priority_boost(&self, now: i64) -> u16 {
+        let waited = (now - self.joined_at).max(0) / QUEUE_PRIORITY_STEP_SECONDS;
+        waited.min(MAX_QUEUE_PRIORITY_BOOST as i64) as u16
+    }
+
+    // Unmatched for longer than queue_timeout_seconds, anyone may refund it
+    pub fn has_expired(&self, settings: &GameSettings, clock: &Clock) -> bool {
+        clock.unix_timestamp - self.joined_at > settings.queue_timeout_seconds
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Tournament {
+    pub creator: Pubkey,
+    pub entry_fee: u64,
+    pub prize_pool: u64,
+    pub max_players: u8,
+    pub current_players: u8,
+    pub status: TournamentStatus,
+    pub created_at: i64,
+    #[max_len(64)]
+    pub participants: Vec<Pubkey>, // Join order, then bracket order once started
+    pub current_round: u8,
+    pub winner: Option<Pubkey>,
+    pub started_at: i64, // Tournament battles must be created after this
+    pub eliminated: u64, // Bit i set once participants[i] lost a tournament battle
+}
+
+// Global tunables, one PDA per program
+#[account]
+#[derive(InitSpace)]
+pub struct GameConfig {
+    pub admin: Pubkey,
+    pub bump: u8,
+    pub paused: bool, // Emergency stop for gameplay, withdrawals keep working
+    pub settings: GameSettings,
+    pub current_season: u32, // Index of the latest Season, 0 before the first
+    pub season_active: bool,
+    pub ai_character: Pubkey, // Opponent of every create_battle, unset until set_ai_character
+}
+
+// Betting pool limits in lamports, config defaults or chosen per pool
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub struct BetLimits {
+    pub min_bet: u64,
+    pub max_bet_per_wallet: u64,
+    pub max_pool_size: u64,
+}
+
+impl BetLimits {
+    pub fn is_valid(&self) -> bool {
+        self.min_bet > 0 && self.min_bet <= self.max_bet_per_wallet && self.max_bet_per_wallet <= self.max_pool_size
+    }
+}
+
+// A class's level 1 stats, chances in percent
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub struct ClassStats {
+    pub max_hp: u64,
+    pub damage_min: u16,
+    pub damage_max: u16,
+    pub crit_chance: u16,
+    pub dodge_chance: u16,
+}
+
+// Timeouts are in seconds, chances and the house edge in percent
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub struct GameSettings {
+    pub turn_timeout_seconds: i64,
+    pub battle_expiry_seconds: i64,
+    pub wildcard_decision_timeout: i64,
+    pub min_battle_interval_seconds: i64,
+    pub challenge_window_seconds: i64,
+    pub revive_downtime_seconds: i64,
+    pub base_wildcard_chance: u8,
+    pub trickster_wildcard_chance: u8,
+    pub heal_price_per_hp: u64, // Lamports
+    pub revive_cost: u64, // Lamports
+    pub respec_cost: u64, // Lamports
+    pub default_house_edge: u8,
+    pub mmr_k_ranked: u64, // Elo K-factors
+    pub mmr_k_provisional: u64,
+    pub mmr_k_casual: u64,
+    pub max_mmr_gap: u64,
+    pub xp_multiplier: u16, // Basis points applied to battle XP, 10_000 = 1x
+    pub loser_xp_percent: u8, // Consolation XP, percent of the winner's base XP
+    pub special_cooldowns: [u8; 6], // Per CharacterClass, see SPECIAL_COOLDOWNS
+    pub class_stats: [ClassStats; 6], // Per CharacterClass, see CLASS_BASE_STATS
+    pub bet_limits: BetLimits,         // Defaults for new betting pools
+    pub instant_kill_enabled: bool, // Never applies outside Casual matches
+    pub queue_timeout_seconds: i64,
+    pub betting_closes_at_turn: u32, // Used for new pools
+    pub bet_claim_window_seconds: i64,
+    pub counter_keeps_combo: bool, // Counter's zero-damage swing doesn't halve the combo
+    pub pve_timeout_refunds_stake: bool, // A human timing out vs the AI gets the stake back
+    pub abandon_threshold: u16,
+    pub abandon_window_seconds: i64,
+    pub abandon_ban_seconds: i64,
+    pub reveal_timeout_seconds: i64, // Counted from both_committed_at, not the last action
+    pub max_battle_turns: u32, // Up to TURN_RECORD_CAPACITY, decided on HP percentage after it
+    pub season_claim_window_seconds: i64,
+}
+
+// Collects heal fees and the betting house cut
+#[account]
+#[derive(InitSpace)]
+pub struct Treasury {
+    pub bump: u8,
+    pub lifetime_fees_collected: u64,
+}
+
+// A ranked season. The reward pool is held as lamports on the account itself.
+#[account]
+#[derive(InitSpace)]
+pub struct Season {
+    pub index: u32,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub ended: bool,
+    pub tier_rewards: [u64; 6], // Lamports per RankTier, Bronze first
+    pub reward_pool: u64, // Lamports left to pay out
+    pub claims: u32,
+    pub bump: u8,
+}
+
+// Marks a character's claim for a season, one per character per season
+#[account]
+#[derive(InitSpace)]
+pub struct SeasonClaim {
+    pub character: Pubkey,
+    pub season: u32,
+    pub rank_tier: RankTier,
+    pub amount: u64,
+}
+
+// Guild names are unique, the PDA is seeded by the name. Donations are held
+// as lamports on the account itself.
+#[account]
+#[derive(InitSpace)]
+pub struct Guild {
+    #[max_len(32)]
+    pub name: String,
+    pub leader: Pubkey, // Founding character, default once the guild empties
+    pub member_count: u32,
+    pub treasury_balance: u64, // Lamports donated
+    pub total_guild_wins: u64, // Ranked PvP wins by members
+    pub bump: u8,
+}
+
+// Top characters by MMR, updated in finalize_battle
+#[account]
+#[derive(InitSpace)]
+pub struct Leaderboard {
+    #[max_len(100)]
+    pub entries: Vec<LeaderboardEntry>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub struct LeaderboardEntry {
+    pub character: Pubkey,
+    pub mmr: u64,
+    pub owner: Pubkey, // As of the entry's last update
+}
+
+// Additional enums
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum RankTier {
+    Bronze,
+    Silver,
+    Gold,
+    Platinum,
+    Diamond,
+    Master,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum Achievement {
+    FirstWin,
+    TenWins,
+    HundredWins,
+    Flawless,
+    ComboMaster,
+    TournamentWinner,
+    Prestiged,
+    StreakMaster,
+    StreakFive,
+}
+
+impl Achievement {
+    // Flag in Character::claimed_achievement_rewards
+    pub fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+// What a betting pool is on. Every market has two sides, bet_on_player picks one.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum BetMarket {
+    Winner,                   // Side is the player who wins
+    TotalTurns { line: u32 }, // Side 1 over, side 2 under, exactly on the line pushes
+    FirstBlood,               // Side is the player who lands the first damage
+    Wildcard,                 // Side 1 a wildcard triggers, side 2 none does
+}
+
+impl BetMarket {
+    // Extra pool PDA seed, empty for Winner so those pools keep their address
+    pub fn seed(&self) -> Vec<u8> {
+        match self {
+            BetMarket::Winner => vec![],
+            BetMarket::TotalTurns { line } => [&[1u8][..], &line.to_le_bytes()].concat(),
+            BetMarket::FirstBlood => vec![2],
+            BetMarket::Wildcard => vec![3],
+        }
+    }
+
+    // Winning side from a finished battle, None is a push
+    pub fn resolve(&self, battle: &Battle) -> Option<u8> {
+        match self {
+            BetMarket::Winner => Some(battle.winner).filter(|winner| *winner != 0),
+            BetMarket::TotalTurns { line } => match battle.turn_number.cmp(line) {
+                std::cmp::Ordering::Greater => Some(1),
+                std::cmp::Ordering::Less => Some(2),
+                std::cmp::Ordering::Equal => None,
+            },
+            BetMarket::FirstBlood => Some(battle.first_blood).filter(|player| *player != 0),
+            BetMarket::Wildcard => Some(if battle.wildcards_triggered > 0 { 1 } else { 2 }),
+        }
+    }
+
+    // True once play so far has settled the proposition, before the battle ends
+    pub fn is_decided(&self, battle: &Battle) -> bool {
+        match self {
+            BetMarket::Winner => false,
+            BetMarket::TotalTurns { line } => battle.turn_number > *line,
+            BetMarket::FirstBlood => battle.first_blood != 0,
+            BetMarket::Wildcard => battle.wildcards_triggered > 0,
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum OddsMode {
+    Parimutuel, // Winners split the pool by stake
+    FixedAtBet, // Winners get the odds quoted when they bet
+}
+
+// Why a betting pool went to refunds instead of paying out
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VoidReason {
+    Abandoned,
+    Draw,
+    Expired,      // Battle ran past battle_expiry_seconds unfinished
+    NeverStarted, // No turn played within BETTING_START_WINDOW_SECONDS
+    NoWinningBets,
+    Push, // A proposition landed exactly on its line, or never happened
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum TournamentStatus {
+    Registration,
+    InProgress,
+    Completed,
+    Cancelled,
+}
+
+// Returned by simulate_turn, chances are percentages
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub struct TurnSimulation {
+    pub min_damage: u64,
+    pub expected_damage: u64,
+    pub max_damage: u64,
+    pub hit_chance: u8,
+    pub crit_chance: u8,
+    pub dodge_chance: u8,
+}
+
+impl BattleStance {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![self.code()]
+    }
+
+    // How the zero-copy Battle stores a stance
+    pub fn code(self) -> u8 {
+        match self {
+            BattleStance::Aggressive => 0,
+            BattleStance::Defensive => 1,
+            BattleStance::Balanced => 2,
+            BattleStance::Berserker => 3,
+            BattleStance::Counter => 4,
+            BattleStance::Venomous => 5,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => BattleStance::Aggressive,
+            1 => BattleStance::Defensive,
+            3 => BattleStance::Berserker,
+            4 => BattleStance::Counter,
+            5 => BattleStance::Venomous,
+            _ => BattleStance::Balanced,
+        }
+    }
+}
+
+impl MatchType {
+    pub fn code(self) -> u8 {
+        match self {
+            MatchType::Casual => 0,
+            MatchType::Ranked => 1,
+            MatchType::Tournament => 2,
+            MatchType::Staked => 3,
+            MatchType::Practice => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => MatchType::Ranked,
+            2 => MatchType::Tournament,
+            3 => MatchType::Staked,
+            4 => MatchType::Practice,
+            _ => MatchType::Casual,
+        }
+    }
+}
+
+impl EquipmentSlot {
+    // Slot in Character::equipment
+    pub fn index(self) -> usize {
+        match self {
+            EquipmentSlot::Weapon => 0,
+            EquipmentSlot::Armor => 1,
+            EquipmentSlot::Trinket => 2,
+        }
+    }
+}
+
+impl BattleItem {
+    pub fn code(self) -> u8 {
+        self.index() as u8 + 1
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(BattleItem::HealthPotion),
+            2 => Some(BattleItem::AdrenalineShot),
+            3 => Some(BattleItem::SmokeBomb),
+            _ => None,
+        }
+    }
+
+    // Slot in ItemInventory::counts
+    pub fn index(self) -> usize {
+        match self {
+            BattleItem::HealthPotion => 0,
+            BattleItem::AdrenalineShot => 1,
+            BattleItem::SmokeBomb => 2,
+        }
+    }
+
+    pub fn price(self) -> u64 {
+        match self {
+            BattleItem::HealthPotion => HEALTH_POTION_LAMPORTS,
+            BattleItem::AdrenalineShot => ADRENALINE_SHOT_LAMPORTS,
+            BattleItem::SmokeBomb => SMOKE_BOMB_LAMPORTS,
+        }
+    }
+}
+
+// 0 is left free so a Battle can store "no wildcard"
+impl WildcardEvent {
+    pub fn code(self) -> u8 {
+        match self {
+            WildcardEvent::DoubleOrNothing => 1,
+            WildcardEvent::ReverseRoles => 2,
+            WildcardEvent::MysteryBox => 3,
+            WildcardEvent::DeathRoulette => 4,
+            WildcardEvent::ComboBreaker => 5,
+            WildcardEvent::TimeWarp => 6,
+            WildcardEvent::LuckySeven => 7,
+            WildcardEvent::GamblersFallacy => 8,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(WildcardEvent::DoubleOrNothing),
+            2 => Some(WildcardEvent::ReverseRoles),
+            3 => Some(WildcardEvent::MysteryBox),
+            4 => Some(WildcardEvent::DeathRoulette),
+            5 => Some(WildcardEvent::ComboBreaker),
+            6 => Some(WildcardEvent::TimeWarp),
+            7 => Some(WildcardEvent::LuckySeven),
+            8 => Some(WildcardEvent::GamblersFallacy),
+            _ => None,
+        }
+    }
+}
+
+// Events
+#[event]
+pub struct CharacterCreated {
+    pub character: Pubkey,
+    pub owner: Pubkey,
+    pub class: CharacterClass,
+    pub name: String,
+}
+
+#[event]
+pub struct QueueJoined {
+    pub player: Pubkey,
+    pub character: Pubkey,
+    pub mmr: u64,
+    pub match_type: MatchType,
+    pub mmr_bucket: u16,
+    pub region: u8,
+}
+
+#[event]
+pub struct QueueBanned {
+    pub character: Pubkey,
+    pub ban_until: i64,
+}
+
+#[event]
+pub struct QueueExpired {
+    pub player: Pubkey,
+    pub character: Pubkey,
+    pub refunded: u64, // Stake plus rent
+}
+
+#[event]
+pub struct BattleCreated {
+    pub battle: Pubkey,
+    pub player1: Pubkey,
+    pub player2: Pubkey,
+    pub match_type: MatchType,
+    pub is_vs_ai: bool,
+}
+
+#[event]
+pub struct ChallengeSent {
+    pub challenge: Pubkey,
+    pub challenger: Pubkey,
+    pub challenged: Pubkey,
+    pub match_type: MatchType,
+    pub stake_amount: u64,
+    pub expires_at: i64,
+}
+
+#[event]
+pub struct ChallengeAccepted {
+    pub challenge: Pubkey,
+    pub battle: Pubkey,
+}
+
+#[event]
+pub struct ChallengeDeclined {
+    pub challenge: Pubkey,
+    pub challenger: Pubkey,
+    pub challenged: Pubkey,
+    pub refunded: u64,
+    pub expired: bool, // Cleared after expiry rather than declined
+}
+
+#[event]
+pub struct StanceCommitted {
+    pub battle: Pubkey,
+    pub player: Pubkey,
+    pub turn: u32,
+}
+
+#[event]
+pub struct StanceRevealed {
+    pub battle: Pubkey,
+    pub player: Pubkey,
+    pub stance: BattleStance,
+    pub turn: u32,
+}
+
+#[event]
+pub struct WildcardTriggered {
+    pub battle: Pubkey,
+    pub wildcard_type: WildcardEvent,
+    pub decision_deadline: i64,
+}
+
+#[event]
+pub struct WildcardDecision {
+    pub battle: Pubkey,
+    pub player: Pubkey,
+    pub accepted: bool,
+}
+
+#[event]
+pub struct DotTick {
+    pub battle: Pubkey,
+    pub victim: u8,
+    pub amount: u64,
+    pub remaining_turns: u8,
+}
+
+#[event]
+pub struct TurnExecuted {
+    pub battle: Pubkey,
+    pub turn: u32,
+    pub player: u8,
+    pub stance: BattleStance,
+    pub base_damage: u64, // Roll plus level bonus, before any multiplier
+    pub was_crit: bool,
+    pub was_dodged: bool,
+    pub combo_multiplier: u16, // Percent
+    pub combo: u16,            // Attacker's combo after this attack
+    pub stance_modifier: u16, // Percent, attacker and defender stances combined
+    pub wildcard: Option<WildcardEvent>,
+    pub applied_dot: bool,
+    pub final_damage: u64,
+    pub self_damage: u64,
+    pub reflected_damage: u64,
+    pub riposte_damage: u64,
+    pub player1_hp: u64,
+    pub player2_hp: u64,
+}
+
+#[event]
+pub struct BattleLogAppended {
+    pub battle: Pubkey,
+    pub turn: u32,
+    pub index: u32, // Sequence number across the whole battle
+    pub entry: BattleLogEvent,
+}
+
+#[event]
+pub struct WildcardApplied {
+    pub battle: Pubkey,
+    pub wildcard_type: WildcardEvent,
+    pub player: u8,
+    pub damage: u64,
+}
+
+#[event]
+pub struct BattleEnded {
+    pub battle: Pubkey,
+    pub winner: u8, // 0 on a draw
+    pub total_turns: u32,
+    pub reason: BattleEndReason,
+}
+
+#[event]
+pub struct TeamBattleCreated {
+    pub team_battle: Pubkey,
+    pub characters: [Pubkey; 4],
+}
+
+#[event]
+pub struct TeamTurnExecuted {
+    pub team_battle: Pubkey,
+    pub turn: u32,
+    pub attacker_slot: u8,
+    pub target: u8,
+    pub damage: u64,
+    pub was_crit: bool,
+    pub was_dodged: bool,
+    pub target_hp: u64,
+}
+
+#[event]
+pub struct TeamBattleEnded {
+    pub team_battle: Pubkey,
+    pub winning_side: u8,
+    pub total_turns: u32,
+}
+
+#[event]
+pub struct BattleAbandoned {
+    pub battle: Pubkey,
+    pub abandoned_by: u8,
+    pub winner: u8,
+}
+
+#[event]
+pub struct BattleFinalized {
+    pub battle: Pubkey,
+    pub winner: Pubkey,
+    pub loser: Pubkey,
+    pub xp_gained: u64,
+    pub loser_xp: u64,
+}
+
+#[event]
+pub struct HouseEdgeCollected {
+    pub betting_pool: Pubkey,
+    pub amount: u64, // Unclaimed lamports swept, rent excluded
+}
+
+#[event]
+pub struct BettingClosed {
+    pub battle: Pubkey,
+    pub betting_pool: Pubkey,
+    pub turn_number: u32, // Battle turn when the close was announced
+}
+
+#[event]
+pub struct PoolSettled {
+    pub betting_pool: Pubkey,
+    pub battle: Pubkey,
+    pub winner: u8, // Winning side of the pool's market
+    pub house_cut: u64,
+}
+
+#[event]
+pub struct PoolVoided {
+    pub betting_pool: Pubkey,
+    pub battle: Pubkey,
+    pub reason: VoidReason,
+}
+
+#[event]
+pub struct BettingPoolCreated {
+    pub betting_pool: Pubkey,
+    pub battle: Pubkey,
+    pub house_edge: u8,
+    pub odds_mode: OddsMode,
+    pub market: BetMarket,
+    pub min_bet: u64,
+    pub max_bet_per_wallet: u64,
+    pub max_pool_size: u64,
+}
+
+#[event]
+pub struct OddsLocked {
+    pub betting_pool: Pubkey,
+    pub bettor: Pubkey,
+    pub amount: u64,
+    pub bet_on_player: u8,
+    pub odds: u64, // Side's odds when the bet was placed
+    pub odds_mode: OddsMode,
+}
+
+#[event]
+pub struct BetCancelled {
+    pub betting_pool: Pubkey,
+    pub bettor: Pubkey,
+    pub amount: u64,
+    pub player1_odds: u64,
+    pub player2_odds: u64,
+}
+
+#[event]
+pub struct BetRefunded {
+    pub betting_pool: Pubkey,
+    pub bettor: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct CharacterHealed {
+    pub character: Pubkey,
+    pub owner: Pubkey,
+    pub hp_restored: u64,
+    pub lamports_paid: u64,
+}
+
+#[event]
+pub struct GuildCreated {
+    pub guild: Pubkey,
+    pub leader: Pubkey,
+    pub name: String,
+}
+
+#[event]
+pub struct GuildMembershipChanged {
+    pub guild: Pubkey,
+    pub character: Pubkey,
+    pub joined: bool, // false when leaving
+    pub member_count: u32,
+}
+
+#[event]
+pub struct GuildDonation {
+    pub guild: Pubkey,
+    pub donor: Pubkey,
+    pub amount: u64,
+    pub treasury_balance: u64,
+}
+
+#[event]
+pub struct EquipmentMinted {
+    pub equipment: Pubkey,
+    pub owner: Pubkey,
+    pub slot: EquipmentSlot,
+}
+
+#[event]
+pub struct EquipmentChanged {
+    pub character: Pubkey,
+    pub equipment: Pubkey,
+    pub slot: EquipmentSlot,
+    pub equipped: bool, // false when taken off
+}
+
+#[event]
+pub struct DailyRewardClaimed {
+    pub character: Pubkey,
+    pub streak: u16,
+    pub xp_gained: u64,
+}
+
+#[event]
+pub struct ItemPurchased {
+    pub character: Pubkey,
+    pub item: BattleItem,
+    pub quantity: u16,
+    pub lamports_paid: u64,
+}
+
+#[event]
+pub struct ItemUsed {
+    pub battle: Pubkey,
+    pub character: Pubkey,
+    pub item: BattleItem,
+    pub amount: u64, // HP restored, combo gained or dodge bonus
+    pub items_used: u8, // This battle, including this one
+}
+
+#[event]
+pub struct CharacterTransferred {
+    pub character: Pubkey,
+    pub from: Pubkey,
+    pub to: Pubkey,
+}
+
+#[event]
+pub struct CharacterNftMinted {
+    pub character: Pubkey,
+    pub mint: Pubkey,
+    pub owner: Pubkey,
+}
+
+#[event]
+pub struct CharacterDeleted {
+    pub character: Pubkey,
+    pub owner: Pubkey,
+    pub name: String,
+}
+
+#[event]
+pub struct MetadataShouldUpdate {
+    pub character: Pubkey,
+    pub level: u16,
+    pub rank_tier: RankTier,
+}
+
+#[event]
+pub struct MetadataUpdated {
+    pub character: Pubkey,
+    pub uri: String,
+    pub version: u16,
+}
+
+#[event]
+pub struct CharacterLeveledUp {
+    pub character: Pubkey,
+    pub level: u16,
+}
+
+#[event]
+pub struct CharacterRenamed {
+    pub character: Pubkey,
+    pub old_name: String,
+    pub new_name: String,
+}
+
+#[event]
+pub struct ClassChanged {
+    pub character: Pubkey,
+    pub old_class: CharacterClass,
+    pub new_class: CharacterClass,
+    pub lamports_paid: u64,
+}
+
+#[event]
+pub struct LeaderboardChanged {
+    pub character: Pubkey,
+    pub mmr: u64,
+    pub rank: u16, // 1 is the top
+}
+
+#[event]
+pub struct StreakUpdated {
+    pub character: Pubkey,
+    pub streak: u32,
+}
+
+#[event]
+pub struct AchievementUnlocked {
+    pub character: Pubkey,
+    pub achievement: Achievement,
+}
+
+#[event]
+pub struct AchievementRewardClaimed {
+    pub character: Pubkey,
+    pub achievement: Achievement,
+}
+
+#[event]
+pub struct CharacterPrestiged {
+    pub character: Pubkey,
+    pub prestige: u8,
+}
+
+#[event]
+pub struct CharacterRevived {
+    pub character: Pubkey,
+    pub owner: Pubkey,
+    pub lamports_paid: u64,
+    pub death_count: u32,
+}
+
+#[event]
+pub struct TournamentCreated {
+    pub tournament: Pubkey,
+    pub creator: Pubkey,
+    pub prize_pool: u64,
+    pub max_players: u8,
+}
+
+#[event]
+pub struct SeasonStarted {
+    pub index: u32,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub reward_pool: u64,
+}
+
+#[event]
+pub struct SeasonEnded {
+    pub index: u32,
+    pub reward_pool: u64,
+}
+
+#[event]
+pub struct SeasonRewardClaimed {
+    pub season: u32,
+    pub character: Pubkey,
+    pub rank_tier: RankTier,
+    pub amount: u64,
+}
+
+#[event]
+pub struct SeasonRewardsSwept {
+    pub season: u32,
+    pub amount: u64,
+}
+
+#[event]
+pub struct RankDecayed {
+    pub character: Pubkey,
+    pub mmr_lost: u64,
+    pub new_mmr: u64,
+    pub rank_tier: RankTier,
+}
+
+#[event]
+pub struct TreasuryWithdrawal {
+    pub admin: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub remaining: u64, // Withdrawable lamports left above rent
+}
+
+#[event]
+pub struct GamePaused {
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct GameUnpaused {
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct AiCharacterSet {
+    pub admin: Pubkey,
+    pub ai_character: Pubkey,
+}
+
+#[event]
+pub struct ConfigUpdated {
+    pub admin: Pubkey,
+    pub old: GameSettings,
+    pub new: GameSettings,
+}
+
+#[event]
+pub struct TournamentPairing {
+    pub tournament: Pubkey,
+    pub round: u8,
+    pub player1: Pubkey,
+    pub player2: Option<Pubkey>, // None is a bye
+}
+
+#[event]
+pub struct TournamentCompleted {
+    pub tournament: Pubkey,
+    pub champion: Pubkey,
+    pub prize: u64,
+}
+
+#[event]
+pub struct TournamentMatchRecorded {
+    pub tournament: Pubkey,
+    pub battle: Pubkey,
+    pub winner: Pubkey,
+    pub loser: Pubkey,
+}
+
+#[event]
+pub struct TournamentCancelled {
+    pub tournament: Pubkey,
+    pub refunded_prize_pool: u64,
+}
+
+#[event]
+pub struct TournamentRefunded {
+    pub tournament: Pubkey,
+    pub character: Pubkey,
+    pub amount: u64,
+}
+
+// Additional error codes
+#[error_code]
+pub enum GameError {
+    #[msg("Name is too long (max 32 characters)")]
+    NameTooLong,
+    #[msg("Battle has already finished")]
+    BattleAlreadyFinished,
+    #[msg("Not your turn")]
+    NotYourTurn,
+    #[msg("Battle is not finished yet")]
+    BattleNotFinished,
+    #[msg("No winner determined")]
+    NoWinner,
+    #[msg("Invalid bet target (must be 1 or 2)")]
+    InvalidBetTarget,
+    #[msg("Invalid bet amount")]
+    InvalidBetAmount,
+    #[msg("Pool already settled")]
+    PoolAlreadySettled,
+    #[msg("Pool not settled yet")]
+    PoolNotSettled,
+    #[msg("Bet already claimed")]
+    AlreadyClaimed,
+    #[msg("Not the bet owner")]
     NotBetOwner,
     #[msg("Bet lost")]
     BetLost,
@@ -1513,312 +6698,4657 @@ pub enum GameError {
     NotAiBattle,
     #[msg("Not AI's turn")]
     NotAiTurn,
+    #[msg("Character fought too recently")]
+    BattleCooldownActive,
+    #[msg("No bets on the winning side")]
+    NoWinningBets,
+    #[msg("Pool is in refund mode, claim a refund instead")]
+    PoolInRefundMode,
+    #[msg("Pool is not in refund mode")]
+    PoolNotInRefundMode,
+    #[msg("Betting is closed for this battle")]
+    BettingClosed,
+    #[msg("Bet is below the pool minimum")]
+    BetTooSmall,
+    #[msg("Bet would take this wallet past the pool's per-wallet maximum")]
+    BetTooLarge,
+    #[msg("Too much already bet on this side")]
+    BetSideFull,
+    #[msg("Bet would push the pool's fixed-odds exposure past its limit")]
+    BetExceedsExposure,
+    #[msg("Character is not in this battle")]
+    NotBattleParticipant,
+    #[msg("Opponent has not committed a stance yet")]
+    OpponentNotCommitted,
+    #[msg("Tournament is not open for registration")]
+    TournamentNotOpen,
+    #[msg("Tournament is full")]
+    TournamentFull,
+    #[msg("Character is already registered")]
+    AlreadyRegistered,
+    #[msg("Character is not in this tournament")]
+    NotTournamentParticipant,
+    #[msg("Tournament has already finished")]
+    TournamentAlreadyFinished,
+    #[msg("Tournament needs at least two players")]
+    NotEnoughPlayers,
+    #[msg("Character accounts don't match the participants")]
+    ParticipantMismatch,
+    #[msg("Randomness already requested or an unused seed is pending")]
+    RandomnessAlreadyRequested,
+    #[msg("No randomness requested for this battle")]
+    RandomnessNotRequested,
+    #[msg("No fresh randomness for this turn")]
+    RandomnessNotReady,
+    #[msg("Invalid Switchboard randomness account")]
+    InvalidRandomnessAccount,
+    #[msg("Randomness has already been revealed")]
+    RandomnessAlreadyRevealed,
+    #[msg("Waiting on wildcard decisions")]
+    WildcardDecisionPending,
+    #[msg("Invalid SlotHashes sysvar data")]
+    InvalidSlotHashes,
+    #[msg("No rank decay is due yet")]
+    NoRankDecayDue,
+    #[msg("Stake recipient is not the winner's owner")]
+    InvalidStakeRecipient,
+    #[msg("Config value out of bounds")]
+    InvalidConfig,
+    #[msg("Target must be a living character on the other side")]
+    InvalidTeamTarget,
+    #[msg("A character can only take one slot in a team battle")]
+    DuplicateTeamMember,
+    #[msg("The game is paused")]
+    GamePaused,
+    #[msg("Not enough lamports in the treasury")]
+    InsufficientTreasuryFunds,
+    #[msg("A character can't challenge itself")]
+    CannotChallengeSelf,
+    #[msg("Challenge has expired")]
+    ChallengeExpired,
+    #[msg("Only the challenged player can decline before expiry")]
+    NotChallengedPlayer,
+    #[msg("Character is already in a guild")]
+    AlreadyInGuild,
+    #[msg("Character is not a member of this guild")]
+    NotGuildMember,
+    #[msg("Guild is full")]
+    GuildFull,
+    #[msg("Guild leader can't leave while other members remain")]
+    GuildLeaderCannotLeave,
+    #[msg("Character is in an unfinished battle")]
+    CharacterBusy,
+    #[msg("Only a character at 0 HP can be revived")]
+    CharacterNotDead,
+    #[msg("Character died too recently to be revived")]
+    ReviveCooldownActive,
+    #[msg("Prestige needs max level and a full XP bar")]
+    PrestigeNotReady,
+    #[msg("Item quantity must be positive and fit in the inventory")]
+    InvalidItemQuantity,
+    #[msg("No such item in the inventory")]
+    ItemNotOwned,
+    #[msg("Items can only be used after committing and before revealing")]
+    ItemUseWindowClosed,
+    #[msg("Only one item per turn")]
+    ItemAlreadyUsedThisTurn,
+    #[msg("No more items allowed this battle")]
+    BattleItemLimitReached,
+    #[msg("Daily reward already claimed, come back later")]
+    DailyAlreadyClaimed,
+    #[msg("Equipment is already worn by a character")]
+    EquipmentAlreadyEquipped,
+    #[msg("That equipment slot is already taken")]
+    EquipmentSlotOccupied,
+    #[msg("Equipment is not worn by this character")]
+    EquipmentNotEquipped,
+    #[msg("Unequip all gear first")]
+    EquipmentStillEquipped,
+    #[msg("Stat change out of range")]
+    StatOutOfRange,
+    #[msg("Character already has that class")]
+    SameClass,
+    #[msg("Character was respecced too recently")]
+    RespecCooldownActive,
+    #[msg("Metadata URI is too long (max 100 characters)")]
+    MetadataUriTooLong,
+    #[msg("Metadata URI must start with https:// or ipfs://")]
+    InvalidMetadataUri,
+    #[msg("MMR gap too large for a ranked match")]
+    MmrGapTooLarge,
+    #[msg("Name doesn't match the character's name")]
+    NameMismatch,
+    #[msg("Leave the guild first")]
+    LeaveGuildFirst,
+    #[msg("Signer doesn't own this character")]
+    NotCharacterOwner,
+    #[msg("Defender is not the battle opponent")]
+    WrongDefender,
+    #[msg("Character is minted as an NFT, move the token instead")]
+    CharacterIsNft,
+    #[msg("Signer doesn't hold this character's NFT")]
+    NotNftHolder,
+    #[msg("Character hasn't been minted as an NFT")]
+    CharacterNotNft,
+    #[msg("NFT metadata is already up to date")]
+    MetadataUpToDate,
+    #[msg("House edge is out of range (max 10%)")]
+    InvalidHouseEdge,
+    #[msg("Character hasn't earned that achievement")]
+    AchievementNotEarned,
+    #[msg("Achievement reward already claimed")]
+    RewardAlreadyClaimed,
+    #[msg("That achievement has no reward")]
+    NoAchievementReward,
+    #[msg("A season is already running")]
+    SeasonAlreadyActive,
+    #[msg("Not the current season, or invalid season parameters")]
+    InvalidSeason,
+    #[msg("Season hasn't reached its end time")]
+    SeasonNotOver,
+    #[msg("Season hasn't ended yet")]
+    SeasonNotEnded,
+    #[msg("Character didn't battle this season")]
+    NoSeasonActivity,
+    #[msg("Character has battled since the season ended")]
+    PlayedSinceSeasonEnd,
+    #[msg("Queue entry hasn't timed out yet")]
+    QueueEntryNotExpired,
+    #[msg("Battle can still produce a winner")]
+    PoolNotVoidable,
+    #[msg("Winnings can still be claimed")]
+    ClaimWindowOpen,
+    #[msg("Betting pool is full")]
+    PoolFull,
+    #[msg("Betting limits must satisfy 0 < min bet <= per-wallet max <= pool size")]
+    InvalidBetLimits,
+    #[msg("Character abandoned too many matches and is temporarily banned from matchmaking")]
+    TooManyAbandons,
+    #[msg("Practice matches can't be staked")]
+    PracticeIsUnstaked,
+    #[msg("Practice battles can't be bet on")]
+    NoBettingOnPractice,
+    #[msg("Tournament is not in progress")]
+    TournamentNotInProgress,
+    #[msg("Not a Tournament battle played since the tournament started")]
+    NotTournamentBattle,
+    #[msg("Character has already been knocked out of the tournament")]
+    ParticipantEliminated,
+    #[msg("More than one participant is still in the tournament")]
+    TournamentUndecided,
+    #[msg("Tournament hasn't been cancelled")]
+    TournamentNotCancelled,
+    #[msg("Character is registered in an unfinished tournament")]
+    CharacterInTournament,
+    #[msg("Battles from create_battle are against the configured AI character only")]
+    NotAiCharacter,
+    #[msg("Donation must be more than zero lamports")]
+    InvalidDonationAmount,
+    #[msg("The pool is still taking bets")]
+    BettingStillOpen,
+    #[msg("A total turns line can't be below the pool's betting cutoff turn")]
+    InvalidMarketLine,
+    #[msg("Fixed-odds bets can't be cancelled")]
+    FixedOddsBetFinal,
+    #[msg("Equipment stats are out of range")]
+    InvalidEquipmentStats,
+    #[msg("Only the program's upgrade authority can initialize the config")]
+    NotUpgradeAuthority,
+    #[msg("The season's reward claim window has closed")]
+    ClaimWindowClosed,
+}
+
+
+// Part 3 - Updated Account Structures and Remaining Contexts
+
+// Updated Character account with all new fields
+#[account]
+#[derive(InitSpace)]
+pub struct Character {
+    pub owner: Pubkey, // Current owner, can change via transfer_character
+    pub character_class: CharacterClass,
+    #[max_len(32)]
+    pub name: String, // Display name, can change via rename_character
+    #[max_len(32)]
+    pub seed_name: String, // Name at creation, the PDA stays derived from it
+    pub seed_owner: Pubkey, // Owner at creation, likewise
+    pub level: u16,
+    pub xp: u64,
+    pub max_hp: u64,
+    pub current_hp: u64,
+    pub base_damage_min: u16,
+    pub base_damage_max: u16,
+    pub crit_chance: u16,
+    pub dodge_chance: u16,
+    pub defense: u16,
+    pub total_wins: u32,
+    pub total_losses: u32,
+    pub max_combo: u16,
+    pub ranked_mmr: u64, // Was mmr, existing accounts keep their rating here
+    pub created_at: i64,
+    pub last_battle: i64,
+    pub last_decay: i64,
+    
+    // New fields
+    pub rank_tier: RankTier,
+    pub season_wins: u32,
+    pub season_losses: u32,
+    #[max_len(20)]
+    pub achievements: Vec<Achievement>,
+    #[max_len(100)]
+    pub metadata_uri: String,
+    pub metadata_version: u16, // Bumped on every set_metadata_uri
+    pub guild: Option<Pubkey>,
+    pub in_battle: Option<Pubkey>, // Set while a 1v1 battle is unfinalized
+    pub tournament: Option<Pubkey>, // Set from join_tournament until knocked out, champion or refunded
+    pub death_count: u32, // Times revived from 0 HP
+    pub prestige: u8,
+    pub last_daily_claim: i64,
+    pub daily_streak: u16, // Consecutive daily claims
+    pub equipment: [Option<Pubkey>; 3], // Indexed by EquipmentSlot::index()
+    pub last_respec: i64,
+    pub nft_mint: Option<Pubkey>, // Set by mint_character_nft, the token holder can claim ownership
+    pub metadata_dirty: bool, // NFT metadata is stale until update_character_metadata runs
+    pub claimed_achievement_rewards: u16, // Achievement::bit() flags
+    #[max_len(32)]
+    pub title: String, // Earned through achievement rewards
+    pub free_heals: u8, // Credits for heal_character at no cost
+    pub cosmetic_flags: u8,
+    pub casual_mmr: u64, // Hidden, only for casual matchmaking
+    pub current_streak: u32, // Consecutive wins, forfeit wins excluded
+    pub best_streak: u32,
+    pub abandons: u16,              // PvP forfeits since abandon_window_start
+    pub abandon_window_start: i64,
+    pub ban_until: i64,             // Queue and challenges are blocked until then
+    pub battle_nonce: u64, // Battles started as player1, part of the next Battle PDA's seeds
+    pub season_index: u32, // Season that season_wins and season_losses belong to
+}
+
+// Updated Battle account with all new fields
+// Zero-copy, so every field is plain data: enums are stored as their code(),
+// flags as 0/1. Fields are ordered by alignment to leave no implicit padding.
+#[account(zero_copy)]
+pub struct Battle {
+    pub stake_amount: u64,
+    pub created_at: i64,
+    pub last_action_time: i64,
+
+    // Battle state
+    pub player1_hp: u64,
+    pub player2_hp: u64,
+    pub player1_max_hp: u64,
+    pub player2_max_hp: u64,
+    pub player1_salt: u64,
+    pub player2_salt: u64,
+    pub player1_dot_damage: u64,
+    pub player2_dot_damage: u64,
+    pub roll_counter: u64,
+    pub wildcard_decision_deadline: i64,
+    pub both_committed_at: i64, // 0 until both stances are committed this turn
+
+    // One summary per attack, indexed by turn_number (battles end by max_battle_turns)
+    pub turn_records: [TurnRecord; TURN_RECORD_CAPACITY],
+
+    pub player1: Pubkey,
+    pub player2: Pubkey,
+    pub randomness_account: Pubkey,
+    pub random_seed: [u8; 32],
+
+    // Stance commitment system
+    pub player1_stance_hash: [u8; 32],
+    pub player2_stance_hash: [u8; 32],
+
+    pub turn_number: u32,
+    pub seed_turn: u32,
+    pub log_offset: u32, // Entries ever logged, battle_log only keeps the latest
+
+    // Battle log, a ring buffer once battle_log_len reaches BATTLE_LOG_CAPACITY
+    pub battle_log: [BattleLogEntry; 64],
+
+    pub player1_combo: u16,
+    pub player2_combo: u16,
+    pub player1_peak_combo: u16,
+    pub player2_peak_combo: u16,
+    pub player1_reflection: u16,
+    pub player2_reflection: u16,
+    pub player1_miss_count: u16,
+    pub player2_miss_count: u16,
+
+    // Combat stats snapshot from create_battle, [player1, player2]
+    pub levels: [u16; 2],
+    pub damage_min: [u16; 2],
+    pub damage_max: [u16; 2],
+    pub crit_chance: [u16; 2],
+    pub dodge_chance: [u16; 2],
+    pub defense: [u16; 2],
+
+    pub match_type: u8, // MatchType::code()
+    pub current_turn: u8,
+    pub is_finished: u8,
+    pub winner: u8, // 0 until there is one, and on a draw
+    pub is_draw: u8,
+    pub is_vs_ai: u8,
+    pub abandoned: u8,
+    pub player1_stance: u8, // BattleStance::code()
+    pub player2_stance: u8,
+    pub player1_stance_committed: u8,
+    pub player2_stance_committed: u8,
+    pub player1_stance_revealed: u8,
+    pub player2_stance_revealed: u8,
+    pub pending_use_special: u8,
+
+    // DOT and effects
+    pub player1_dot_turns: u8,
+    pub player2_dot_turns: u8,
+    pub player1_pending_miss: u8,
+    pub player2_pending_miss: u8,
+
+    // Special cooldowns
+    pub player1_special_cooldown: u8,
+    pub player2_special_cooldown: u8,
+
+    // Turn randomness (Switchboard VRF)
+    pub randomness_pending: u8,
+    pub seed_consumed: u8,
+
+    // Wildcard system
+    pub last_damage_roll: u8,
+    pub wildcard_active: u8,
+    pub wildcard_type: u8, // WildcardEvent::code(), 0 for none
+    pub wildcard_player1_decision: u8, // WILDCARD_UNDECIDED, _DECLINED or _ACCEPTED
+    pub wildcard_player2_decision: u8,
+
+    // Consumable items
+    pub player1_item_this_turn: u8, // BattleItem::code(), 0 for none
+    pub player2_item_this_turn: u8,
+    pub player1_items_used: u8,
+    pub player2_items_used: u8,
+    pub player1_smoke_dodge: u8, // Dodge bonus on the next incoming attack
+    pub player2_smoke_dodge: u8,
+
+    pub battle_log_len: u8,
+    pub battle_log_next: u8,
+
+    // Facts for proposition bets
+    pub first_blood: u8, // Player who first landed attack damage, 0 before anyone has
+    pub wildcards_triggered: u8,
+    pub _padding: [u8; 7],
+}
+
+// Holds only a battle's staked lamports (plus its own rent)
+#[account]
+#[derive(InitSpace)]
+pub struct BattleEscrow {
+    pub battle: Pubkey,
+    pub bump: u8,
+}
+
+// Open invite from one character to another. Holds the challenger's stake
+// (plus its own rent) until it's accepted, declined or expires.
+#[account]
+#[derive(InitSpace)]
+pub struct Challenge {
+    pub challenger: Pubkey,
+    pub challenged: Pubkey,
+    pub challenger_owner: Pubkey, // Gets the stake and rent back on close
+    pub match_type: MatchType,
+    pub stake_amount: u64,
+    pub expires_at: i64,
+    pub bump: u8,
+}
+
+// A piece of gear, its bonuses are added to the character's stats while equipped
+#[account]
+#[derive(InitSpace)]
+pub struct Equipment {
+    pub owner: Pubkey,
+    pub slot: EquipmentSlot,
+    pub stats: EquipmentStats,
+    pub equipped_to: Option<Pubkey>, // Character wearing it
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub struct EquipmentStats {
+    pub damage_min: u16,
+    pub damage_max: u16,
+    pub crit_chance: u16,
+    pub dodge_chance: u16,
+    pub defense: u16,
+    pub max_hp: u64,
+}
+
+impl EquipmentStats {
+    // The same bounds validate_settings puts on class stats, applied to one piece
+    pub fn is_valid(&self) -> bool {
+        self.damage_min <= self.damage_max
+            && self.damage_max <= 100
+            && self.crit_chance <= MAX_CRIT_CHANCE
+            && self.dodge_chance <= MAX_DODGE_CHANCE
+            && self.defense <= 100
+            && self.max_hp <= 1000
+    }
+}
+
+// Consumables owned by a character, counts indexed by BattleItem::index()
+#[account]
+#[derive(InitSpace)]
+pub struct ItemInventory {
+    pub character: Pubkey,
+    pub counts: [u16; 3],
+    pub bump: u8,
+}
+
+// 2v2 battle, kept apart from Battle so 1v1 stays lean
+#[account]
+#[derive(InitSpace)]
+pub struct TeamBattle {
+    pub characters: [Pubkey; 4], // Side A is slots 0 and 1, side B slots 2 and 3
+    pub hp: [u64; 4],
+    pub stats: [CombatStats; 4], // Snapshot taken at creation
+    pub created_at: i64,
+    pub last_action_time: i64,
+    pub turn_number: u32,
+    pub current_slot: u8,
+    pub is_finished: bool,
+    pub winning_side: u8, // 1 or 2, 0 while the battle runs
+
+    // Turn randomness (Switchboard VRF), the target is chosen with the request
+    pub randomness_account: Pubkey,
+    pub randomness_pending: bool,
+    pub pending_target: u8,
+}
+
+// Betting pool for spectators
+#[account]
+#[derive(InitSpace)]
+pub struct BettingPool {
+    pub battle: Pubkey,
+    pub total_pool: u64,
+    pub player1_bets: u64,
+    pub player2_bets: u64,
+    pub player1_odds: u64,
+    pub player2_odds: u64,
+    pub house_edge: u8,
+    pub odds_mode: OddsMode,
+    pub player1_liability: u64, // FixedAtBet: locked payouts owed if player 1 wins
+    pub player2_liability: u64,
+    pub is_settled: bool,
+    pub winner: Option<u8>,
+    pub created_at: i64,
+    pub total_claimed: u64,
+    pub claimed_winning_bets: u64,
+    pub refund_mode: bool,
+    pub min_bet: u64,
+    pub max_bet_per_wallet: u64, // Across all of a wallet's bets, see BetTally
+    pub max_pool_size: u64,
+    pub max_side_total: u64,
+    pub max_exposure: u64, // FixedAtBet: cap on a side's liability beyond what the pool holds
+    pub betting_closes_at_turn: u32, // Copied from config at creation
+    pub betting_closed_announced: bool, // BettingClosed has been emitted for this pool
+    pub settled_at: i64,             // Starts the claim window, also set when voided
+    pub market: BetMarket,           // What sides 1 and 2 mean, winner is the winning side
+}
+
+// A wallet's running total on one pool, PDA [b"bet_tally", pool, bettor]
+#[account]
+#[derive(InitSpace)]
+pub struct BetTally {
+    pub bettor: Pubkey,
+    pub betting_pool: Pubkey,
+    pub total: u64, // Open bets only, cancelling takes the amount back off
+    pub bump: u8,
+}
+
+// Individual spectator bet
+#[account]
+#[derive(InitSpace)]
+pub struct Bet {
+    pub bettor: Pubkey,
+    pub betting_pool: Pubkey,
+    pub amount: u64,
+    pub bet_on_player: u8,
+    pub is_claimed: bool,
+    pub locked_odds: u64, // Side's odds when placed, only paid on by FixedAtBet pools
+    pub index: u32,       // Part of the PDA seeds, per bettor per pool
+}
+
+// Updated CharacterClass with Trickster
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum CharacterClass {
+    Warrior,
+    Assassin,
+    Mage,
+    Tank,
+    Trickster, // New class!
+    Paladin,
+}
+
+impl CharacterClass {
+    pub fn to_string(&self) -> &str {
+        match self {
+            CharacterClass::Warrior => "Warrior",
+            CharacterClass::Assassin => "Assassin",
+            CharacterClass::Mage => "Mage",
+            CharacterClass::Tank => "Tank",
+            CharacterClass::Trickster => "Trickster",
+            CharacterClass::Paladin => "Paladin",
+        }
+    }
+}
+
+// Existing enums (unchanged)
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum MatchType {
+    Casual,
+    Ranked,
+    Tournament,
+    Staked,
+    Practice, // No stakes, betting, XP, MMR or record, see finalize_battle
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum BattleStance {
+    Aggressive,
+    Defensive,
+    Balanced,
+    Berserker,
+    Counter,
+    Venomous,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum EquipmentSlot {
+    Weapon,
+    Armor,
+    Trinket,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum BattleItem {
+    HealthPotion,
+    AdrenalineShot,
+    SmokeBomb,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum WildcardEvent {
+    DoubleOrNothing,
+    ReverseRoles,
+    MysteryBox,
+    DeathRoulette,
+    ComboBreaker,
+    TimeWarp,
+    LuckySeven,
+    GamblersFallacy,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
+pub enum BattleEndReason {
+    Knockout,
+    TurnLimit,
+}
+
+// Typed battle log events, players are 1 or 2 (0 means both, or a draw)
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BattleLogEvent {
+    DamageDealt { by: u8, amount: u32, crit: bool },
+    Dodged { by: u8 },
+    SelfDamage { player: u8, amount: u32 },
+    Reflected { player: u8, amount: u32 },
+    Riposte { player: u8, amount: u32 },
+    DotTick { victim: u8, amount: u16 },
+    PendingMissConsumed { player: u8 },
+    WildcardBoost { player: u8 },
+    WildcardTriggered { kind: WildcardEvent, needs_decision: bool },
+    WildcardDecisionsLocked,
+    WildcardAutoDeclined { player: u8 },
+    DoubleOrNothing { player: u8, won: bool },
+    DeathRoulette { player: u8, nearly_killed: bool, both_accepted: bool },
+    ReverseRoles { player1_before: u32, player1_after: u32, player2_before: u32, player2_after: u32 },
+    MysteryBox { player: u8, prize: u8 },
+    Healed { player: u8, amount: u32 },
+    LuckySeven { player: u8 },
+    Forfeit { player: u8 },
+    BattleFinished { winner: u8 },
+    VenomStacked { victim: u8, amount: u16 }, // amount is the DOT per tick after stacking
+    ItemUsed { player: u8, item: BattleItem, amount: u32 },
+}
+
+// A BattleLogEvent packed into plain data for the zero-copy Battle. kind is
+// 0 for an empty slot, what the other fields mean depends on the kind.
+#[zero_copy]
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct BattleLogEntry {
+    pub kind: u8,
+    pub player: u8,
+    pub detail: u8,
+    pub flag: u8,
+    pub amounts: [u32; 4],
+}
+
+impl From<BattleLogEvent> for BattleLogEntry {
+    fn from(event: BattleLogEvent) -> Self {
+        let entry = |kind: u8, player: u8, detail: u8, flag: bool, amount: u32| BattleLogEntry {
+            kind,
+            player,
+            detail,
+            flag: flag as u8,
+            amounts: [amount, 0, 0, 0],
+        };
+        match event {
+            BattleLogEvent::DamageDealt { by, amount, crit } => entry(1, by, 0, crit, amount),
+            BattleLogEvent::Dodged { by } => entry(2, by, 0, false, 0),
+            BattleLogEvent::SelfDamage { player, amount } => entry(3, player, 0, false, amount),
+            BattleLogEvent::Reflected { player, amount } => entry(4, player, 0, false, amount),
+            BattleLogEvent::Riposte { player, amount } => entry(5, player, 0, false, amount),
+            BattleLogEvent::DotTick { victim, amount } => entry(6, victim, 0, false, amount as u32),
+            BattleLogEvent::PendingMissConsumed { player } => entry(7, player, 0, false, 0),
+            BattleLogEvent::WildcardBoost { player } => entry(8, player, 0, false, 0),
+            BattleLogEvent::WildcardTriggered { kind, needs_decision } => entry(9, 0, kind.code(), needs_decision, 0),
+            BattleLogEvent::WildcardDecisionsLocked => entry(10, 0, 0, false, 0),
+            BattleLogEvent::WildcardAutoDeclined { player } => entry(11, player, 0, false, 0),
+            BattleLogEvent::DoubleOrNothing { player, won } => entry(12, player, 0, won, 0),
+            BattleLogEvent::DeathRoulette { player, nearly_killed, both_accepted } => {
+                entry(13, player, both_accepted as u8, nearly_killed, 0)
+            }
+            BattleLogEvent::ReverseRoles { player1_before, player1_after, player2_before, player2_after } => BattleLogEntry {
+                kind: 14,
+                amounts: [player1_before, player1_after, player2_before, player2_after],
+                ..Default::default()
+            },
+            BattleLogEvent::MysteryBox { player, prize } => entry(15, player, prize, false, 0),
+            BattleLogEvent::Healed { player, amount } => entry(16, player, 0, false, amount),
+            BattleLogEvent::LuckySeven { player } => entry(17, player, 0, false, 0),
+            BattleLogEvent::Forfeit { player } => entry(18, player, 0, false, 0),
+            BattleLogEvent::BattleFinished { winner } => entry(19, winner, 0, false, 0),
+            BattleLogEvent::VenomStacked { victim, amount } => entry(20, victim, 0, false, amount as u32),
+            BattleLogEvent::ItemUsed { player, item, amount } => entry(21, player, item.code(), false, amount),
+        }
+    }
+}
+
+// Numeric summary of one attack: what the attacker landed on the defender
+// after stance, wildcard and miss adjustments. wildcard is 0 when none applied.
+#[zero_copy]
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct TurnRecord {
+    pub damage: u64,
+    pub turn: u16,
+    pub attacker: u8,
+    pub was_crit: u8,
+    pub was_dodged: u8,
+    pub stance: u8,
+    pub wildcard: u8,
+    pub combo: u8, // Attacker's combo after this attack, saturating
+    pub seed: [u8; 32], // Turn seed every roll was drawn from, roll counter starting at 0
+}
+
+impl TurnRecord {
+    pub fn stance(&self) -> BattleStance {
+        BattleStance::from_code(self.stance)
+    }
+
+    pub fn wildcard(&self) -> Option<WildcardEvent> {
+        WildcardEvent::from_code(self.wildcard)
+    }
+}
+
+impl BattleLogEntry {
+    // None for an empty slot
+    pub fn event(&self) -> Option<BattleLogEvent> {
+        let (player, flag, amount) = (self.player, self.flag != 0, self.amounts[0]);
+        Some(match self.kind {
+            1 => BattleLogEvent::DamageDealt { by: player, amount, crit: flag },
+            2 => BattleLogEvent::Dodged { by: player },
+            3 => BattleLogEvent::SelfDamage { player, amount },
+            4 => BattleLogEvent::Reflected { player, amount },
+            5 => BattleLogEvent::Riposte { player, amount },
+            6 => BattleLogEvent::DotTick { victim: player, amount: amount as u16 },
+            7 => BattleLogEvent::PendingMissConsumed { player },
+            8 => BattleLogEvent::WildcardBoost { player },
+            9 => BattleLogEvent::WildcardTriggered {
+                kind: WildcardEvent::from_code(self.detail)?,
+                needs_decision: flag,
+            },
+            10 => BattleLogEvent::WildcardDecisionsLocked,
+            11 => BattleLogEvent::WildcardAutoDeclined { player },
+            12 => BattleLogEvent::DoubleOrNothing { player, won: flag },
+            13 => BattleLogEvent::DeathRoulette { player, nearly_killed: flag, both_accepted: self.detail != 0 },
+            14 => BattleLogEvent::ReverseRoles {
+                player1_before: self.amounts[0],
+                player1_after: self.amounts[1],
+                player2_before: self.amounts[2],
+                player2_after: self.amounts[3],
+            },
+            15 => BattleLogEvent::MysteryBox { player, prize: self.detail },
+            16 => BattleLogEvent::Healed { player, amount },
+            17 => BattleLogEvent::LuckySeven { player },
+            18 => BattleLogEvent::Forfeit { player },
+            19 => BattleLogEvent::BattleFinished { winner: player },
+            20 => BattleLogEvent::VenomStacked { victim: player, amount: amount as u16 },
+            21 => BattleLogEvent::ItemUsed { player, item: BattleItem::from_code(self.detail)?, amount },
+            _ => return None,
+        })
+    }
+}
+
+// Client-side rendering of the typed log, never needed on-chain
+#[cfg(not(target_os = "solana"))]
+impl std::fmt::Display for BattleLogEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            BattleLogEvent::DamageDealt { by, amount, crit } => {
+                write!(f, "Player {} dealt {} damage{}", by, amount, if crit { " (crit)" } else { "" })
+            }
+            BattleLogEvent::Dodged { by } => write!(f, "Player {} dodged the attack", by),
+            BattleLogEvent::SelfDamage { player, amount } => {
+                write!(f, "Player {} takes {} berserker self-damage", player, amount)
+            }
+            BattleLogEvent::Reflected { player, amount } => {
+                write!(f, "Player {} takes {} reflected damage", player, amount)
+            }
+            BattleLogEvent::Riposte { player, amount } => {
+                write!(f, "Counter riposte: player {} takes {} damage", player, amount)
+            }
+            BattleLogEvent::DotTick { victim, amount } => write!(f, "Player {} takes {} DOT damage", victim, amount),
+            BattleLogEvent::PendingMissConsumed { player } => {
+                write!(f, "Player {} misses (Double or Nothing)", player)
+            }
+            BattleLogEvent::WildcardBoost { player } => {
+                write!(f, "Player {}'s wildcard manipulation active!", player)
+            }
+            BattleLogEvent::WildcardTriggered { kind, needs_decision } => {
+                write!(f, "Wildcard event triggered: {:?}{}", kind, if needs_decision { " - Decision required!" } else { "" })
+            }
+            BattleLogEvent::WildcardDecisionsLocked => write!(f, "Wildcard decisions locked in"),
+            BattleLogEvent::WildcardAutoDeclined { player } => {
+                write!(f, "Player {} auto-declined wildcard (timeout)", player)
+            }
+            BattleLogEvent::DoubleOrNothing { player, won } => {
+                let who = if player == 0 { "Both players".to_string() } else { format!("Player {}", player) };
+                write!(f, "Double or Nothing: {} {}", who, if won { "boosted!" } else { "MISS next attack!" })
+            }
+            BattleLogEvent::DeathRoulette { player, nearly_killed, both_accepted } => {
+                if both_accepted {
+                    write!(f, "Death Roulette: P{} nearly killed, P{} healed!", player, if player == 1 { 2 } else { 1 })
+                } else if nearly_killed {
+                    write!(f, "P{} Death Roulette: Nearly killed!", player)
+                } else {
+                    write!(f, "P{} Death Roulette: Restored to full!", player)
+                }
+            }
+            BattleLogEvent::ReverseRoles { player1_before, player1_after, player2_before, player2_after } => {
+                write!(
+                    f,
+                    "Reverse Roles: P1 {} -> {}, P2 {} -> {}",
+                    player1_before, player1_after, player2_before, player2_after
+                )
+            }
+            BattleLogEvent::MysteryBox { player, prize } => {
+                let prize = match prize {
+                    0 => "Triple damage!",
+                    1 => "50% reflection!",
+                    2 => "+50 HP!",
+                    _ => "+3 combo!",
+                };
+                write!(f, "Player {} Mystery Box: {}", player, prize)
+            }
+            BattleLogEvent::Healed { player, amount } => write!(f, "Player {} healed for {}", player, amount),
+            BattleLogEvent::LuckySeven { player } => write!(f, "Player {} Lucky Seven: 7x damage!", player),
+            BattleLogEvent::Forfeit { player } => write!(f, "Player {} forfeited (timeout)", player),
+            BattleLogEvent::BattleFinished { winner } => {
+                if winner == 0 {
+                    write!(f, "Battle finished in a draw")
+                } else {
+                    write!(f, "Battle finished! Winner: Player {}", winner)
+                }
+            }
+            BattleLogEvent::VenomStacked { victim, amount } => {
+                write!(f, "Player {} is poisoned ({} per round)", victim, amount)
+            }
+            BattleLogEvent::ItemUsed { player, item, amount } => match item {
+                BattleItem::HealthPotion => write!(f, "Player {} drank a Health Potion (+{} HP)", player, amount),
+                BattleItem::AdrenalineShot => write!(f, "Player {} used an Adrenaline Shot (+1 combo)", player),
+                BattleItem::SmokeBomb => write!(f, "Player {} threw a Smoke Bomb (+{}% dodge)", player, amount),
+            },
+        }
+    }
+}
+
+// Records for the attacks made so far, in order
+pub fn turn_records(battle: &Battle) -> &[TurnRecord] {
+    let len = (battle.turn_number as usize).min(battle.turn_records.len());
+    &battle.turn_records[..len]
+}
+
+// Oldest first, unrolling the ring buffer
+#[cfg(not(target_os = "solana"))]
+pub fn render_battle_log(battle: &Battle) -> Vec<String> {
+    let len = battle.battle_log_len as usize;
+    let split = if len < BATTLE_LOG_CAPACITY { 0 } else { battle.battle_log_next as usize };
+    battle.battle_log[split..len]
+        .iter()
+        .chain(battle.battle_log[..split].iter())
+        .filter_map(|entry| entry.event())
+        .map(|event| event.to_string())
+        .collect()
+}
+
+// All remaining account contexts
+
+#[derive(Accounts)]
+#[instruction(character_class: CharacterClass, name: String)]
+pub struct CreateCharacter<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Character::INIT_SPACE,
+        seeds = [b"character", name.as_bytes(), owner.key().as_ref()],
+        bump
+    )]
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateBattle<'info> {
+    #[account(
+        init,
+        payer = player1_owner,
+        space = 8 + std::mem::size_of::<Battle>(),
+        seeds = [
+            b"battle",
+            player1_character.key().as_ref(),
+            player2_character.key().as_ref(),
+            &player1_character.battle_nonce.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(
+        init,
+        payer = player1_owner,
+        space = 8 + BattleEscrow::INIT_SPACE,
+        seeds = [b"escrow", battle.key().as_ref()],
+        bump
+    )]
+    pub escrow: Account<'info, BattleEscrow>,
+    #[account(
+        mut,
+        constraint = player1_character.owner == player1_owner.key() @ GameError::NotCharacterOwner
+    )]
+    pub player1_character: Account<'info, Character>,
+    // The AI opponent, only the designated AI character can be dragged in
+    #[account(address = config.ai_character @ GameError::NotAiCharacter)]
+    pub player2_character: Account<'info, Character>,
+    #[account(mut)]
+    pub player1_owner: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SendChallenge<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Challenge::INIT_SPACE,
+        seeds = [b"challenge", challenger_character.key().as_ref(), challenged_character.key().as_ref()],
+        bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+    #[account(has_one = owner)]
+    pub challenger_character: Account<'info, Character>,
+    pub challenged_character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptChallenge<'info> {
+    #[account(
+        mut,
+        close = challenger_owner,
+        has_one = challenger_owner,
+        seeds = [b"challenge", challenger_character.key().as_ref(), challenged_character.key().as_ref()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + std::mem::size_of::<Battle>(),
+        seeds = [
+            b"battle",
+            challenger_character.key().as_ref(),
+            challenged_character.key().as_ref(),
+            &challenger_character.battle_nonce.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + BattleEscrow::INIT_SPACE,
+        seeds = [b"escrow", battle.key().as_ref()],
+        bump
+    )]
+    pub escrow: Account<'info, BattleEscrow>,
+    #[account(mut)]
+    pub challenger_character: Account<'info, Character>,
+    #[account(mut, has_one = owner)]
+    pub challenged_character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    /// CHECK: Checked against challenge.challenger_owner, only receives lamports
+    #[account(mut)]
+    pub challenger_owner: AccountInfo<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeclineChallenge<'info> {
+    #[account(
+        mut,
+        close = challenger_owner,
+        has_one = challenger_owner,
+        seeds = [b"challenge", challenge.challenger.as_ref(), challenged_character.key().as_ref()],
+        bump = challenge.bump
+    )]
+    pub challenge: Account<'info, Challenge>,
+    pub challenged_character: Account<'info, Character>,
+    pub signer: Signer<'info>,
+    /// CHECK: Checked against challenge.challenger_owner, only receives lamports
+    #[account(mut)]
+    pub challenger_owner: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTurn<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(mut)]
+    pub attacker_character: Account<'info, Character>,
+    pub defender_character: Account<'info, Character>,
+    pub attacker: Signer<'info>,
+    /// CHECK: SlotHashes sysvar, read raw since it's too large to deserialize
+    #[account(address = sysvar::slot_hashes::ID)]
+    pub recent_slothashes: AccountInfo<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct CreateTeamBattle<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + TeamBattle::INIT_SPACE,
+        seeds = [
+            b"team_battle",
+            side_a_character1.key().as_ref(),
+            side_a_character2.key().as_ref(),
+            side_b_character1.key().as_ref(),
+            side_b_character2.key().as_ref(),
+        ],
+        bump
+    )]
+    pub team_battle: Account<'info, TeamBattle>,
+    #[account(mut, has_one = owner)]
+    pub side_a_character1: Account<'info, Character>,
+    #[account(mut, constraint = side_a_character2.owner == side_a_owner2.key() @ GameError::NotCharacterOwner)]
+    pub side_a_character2: Account<'info, Character>,
+    #[account(mut, constraint = side_b_character1.owner == side_b_owner1.key() @ GameError::NotCharacterOwner)]
+    pub side_b_character1: Account<'info, Character>,
+    #[account(mut, constraint = side_b_character2.owner == side_b_owner2.key() @ GameError::NotCharacterOwner)]
+    pub side_b_character2: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>, // Owns side_a_character1 and pays for the account
+    pub side_a_owner2: Signer<'info>,
+    pub side_b_owner1: Signer<'info>,
+    pub side_b_owner2: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RequestTeamTurn<'info> {
+    #[account(mut)]
+    pub team_battle: Account<'info, TeamBattle>,
+    #[account(has_one = owner)]
+    pub attacker_character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+    /// CHECK: Switchboard randomness account, owner and layout checked on use
+    pub randomness_account: AccountInfo<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct TeamExecuteTurn<'info> {
+    #[account(mut)]
+    pub team_battle: Account<'info, TeamBattle>,
+    /// CHECK: Must be the account bound by request_team_turn
+    #[account(address = team_battle.randomness_account)]
+    pub randomness_account: AccountInfo<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct CheckTeamTimeout<'info> {
+    #[account(mut)]
+    pub team_battle: Account<'info, TeamBattle>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeTeamBattle<'info> {
+    pub team_battle: Account<'info, TeamBattle>,
+    #[account(mut, address = team_battle.characters[0])]
+    pub side_a_character1: Account<'info, Character>,
+    #[account(mut, address = team_battle.characters[1])]
+    pub side_a_character2: Account<'info, Character>,
+    #[account(mut, address = team_battle.characters[2])]
+    pub side_b_character1: Account<'info, Character>,
+    #[account(mut, address = team_battle.characters[3])]
+    pub side_b_character2: Account<'info, Character>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeBattle<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    // Closing the escrow also makes finalize a one-shot per battle
+    #[account(
+        mut,
+        seeds = [b"escrow", battle.key().as_ref()],
+        bump = escrow.bump,
+        close = player1_owner
+    )]
+    pub escrow: Account<'info, BattleEscrow>,
+    #[account(mut, address = battle.load()?.player1)]
+    pub player1_character: Account<'info, Character>,
+    #[account(mut, address = battle.load()?.player2)]
+    pub player2_character: Account<'info, Character>,
+    /// CHECK: Owner for stake transfer, also gets the escrow rent back
+    #[account(mut, address = player1_character.owner)]
+    pub player1_owner: AccountInfo<'info>,
+    /// CHECK: Owner for stake transfer
+    #[account(mut, address = player2_character.owner)]
+    pub player2_owner: AccountInfo<'info>,
+    #[account(mut, seeds = [b"leaderboard"], bump)]
+    pub leaderboard: Account<'info, Leaderboard>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    // Pass the winner's guild to credit it with a ranked win
+    #[account(mut)]
+    pub winner_guild: Option<Account<'info, Guild>>,
+    // Pass the battle's betting pool to settle it in the same transaction
+    #[account(mut, has_one = battle)]
+    pub betting_pool: Option<Account<'info, BettingPool>>,
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+pub struct DecayRank<'info> {
+    #[account(mut)]
+    pub character: Account<'info, Character>,
+    #[account(mut, seeds = [b"leaderboard"], bump)]
+    pub leaderboard: Account<'info, Leaderboard>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32)]
+pub struct StartSeason<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Season::INIT_SPACE,
+        seeds = [b"season", index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub season: Account<'info, Season>,
+    #[account(mut, seeds = [b"config"], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, GameConfig>,
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct EndSeason<'info> {
+    #[account(mut, seeds = [b"season", season.index.to_le_bytes().as_ref()], bump = season.bump)]
+    pub season: Account<'info, Season>,
+    #[account(mut, seeds = [b"config"], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, GameConfig>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimSeasonReward<'info> {
+    #[account(mut, seeds = [b"season", season.index.to_le_bytes().as_ref()], bump = season.bump)]
+    pub season: Account<'info, Season>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + SeasonClaim::INIT_SPACE,
+        seeds = [b"season_claim", season.key().as_ref(), character.key().as_ref()],
+        bump
+    )]
+    pub season_claim: Account<'info, SeasonClaim>,
+    #[account(has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SweepSeasonRewards<'info> {
+    #[account(mut, seeds = [b"season", season.index.to_le_bytes().as_ref()], bump = season.bump)]
+    pub season: Account<'info, Season>,
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + GameConfig::INIT_SPACE,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, GameConfig>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Treasury::INIT_SPACE,
+        seeds = [b"treasury"],
+        bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    // Stops anyone from front-running the deploy and claiming the config
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::MyProgram>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(admin.key()) @ GameError::NotUpgradeAuthority)]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawTreasury<'info> {
+    #[account(seeds = [b"config"], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, GameConfig>,
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    pub admin: Signer<'info>,
+    /// CHECK: Any account the admin sends the funds to
+    #[account(mut)]
+    pub recipient: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut, seeds = [b"config"], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, GameConfig>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    #[account(mut, seeds = [b"config"], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, GameConfig>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAiCharacter<'info> {
+    #[account(mut, seeds = [b"config"], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, GameConfig>,
+    #[account(constraint = ai_character.owner == admin.key() @ GameError::NotCharacterOwner)]
+    pub ai_character: Account<'info, Character>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeLeaderboard<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Leaderboard::INIT_SPACE,
+        seeds = [b"leaderboard"],
+        bump
+    )]
+    pub leaderboard: Account<'info, Leaderboard>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(house_edge: Option<u8>, odds_mode: OddsMode, limits: Option<BetLimits>, market: BetMarket)]
+pub struct CreateBettingPool<'info> {
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + BettingPool::INIT_SPACE,
+        seeds = [b"betting_pool", battle.key().as_ref(), &market.seed()],
+        bump
+    )]
+    pub betting_pool: Account<'info, BettingPool>,
+    pub battle: AccountLoader<'info, Battle>,
+    pub player1_character: Account<'info, Character>,
+    pub player2_character: Account<'info, Character>,
+    #[account(mut)]
+    pub creator: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, bet_on_player: u8, bet_index: u32)]
+pub struct PlaceBet<'info> {
+    #[account(
+        init,
+        payer = bettor,
+        space = 8 + Bet::INIT_SPACE,
+        seeds = [b"bet", betting_pool.key().as_ref(), bettor.key().as_ref(), &bet_index.to_le_bytes()],
+        bump
+    )]
+    pub bet: Account<'info, Bet>,
+    #[account(mut, has_one = battle)]
+    pub betting_pool: Account<'info, BettingPool>,
+    #[account(
+        mut,
+        seeds = [b"bet_tally", betting_pool.key().as_ref(), bettor.key().as_ref()],
+        bump = bet_tally.bump
+    )]
+    pub bet_tally: Account<'info, BetTally>,
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(mut)]
+    pub bettor: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseBetTally<'info> {
+    #[account(mut, close = bettor, has_one = bettor, has_one = betting_pool)]
+    pub bet_tally: Account<'info, BetTally>,
+    /// CHECK: The tally's pool, deserialized only while it hasn't been closed
+    pub betting_pool: AccountInfo<'info>,
+    #[account(mut)]
+    pub bettor: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct OpenBetTally<'info> {
+    #[account(
+        init,
+        payer = bettor,
+        space = 8 + BetTally::INIT_SPACE,
+        seeds = [b"bet_tally", betting_pool.key().as_ref(), bettor.key().as_ref()],
+        bump
+    )]
+    pub bet_tally: Account<'info, BetTally>,
+    pub betting_pool: Account<'info, BettingPool>,
+    #[account(mut)]
+    pub bettor: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AnnounceBettingClosed<'info> {
+    #[account(mut, has_one = battle)]
+    pub betting_pool: Account<'info, BettingPool>,
+    pub battle: AccountLoader<'info, Battle>,
+}
+
+#[derive(Accounts)]
+pub struct CancelBet<'info> {
+    #[account(mut, close = bettor, has_one = betting_pool, has_one = bettor)]
+    pub bet: Account<'info, Bet>,
+    #[account(mut, has_one = battle)]
+    pub betting_pool: Account<'info, BettingPool>,
+    #[account(
+        mut,
+        seeds = [b"bet_tally", betting_pool.key().as_ref(), bettor.key().as_ref()],
+        bump = bet_tally.bump
+    )]
+    pub bet_tally: Account<'info, BetTally>,
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(mut)]
+    pub bettor: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SettleBettingPool<'info> {
+    #[account(mut, has_one = battle)]
+    pub betting_pool: Account<'info, BettingPool>,
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+pub struct VoidBettingPool<'info> {
+    #[account(mut, has_one = battle)]
+    pub betting_pool: Account<'info, BettingPool>,
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct CollectHouseEdge<'info> {
+    #[account(mut, close = treasury)]
+    pub betting_pool: Account<'info, BettingPool>,
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimBetWinnings<'info> {
+    #[account(mut)]
+    pub betting_pool: Account<'info, BettingPool>,
+    #[account(mut, has_one = betting_pool)]
+    pub bet: Account<'info, Bet>,
+    #[account(mut)]
+    pub bettor: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimBetRefund<'info> {
+    #[account(mut)]
+    pub betting_pool: Account<'info, BettingPool>,
+    #[account(mut, has_one = betting_pool, close = bettor)]
+    pub bet: Account<'info, Bet>,
+    #[account(mut)]
+    pub bettor: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct HealCharacter<'info> {
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    // Game treasury for heal payments
+    #[account(mut, seeds = [b"treasury"], bump = game_treasury.bump)]
+    pub game_treasury: Account<'info, Treasury>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MintEquipment<'info> {
+    #[account(init, payer = admin, space = 8 + Equipment::INIT_SPACE)]
+    pub equipment: Account<'info, Equipment>,
+    #[account(seeds = [b"config"], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, GameConfig>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct EquipItem<'info> {
+    #[account(mut, has_one = owner)]
+    pub equipment: Account<'info, Equipment>,
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UnequipItem<'info> {
+    #[account(mut, has_one = owner)]
+    pub equipment: Account<'info, Equipment>,
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimDailyReward<'info> {
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimAchievementReward<'info> {
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateItemInventory<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + ItemInventory::INIT_SPACE,
+        seeds = [b"inventory", character.key().as_ref()],
+        bump
+    )]
+    pub inventory: Account<'info, ItemInventory>,
+    #[account(has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct BuyItem<'info> {
+    #[account(mut, has_one = character, seeds = [b"inventory", character.key().as_ref()], bump = inventory.bump)]
+    pub inventory: Account<'info, ItemInventory>,
+    #[account(has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut, seeds = [b"treasury"], bump = game_treasury.bump)]
+    pub game_treasury: Account<'info, Treasury>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UseItem<'info> {
+    #[account(mut)]
+    pub battle: AccountLoader<'info, Battle>,
+    #[account(mut, has_one = character, seeds = [b"inventory", character.key().as_ref()], bump = inventory.bump)]
+    pub inventory: Account<'info, ItemInventory>,
+    #[account(has_one = owner)]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct TransferCharacter<'info> {
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    /// CHECK: The character's queue PDA, must not exist
+    #[account(seeds = [b"queue", character.key().as_ref()], bump)]
+    pub queue_entry: AccountInfo<'info>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MintCharacterNft<'info> {
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(
+        init,
+        payer = owner,
+        seeds = [b"character_mint", character.key().as_ref()],
+        bump,
+        mint::decimals = 0,
+        mint::authority = mint,
+        mint::freeze_authority = mint,
+    )]
+    pub mint: Account<'info, Mint>,
+    #[account(
+        init,
+        payer = owner,
+        associated_token::mint = mint,
+        associated_token::authority = owner,
+    )]
+    pub token_account: Account<'info, TokenAccount>,
+    /// CHECK: Created by the token metadata program
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), mint.key().as_ref()],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata: AccountInfo<'info>,
+    /// CHECK: Created by the token metadata program
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), mint.key().as_ref(), b"edition"],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub master_edition: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct SyncCharacterOwner<'info> {
+    #[account(mut)]
+    pub character: Account<'info, Character>,
+    pub token_account: Account<'info, TokenAccount>,
+    /// CHECK: The character's queue PDA, must not exist
+    #[account(seeds = [b"queue", character.key().as_ref()], bump)]
+    pub queue_entry: AccountInfo<'info>,
+    pub holder: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateCharacterMetadata<'info> {
+    #[account(mut)]
+    pub character: Account<'info, Character>,
+    #[account(seeds = [b"character_mint", character.key().as_ref()], bump)]
+    pub mint: Account<'info, Mint>,
+    /// CHECK: The mint's metadata account, verified by the token metadata program
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), mint.key().as_ref()],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata: AccountInfo<'info>,
+    pub token_metadata_program: Program<'info, Metadata>,
+}
+
+#[derive(Accounts)]
+pub struct CloseCharacter<'info> {
+    #[account(mut, has_one = owner, close = owner)]
+    pub character: Account<'info, Character>,
+    /// CHECK: The character's queue PDA, must not exist
+    #[account(seeds = [b"queue", character.key().as_ref()], bump)]
+    pub queue_entry: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetMetadataUri<'info> {
+    #[account(mut)]
+    pub character: Account<'info, Character>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub authority: Signer<'info>, // Character owner or config admin
+}
+
+#[derive(Accounts)]
+pub struct RenameCharacter<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        seeds = [b"character", character.seed_name.as_bytes(), character.seed_owner.as_ref()],
+        bump
+    )]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RespecCharacter<'info> {
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    /// CHECK: The character's queue PDA, must not exist
+    #[account(seeds = [b"queue", character.key().as_ref()], bump)]
+    pub queue_entry: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut, seeds = [b"treasury"], bump = game_treasury.bump)]
+    pub game_treasury: Account<'info, Treasury>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PrestigeCharacter<'info> {
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    pub owner: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+}
+
+#[derive(Accounts)]
+pub struct ReviveCharacter<'info> {
+    #[account(mut, has_one = owner)]
+    pub character: Account<'info, Character>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut, seeds = [b"treasury"], bump = game_treasury.bump)]
+    pub game_treasury: Account<'info, Treasury>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, GameConfig>,
+    pub system_program: Program<'info, System>,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_settings() -> GameSettings {
+        GameSettings {
+            turn_timeout_seconds: TURN_TIMEOUT_SECONDS,
+            battle_expiry_seconds: BATTLE_EXPIRY_SECONDS,
+            wildcard_decision_timeout: WILDCARD_DECISION_TIMEOUT,
+            min_battle_interval_seconds: MIN_BATTLE_INTERVAL_SECONDS,
+            challenge_window_seconds: CHALLENGE_WINDOW_SECONDS,
+            revive_downtime_seconds: REVIVE_DOWNTIME_SECONDS,
+            base_wildcard_chance: BASE_WILDCARD_CHANCE,
+            trickster_wildcard_chance: TRICKSTER_WILDCARD_CHANCE,
+            heal_price_per_hp: HEAL_PRICE_PER_HP_LAMPORTS,
+            revive_cost: REVIVE_COST_LAMPORTS,
+            respec_cost: RESPEC_COST_LAMPORTS,
+            default_house_edge: DEFAULT_HOUSE_EDGE,
+            mmr_k_ranked: MMR_K_RANKED,
+            mmr_k_provisional: MMR_K_PROVISIONAL,
+            mmr_k_casual: MMR_K_CASUAL,
+            max_mmr_gap: MAX_MMR_GAP,
+            xp_multiplier: XP_MULTIPLIER_BPS,
+            loser_xp_percent: LOSER_XP_PERCENT,
+            special_cooldowns: SPECIAL_COOLDOWNS,
+            class_stats: CLASS_BASE_STATS,
+            bet_limits: BetLimits {
+                min_bet: MIN_BET_LAMPORTS,
+                max_bet_per_wallet: MAX_BET_PER_WALLET_LAMPORTS,
+                max_pool_size: MAX_POOL_SIZE_LAMPORTS,
+            },
+            instant_kill_enabled: INSTANT_KILL_ENABLED,
+            queue_timeout_seconds: QUEUE_TIMEOUT_SECONDS,
+            betting_closes_at_turn: BETTING_CLOSES_AT_TURN,
+            bet_claim_window_seconds: BET_CLAIM_WINDOW_SECONDS,
+            counter_keeps_combo: COUNTER_KEEPS_COMBO,
+            pve_timeout_refunds_stake: PVE_TIMEOUT_REFUNDS_STAKE,
+            abandon_threshold: ABANDON_THRESHOLD,
+            abandon_window_seconds: ABANDON_WINDOW_SECONDS,
+            abandon_ban_seconds: ABANDON_BAN_SECONDS,
+            reveal_timeout_seconds: REVEAL_TIMEOUT_SECONDS,
+            max_battle_turns: MAX_BATTLE_TURNS,
+            season_claim_window_seconds: SEASON_CLAIM_WINDOW_SECONDS,
+        }
+    }
+
+    fn test_character(class: CharacterClass) -> Character {
+        let mut character = Character {
+            owner: Pubkey::new_unique(),
+            character_class: class,
+            name: "Tester".to_string(),
+            seed_name: "Tester".to_string(),
+            seed_owner: Pubkey::default(),
+            level: 1,
+            xp: 0,
+            max_hp: 0,
+            current_hp: 0,
+            base_damage_min: 0,
+            base_damage_max: 0,
+            crit_chance: 0,
+            dodge_chance: 0,
+            defense: 0,
+            total_wins: 0,
+            total_losses: 0,
+            max_combo: 0,
+            ranked_mmr: 1000,
+            created_at: 0,
+            last_battle: 0,
+            last_decay: 0,
+            rank_tier: RankTier::Bronze,
+            season_wins: 0,
+            season_losses: 0,
+            achievements: vec![],
+            metadata_uri: String::new(),
+            metadata_version: 0,
+            guild: None,
+            in_battle: None,
+            tournament: None,
+            death_count: 0,
+            prestige: 0,
+            last_daily_claim: 0,
+            daily_streak: 0,
+            equipment: [None; 3],
+            last_respec: 0,
+            nft_mint: None,
+            metadata_dirty: false,
+            claimed_achievement_rewards: 0,
+            title: String::new(),
+            free_heals: 0,
+            cosmetic_flags: 0,
+            casual_mmr: 1000,
+            current_streak: 0,
+            best_streak: 0,
+            abandons: 0,
+            abandon_window_start: 0,
+            ban_until: 0,
+            battle_nonce: 0,
+            season_index: 0,
+        };
+        set_class_base_stats(&mut character, &default_settings());
+        character
+    }
+
+    fn account_data<T: AccountSerialize>(account: &T) -> Vec<u8> {
+        let mut data = Vec::new();
+        account.try_serialize(&mut data).unwrap();
+        data
+    }
+
+    // Runs f on the character wrapped in an Account, for the helpers that
+    // take one to emit events with its key
+    fn on_account<R>(character: &mut Character, f: impl FnOnce(&mut Account<Character>) -> R) -> R {
+        let key = Pubkey::new_unique();
+        let mut data = account_data(character);
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+        let mut account = Account::<Character>::try_from(&info).unwrap();
+        let result = f(&mut account);
+        *character = (*account).clone();
+        result
+    }
+
+    // An open, empty pool on a battle that hasn't started
+    fn test_pool(odds_mode: OddsMode, market: BetMarket) -> BettingPool {
+        let limits = default_settings().bet_limits;
+        BettingPool {
+            battle: Pubkey::new_unique(),
+            total_pool: 0,
+            player1_bets: 0,
+            player2_bets: 0,
+            player1_odds: 50,
+            player2_odds: 50,
+            house_edge: DEFAULT_HOUSE_EDGE,
+            odds_mode,
+            player1_liability: 0,
+            player2_liability: 0,
+            is_settled: false,
+            winner: None,
+            created_at: 0,
+            total_claimed: 0,
+            claimed_winning_bets: 0,
+            refund_mode: false,
+            min_bet: limits.min_bet,
+            max_bet_per_wallet: limits.max_bet_per_wallet,
+            max_pool_size: limits.max_pool_size,
+            max_side_total: MAX_SIDE_BETS_LAMPORTS,
+            max_exposure: MAX_FIXED_ODDS_EXPOSURE_LAMPORTS,
+            betting_closes_at_turn: BETTING_CLOSES_AT_TURN,
+            betting_closed_announced: false,
+            settled_at: 0,
+            market,
+        }
+    }
+
+    // Runs settle_pool on a pool funded with its total_pool, returns the
+    // pool afterwards and the lamports that went to the treasury
+    fn settle(pool: BettingPool, battle: &Battle) -> Result<(BettingPool, u64)> {
+        settle_with(pool, battle, settle_pool)
+    }
+
+    // Like settle, with finalize_battle's inline settlement or the manual one
+    fn settle_with(
+        pool: BettingPool,
+        battle: &Battle,
+        settle_fn: for<'a> fn(&mut Account<'a, BettingPool>, &Battle, &mut Account<'a, Treasury>, &Clock) -> Result<()>,
+    ) -> Result<(BettingPool, u64)> {
+        let rent = Rent::default();
+        let owner = crate::ID;
+
+        let pool_key = Pubkey::new_unique();
+        let mut pool_data = account_data(&pool);
+        let mut pool_lamports = rent.minimum_balance(pool_data.len()) + pool.total_pool;
+        let pool_info = AccountInfo::new(&pool_key, false, true, &mut pool_lamports, &mut pool_data, &owner, false, 0);
+
+        let treasury_key = Pubkey::new_unique();
+        let mut treasury_data = account_data(&Treasury { bump: 255, lifetime_fees_collected: 0 });
+        let mut treasury_lamports = 0;
+        let treasury_info = AccountInfo::new(
+            &treasury_key, false, true, &mut treasury_lamports, &mut treasury_data, &owner, false, 0,
+        );
+
+        let mut pool = Account::<BettingPool>::try_from(&pool_info).unwrap();
+        let mut treasury = Account::<Treasury>::try_from(&treasury_info).unwrap();
+        let clock = Clock { unix_timestamp: 5_000, ..Clock::default() };
+        settle_fn(&mut pool, battle, &mut treasury, &clock)?;
+
+        Ok(((*pool).clone(), treasury_info.lamports()))
+    }
+
+    // Runs pay_ai_win_stake on a funded escrow and returns the escrow,
+    // treasury and player balances afterwards plus the treasury's fee count
+    fn pay_ai_win(refunds_stake: bool, stake: u64) -> (u64, u64, u64, u64) {
+        let rent = Rent::default();
+        let owner = crate::ID;
+        let system = Pubkey::default();
+
+        let escrow_key = Pubkey::new_unique();
+        let mut escrow_data = account_data(&BattleEscrow { battle: Pubkey::new_unique(), bump: 255 });
+        let escrow_rent = rent.minimum_balance(escrow_data.len());
+        let mut escrow_lamports = escrow_rent + stake;
+        let escrow_info = AccountInfo::new(
+            &escrow_key, false, true, &mut escrow_lamports, &mut escrow_data, &owner, false, 0,
+        );
+
+        let treasury_key = Pubkey::new_unique();
+        let mut treasury_data = account_data(&Treasury { bump: 255, lifetime_fees_collected: 0 });
+        let mut treasury_lamports = rent.minimum_balance(treasury_data.len());
+        let treasury_info = AccountInfo::new(
+            &treasury_key, false, true, &mut treasury_lamports, &mut treasury_data, &owner, false, 0,
+        );
+
+        let player_key = Pubkey::new_unique();
+        let mut player_data = vec![];
+        let mut player_lamports = 0;
+        let player_info = AccountInfo::new(
+            &player_key, true, true, &mut player_lamports, &mut player_data, &system, false, 0,
+        );
+
+        let escrow = Account::<BattleEscrow>::try_from(&escrow_info).unwrap();
+        let mut treasury = Account::<Treasury>::try_from(&treasury_info).unwrap();
+        let settings = GameSettings { pve_timeout_refunds_stake: refunds_stake, ..default_settings() };
+
+        pay_ai_win_stake(&escrow, &mut treasury, &player_info, &settings, stake).unwrap();
+
+        (
+            escrow_info.lamports() - escrow_rent,
+            treasury_info.lamports() - rent.minimum_balance(treasury_info.data_len()),
+            player_info.lamports(),
+            treasury.lifetime_fees_collected,
+        )
+    }
+
+    #[test]
+    fn rematch_starts_like_a_new_battle() {
+        let owner = crate::ID;
+        let (key1, key2) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut data1 = account_data(&test_character(CharacterClass::Warrior));
+        let mut data2 = account_data(&test_character(CharacterClass::Assassin));
+        let (mut lamports1, mut lamports2) = (0, 0);
+        let info1 = AccountInfo::new(&key1, false, false, &mut lamports1, &mut data1, &owner, false, 0);
+        let info2 = AccountInfo::new(&key2, false, false, &mut lamports2, &mut data2, &owner, false, 0);
+        let player1 = Account::<Character>::try_from(&info1).unwrap();
+        let player2 = Account::<Character>::try_from(&info2).unwrap();
+        let clock = Clock { unix_timestamp: 1_000, ..Clock::default() };
+
+        let mut fresh: Battle = bytemuck::Zeroable::zeroed();
+        init_battle(&mut fresh, &player1, &player2, MatchType::Ranked, 0, false, &clock);
+
+        // Everything a finished battle can leave behind
+        let mut used: Battle = bytemuck::Zeroable::zeroed();
+        init_battle(&mut used, &player1, &player2, MatchType::Ranked, 0, false, &clock);
+        used.turn_number = 12;
+        used.is_finished = 1;
+        used.winner = 2;
+        used.player1_hp = 0;
+        used.player1_dot_damage = 6;
+        used.player1_dot_turns = 2;
+        used.player2_reflection = 25;
+        used.player1_special_cooldown = 3;
+        used.player2_special_cooldown = 1;
+        used.player1_combo = 4;
+        used.player2_peak_combo = 7;
+        used.player1_miss_count = 2;
+        used.player2_pending_miss = 1;
+        used.player1_items_used = 3;
+        used.player2_item_this_turn = BattleItem::SmokeBomb.code();
+        used.player1_smoke_dodge = SMOKE_BOMB_DODGE;
+        used.wildcard_active = 1;
+        used.wildcard_type = WildcardEvent::DeathRoulette.code();
+        used.wildcard_player1_decision = WILDCARD_ACCEPTED;
+        used.random_seed = [7; 32];
+        used.seed_consumed = 0;
+        used.battle_log_len = 5;
+        used.battle_log_next = 5;
+        used.log_offset = 5;
+        used.first_blood = 2;
+        used.turn_records[0].damage = 40;
+
+        init_battle(&mut used, &player1, &player2, MatchType::Ranked, 0, false, &clock);
+        assert_eq!(bytemuck::bytes_of(&used), bytemuck::bytes_of(&fresh));
+    }
+
+    #[test]
+    fn reverse_roles_swaps_hp_percentages() {
+        let mut battle: Battle = bytemuck::Zeroable::zeroed();
+        battle.player1_max_hp = 100;
+        battle.player2_max_hp = 100;
+        battle.player1_hp = 100;
+        battle.player2_hp = 80;
+        battle.wildcard_type = WildcardEvent::ReverseRoles.code();
+
+        apply_wildcard_effects(0, &mut battle, Pubkey::default(), true).unwrap();
+        assert_eq!((battle.player1_hp, battle.player2_hp), (80, 100));
+
+        // 80% of 150 against 100% of 80, each side keeps its own max
+        battle.player1_max_hp = 150;
+        battle.player2_max_hp = 80;
+        battle.player1_hp = 80 * 150 / 100;
+        battle.player2_hp = 80;
+        apply_wildcard_effects(0, &mut battle, Pubkey::default(), true).unwrap();
+        assert_eq!((battle.player1_hp, battle.player2_hp), (150, 64));
+    }
+
+    #[test]
+    fn swap_hp_fraction_never_kills() {
+        assert_eq!(swap_hp_fraction(100, 100, 80), 80);
+        assert_eq!(swap_hp_fraction(80, 100, 150), 120);
+        assert_eq!(swap_hp_fraction(1, 1000, 80), 1);
+        assert_eq!(swap_hp_fraction(0, 100, 80), 0);
+    }
+
+    #[test]
+    fn ai_win_sends_the_stake_to_the_treasury() {
+        // Only the escrow's rent is left for finalize_battle's close
+        let (escrow_left, treasury_gain, player_gain, fees) = pay_ai_win(false, 1_000_000);
+        assert_eq!(escrow_left, 0);
+        assert_eq!(treasury_gain, 1_000_000);
+        assert_eq!(player_gain, 0);
+        assert_eq!(fees, 1_000_000);
+    }
+
+    #[test]
+    fn ai_win_refunds_the_stake_when_configured() {
+        let (escrow_left, treasury_gain, player_gain, fees) = pay_ai_win(true, 1_000_000);
+        assert_eq!(escrow_left, 0);
+        assert_eq!(treasury_gain, 0);
+        assert_eq!(player_gain, 1_000_000);
+        assert_eq!(fees, 0);
+    }
+
+    #[test]
+    fn mmr_delta_follows_elo() {
+        // Even match: half of K either way
+        assert_eq!(compute_mmr_delta(1000, 1000, 32), 16);
+        // Halfway between table steps is interpolated, 25 points up expects 535
+        assert_eq!(compute_mmr_delta(1025, 1000, 32), 15);
+        assert_eq!(compute_mmr_delta(1000, 1025, 32), 17);
+        // Leads past 800 are capped, a sure win still moves at least 1
+        assert_eq!(compute_mmr_delta(2000, 1000, 32), 1);
+        assert_eq!(compute_mmr_delta(1000, 2000, 32), 32);
+        assert_eq!(compute_mmr_delta(1000, 1000, 0), 0);
+    }
+
+    #[test]
+    fn mmr_delta_is_zero_sum_at_equal_k() {
+        for lead in (0..=900).step_by(25) {
+            let favorite_win = compute_mmr_delta(1000 + lead, 1000, 64);
+            let upset = compute_mmr_delta(1000, 1000 + lead, 64);
+            assert!((63..=65).contains(&(favorite_win + upset)), "lead {}", lead);
+        }
+    }
+
+    #[test]
+    fn streak_mmr_bonus_escalates() {
+        assert_eq!(streak_mmr_bonus(STREAK_MMR_BONUS_START - 1), 0);
+        assert_eq!(streak_mmr_bonus(STREAK_MMR_BONUS_START), STREAK_MMR_BONUS);
+        assert_eq!(streak_mmr_bonus(STREAK_MASTER_THRESHOLD - 1), STREAK_MMR_BONUS);
+        assert_eq!(streak_mmr_bonus(STREAK_MASTER_THRESHOLD), STREAK_MASTER_MMR_BONUS);
+        assert_eq!(streak_mmr_bonus(u32::MAX), STREAK_MASTER_MMR_BONUS);
+    }
+
+    #[test]
+    fn fixed_odds_payout_returns_the_quoted_multiple() {
+        assert_eq!(fixed_odds_payout(1_000, 0), 1_000);
+        assert_eq!(fixed_odds_payout(1_000, 50), 2_000);
+        assert_eq!(fixed_odds_payout(3, 75), 12);
+        assert_eq!(fixed_odds_payout(1_000, 99), 100_000);
+        // Odds are capped below 100 so the divisor never reaches zero
+        assert_eq!(fixed_odds_payout(1_000, 100), 100_000);
+        assert_eq!(fixed_odds_payout(1_000, u64::MAX), 100_000);
+    }
+
+    #[test]
+    fn bet_markets_resolve_from_the_finished_battle() {
+        let mut battle: Battle = bytemuck::Zeroable::zeroed();
+        battle.turn_number = 20;
+        battle.wildcards_triggered = 0;
+
+        // Draws and battles without damage push
+        assert_eq!(BetMarket::Winner.resolve(&battle), None);
+        assert_eq!(BetMarket::FirstBlood.resolve(&battle), None);
+        assert_eq!(BetMarket::Wildcard.resolve(&battle), Some(2));
+
+        battle.winner = 2;
+        battle.first_blood = 1;
+        battle.wildcards_triggered = 3;
+        assert_eq!(BetMarket::Winner.resolve(&battle), Some(2));
+        assert_eq!(BetMarket::FirstBlood.resolve(&battle), Some(1));
+        assert_eq!(BetMarket::Wildcard.resolve(&battle), Some(1));
+
+        assert_eq!(BetMarket::TotalTurns { line: 19 }.resolve(&battle), Some(1));
+        assert_eq!(BetMarket::TotalTurns { line: 20 }.resolve(&battle), None);
+        assert_eq!(BetMarket::TotalTurns { line: 21 }.resolve(&battle), Some(2));
+    }
+
+    #[test]
+    fn battle_log_entries_round_trip() {
+        let events = [
+            BattleLogEvent::DamageDealt { by: 1, amount: 123, crit: true },
+            BattleLogEvent::Dodged { by: 2 },
+            BattleLogEvent::SelfDamage { player: 1, amount: 7 },
+            BattleLogEvent::Reflected { player: 2, amount: 40 },
+            BattleLogEvent::Riposte { player: 1, amount: 15 },
+            BattleLogEvent::DotTick { victim: 2, amount: u16::MAX },
+            BattleLogEvent::PendingMissConsumed { player: 1 },
+            BattleLogEvent::WildcardBoost { player: 2 },
+            BattleLogEvent::WildcardTriggered { kind: WildcardEvent::DoubleOrNothing, needs_decision: true },
+            BattleLogEvent::WildcardTriggered { kind: WildcardEvent::GamblersFallacy, needs_decision: false },
+            BattleLogEvent::WildcardDecisionsLocked,
+            BattleLogEvent::WildcardAutoDeclined { player: 2 },
+            BattleLogEvent::DoubleOrNothing { player: 1, won: true },
+            BattleLogEvent::DeathRoulette { player: 2, nearly_killed: true, both_accepted: false },
+            BattleLogEvent::DeathRoulette { player: 1, nearly_killed: false, both_accepted: true },
+            BattleLogEvent::ReverseRoles { player1_before: 100, player1_after: 80, player2_before: 120, player2_after: 150 },
+            BattleLogEvent::MysteryBox { player: 1, prize: 3 },
+            BattleLogEvent::Healed { player: 2, amount: 25 },
+            BattleLogEvent::LuckySeven { player: 1 },
+            BattleLogEvent::Forfeit { player: 2 },
+            BattleLogEvent::BattleFinished { winner: 0 },
+            BattleLogEvent::VenomStacked { victim: 1, amount: 12 },
+            BattleLogEvent::ItemUsed { player: 2, item: BattleItem::SmokeBomb, amount: u32::MAX },
+        ];
+        for event in events {
+            assert_eq!(BattleLogEntry::from(event).event(), Some(event));
+        }
+
+        assert_eq!(BattleLogEntry::default().event(), None);
+    }
+
+    #[test]
+    fn gen_range_stays_in_bounds_and_advances() {
+        let seed = [7u8; 32];
+        let mut counter = 0;
+        let mut seen = [false; 6];
+        for _ in 0..200 {
+            let before = counter;
+            let value = gen_range(&seed, &mut counter, 10, 15);
+            assert!((10..=15).contains(&value));
+            assert!(counter > before);
+            seen[(value - 10) as usize] = true;
+        }
+        assert!(seen.iter().all(|hit| *hit));
+
+        // Same seed and counter give the same roll
+        let (mut first, mut second) = (42, 42);
+        assert_eq!(gen_range(&seed, &mut first, 0, u64::MAX - 1), gen_range(&seed, &mut second, 0, u64::MAX - 1));
+        assert_eq!(first, second);
+
+        let mut counter = 0;
+        assert_eq!(gen_range(&seed, &mut counter, 5, 5), 5);
+        assert_eq!(counter, 1);
+    }
+
+    #[test]
+    fn turn_limit_decides_on_hp_percentage() {
+        let at_turn_limit = |player1_hp: u64, player1_max_hp: u64, player2_hp: u64, player2_max_hp: u64| {
+            let mut battle: Battle = bytemuck::Zeroable::zeroed();
+            battle.turn_number = 20;
+            battle.player1_hp = player1_hp;
+            battle.player1_max_hp = player1_max_hp;
+            battle.player2_hp = player2_hp;
+            battle.player2_max_hp = player2_max_hp;
+            assert!(resolve_battle_end(&mut battle, Pubkey::default(), 20));
+            assert_eq!(battle.is_finished, 1);
+            assert_eq!(battle.is_draw, (battle.winner == 0) as u8);
+            battle
+        };
+
+        // More raw HP loses to a higher share of max HP
+        assert_eq!(at_turn_limit(60, 200, 40, 100).winner, 2);
+        assert_eq!(at_turn_limit(90, 100, 150, 200).winner, 1);
+        assert_eq!(at_turn_limit(50, 100, 100, 200).winner, 0);
+        // 50/101 and 49/99 both round to 49%, cross-multiplying still tells them apart
+        assert_eq!(at_turn_limit(50, 101, 49, 99).winner, 1);
+
+        let battle = at_turn_limit(50, 100, 100, 200);
+        assert_eq!(battle.battle_log[0].event(), Some(BattleLogEvent::BattleFinished { winner: 0 }));
+    }
+
+    #[test]
+    fn battles_below_the_turn_limit_keep_going() {
+        let mut battle: Battle = bytemuck::Zeroable::zeroed();
+        battle.turn_number = MAX_BATTLE_TURNS - 1;
+        battle.player1_hp = 10;
+        battle.player1_max_hp = 100;
+        battle.player2_hp = 90;
+        battle.player2_max_hp = 100;
+        assert!(!resolve_battle_end(&mut battle, Pubkey::default(), MAX_BATTLE_TURNS));
+        assert_eq!(battle.is_finished, 0);
+        assert_eq!(battle.winner, 0);
+    }
+
+    #[test]
+    fn evasion_raises_the_next_incoming_dodge() {
+        let mut battle: Battle = bytemuck::Zeroable::zeroed();
+        apply_special_effect(&mut battle, Pubkey::default(), true, SpecialEffect::Evasion);
+        assert_eq!(battle.player1_smoke_dodge, EVASION_DODGE);
+        assert_eq!(battle.player2_smoke_dodge, 0);
+
+        // A stronger smoke bomb already in place is not weakened
+        battle.player2_smoke_dodge = 90;
+        apply_special_effect(&mut battle, Pubkey::default(), false, SpecialEffect::Evasion);
+        assert_eq!(battle.player2_smoke_dodge, 90);
+    }
+
+    #[test]
+    fn tournament_release_leaves_other_tournaments_alone() {
+        let old = Pubkey::new_unique();
+        let current = Pubkey::new_unique();
+        let mut character = test_character(CharacterClass::Warrior);
+
+        character.tournament = Some(current);
+        release_from_tournament(&mut character, &old);
+        assert_eq!(character.tournament, Some(current));
+
+        release_from_tournament(&mut character, &current);
+        assert_eq!(character.tournament, None);
+    }
+
+    #[test]
+    fn repeated_abandons_ban_until_the_cooldown_ends() {
+        let settings = default_settings();
+        let key = Pubkey::new_unique();
+        let mut data = account_data(&test_character(CharacterClass::Warrior));
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+        let mut character = Account::<Character>::try_from(&info).unwrap();
+        let clock = Clock { unix_timestamp: 10_000, ..Clock::default() };
+
+        for _ in 1..settings.abandon_threshold {
+            record_abandon(&mut character, &settings, &clock);
+            assert!(check_not_banned(&character, &clock).is_ok());
+        }
+        record_abandon(&mut character, &settings, &clock);
+        assert!(check_not_banned(&character, &clock).is_err());
+
+        let still_banned = Clock { unix_timestamp: clock.unix_timestamp + settings.abandon_ban_seconds - 1, ..Clock::default() };
+        assert!(check_not_banned(&character, &still_banned).is_err());
+        let lifted = Clock { unix_timestamp: clock.unix_timestamp + settings.abandon_ban_seconds, ..Clock::default() };
+        assert!(check_not_banned(&character, &lifted).is_ok());
+    }
+
+    #[test]
+    fn an_emptied_guild_passes_to_the_next_joiner() {
+        let founder = Pubkey::new_unique();
+        let newcomer = Pubkey::new_unique();
+        let mut guild = Guild {
+            name: "Wolves".to_string(),
+            leader: founder,
+            member_count: 1,
+            treasury_balance: 5_000,
+            total_guild_wins: 0,
+            bump: 255,
+        };
+
+        remove_guild_member(&mut guild);
+        assert_eq!(guild.member_count, 0);
+        assert_eq!(guild.leader, Pubkey::default());
+
+        add_guild_member(&mut guild, newcomer);
+        add_guild_member(&mut guild, founder);
+        assert_eq!(guild.leader, newcomer);
+        assert_eq!(guild.member_count, 2);
+        assert_eq!(guild.treasury_balance, 5_000);
+    }
+
+    #[test]
+    fn betting_closes_at_each_pools_own_turn() {
+        let mut battle: Battle = bytemuck::Zeroable::zeroed();
+        let mut early = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        early.betting_closes_at_turn = 2;
+        let mut late = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        late.betting_closes_at_turn = 5;
+
+        battle.turn_number = 1;
+        assert!(!betting_has_closed(&battle, &early));
+        battle.turn_number = 2;
+        assert!(betting_has_closed(&battle, &early));
+        assert!(!betting_has_closed(&battle, &late));
+
+        // A battle that ends early closes every pool
+        battle.is_finished = 1;
+        assert!(betting_has_closed(&battle, &late));
+    }
+
+    #[test]
+    fn leaderboard_reports_only_rank_moves() {
+        let mut leaderboard = Leaderboard { entries: vec![] };
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let owner = Pubkey::new_unique();
+
+        assert_eq!(place_on_leaderboard(&mut leaderboard, first, 1_500, owner), Some(1));
+        assert_eq!(place_on_leaderboard(&mut leaderboard, second, 1_400, owner), Some(2));
+
+        // Gaining MMR without passing anyone keeps the rank
+        assert_eq!(place_on_leaderboard(&mut leaderboard, second, 1_450, owner), None);
+        assert_eq!(place_on_leaderboard(&mut leaderboard, second, 1_600, owner), Some(1));
+        assert_eq!(leaderboard.entries[1].character, first);
+    }
+
+    #[test]
+    fn stance_commitment_binds_the_special() {
+        let (battle, player) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let committed = stance_commitment(BattleStance::Aggressive, 0, false, 42, &battle, 3, &player);
+
+        assert_eq!(committed, stance_commitment(BattleStance::Aggressive, 0, false, 42, &battle, 3, &player));
+        assert_ne!(committed, stance_commitment(BattleStance::Aggressive, 0, true, 42, &battle, 3, &player));
+    }
+
+    // Two level 1 fighters with 10-20 damage, no crits or dodges, full HP
+    fn test_battle() -> Battle {
+        let mut battle: Battle = bytemuck::Zeroable::zeroed();
+        battle.levels = [1, 1];
+        battle.damage_min = [10, 10];
+        battle.damage_max = [20, 20];
+        battle.player1_hp = 200;
+        battle.player2_hp = 200;
+        battle.player1_max_hp = 200;
+        battle.player2_max_hp = 200;
+        battle.current_turn = 1;
+        battle
+    }
+
+    #[test]
+    fn only_rolled_misses_feed_gamblers_fallacy() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+
+        // A Counter swing that lands does no damage but isn't a miss
+        let mut battle = test_battle();
+        battle.player1_stance = BattleStance::Counter.code();
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        assert_eq!(battle.player1_miss_count, 0);
+
+        // Neither is a lost Double or Nothing
+        let mut battle = test_battle();
+        battle.player1_pending_miss = 1;
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        assert_eq!(battle.player1_miss_count, 0);
+        assert_eq!(battle.player2_hp, 200);
+
+        // A dodge is
+        let mut battle = test_battle();
+        battle.dodge_chance = [0, 100];
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        assert_eq!(battle.player1_miss_count, 1);
+    }
+
+    #[test]
+    fn special_cooldown_only_ticks_on_the_owners_turn() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let cooldown = special_cooldown_for(CharacterClass::Warrior, &settings);
+        let mut battle = test_battle();
+
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, true, &settings).unwrap();
+        assert_eq!(battle.player1_special_cooldown, cooldown);
+
+        // The opponent's turn leaves it alone
+        execute_battle_turn(&mut battle, Pubkey::default(), &defender, &attacker, false, false, &settings).unwrap();
+        assert_eq!(battle.player1_special_cooldown, cooldown);
+
+        for remaining in (0..cooldown).rev() {
+            execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+            assert_eq!(battle.player1_special_cooldown, remaining);
+            execute_battle_turn(&mut battle, Pubkey::default(), &defender, &attacker, false, false, &settings).unwrap();
+        }
+    }
+
+    #[test]
+    fn turn_limit_is_bounded_by_the_turn_records() {
+        let mut settings = default_settings();
+        assert!(validate_settings(&settings).is_ok());
+
+        settings.max_battle_turns = TURN_RECORD_CAPACITY as u32 + 1;
+        assert!(validate_settings(&settings).is_err());
+
+        // Betting has to close before the battle can be decided on time
+        settings.max_battle_turns = 2;
+        settings.betting_closes_at_turn = 3;
+        assert!(validate_settings(&settings).is_err());
+    }
+
+    #[test]
+    fn markets_close_once_their_outcome_is_known() {
+        let mut battle = test_battle();
+        battle.turn_number = 1;
+        let first_blood = test_pool(OddsMode::Parimutuel, BetMarket::FirstBlood);
+        let wildcard = test_pool(OddsMode::Parimutuel, BetMarket::Wildcard);
+        let winner = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        assert!(!betting_has_closed(&battle, &first_blood));
+        assert!(!betting_has_closed(&battle, &wildcard));
+
+        battle.first_blood = 2;
+        assert!(betting_has_closed(&battle, &first_blood));
+        assert!(!betting_has_closed(&battle, &winner));
+
+        battle.wildcards_triggered = 1;
+        assert!(betting_has_closed(&battle, &wildcard));
+        assert!(!betting_has_closed(&battle, &winner));
+    }
+
+    #[test]
+    fn every_market_pushes_to_refunds() {
+        let funded = |market: BetMarket| {
+            let mut pool = test_pool(OddsMode::Parimutuel, market);
+            pool.player1_bets = 30_000_000;
+            pool.player2_bets = 20_000_000;
+            pool.total_pool = 50_000_000;
+            pool
+        };
+        let mut battle = test_battle();
+        battle.is_finished = 1;
+        battle.is_draw = 1;
+        battle.turn_number = 20;
+
+        // A draw, exactly on the line, and a battle where nobody ever landed a hit
+        let markets = [BetMarket::Winner, BetMarket::TotalTurns { line: 20 }, BetMarket::FirstBlood];
+        for market in markets {
+            let (pool, to_treasury) = settle(funded(market), &battle).unwrap();
+            assert!(pool.is_settled && pool.refund_mode, "{:?}", market);
+            assert_eq!(pool.winner, None);
+            assert_eq!(to_treasury, 0);
+        }
+
+        // Wildcard always resolves, it pushes only when the winning side is empty
+        let mut pool = funded(BetMarket::Wildcard);
+        pool.player2_bets = 0;
+        pool.total_pool = pool.player1_bets;
+        let (pool, to_treasury) = settle(pool, &battle).unwrap();
+        assert!(pool.refund_mode);
+        assert_eq!(to_treasury, 0);
+    }
+
+    #[test]
+    fn odds_modes_pay_the_same_bets_differently() {
+        // Both sides open even, a whale floods side 1, then side 2 gets a bet
+        // at the long odds
+        let bets = [(1, 10_000_000), (2, 10_000_000), (1, 980_000_000), (2, 10_000_000)];
+        let payouts = |odds_mode: OddsMode| {
+            let mut pool = test_pool(odds_mode, BetMarket::Winner);
+            let placed: Vec<Bet> = bets
+                .iter()
+                .map(|&(bet_on_player, amount)| Bet {
+                    bettor: Pubkey::new_unique(),
+                    betting_pool: Pubkey::default(),
+                    amount,
+                    bet_on_player,
+                    is_claimed: false,
+                    locked_odds: book_bet(&mut pool, amount, bet_on_player).unwrap(),
+                    index: 0,
+                })
+                .collect();
+            pool.is_settled = true;
+            pool.winner = Some(2);
+
+            let mut paid = vec![];
+            for bet in placed.iter().filter(|bet| bet.bet_on_player == 2) {
+                let winnings = bet_winnings(&pool, bet);
+                pool.total_claimed += winnings;
+                pool.claimed_winning_bets += bet.amount;
+                paid.push(winnings);
+            }
+            (placed[3].locked_odds, paid)
+        };
+
+        // Parimutuel splits the 959.5M left after the house cut by stake, the
+        // snapshot odds don't matter
+        let (_, parimutuel) = payouts(OddsMode::Parimutuel);
+        assert_eq!(parimutuel, vec![479_750_000, 479_750_000]);
+
+        // Fixed odds pay each bet what it locked: 2x at the even opening odds,
+        // and 10x for the last one, whose 99 quote was capped
+        let (locked, fixed) = payouts(OddsMode::FixedAtBet);
+        assert_eq!(locked, MAX_LOCKED_ODDS);
+        assert_eq!(fixed, vec![20_000_000, 100_000_000]);
+    }
+
+    #[test]
+    fn wallet_limit_counts_every_bet_on_the_pool() {
+        let pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        let half = pool.max_bet_per_wallet / 2;
+
+        // Two bets that fit the cap together, a third that doesn't
+        assert!(check_bet_limits(&pool, 0, half, 1).is_ok());
+        assert!(check_bet_limits(&pool, half, pool.max_bet_per_wallet - half, 2).is_ok());
+        assert!(check_bet_limits(&pool, pool.max_bet_per_wallet, pool.min_bet, 1).is_err());
+    }
+
+    #[test]
+    fn pool_limit_allows_filling_it_exactly() {
+        let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        let amount = pool.min_bet;
+        pool.max_pool_size = 50_000_000_000; // Below either side's cap
+        pool.total_pool = pool.max_pool_size - amount;
+        pool.player1_bets = pool.total_pool / 2;
+        pool.player2_bets = pool.total_pool - pool.player1_bets;
+
+        assert!(check_bet_limits(&pool, 0, amount, 1).is_ok());
+        assert!(check_bet_limits(&pool, 0, amount + 1, 1).is_err());
+    }
+
+    fn test_equipment(slot: EquipmentSlot, stats: EquipmentStats) -> Equipment {
+        Equipment { owner: Pubkey::new_unique(), slot, stats, equipped_to: None }
+    }
+
+    #[test]
+    fn equipment_stats_are_validated_on_mint() {
+        let stats = EquipmentStats { damage_min: 2, damage_max: 5, crit_chance: 10, dodge_chance: 5, defense: 3, max_hp: 20 };
+        assert!(stats.is_valid());
+        assert!(!EquipmentStats { damage_min: 6, ..stats }.is_valid());
+        assert!(!EquipmentStats { crit_chance: MAX_CRIT_CHANCE + 1, ..stats }.is_valid());
+        assert!(!EquipmentStats { dodge_chance: MAX_DODGE_CHANCE + 1, ..stats }.is_valid());
+    }
+
+    #[test]
+    fn equipping_adds_the_gear_and_unequipping_removes_it() {
+        let stats = EquipmentStats { damage_min: 2, damage_max: 5, crit_chance: 10, dodge_chance: 5, defense: 3, max_hp: 20 };
+        let mut character = test_character(CharacterClass::Warrior);
+        let before = character.clone();
+        let (character_key, sword_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut sword = test_equipment(EquipmentSlot::Weapon, stats);
+
+        equip_gear(&mut character, character_key, &mut sword, sword_key).unwrap();
+        assert_eq!(character.base_damage_min, before.base_damage_min + 2);
+        assert_eq!(character.base_damage_max, before.base_damage_max + 5);
+        assert_eq!(character.crit_chance, before.crit_chance + 10);
+        assert_eq!(character.dodge_chance, before.dodge_chance + 5);
+        assert_eq!(character.defense, before.defense + 3);
+        assert_eq!(character.max_hp, before.max_hp + 20);
+        assert_eq!(sword.equipped_to, Some(character_key));
+
+        // Worn already, and a second weapon can't share the slot
+        let mut other = test_character(CharacterClass::Mage);
+        assert!(equip_gear(&mut other, Pubkey::new_unique(), &mut sword, sword_key).is_err());
+        let mut axe = test_equipment(EquipmentSlot::Weapon, stats);
+        assert!(equip_gear(&mut character, character_key, &mut axe, Pubkey::new_unique()).is_err());
+
+        unequip_gear(&mut character, &mut sword, sword_key).unwrap();
+        assert_eq!(account_data(&character), account_data(&before));
+        assert_eq!(sword.equipped_to, None);
+    }
 
-// Part 3 - Updated Account Structures and Remaining Contexts
+    #[test]
+    fn battles_keep_the_gear_they_started_with() {
+        let stats = EquipmentStats { damage_min: 0, damage_max: 0, crit_chance: 0, dodge_chance: 60, defense: 0, max_hp: 0 };
+        let (key1, key2, cloak_key) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut cloak = test_equipment(EquipmentSlot::Trinket, stats);
+        let mut wearer = test_character(CharacterClass::Assassin);
+        equip_gear(&mut wearer, key1, &mut cloak, cloak_key).unwrap();
+        assert!(wearer.dodge_chance > MAX_DODGE_CHANCE);
+
+        let mut data1 = account_data(&wearer);
+        let mut data2 = account_data(&test_character(CharacterClass::Warrior));
+        let (mut lamports1, mut lamports2) = (0, 0);
+        let info1 = AccountInfo::new(&key1, false, true, &mut lamports1, &mut data1, &crate::ID, false, 0);
+        let info2 = AccountInfo::new(&key2, false, false, &mut lamports2, &mut data2, &crate::ID, false, 0);
+        let mut player1 = Account::<Character>::try_from(&info1).unwrap();
+        let player2 = Account::<Character>::try_from(&info2).unwrap();
+
+        let mut battle: Battle = bytemuck::Zeroable::zeroed();
+        init_battle(&mut battle, &player1, &player2, MatchType::Ranked, 0, false, &Clock::default());
+        // Gear pushes the raw stat past the cap, the battle only sees the cap
+        assert_eq!(battle.dodge_chance[0], MAX_DODGE_CHANCE);
+
+        // Taking the gear off mid-battle doesn't change the snapshot
+        unequip_gear(&mut player1, &mut cloak, cloak_key).unwrap();
+        assert_eq!(battle_stats(&battle, &player1, true).dodge_chance, MAX_DODGE_CHANCE);
+    }
 
-// Updated Character account with all new fields
-#[account]
-#[derive(InitSpace)]
-pub struct Character {
-    pub owner: Pubkey,
-    pub character_class: CharacterClass,
-    #[max_len(32)]
-    pub name: String,
-    pub level: u16,
-    pub xp: u64,
-    pub max_hp: u64,
-    pub current_hp: u64,
-    pub base_damage_min: u16,
-    pub base_damage_max: u16,
-    pub crit_chance: u16,
-    pub dodge_chance: u16,
-    pub defense: u16,
-    pub total_wins: u32,
-    pub total_losses: u32,
-    pub max_combo: u16,
-    pub mmr: u64,
-    pub special_cooldown: u8,
-    pub created_at: i64,
-    pub last_battle: i64,
-    
-    // New fields
-    pub rank_tier: RankTier,
-    pub season_wins: u32,
-    pub season_losses: u32,
-    #[max_len(20)]
-    pub achievements: Vec<Achievement>,
-    #[max_len(100)]
-    pub metadata_uri: String,
-}
+    #[test]
+    fn only_the_token_holder_can_sync() {
+        let (mint, holder) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut character = test_character(CharacterClass::Warrior);
+        character.nft_mint = Some(mint);
+
+        assert!(holds_character_nft(&character, mint, holder, 1, &holder));
+        assert!(!holds_character_nft(&character, mint, holder, 0, &holder));
+        assert!(!holds_character_nft(&character, mint, Pubkey::new_unique(), 1, &holder));
+        assert!(!holds_character_nft(&character, Pubkey::new_unique(), holder, 1, &holder));
+    }
+
+    #[test]
+    fn sync_takes_the_previous_owners_gear_off() {
+        let stats = EquipmentStats { damage_min: 1, damage_max: 4, crit_chance: 5, dodge_chance: 5, defense: 2, max_hp: 15 };
+        let mut character = test_character(CharacterClass::Warrior);
+        let before = character.clone();
+        let (character_key, gear_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut gear = test_equipment(EquipmentSlot::Armor, stats);
+        equip_gear(&mut character, character_key, &mut gear, gear_key).unwrap();
+
+        // As sync_character_owner does it, through the account in remaining_accounts
+        let mut data = account_data(&gear);
+        let mut lamports = 0;
+        let info = AccountInfo::new(&gear_key, false, true, &mut lamports, &mut data, &crate::ID, false, 0);
+        let mut equipment = Account::<Equipment>::try_from(&info).unwrap();
+        unequip_gear(&mut character, &mut equipment, info.key()).unwrap();
+        equipment.exit(&crate::ID).unwrap();
+
+        assert!(character.equipment.iter().all(Option::is_none));
+        assert_eq!(account_data(&character), account_data(&before));
+        let stored = Equipment::try_deserialize(&mut &info.try_borrow_data().unwrap()[..]).unwrap();
+        assert_eq!(stored.equipped_to, None);
+        assert_eq!(stored.owner, gear.owner);
+    }
+
+    #[test]
+    fn team_snapshot_ignores_later_gear_and_stalling_side_forfeits() {
+        let mut character = test_character(CharacterClass::Warrior);
+        let snapshot = CombatStats::from(&character);
+        character.base_damage_max += 50;
+        character.defense += 50;
+        assert_ne!(CombatStats::from(&character), snapshot);
+
+        let settings = default_settings();
+        let mut team_battle = TeamBattle {
+            characters: [Pubkey::new_unique(); 4],
+            hp: [100; 4],
+            stats: [snapshot; 4],
+            created_at: 1_000,
+            last_action_time: 1_000,
+            turn_number: 3,
+            current_slot: 2,
+            is_finished: false,
+            winning_side: 0,
+            randomness_account: Pubkey::default(),
+            randomness_pending: true,
+            pending_target: 0,
+        };
+        assert_eq!(team_battle.stats[0].base_damage_max, snapshot.base_damage_max);
+
+        let at = |t: i64| Clock { unix_timestamp: t, ..Clock::default() };
+        assert!(!team_turn_timed_out(&team_battle, &settings, &at(1_000 + settings.turn_timeout_seconds)));
+        assert!(team_turn_timed_out(&team_battle, &settings, &at(1_001 + settings.turn_timeout_seconds)));
+
+        // Slot 2 is on side B, so side A wins
+        let stalled_side = team_side(team_battle.current_slot);
+        end_team_battle(&mut team_battle, Pubkey::new_unique(), if stalled_side == 1 { 2 } else { 1 });
+        assert!(team_battle.is_finished);
+        assert!(!team_battle.randomness_pending);
+        assert_eq!(team_battle.winning_side, 1);
+    }
+
+    #[test]
+    fn new_season_resets_record_once_and_unclaimed_rewards_expire() {
+        let mut character = test_character(CharacterClass::Mage);
+        character.season_index = 1;
+        character.season_wins = 12;
+        character.season_losses = 4;
+        character.ranked_mmr = 2000;
+
+        assert!(!roll_over_season(&mut character, 1));
+        assert_eq!(character.season_wins, 12);
+
+        assert!(roll_over_season(&mut character, 2));
+        assert_eq!((character.season_index, character.season_wins, character.season_losses), (2, 0, 0));
+        assert_eq!(character.ranked_mmr, 1500);
+
+        // Later battles in the same season leave it alone, and sitting out
+        // seasons still only soft-resets once
+        assert!(!roll_over_season(&mut character, 2));
+        assert_eq!(character.ranked_mmr, 1500);
+        assert!(roll_over_season(&mut character, 5));
+        assert_eq!(character.ranked_mmr, 1250);
+
+        let settings = default_settings();
+        let season = Season {
+            index: 1,
+            start_time: 0,
+            end_time: 10_000,
+            ended: true,
+            tier_rewards: [0; 6],
+            reward_pool: 0,
+            claims: 0,
+            bump: 0,
+        };
+        let at = |t: i64| Clock { unix_timestamp: t, ..Clock::default() };
+        let closes_at = season.end_time + settings.season_claim_window_seconds;
+        assert!(!season_claim_window_closed(&season, &settings, &at(closes_at - 1)));
+        assert!(season_claim_window_closed(&season, &settings, &at(closes_at)));
+    }
+
+    #[test]
+    fn a_pool_settles_once_inline_or_manually() {
+        let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        pool.player1_bets = 30_000_000;
+        pool.player2_bets = 20_000_000;
+        pool.total_pool = 50_000_000;
+        let mut battle = test_battle();
+
+        // Neither path settles a battle that's still running
+        assert!(settle(pool.clone(), &battle).is_err());
+        assert!(settle_with(pool.clone(), &battle, settle_pool_if_open).is_err());
+
+        battle.is_finished = 1;
+        battle.winner = 1;
+
+        // finalize_battle settles inline and takes the house edge
+        let (inline, to_treasury) = settle_with(pool.clone(), &battle, settle_pool_if_open).unwrap();
+        assert!(inline.is_settled && !inline.refund_mode);
+        assert_eq!(inline.winner, Some(1));
+        assert_eq!(to_treasury, 50_000_000 * DEFAULT_HOUSE_EDGE as u64 / 100);
+
+        // The manual fallback settles the same way when finalize didn't
+        let (manual, to_treasury_manual) = settle(pool, &battle).unwrap();
+        assert_eq!((manual.winner, to_treasury_manual), (inline.winner, to_treasury));
+
+        // A second manual settlement is rejected, finalize skips the pool
+        // without charging the edge again
+        let second = settle(manual.clone(), &battle);
+        assert!(matches!(second, Err(err) if err == GameError::PoolAlreadySettled.into()));
+        let (after_finalize, to_treasury) = settle_with(manual.clone(), &battle, settle_pool_if_open).unwrap();
+        assert_eq!(after_finalize.settled_at, manual.settled_at);
+        assert_eq!(to_treasury, 0);
+    }
+
+    #[test]
+    fn turn_record_seeds_replay_the_battle() {
+        let settings = default_settings();
+        let warrior = test_character(CharacterClass::Warrior);
+        let assassin = test_character(CharacterClass::Assassin);
+        let play = |seeds: &[[u8; 32]]| {
+            let mut battle = test_battle();
+            battle.crit_chance = [30, 30];
+            battle.dodge_chance = [20, 20];
+            let mut hp = vec![];
+            for seed in seeds {
+                if battle.is_finished != 0 {
+                    break;
+                }
+                battle.random_seed = *seed;
+                battle.roll_counter = 0;
+                let is_player1 = battle.current_turn == 1;
+                let (attacker, defender) = if is_player1 { (&warrior, &assassin) } else { (&assassin, &warrior) };
+                execute_battle_turn(&mut battle, Pubkey::default(), attacker, defender, is_player1, false, &settings).unwrap();
+                hp.push((battle.player1_hp, battle.player2_hp));
+            }
+            (battle, hp)
+        };
+
+        let seeds: Vec<[u8; 32]> = (0..TURN_RECORD_CAPACITY as u32).map(|i| hash(&i.to_le_bytes()).to_bytes()).collect();
+        let (battle, hp) = play(&seeds);
+        assert!(battle.is_finished != 0);
+        assert!(battle.turn_records[..hp.len()].iter().any(|record| record.was_crit != 0));
+
+        let recorded: Vec<[u8; 32]> = battle.turn_records[..hp.len()].iter().map(|record| record.seed).collect();
+        let (replayed, replayed_hp) = play(&recorded);
+        assert_eq!(replayed_hp, hp);
+        assert_eq!(replayed.winner, battle.winner);
+        assert_eq!(replayed.turn_records, battle.turn_records);
+
+        // The seeds really drive it, shifting them by a turn changes the battle
+        assert_ne!(play(&recorded[1..]).1, hp);
+    }
+
+    #[test]
+    fn practice_win_leaves_every_stat_but_hp_alone() {
+        let mut winner = test_character(CharacterClass::Paladin);
+        let mut loser = test_character(CharacterClass::Tank);
+        winner.current_hp = winner.max_hp / 2;
+        loser.current_hp = 0;
+        let mut expected = [winner.clone(), loser.clone()];
+        for character in expected.iter_mut() {
+            character.current_hp = character.max_hp;
+        }
+
+        let mut battle = test_battle();
+        battle.is_finished = 1;
+        battle.winner = 1;
+        battle.match_type = MatchType::Practice.code();
+        assert!(finish_practice_battle(&battle, &mut winner, &mut loser));
+        assert_eq!(account_data(&winner), account_data(&expected[0]));
+        assert_eq!(account_data(&loser), account_data(&expected[1]));
+
+        // Every other match type goes on to record the result
+        battle.match_type = MatchType::Ranked.code();
+        loser.current_hp = 0;
+        assert!(!finish_practice_battle(&battle, &mut winner, &mut loser));
+        assert_eq!(loser.current_hp, 0);
+    }
+
+    #[test]
+    fn committing_without_revealing_forfeits_on_the_reveal_window() {
+        let settings = default_settings();
+        assert!(settings.reveal_timeout_seconds < settings.turn_timeout_seconds);
+        let at = |t: i64| Clock { unix_timestamp: t, ..Clock::default() };
+
+        let mut battle = test_battle();
+        battle.last_action_time = 1_000;
+        battle.both_committed_at = 1_000;
+        battle.player1_stance_revealed = 1;
+        let deadline = 1_000 + settings.reveal_timeout_seconds;
+        assert_eq!(timed_out_player(&battle, &settings, &at(deadline)), None);
+
+        // Player 2 committed but sat on the reveal, well inside the turn timeout
+        assert!(deadline + 1 < 1_000 + settings.turn_timeout_seconds);
+        assert_eq!(timed_out_player(&battle, &settings, &at(deadline + 1)), Some(2));
+
+        battle.player1_stance_revealed = 0;
+        battle.player2_stance_revealed = 1;
+        assert_eq!(timed_out_player(&battle, &settings, &at(deadline + 1)), Some(1));
+
+        // Before both commit only the turn timeout applies, to the current player
+        battle.both_committed_at = 0;
+        battle.player2_stance_revealed = 0;
+        battle.current_turn = 2;
+        assert_eq!(timed_out_player(&battle, &settings, &at(deadline + 1)), None);
+        assert_eq!(timed_out_player(&battle, &settings, &at(1_001 + settings.turn_timeout_seconds)), Some(2));
+    }
+
+    // An AccountInfo that lives as long as the test, so a whole account list
+    // can be handed to try_accounts
+    fn leaked_info(key: Pubkey, owner: Pubkey, lamports: u64, data: Vec<u8>, is_signer: bool) -> AccountInfo<'static> {
+        AccountInfo::new(
+            Box::leak(Box::new(key)),
+            is_signer,
+            true,
+            Box::leak(Box::new(lamports)),
+            Box::leak(data.into_boxed_slice()),
+            Box::leak(Box::new(owner)),
+            false,
+            0,
+        )
+    }
+
+    // A staked challenge at its PDA, the challenged character and both owners
+    fn open_challenge(stake: u64) -> (Challenge, Pubkey, Character, Pubkey) {
+        let challenger = Pubkey::new_unique();
+        let challenged_key = Pubkey::new_unique();
+        let challenged = test_character(CharacterClass::Mage);
+        let (challenge_key, bump) =
+            Pubkey::find_program_address(&[b"challenge", challenger.as_ref(), challenged_key.as_ref()], &crate::ID);
+        let challenge = Challenge {
+            challenger,
+            challenged: challenged_key,
+            challenger_owner: Pubkey::new_unique(),
+            match_type: MatchType::Staked,
+            stake_amount: stake,
+            expires_at: 10_000,
+            bump,
+        };
+        (challenge, challenge_key, challenged, challenged_key)
+    }
+
+    #[test]
+    fn challenges_need_the_challenged_owner_or_expiry() {
+        let stake = 1_000_000_000;
+        let (challenge, challenge_key, challenged, challenged_key) = open_challenge(stake);
+        let challenge_data = account_data(&challenge);
+        let challenge_lamports = Rent::default().minimum_balance(challenge_data.len()) + stake;
+        let system = Pubkey::default();
+
+        // accept_challenge is the only way into a PvP battle, and it fails
+        // unless the challenged character's owner signs it
+        let mut accounts: &[AccountInfo] = Vec::leak(vec![
+            leaked_info(challenge_key, crate::ID, challenge_lamports, challenge_data.clone(), false),
+            leaked_info(Pubkey::new_unique(), system, 0, vec![], false),
+            leaked_info(Pubkey::new_unique(), system, 0, vec![], false),
+            leaked_info(challenge.challenger, crate::ID, 1, account_data(&test_character(CharacterClass::Warrior)), false),
+            leaked_info(challenged_key, crate::ID, 1, account_data(&challenged), false),
+            leaked_info(challenged.owner, system, 1, vec![], false),
+        ]);
+        let unsigned = AcceptChallenge::try_accounts(
+            &crate::ID, &mut accounts, &[], &mut AcceptChallengeBumps::default(), &mut std::collections::BTreeSet::new(),
+        );
+        assert!(matches!(unsigned, Err(err) if err == ErrorCode::AccountNotSigner.into()));
+
+        // A decline hands the challenge's whole balance, stake included, to
+        // the challenger's owner and nobody else
+        let decline_infos = |refund_to: Pubkey, signer: Pubkey| -> &'static [AccountInfo<'static>] {
+            Vec::leak(vec![
+                leaked_info(challenge_key, crate::ID, challenge_lamports, challenge_data.clone(), false),
+                leaked_info(challenged_key, crate::ID, 1, account_data(&challenged), false),
+                leaked_info(signer, system, 1, vec![], true),
+                leaked_info(refund_to, system, 0, vec![], false),
+            ])
+        };
+        let mut accounts = decline_infos(challenge.challenger_owner, challenged.owner);
+        let decline = DeclineChallenge::try_accounts(
+            &crate::ID, &mut accounts, &[], &mut DeclineChallengeBumps::default(), &mut std::collections::BTreeSet::new(),
+        )
+        .unwrap();
+        assert_eq!(decline.challenge.to_account_info().lamports(), challenge_lamports);
+        assert_eq!(decline.challenger_owner.key(), challenge.challenger_owner);
+
+        let mut accounts = decline_infos(Pubkey::new_unique(), challenged.owner);
+        let redirected = DeclineChallenge::try_accounts(
+            &crate::ID, &mut accounts, &[], &mut DeclineChallengeBumps::default(), &mut std::collections::BTreeSet::new(),
+        );
+        assert!(matches!(redirected, Err(err) if err == ErrorCode::ConstraintHasOne.into()));
+
+        // Only the challenged player may decline early, anyone can clear it
+        // once it has expired
+        let at = |t: i64| Clock { unix_timestamp: t, ..Clock::default() };
+        let stranger = Pubkey::new_unique();
+        assert!(check_can_clear_challenge(&challenge, &challenged, &stranger, &at(9_999)).is_err());
+        assert!(!check_can_clear_challenge(&challenge, &challenged, &challenged.owner, &at(9_999)).unwrap());
+        assert!(check_can_clear_challenge(&challenge, &challenged, &stranger, &at(10_000)).unwrap());
+    }
+
+    #[test]
+    fn crossing_into_gold_flags_the_metadata() {
+        let mut character = test_character(CharacterClass::Warrior);
+        character.ranked_mmr = 1_200;
+        on_account(&mut character, update_rank_tier);
+        assert_eq!(character.rank_tier, RankTier::Silver);
+        character.metadata_dirty = false;
+
+        // Moving within a tier leaves the art alone
+        character.ranked_mmr = 1_499;
+        on_account(&mut character, update_rank_tier);
+        assert!(!character.metadata_dirty);
+
+        character.ranked_mmr = 1_500;
+        on_account(&mut character, update_rank_tier);
+        assert_eq!(character.rank_tier, RankTier::Gold);
+        assert!(character.metadata_dirty);
+    }
+
+    #[test]
+    fn consolation_xp_adds_up_but_skips_abandoners() {
+        let settings = default_settings();
+        let mut battle = test_battle();
+        battle.is_finished = 1;
+        battle.winner = 1;
+        let loser_xp = consolation_xp(&battle, 100, &settings);
+        assert_eq!(loser_xp, 100 * LOSER_XP_PERCENT as u64 / 100);
+
+        let mut loser = test_character(CharacterClass::Tank);
+        for _ in 0..3 {
+            on_account(&mut loser, |account| update_loser_stats(account, loser_xp, None, 0)).unwrap();
+        }
+        assert_eq!(loser.level, 1);
+        on_account(&mut loser, |account| update_loser_stats(account, loser_xp, None, 0)).unwrap();
+        assert_eq!((loser.level, loser.xp), (2, 4 * loser_xp - get_required_xp(1)));
+
+        battle.abandoned = 1;
+        assert_eq!(consolation_xp(&battle, 100, &settings), 0);
+
+        // The AI never levels off a loss either
+        battle.abandoned = 0;
+        battle.is_vs_ai = 1;
+        assert_eq!(consolation_xp(&battle, 100, &settings), 0);
+    }
+
+    #[test]
+    fn ranked_rejects_an_mmr_gap_past_the_limit() {
+        let settings = default_settings();
+        let player1 = test_character(CharacterClass::Warrior);
+        let mut player2 = test_character(CharacterClass::Mage);
+
+        player2.ranked_mmr = player1.ranked_mmr + settings.max_mmr_gap;
+        assert!(check_mmr_gap(MatchType::Ranked, &player1, &player2, &settings).is_ok());
+        assert!(check_mmr_gap(MatchType::Ranked, &player2, &player1, &settings).is_ok());
+
+        player2.ranked_mmr += 1;
+        let too_far = check_mmr_gap(MatchType::Ranked, &player1, &player2, &settings);
+        assert!(matches!(too_far, Err(err) if err == GameError::MmrGapTooLarge.into()));
+
+        for match_type in [MatchType::Casual, MatchType::Staked, MatchType::Practice] {
+            assert!(check_mmr_gap(match_type, &player1, &player2, &settings).is_ok());
+        }
+    }
+
+    #[test]
+    fn flawless_needs_a_scratch_free_win() {
+        let mut hurt = test_character(CharacterClass::Warrior);
+        on_account(&mut hurt, |account| update_winner_stats(account, 0, None, 0, false, true)).unwrap();
+        assert!(!hurt.achievements.contains(&Achievement::Flawless));
+        assert!(hurt.achievements.contains(&Achievement::FirstWin));
+        assert_eq!(hurt.current_hp, hurt.max_hp);
+
+        let mut untouched = test_character(CharacterClass::Warrior);
+        on_account(&mut untouched, |account| update_winner_stats(account, 0, None, 0, true, true)).unwrap();
+        assert!(untouched.achievements.contains(&Achievement::Flawless));
+    }
+
+    #[test]
+    fn a_transferred_character_answers_only_to_its_new_owner() {
+        let character_key = Pubkey::new_unique();
+        let (queue_key, _) = Pubkey::find_program_address(&[b"queue", character_key.as_ref()], &crate::ID);
+        let character = test_character(CharacterClass::Assassin);
+        let (old_owner, new_owner) = (character.owner, Pubkey::new_unique());
+        let character_info = leaked_info(character_key, crate::ID, 1, account_data(&character), false);
+        let transfer_accounts = |signer: Pubkey| -> &'static [AccountInfo<'static>] {
+            Vec::leak(vec![
+                character_info.clone(),
+                leaked_info(queue_key, Pubkey::default(), 0, vec![], false),
+                leaked_info(signer, Pubkey::default(), 1, vec![], true),
+            ])
+        };
+        let load = |signer: Pubkey| {
+            let mut infos = transfer_accounts(signer);
+            TransferCharacter::try_accounts(
+                &crate::ID, &mut infos, &[], &mut TransferCharacterBumps::default(), &mut std::collections::BTreeSet::new(),
+            )
+        };
+
+        let mut accounts = load(old_owner).unwrap();
+        accounts.character.in_battle = Some(Pubkey::new_unique());
+        let busy = my_program::transfer_character(
+            Context::new(&crate::ID, &mut accounts, &[], TransferCharacterBumps::default()),
+            new_owner,
+        );
+        assert!(matches!(busy, Err(err) if err == GameError::CharacterBusy.into()));
+
+        accounts.character.in_battle = None;
+        my_program::transfer_character(
+            Context::new(&crate::ID, &mut accounts, &[], TransferCharacterBumps::default()),
+            new_owner,
+        )
+        .unwrap();
+        accounts.exit(&crate::ID).unwrap();
+
+        // The address stays the same, only the signer it answers to changes
+        assert!(matches!(load(old_owner), Err(err) if err == ErrorCode::ConstraintHasOne.into()));
+        assert_eq!(load(new_owner).unwrap().character.owner, new_owner);
+    }
+
+    // The GameConfig PDA with default settings, as initialize_config leaves it
+    fn config_info(admin: Pubkey) -> AccountInfo<'static> {
+        let (key, bump) = Pubkey::find_program_address(&[b"config"], &crate::ID);
+        let config = GameConfig {
+            admin,
+            bump,
+            paused: false,
+            settings: default_settings(),
+            current_season: 0,
+            season_active: false,
+            ai_character: Pubkey::default(),
+        };
+        leaked_info(key, crate::ID, 1, account_data(&config), false)
+    }
+
+    #[test]
+    fn only_the_admin_pauses_and_a_pause_stops_turns() {
+        let admin = Pubkey::new_unique();
+        let config = config_info(admin);
+        let set_paused = |signer: Pubkey, paused: bool| {
+            let mut infos: &[AccountInfo] =
+                Vec::leak(vec![config.clone(), leaked_info(signer, Pubkey::default(), 1, vec![], true)]);
+            let mut accounts = SetPaused::try_accounts(
+                &crate::ID, &mut infos, &[], &mut SetPausedBumps::default(), &mut std::collections::BTreeSet::new(),
+            )?;
+            my_program::set_paused(Context::new(&crate::ID, &mut accounts, &[], SetPausedBumps::default()), paused)?;
+            accounts.exit(&crate::ID)
+        };
+        assert!(matches!(set_paused(Pubkey::new_unique(), true), Err(err) if err == ErrorCode::ConstraintHasOne.into()));
+        set_paused(admin, true).unwrap();
+
+        let character = test_character(CharacterClass::Warrior);
+        let team_battle = TeamBattle {
+            characters: [Pubkey::new_unique(); 4],
+            hp: [100; 4],
+            stats: [CombatStats::from(&character); 4],
+            created_at: 0,
+            last_action_time: 0,
+            turn_number: 0,
+            current_slot: 0,
+            is_finished: false,
+            winning_side: 0,
+            randomness_account: Pubkey::default(),
+            randomness_pending: false,
+            pending_target: 2,
+        };
+        let request_turn = || {
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                leaked_info(Pubkey::new_unique(), crate::ID, 1, account_data(&team_battle), false),
+                leaked_info(Pubkey::new_unique(), crate::ID, 1, account_data(&character), false),
+                leaked_info(character.owner, Pubkey::default(), 1, vec![], true),
+                leaked_info(Pubkey::new_unique(), Pubkey::default(), 1, vec![], false),
+                config.clone(),
+            ]);
+            let mut accounts = RequestTeamTurn::try_accounts(
+                &crate::ID, &mut infos, &[], &mut RequestTeamTurnBumps::default(), &mut std::collections::BTreeSet::new(),
+            )?;
+            my_program::request_team_turn(Context::new(&crate::ID, &mut accounts, &[], RequestTeamTurnBumps::default()), 2)
+        };
+        assert!(matches!(request_turn(), Err(err) if err == GameError::GamePaused.into()));
+
+        // Unpaused, the request gets past the switch (and on to the clock,
+        // which isn't there off-chain)
+        set_paused(admin, false).unwrap();
+        assert!(matches!(request_turn(), Err(err) if err == ProgramError::UnsupportedSysvar.into()));
+    }
+
+    #[test]
+    fn respec_rebuilds_stats_as_if_always_the_new_class() {
+        let settings = default_settings();
+        let levelled = |class: CharacterClass| {
+            let mut character = test_character(class);
+            for _ in 1..6 {
+                character.level += 1;
+                apply_level_up_gains(&mut character);
+            }
+            character.prestige = 1;
+            apply_permanent_bonuses(&mut character);
+            character
+        };
+
+        let mut warrior = levelled(CharacterClass::Warrior);
+        warrior.current_hp = warrior.max_hp;
+        respec_stats(&mut warrior, CharacterClass::Mage, &settings);
+        let mage = levelled(CharacterClass::Mage);
+        assert_eq!(CombatStats::from(&warrior), CombatStats::from(&mage));
+
+        // Full HP can't exceed the Mage's lower max_hp, damage taken survives a respec back
+        assert_eq!(warrior.current_hp, mage.max_hp);
+        warrior.current_hp = 10;
+        respec_stats(&mut warrior, CharacterClass::Warrior, &settings);
+        assert_eq!(CombatStats::from(&warrior), CombatStats::from(&levelled(CharacterClass::Warrior)));
+        assert_eq!(warrior.current_hp, 10);
+    }
+
+    #[test]
+    fn turns_only_pit_a_player_against_its_opponent() {
+        let mut battle = test_battle();
+        let (player1, player2, outsider) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        battle.player1 = player1;
+        battle.player2 = player2;
+
+        assert!(turn_sides(&battle, &player1, &player2).unwrap());
+        assert!(!turn_sides(&battle, &player2, &player1).unwrap());
+
+        let wrong_defender = turn_sides(&battle, &player1, &outsider);
+        assert!(matches!(wrong_defender, Err(err) if err == GameError::WrongDefender.into()));
+        let self_target = turn_sides(&battle, &player1, &player1);
+        assert!(matches!(self_target, Err(err) if err == GameError::WrongDefender.into()));
+        let outsider_attacks = turn_sides(&battle, &outsider, &player2);
+        assert!(matches!(outsider_attacks, Err(err) if err == GameError::NotBattleParticipant.into()));
+    }
+
+    #[test]
+    fn metadata_uri_is_checked_and_versioned() {
+        let admin = Pubkey::new_unique();
+        let character = test_character(CharacterClass::Trickster);
+        let owner = character.owner;
+        let mut data = account_data(&character);
+        data.resize(8 + Character::INIT_SPACE, 0);
+        let character_info = leaked_info(Pubkey::new_unique(), crate::ID, 1, data, false);
+        let config = config_info(admin);
+        let set_uri = |authority: Pubkey, uri: &str| {
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                character_info.clone(),
+                config.clone(),
+                leaked_info(authority, Pubkey::default(), 1, vec![], true),
+            ]);
+            let mut accounts = SetMetadataUri::try_accounts(
+                &crate::ID, &mut infos, &[], &mut SetMetadataUriBumps::default(), &mut std::collections::BTreeSet::new(),
+            )?;
+            let ctx = Context::new(&crate::ID, &mut accounts, &[], SetMetadataUriBumps::default());
+            my_program::set_metadata_uri(ctx, uri.to_string())?;
+            accounts.exit(&crate::ID)?;
+            Ok::<_, Error>(accounts.character.metadata_version)
+        };
+
+        assert_eq!(set_uri(owner, "https://art.example/1.json").unwrap(), 1);
+        assert_eq!(set_uri(admin, "ipfs://bafy").unwrap(), 2);
+
+        let stranger = set_uri(Pubkey::new_unique(), "https://art.example/2.json");
+        assert!(matches!(stranger, Err(err) if err == GameError::NotCharacterOwner.into()));
+        let scheme = set_uri(owner, "http://art.example/2.json");
+        assert!(matches!(scheme, Err(err) if err == GameError::InvalidMetadataUri.into()));
+        let long = set_uri(owner, &format!("https://{}", "a".repeat(93)));
+        assert!(matches!(long, Err(err) if err == GameError::MetadataUriTooLong.into()));
+        assert_eq!(set_uri(owner, &format!("https://{}", "a".repeat(92))).unwrap(), 3);
+    }
+
+    #[test]
+    fn a_crit_turn_is_recorded_with_its_damage() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let mut battle = test_battle();
+        battle.crit_chance = [MAX_CRIT_CHANCE, 0];
+
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        let record = battle.turn_records[0];
+        assert_eq!((record.turn, record.attacker, record.was_crit, record.was_dodged), (0, 1, 1, 0));
+        assert_eq!(record.damage, 200 - battle.player2_hp);
+        assert!(record.damage >= crit_damage(CharacterClass::Warrior, battle.damage_min[0] as u64));
+        assert_eq!(record.stance(), BattleStance::from_code(0));
+        assert_eq!(record.wildcard(), None);
+
+        // The defender's reply can't crit
+        execute_battle_turn(&mut battle, Pubkey::default(), &defender, &attacker, false, false, &settings).unwrap();
+        let record = battle.turn_records[1];
+        assert_eq!((record.turn, record.attacker, record.was_crit), (1, 2, 0));
+    }
+
+    #[test]
+    fn nft_name_follows_the_rank_tier() {
+        let mut character = test_character(CharacterClass::Assassin);
+        character.name = "Shadow".to_string();
+        character.ranked_mmr = 2_600;
+        on_account(&mut character, update_rank_tier);
+        assert!(character.metadata_dirty);
+
+        let data = nft_data(&character);
+        assert_eq!(data.name, "Shadow [Diamond]");
+        assert_eq!(data.symbol, "ASSASSIN");
+
+        // Long names are cut on a char boundary to fit Metaplex's 32 bytes
+        character.name = "é".repeat(16);
+        character.ranked_mmr = 3_000;
+        on_account(&mut character, update_rank_tier);
+        let name = nft_data(&character).name;
+        assert!(name.len() <= 32 && name.ends_with(" [Master]"), "{}", name);
+    }
+
+    #[test]
+    fn combo_master_and_tournament_winner_are_earned() {
+        let mut character = test_character(CharacterClass::Warrior);
+        on_account(&mut character, |account| update_combo_stats(account, COMBO_MASTER_THRESHOLD - 1));
+        assert!(!character.achievements.contains(&Achievement::ComboMaster));
+        on_account(&mut character, |account| update_combo_stats(account, COMBO_MASTER_THRESHOLD));
+        assert!(character.achievements.contains(&Achievement::ComboMaster));
+        assert_eq!(character.max_combo, COMBO_MASTER_THRESHOLD);
+
+        // The last one standing completes the tournament and takes the prize
+        let champion_key = Pubkey::new_unique();
+        let tournament_key = Pubkey::new_unique();
+        let mut champion = test_character(CharacterClass::Paladin);
+        champion.tournament = Some(tournament_key);
+        let tournament = Tournament {
+            creator: Pubkey::new_unique(),
+            entry_fee: 1_000,
+            prize_pool: 5_000,
+            max_players: 2,
+            current_players: 2,
+            status: TournamentStatus::InProgress,
+            created_at: 0,
+            participants: vec![Pubkey::new_unique(), champion_key],
+            current_round: 1,
+            winner: None,
+            started_at: 0,
+            eliminated: 1,
+        };
+        let mut champion_data = account_data(&champion);
+        champion_data.resize(8 + Character::INIT_SPACE, 0);
+        let mut infos: &[AccountInfo] = Vec::leak(vec![
+            leaked_info(tournament_key, crate::ID, 10_000, account_data(&tournament), false),
+            leaked_info(champion_key, crate::ID, 1, champion_data, false),
+            leaked_info(champion.owner, Pubkey::default(), 0, vec![], false),
+        ]);
+        let mut accounts = CompleteTournament::try_accounts(
+            &crate::ID, &mut infos, &[], &mut CompleteTournamentBumps::default(), &mut std::collections::BTreeSet::new(),
+        )
+        .unwrap();
+        my_program::complete_tournament(Context::new(&crate::ID, &mut accounts, &[], CompleteTournamentBumps::default()))
+            .unwrap();
+
+        assert!(accounts.champion.achievements.contains(&Achievement::TournamentWinner));
+        assert_eq!(accounts.champion.tournament, None);
+        assert_eq!(accounts.tournament.winner, Some(champion_key));
+        assert_eq!(accounts.champion_owner.lamports(), 2 * 1_000 + 5_000);
+    }
+
+    #[test]
+    fn house_edge_is_capped_and_its_cut_reaches_the_treasury() {
+        let settings = default_settings();
+        assert_eq!(pool_house_edge(None, &settings).unwrap(), DEFAULT_HOUSE_EDGE);
+        assert_eq!(pool_house_edge(Some(MAX_HOUSE_EDGE), &settings).unwrap(), MAX_HOUSE_EDGE);
+        let too_high = pool_house_edge(Some(MAX_HOUSE_EDGE + 1), &settings);
+        assert!(matches!(too_high, Err(err) if err == GameError::InvalidHouseEdge.into()));
+
+        let mut bad_default = default_settings();
+        bad_default.default_house_edge = MAX_HOUSE_EDGE + 1;
+        assert!(validate_settings(&bad_default).is_err());
+
+        let mut battle = test_battle();
+        battle.is_finished = 1;
+        battle.winner = 2;
+        for house_edge in [0, MAX_HOUSE_EDGE] {
+            let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+            pool.house_edge = house_edge;
+            pool.player1_bets = 60_000_000;
+            pool.player2_bets = 40_000_000;
+            pool.total_pool = 100_000_000;
+            let (pool, to_treasury) = settle(pool, &battle).unwrap();
+            assert_eq!(pool.winner, Some(2));
+            assert_eq!(to_treasury, 100_000_000 * house_edge as u64 / 100);
+        }
+    }
+
+    #[test]
+    fn battle_snapshot_matches_the_account() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let mut battle = test_battle();
+        battle.wildcard_type = WildcardEvent::LuckySeven.code();
+        for is_player1 in [true, false, true] {
+            let (acting, other) = if is_player1 { (&attacker, &defender) } else { (&defender, &attacker) };
+            execute_battle_turn(&mut battle, Pubkey::default(), acting, other, is_player1, false, &settings).unwrap();
+        }
+
+        let mut data = <Battle as anchor_lang::Discriminator>::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(&battle));
+        let mut infos: &[AccountInfo] = Vec::leak(vec![leaked_info(Pubkey::new_unique(), crate::ID, 1, data, false)]);
+        let mut accounts = GetBattleSnapshot::try_accounts(
+            &crate::ID, &mut infos, &[], &mut GetBattleSnapshotBumps::default(), &mut std::collections::BTreeSet::new(),
+        )
+        .unwrap();
+        let snapshot = my_program::battle_snapshot(Context::new(&crate::ID, &mut accounts, &[], GetBattleSnapshotBumps::default()))
+            .unwrap();
+
+        // What a client reads back from the return data
+        let snapshot = BattleSnapshot::try_from_slice(&snapshot.try_to_vec().unwrap()).unwrap();
+        assert_eq!(snapshot.turn_number, battle.turn_number);
+        assert_eq!(snapshot.current_turn, battle.current_turn);
+        assert_eq!((snapshot.player1_hp, snapshot.player2_hp), (battle.player1_hp, battle.player2_hp));
+        assert_eq!((snapshot.player1_combo, snapshot.player2_combo), (battle.player1_combo, battle.player2_combo));
+        assert_eq!(snapshot.wildcard_type, WildcardEvent::from_code(battle.wildcard_type));
+        assert_eq!(snapshot.is_finished, battle.is_finished != 0);
+        assert_eq!(snapshot.winner, battle.winner);
+    }
+
+    #[test]
+    fn each_class_gets_its_own_special_cooldown() {
+        let mut settings = default_settings();
+        let defender = test_character(CharacterClass::Tank);
+        let classes = [
+            CharacterClass::Warrior,
+            CharacterClass::Assassin,
+            CharacterClass::Mage,
+            CharacterClass::Tank,
+            CharacterClass::Trickster,
+            CharacterClass::Paladin,
+        ];
+        let used_special = |class: CharacterClass, settings: &GameSettings| {
+            let mut battle = test_battle();
+            let attacker = test_character(class);
+            execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, true, settings).unwrap();
+            battle
+        };
+
+        for class in classes {
+            let battle = used_special(class, &settings);
+            assert_eq!(battle.player1_special_cooldown, SPECIAL_COOLDOWNS[class as usize], "{:?}", class);
+        }
+
+        // The config overrides the table, and the cooldown never goes below zero
+        settings.special_cooldowns[CharacterClass::Tank as usize] = 1;
+        let mut battle = used_special(CharacterClass::Tank, &settings);
+        assert_eq!(battle.player1_special_cooldown, 1);
+        let tank = test_character(CharacterClass::Tank);
+        for _ in 0..2 {
+            execute_battle_turn(&mut battle, Pubkey::default(), &defender, &tank, false, false, &settings).unwrap();
+            execute_battle_turn(&mut battle, Pubkey::default(), &tank, &defender, true, false, &settings).unwrap();
+            assert_eq!(battle.player1_special_cooldown, 0);
+        }
+    }
+
+    #[test]
+    fn k_factor_depends_on_match_type_and_season_games() {
+        let settings = default_settings();
+        let mut character = test_character(CharacterClass::Mage);
+        character.season_wins = PROVISIONAL_SEASON_GAMES - 1;
+        assert_eq!(mmr_k_factor(&character, MatchType::Ranked, &settings), MMR_K_PROVISIONAL);
+        assert_eq!(mmr_k_factor(&character, MatchType::Casual, &settings), MMR_K_CASUAL);
+
+        character.season_losses = 1;
+        for match_type in [MatchType::Ranked, MatchType::Tournament, MatchType::Staked] {
+            assert_eq!(mmr_k_factor(&character, match_type, &settings), MMR_K_RANKED);
+        }
+        assert_eq!(mmr_k_factor(&character, MatchType::Casual, &settings), MMR_K_CASUAL);
+
+        // Table-driven: the underdog always gains more for a win than the favorite
+        let cases = [(1000, 1000, 16, 16), (1200, 1000, 8, 24), (1400, 1000, 3, 29)];
+        for (favorite, underdog, favorite_win, upset) in cases {
+            assert_eq!(compute_mmr_delta(favorite, underdog, MMR_K_RANKED), favorite_win, "{} v {}", favorite, underdog);
+            assert_eq!(compute_mmr_delta(underdog, favorite, MMR_K_RANKED), upset, "{} v {}", underdog, favorite);
+        }
+
+        // AI and Practice battles move no rating at all
+        assert!(mmr_pool(MatchType::Ranked, true).is_none());
+        assert!(mmr_pool(MatchType::Practice, false).is_none());
+    }
+
+    #[test]
+    fn instant_kill_only_fires_in_casual() {
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let kills = |match_type: MatchType, settings: &GameSettings| {
+            (0u32..400)
+                .filter(|i| {
+                    let mut battle = test_battle();
+                    battle.match_type = match_type.code();
+                    battle.damage_min = [5, 5];
+                    battle.damage_max = [5, 5];
+                    battle.crit_chance = [MAX_CRIT_CHANCE, 0];
+                    battle.player2_hp = 39; // Under 20% of 200, a plain crit deals 10
+                    battle.random_seed = hash(&i.to_le_bytes()).to_bytes();
+                    let outcome = calculate_damage(&attacker, &defender, &mut battle, true, false, settings).unwrap();
+                    assert!(outcome.was_crit);
+                    outcome.amount == 39
+                })
+                .count()
+        };
+
+        // About 5% of crits on a low target, never outside Casual
+        let mut settings = default_settings();
+        assert!((5..=40).contains(&kills(MatchType::Casual, &settings)));
+        for match_type in [MatchType::Ranked, MatchType::Tournament, MatchType::Staked] {
+            assert_eq!(kills(match_type, &settings), 0, "{:?}", match_type);
+        }
+
+        settings.instant_kill_enabled = false;
+        assert_eq!(kills(MatchType::Casual, &settings), 0);
+    }
+
+    #[test]
+    fn streaks_skip_forfeit_wins_and_cap_their_bonus() {
+        let win = |character: &mut Character, extends_streak: bool| {
+            on_account(character, |account| update_winner_stats(account, 100, None, 0, false, extends_streak)).unwrap()
+        };
+
+        let mut character = test_character(CharacterClass::Warrior);
+        character.current_streak = STREAK_XP_BONUS_START - 1;
+        assert_eq!(win(&mut character, true), 110);
+        assert_eq!(character.current_streak, STREAK_XP_BONUS_START);
+
+        // A timeout win neither extends the streak nor breaks it
+        win(&mut character, false);
+        assert_eq!(character.current_streak, STREAK_XP_BONUS_START);
+
+        character.current_streak = STREAK_MASTER_THRESHOLD - 1;
+        assert_eq!(win(&mut character, true), 100 + STREAK_XP_BONUS_CAP);
+        assert!(character.achievements.contains(&Achievement::StreakMaster));
+        assert_eq!(character.best_streak, STREAK_MASTER_THRESHOLD);
+
+        on_account(&mut character, |account| update_loser_stats(account, 0, None, 0)).unwrap();
+        assert_eq!((character.current_streak, character.best_streak), (0, STREAK_MASTER_THRESHOLD));
+    }
+
+    fn test_queue_entry(mmr: u64, joined_at: i64) -> QueueEntry {
+        QueueEntry {
+            player: Pubkey::new_unique(),
+            character: Pubkey::new_unique(),
+            mmr,
+            match_type: MatchType::Ranked,
+            stake_amount: 0,
+            joined_at,
+            matched: false,
+            mmr_bucket: mmr_bucket(mmr),
+            region: 0,
+        }
+    }
+
+    #[test]
+    fn queue_entries_expire_after_the_timeout() {
+        let settings = default_settings();
+        let entry = test_queue_entry(1_000, 1_000);
+        let at = |t: i64| Clock { unix_timestamp: t, ..Clock::default() };
+        let deadline = 1_000 + settings.queue_timeout_seconds;
+
+        assert!(!entry.has_expired(&settings, &at(1_000)));
+        assert!(!entry.has_expired(&settings, &at(deadline)));
+        assert!(entry.has_expired(&settings, &at(deadline + 1)));
+
+        // The refund can only go to the player who queued
+        let config = config_info(Pubkey::new_unique());
+        let load = |refund_to: Pubkey| {
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                leaked_info(Pubkey::new_unique(), crate::ID, 1, account_data(&entry), false),
+                leaked_info(refund_to, Pubkey::default(), 0, vec![], false),
+                config.clone(),
+            ]);
+            ExpireQueueEntry::try_accounts(
+                &crate::ID, &mut infos, &[], &mut ExpireQueueEntryBumps::default(), &mut std::collections::BTreeSet::new(),
+            )
+            .map(|_| ())
+        };
+        assert!(load(entry.player).is_ok());
+        assert!(matches!(load(Pubkey::new_unique()), Err(err) if err == ErrorCode::ConstraintHasOne.into()));
+    }
+
+    #[test]
+    fn unfinishable_pools_void_and_refund_every_bet() {
+        let settings = default_settings();
+        let at = |unix_timestamp| Clock { unix_timestamp, ..Clock::default() };
+        let mut battle = test_battle();
+        battle.created_at = 1_000;
+
+        // Not started yet, but still inside the start window
+        assert!(matches!(
+            void_reason(&battle, &settings, &at(1_000 + BETTING_START_WINDOW_SECONDS - 1)),
+            Err(err) if err == GameError::PoolNotVoidable.into()
+        ));
+        assert_eq!(
+            void_reason(&battle, &settings, &at(1_000 + BETTING_START_WINDOW_SECONDS)).unwrap(),
+            VoidReason::NeverStarted
+        );
+
+        // Once it has started only expiry voids it
+        battle.turn_number = 3;
+        assert!(void_reason(&battle, &settings, &at(1_000 + settings.battle_expiry_seconds - 1)).is_err());
+        assert_eq!(
+            void_reason(&battle, &settings, &at(1_000 + settings.battle_expiry_seconds)).unwrap(),
+            VoidReason::Expired
+        );
+
+        // An abandoned battle settles into refunds and the house takes nothing
+        let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        pool.player1_bets = 30_000_000;
+        pool.player2_bets = 20_000_000;
+        pool.total_pool = 50_000_000;
+        battle.is_finished = 1;
+        battle.abandoned = 1;
+        battle.winner = 1;
+        let (pool, to_treasury) = settle(pool, &battle).unwrap();
+        assert!(pool.refund_mode && pool.winner.is_none());
+        assert_eq!(to_treasury, 0);
+
+        // Either side gets its full stake back
+        let refund = |bet_on_player: u8, amount: u64| {
+            let bettor = Pubkey::new_unique();
+            let pool_key = Pubkey::new_unique();
+            let bet = Bet {
+                bettor,
+                betting_pool: pool_key,
+                amount,
+                bet_on_player,
+                is_claimed: false,
+                locked_odds: 0,
+                index: 0,
+            };
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                leaked_info(pool_key, crate::ID, 100_000_000, account_data(&pool), false),
+                leaked_info(Pubkey::new_unique(), crate::ID, 1, account_data(&bet), false),
+                leaked_info(bettor, Pubkey::default(), 0, vec![], true),
+            ]);
+            let mut accounts = ClaimBetRefund::try_accounts(
+                &crate::ID, &mut infos, &[], &mut ClaimBetRefundBumps::default(), &mut std::collections::BTreeSet::new(),
+            )
+            .unwrap();
+            my_program::claim_bet_refund(Context::new(&crate::ID, &mut accounts, &[], ClaimBetRefundBumps::default()))
+                .unwrap();
+            accounts.bettor.lamports()
+        };
+        assert_eq!(refund(1, 30_000_000), 30_000_000);
+        assert_eq!(refund(2, 20_000_000), 20_000_000);
+    }
+
+    #[test]
+    fn queue_entries_bucket_by_mmr_and_widen_while_waiting() {
+        assert_eq!(mmr_bucket(0), 0);
+        assert_eq!(mmr_bucket(MMR_BUCKET_SIZE - 1), 0);
+        assert_eq!(mmr_bucket(MMR_BUCKET_SIZE), 1);
+        assert_eq!(mmr_bucket(1_250), 12);
+        assert_eq!(mmr_bucket(u64::MAX), u16::MAX);
+        assert_eq!(test_queue_entry(1_250, 0).mmr_bucket, 12);
+
+        // One bucket per full step waited, capped, never negative on clock skew
+        let entry = test_queue_entry(1_000, 1_000);
+        assert_eq!(entry.priority_boost(900), 0);
+        assert_eq!(entry.priority_boost(1_000 + QUEUE_PRIORITY_STEP_SECONDS - 1), 0);
+        assert_eq!(entry.priority_boost(1_000 + QUEUE_PRIORITY_STEP_SECONDS), 1);
+        assert_eq!(entry.priority_boost(1_000 + 3 * QUEUE_PRIORITY_STEP_SECONDS), 3);
+        assert_eq!(entry.priority_boost(i64::MAX), MAX_QUEUE_PRIORITY_BOOST);
+    }
+
+    #[test]
+    fn betting_closed_is_announced_once_per_pool() {
+        let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        pool.betting_closes_at_turn = 1;
+        let battle_key = pool.battle;
+
+        let announce = |pool: &BettingPool, turn_number: u32| {
+            let mut battle = test_battle();
+            battle.turn_number = turn_number;
+            let mut battle_data = <Battle as anchor_lang::Discriminator>::DISCRIMINATOR.to_vec();
+            battle_data.extend_from_slice(bytemuck::bytes_of(&battle));
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                leaked_info(Pubkey::new_unique(), crate::ID, 1, account_data(pool), false),
+                leaked_info(battle_key, crate::ID, 1, battle_data, false),
+            ]);
+            let mut accounts = AnnounceBettingClosed::try_accounts(
+                &crate::ID, &mut infos, &[], &mut AnnounceBettingClosedBumps::default(), &mut std::collections::BTreeSet::new(),
+            )?;
+            my_program::announce_betting_closed(Context::new(
+                &crate::ID, &mut accounts, &[], AnnounceBettingClosedBumps::default(),
+            ))?;
+            Ok::<_, Error>((*accounts.betting_pool).clone())
+        };
+
+        // The earliest cutoff closes as soon as the first turn is played
+        assert!(matches!(announce(&pool, 0), Err(err) if err == GameError::BettingStillOpen.into()));
+        pool = announce(&pool, 1).unwrap();
+        assert!(pool.betting_closed_announced);
+        assert!(matches!(announce(&pool, 2), Err(err) if err == GameError::BettingClosed.into()));
+
+        let mut later = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        later.battle = battle_key;
+        later.betting_closes_at_turn = 3;
+        assert!(matches!(announce(&later, 2), Err(err) if err == GameError::BettingStillOpen.into()));
+        assert!(announce(&later, 3).unwrap().betting_closed_announced);
+    }
+
+    #[test]
+    fn one_wallet_can_hold_and_cancel_several_bets() {
+        let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        let pool_key = Pubkey::new_unique();
+        let bettor = Pubkey::new_unique();
+        let (tally_key, tally_bump) =
+            Pubkey::find_program_address(&[b"bet_tally", pool_key.as_ref(), bettor.as_ref()], &crate::ID);
+        let mut tally = BetTally { bettor, betting_pool: pool_key, total: 0, bump: tally_bump };
+
+        // Backing a side twice, then hedging the other, all under one tally
+        let mut bets = vec![];
+        for (index, (amount, side)) in [(20_000_000, 1), (10_000_000, 1), (15_000_000, 2)].into_iter().enumerate() {
+            check_bet_limits(&pool, tally.total, amount, side).unwrap();
+            tally.total += amount;
+            let locked_odds = book_bet(&mut pool, amount, side).unwrap();
+            bets.push(Bet {
+                bettor,
+                betting_pool: pool_key,
+                amount,
+                bet_on_player: side,
+                is_claimed: false,
+                locked_odds,
+                index: index as u32,
+            });
+        }
+        assert_eq!(tally.total, 45_000_000);
+        assert_eq!((pool.player1_bets, pool.player2_bets), (30_000_000, 15_000_000));
+
+        // Cancelling one bet takes it off the pool and the wallet's total
+        let battle_data = {
+            let mut data = <Battle as anchor_lang::Discriminator>::DISCRIMINATOR.to_vec();
+            data.extend_from_slice(bytemuck::bytes_of(&test_battle()));
+            data
+        };
+        let mut infos: &[AccountInfo] = Vec::leak(vec![
+            leaked_info(Pubkey::new_unique(), crate::ID, 1, account_data(&bets[1]), false),
+            leaked_info(pool_key, crate::ID, 100_000_000, account_data(&pool), false),
+            leaked_info(tally_key, crate::ID, 1, account_data(&tally), false),
+            leaked_info(pool.battle, crate::ID, 1, battle_data, false),
+            leaked_info(bettor, Pubkey::default(), 0, vec![], true),
+        ]);
+        let mut accounts = CancelBet::try_accounts(
+            &crate::ID, &mut infos, &[], &mut CancelBetBumps::default(), &mut std::collections::BTreeSet::new(),
+        )
+        .unwrap();
+        my_program::cancel_bet(Context::new(&crate::ID, &mut accounts, &[], CancelBetBumps::default())).unwrap();
+
+        assert_eq!(accounts.bet_tally.total, 35_000_000);
+        assert_eq!(accounts.betting_pool.player1_bets, 20_000_000);
+        assert_eq!(accounts.betting_pool.total_pool, 35_000_000);
+        assert_eq!(accounts.bettor.lamports(), 10_000_000);
+
+        // The freed room under the wallet cap can be bet again
+        let room = pool.max_bet_per_wallet - accounts.bet_tally.total;
+        assert!(check_bet_limits(&accounts.betting_pool, accounts.bet_tally.total, room, 2).is_ok());
+        assert!(check_bet_limits(&accounts.betting_pool, accounts.bet_tally.total, room + 1, 2).is_err());
+    }
+
+    #[test]
+    fn brackets_pair_top_seeds_with_bottom_seeds() {
+        let keys: Vec<Pubkey> = (0..5).map(|_| Pubkey::new_unique()).collect();
+
+        // Join order doesn't matter, only MMR
+        let seeded = vec![(1_100, keys[2]), (1_500, keys[0]), (900, keys[3]), (1_300, keys[1])];
+        assert_eq!(seed_bracket(seeded), vec![keys[0], keys[3], keys[1], keys[2]]);
+
+        // The odd one out is the top seed, sitting at the end with a bye
+        let seeded = vec![(1_100, keys[2]), (1_500, keys[0]), (900, keys[3]), (1_300, keys[1]), (700, keys[4])];
+        assert_eq!(seed_bracket(seeded), vec![keys[1], keys[4], keys[2], keys[3], keys[0]]);
+
+        // Equal MMRs fall back to the key so every validator seeds alike
+        let (low, high) = if keys[0] < keys[1] { (keys[0], keys[1]) } else { (keys[1], keys[0]) };
+        assert_eq!(seed_bracket(vec![(1_000, high), (1_000, low)]), vec![low, high]);
+        assert!(seed_bracket(vec![]).is_empty());
+    }
+
+    #[test]
+    fn house_edge_and_every_claim_empty_the_pool_exactly() {
+        // Stakes that don't divide evenly, to leave rounding dust
+        let stakes = [(10_000_001, 1), (20_000_000, 1), (3_333_333, 1), (7_777_777, 2)];
+        let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        for (amount, side) in stakes {
+            book_bet(&mut pool, amount, side).unwrap();
+        }
+        let total = pool.total_pool;
+        let mut battle = test_battle();
+        battle.is_finished = 1;
+        battle.winner = 1;
+
+        let (pool, to_treasury) = settle(pool, &battle).unwrap();
+        assert_eq!(to_treasury, total * DEFAULT_HOUSE_EDGE as u64 / 100);
+
+        let rent = Rent::default().minimum_balance(account_data(&pool).len());
+        let pool_info = leaked_info(Pubkey::new_unique(), crate::ID, rent + total - to_treasury, account_data(&pool), false);
+        let mut paid = 0;
+        for (index, (amount, side)) in stakes.into_iter().enumerate().filter(|(_, (_, side))| *side == 1) {
+            let bettor = Pubkey::new_unique();
+            let bet = Bet {
+                bettor,
+                betting_pool: pool_info.key(),
+                amount,
+                bet_on_player: side,
+                is_claimed: false,
+                locked_odds: 0,
+                index: index as u32,
+            };
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                pool_info.clone(),
+                leaked_info(Pubkey::new_unique(), crate::ID, 1, account_data(&bet), false),
+                leaked_info(bettor, Pubkey::default(), 0, vec![], true),
+            ]);
+            let mut accounts = ClaimBetWinnings::try_accounts(
+                &crate::ID, &mut infos, &[], &mut ClaimBetWinningsBumps::default(), &mut std::collections::BTreeSet::new(),
+            )
+            .unwrap();
+            my_program::claim_bet_winnings(Context::new(&crate::ID, &mut accounts, &[], ClaimBetWinningsBumps::default()))
+                .unwrap();
+            paid += accounts.bettor.lamports();
+            accounts.exit(&crate::ID).unwrap();
+        }
+
+        // Nothing is stranded: the winners and the treasury split every lamport
+        assert_eq!(paid + to_treasury, total);
+        assert_eq!(pool_info.lamports(), rent);
+    }
+
+    #[test]
+    fn whiffs_halve_the_attackers_combo() {
+        let mut settings = default_settings();
+        let mut battle = test_battle();
+        battle.player1_combo = 7;
+        battle.player2_combo = 5;
+
+        // Only the attacker's combo decays, rounding down
+        update_attack_combo(&mut battle, true, 0, BattleStance::Aggressive, &settings);
+        assert_eq!((battle.player1_combo, battle.player2_combo), (3, 5));
+        update_attack_combo(&mut battle, false, 0, BattleStance::Balanced, &settings);
+        assert_eq!(battle.player2_combo, 2);
+        for _ in 0..3 {
+            update_attack_combo(&mut battle, true, 0, BattleStance::Defensive, &settings);
+        }
+        assert_eq!(battle.player1_combo, 0);
+
+        // Counter's whiff keeps the combo unless the config says otherwise
+        settings.counter_keeps_combo = true;
+        update_attack_combo(&mut battle, false, 0, BattleStance::Counter, &settings);
+        assert_eq!(battle.player2_combo, 2);
+        settings.counter_keeps_combo = false;
+        update_attack_combo(&mut battle, false, 0, BattleStance::Counter, &settings);
+        assert_eq!(battle.player2_combo, 1);
+    }
+
+    #[test]
+    fn landed_hits_build_the_combo_up_to_the_cap() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let mut battle = test_battle();
+        battle.player1_hp = 100_000;
+        battle.player2_hp = 100_000;
+        battle.player1_max_hp = 100_000;
+        battle.player2_max_hp = 100_000;
+
+        // No dodge chance, so every swing lands and counts
+        for hit in 1..=MAX_HIT_COMBO + 2 {
+            let hp_before = battle.player2_hp;
+            execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+            assert!(battle.player2_hp < hp_before);
+            assert_eq!(battle.player1_combo, hit.min(MAX_HIT_COMBO));
+            execute_battle_turn(&mut battle, Pubkey::default(), &defender, &attacker, false, false, &settings).unwrap();
+        }
+        assert_eq!(battle.player1_peak_combo, MAX_HIT_COMBO);
+        assert_eq!(battle.player2_combo, MAX_HIT_COMBO);
+
+        // Past the cap only wildcards raise it, and the peak follows
+        battle.player1_combo = MAX_HIT_COMBO + 3;
+        update_attack_combo(&mut battle, true, 15, BattleStance::Aggressive, &settings);
+        assert_eq!(battle.player1_combo, MAX_HIT_COMBO + 3);
+        record_peak_combos(&mut battle);
+        assert_eq!(battle.player1_peak_combo, MAX_HIT_COMBO + 3);
+    }
+
+    #[test]
+    fn timeout_stakes_go_to_the_player_left_standing() {
+        let mut settings = default_settings();
+        let (owner1, owner2) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut battle = test_battle();
+        battle.stake_amount = 1_000_000;
+
+        // PvP escrows both stakes, the player who didn't time out takes them
+        assert_eq!(escrowed_stake(&battle), 2_000_000);
+        battle.winner = 1;
+        assert_eq!(timeout_stake_recipient(&battle, &settings, owner1, owner2), Some(owner1));
+        battle.winner = 2;
+        assert_eq!(timeout_stake_recipient(&battle, &settings, owner1, owner2), Some(owner2));
+
+        // Against the AI only the player's stake is escrowed
+        battle.is_vs_ai = 1;
+        assert_eq!(escrowed_stake(&battle), 1_000_000);
+        battle.winner = 1;
+        assert_eq!(timeout_stake_recipient(&battle, &settings, owner1, owner2), Some(owner1));
+
+        // A player who times out on the AI gets it back only when configured
+        battle.winner = 2;
+        settings.pve_timeout_refunds_stake = true;
+        assert_eq!(timeout_stake_recipient(&battle, &settings, owner1, owner2), Some(owner1));
+        settings.pve_timeout_refunds_stake = false;
+        assert_eq!(timeout_stake_recipient(&battle, &settings, owner1, owner2), None);
+    }
+
+    #[test]
+    fn fixed_odds_pay_locked_odds_and_sweep_the_surplus() {
+        let mut pool = test_pool(OddsMode::FixedAtBet, BetMarket::Winner);
+        let first = book_bet(&mut pool, 10_000_000, 1).unwrap();
+        let second = book_bet(&mut pool, 30_000_000, 2).unwrap();
+
+        // Both quoted at the opening 50, the odds moving afterwards changes neither
+        assert_eq!((first, second), (50, 50));
+        assert_eq!(pool.player1_odds, 75);
+        assert_eq!((pool.player1_liability, pool.player2_liability), (20_000_000, 60_000_000));
+
+        let bet = |amount, bet_on_player, locked_odds| Bet {
+            bettor: Pubkey::new_unique(),
+            betting_pool: Pubkey::new_unique(),
+            amount,
+            bet_on_player,
+            is_claimed: false,
+            locked_odds,
+            index: 0,
+        };
+        let mut battle = test_battle();
+        battle.is_finished = 1;
+
+        // Player 1 winning owes 20M of the 38M left after the edge, the rest is the house's
+        battle.winner = 1;
+        let (settled, to_treasury) = settle(pool.clone(), &battle).unwrap();
+        assert_eq!(to_treasury, 2_000_000 + 18_000_000);
+        assert_eq!(bet_winnings(&settled, &bet(10_000_000, 1, first)), 20_000_000);
+
+        // Player 2 winning is a short book, winners split what there is
+        battle.winner = 2;
+        let (settled, to_treasury) = settle(pool.clone(), &battle).unwrap();
+        assert_eq!(to_treasury, 2_000_000);
+        assert_eq!(bet_winnings(&settled, &bet(30_000_000, 2, second)), 38_000_000);
+
+        // How short a book may run is capped when the bet is placed
+        let mut capped = test_pool(OddsMode::FixedAtBet, BetMarket::Winner);
+        capped.max_exposure = 0;
+        assert!(matches!(
+            book_bet(&mut capped, 10_000_000, 1),
+            Err(err) if err == GameError::BetExceedsExposure.into()
+        ));
+    }
+
+    #[test]
+    fn starting_stats_come_from_the_config_table() {
+        let mut settings = default_settings();
+        let classes = [
+            CharacterClass::Warrior,
+            CharacterClass::Assassin,
+            CharacterClass::Mage,
+            CharacterClass::Tank,
+            CharacterClass::Trickster,
+            CharacterClass::Paladin,
+        ];
+        for class in classes {
+            let character = test_character(class);
+            let stats = CLASS_BASE_STATS[class as usize];
+            assert_eq!((character.max_hp, character.current_hp), (stats.max_hp, stats.max_hp));
+            assert_eq!((character.base_damage_min, character.base_damage_max), (stats.damage_min, stats.damage_max));
+            assert_eq!((character.crit_chance, character.dodge_chance), (stats.crit_chance, stats.dodge_chance));
+        }
+
+        // A rebalance only has to touch the config
+        settings.class_stats[CharacterClass::Tank as usize].max_hp = 200;
+        let mut tank = test_character(CharacterClass::Tank);
+        tank.defense = 4;
+        set_class_base_stats(&mut tank, &settings);
+        assert_eq!((tank.max_hp, tank.current_hp, tank.defense), (200, 200, 0));
+        assert!(validate_settings(&settings).is_ok());
+
+        // Each row is validated like any other setting
+        let invalid = [
+            ClassStats { max_hp: 0, ..CLASS_BASE_STATS[0] },
+            ClassStats { damage_min: 0, ..CLASS_BASE_STATS[0] },
+            ClassStats { damage_min: 16, ..CLASS_BASE_STATS[0] },
+            ClassStats { crit_chance: MAX_CRIT_CHANCE + 1, ..CLASS_BASE_STATS[0] },
+            ClassStats { dodge_chance: MAX_DODGE_CHANCE + 1, ..CLASS_BASE_STATS[0] },
+        ];
+        for stats in invalid {
+            settings.class_stats[CharacterClass::Warrior as usize] = stats;
+            assert!(validate_settings(&settings).is_err());
+        }
+    }
+
+    #[test]
+    fn the_last_battle_starts_the_cooldown() {
+        let mut settings = default_settings();
+        settings.min_battle_interval_seconds = 30;
+        let at = |unix_timestamp| Clock { unix_timestamp, ..Clock::default() };
+        let mut character = test_character(CharacterClass::Warrior);
+
+        // A new character has never fought
+        assert!(check_battle_cooldown(&character, &settings, &at(1_700_000_000)).is_ok());
+
+        character.last_battle = 1_700_000_000;
+        assert!(matches!(
+            check_battle_cooldown(&character, &settings, &at(1_700_000_029)),
+            Err(err) if err == GameError::BattleCooldownActive.into()
+        ));
+        assert!(check_battle_cooldown(&character, &settings, &at(1_700_000_030)).is_ok());
+    }
+
+    #[test]
+    fn opening_odds_never_divide_by_zero() {
+        // Propositions, or two sides that score nothing, open even
+        assert_eq!(opening_odds(0, 0), (50, 50));
+        assert_eq!(opening_odds(300, 100), (25, 75));
+        assert_eq!(opening_odds(0, 100), (100, 0));
+
+        // A character with no record and no max HP still scores
+        let mut fresh = test_character(CharacterClass::Mage);
+        fresh.max_hp = 0;
+        fresh.total_wins = 0;
+        fresh.total_losses = 0;
+        let score = calculate_betting_score(&fresh, 0);
+        assert_eq!(score, fresh.level as u64 * 10 + 50 + fresh.ranked_mmr / 10);
+    }
+
+    #[test]
+    fn lucky_seven_reads_this_turns_damage_roll() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+
+        // With neutral stances the hit is the roll, and its ones digit is kept
+        let mut battle = test_battle();
+        battle.player1_stance = BattleStance::Balanced.code();
+        battle.player2_stance = BattleStance::Balanced.code();
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        assert!(battle.player2_hp < 200);
+        assert_eq!(battle.last_damage_roll as u64, (200 - battle.player2_hp) % 10);
+
+        // Only a roll ending in 7 pays out
+        let mut battle = test_battle();
+        battle.wildcard_active = 1;
+        battle.wildcard_type = WildcardEvent::LuckySeven.code();
+        battle.last_damage_roll = 7;
+        assert_eq!(apply_wildcard_effects(17, &mut battle, Pubkey::default(), true).unwrap(), 119);
+        battle.last_damage_roll = 3;
+        assert_eq!(apply_wildcard_effects(13, &mut battle, Pubkey::default(), true).unwrap(), 13);
+    }
+
+    #[test]
+    fn the_last_winning_claim_takes_the_dust() {
+        let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        pool.house_edge = 0;
+        pool.player1_bets = 3;
+        pool.player2_bets = 97;
+        pool.total_pool = 100;
+        pool.is_settled = true;
+        pool.winner = Some(1);
+
+        let bet = Bet {
+            bettor: Pubkey::new_unique(),
+            betting_pool: Pubkey::new_unique(),
+            amount: 1,
+            bet_on_player: 1,
+            is_claimed: false,
+            locked_odds: 0,
+            index: 0,
+        };
+
+        // Three equal bets on 100 lamports: 33, 33, then the remaining 34
+        let mut paid = vec![];
+        for _ in 0..3 {
+            let winnings = bet_winnings(&pool, &bet);
+            pool.total_claimed += winnings;
+            pool.claimed_winning_bets += bet.amount;
+            paid.push(winnings);
+        }
+        assert_eq!(paid, vec![33, 33, 34]);
+        assert_eq!(pool.total_claimed, pool.total_pool);
+    }
+
+    #[test]
+    fn bets_can_only_be_cancelled_before_the_battle_in_parimutuel() {
+        let cancel = |odds_mode: OddsMode, turn_number: u32| {
+            let mut pool = test_pool(odds_mode, BetMarket::Winner);
+            let pool_key = Pubkey::new_unique();
+            let bettor = Pubkey::new_unique();
+            let (tally_key, bump) =
+                Pubkey::find_program_address(&[b"bet_tally", pool_key.as_ref(), bettor.as_ref()], &crate::ID);
+            let tally = BetTally { bettor, betting_pool: pool_key, total: 10_000_000, bump };
+            let locked_odds = book_bet(&mut pool, 10_000_000, 1).unwrap();
+            let bet = Bet {
+                bettor,
+                betting_pool: pool_key,
+                amount: 10_000_000,
+                bet_on_player: 1,
+                is_claimed: false,
+                locked_odds,
+                index: 0,
+            };
+            let mut battle = test_battle();
+            battle.turn_number = turn_number;
+            let mut battle_data = <Battle as anchor_lang::Discriminator>::DISCRIMINATOR.to_vec();
+            battle_data.extend_from_slice(bytemuck::bytes_of(&battle));
+
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                leaked_info(Pubkey::new_unique(), crate::ID, 1, account_data(&bet), false),
+                leaked_info(pool_key, crate::ID, 100_000_000, account_data(&pool), false),
+                leaked_info(tally_key, crate::ID, 1, account_data(&tally), false),
+                leaked_info(pool.battle, crate::ID, 1, battle_data, false),
+                leaked_info(bettor, Pubkey::default(), 0, vec![], true),
+            ]);
+            let mut accounts = CancelBet::try_accounts(
+                &crate::ID, &mut infos, &[], &mut CancelBetBumps::default(), &mut std::collections::BTreeSet::new(),
+            )?;
+            my_program::cancel_bet(Context::new(&crate::ID, &mut accounts, &[], CancelBetBumps::default()))?;
+            Ok::<_, Error>((accounts.bettor.lamports(), accounts.betting_pool.total_pool, accounts.bet_tally.total))
+        };
+
+        assert_eq!(cancel(OddsMode::Parimutuel, 0).unwrap(), (10_000_000, 0, 0));
+        assert!(matches!(cancel(OddsMode::Parimutuel, 1), Err(err) if err == GameError::BettingClosed.into()));
+        assert!(matches!(cancel(OddsMode::FixedAtBet, 0), Err(err) if err == GameError::FixedOddsBetFinal.into()));
+    }
+
+    #[test]
+    fn bets_respect_the_minimum_and_each_sides_cap() {
+        let mut pool = test_pool(OddsMode::Parimutuel, BetMarket::Winner);
+        assert!(matches!(
+            check_bet_limits(&pool, 0, pool.min_bet - 1, 1),
+            Err(err) if err == GameError::BetTooSmall.into()
+        ));
+        assert!(check_bet_limits(&pool, 0, pool.min_bet, 1).is_ok());
+        assert!(matches!(
+            check_bet_limits(&pool, 0, pool.max_bet_per_wallet + 1, 1),
+            Err(err) if err == GameError::BetTooLarge.into()
+        ));
+
+        // A full side still takes bets on the other one
+        pool.player1_bets = pool.max_side_total;
+        pool.total_pool = pool.max_side_total;
+        assert!(matches!(
+            check_bet_limits(&pool, 0, pool.min_bet, 1),
+            Err(err) if err == GameError::BetSideFull.into()
+        ));
+        assert!(check_bet_limits(&pool, 0, pool.min_bet, 2).is_ok());
+
+        // Per-pool limits have to nest
+        let limits = default_settings().bet_limits;
+        assert!(limits.is_valid());
+        assert!(!BetLimits { min_bet: 0, ..limits }.is_valid());
+        assert!(!BetLimits { min_bet: limits.max_bet_per_wallet + 1, ..limits }.is_valid());
+        assert!(!BetLimits { max_bet_per_wallet: limits.max_pool_size + 1, ..limits }.is_valid());
+    }
+
+    #[test]
+    fn dot_ticks_once_a_round_whoever_acts() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let mut battle = test_battle();
+        battle.dodge_chance = [100, 100]; // Only the DOT lands
+        battle.player1_dot_damage = 5;
+        battle.player1_dot_turns = 2;
+        battle.player2_dot_damage = 7;
+        battle.player2_dot_turns = 2;
+
+        // Both victims tick at the start of the round, not on their own turns
+        let mut hp = vec![];
+        for turn in 0..5 {
+            let is_player1 = turn % 2 == 0;
+            let (a, d) = if is_player1 { (&attacker, &defender) } else { (&defender, &attacker) };
+            execute_battle_turn(&mut battle, Pubkey::default(), a, d, is_player1, false, &settings).unwrap();
+            hp.push((battle.player1_hp, battle.player2_hp));
+        }
+        assert_eq!(hp, vec![(195, 193), (195, 193), (190, 186), (190, 186), (190, 186)]);
+        assert_eq!((battle.player1_dot_turns, battle.player2_dot_turns), (0, 0));
+    }
+
+    #[test]
+    fn healing_never_passes_max_hp() {
+        let mut battle = test_battle();
+        battle.player1_hp = 150;
+        battle.player2_hp = 10;
+
+        heal_player(&mut battle, 1, 30);
+        assert_eq!(battle.player1_hp, 180);
+        heal_player(&mut battle, 1, 30);
+        assert_eq!(battle.player1_hp, 200);
+        heal_player(&mut battle, 2, u64::MAX);
+        assert_eq!(battle.player2_hp, 200);
+
+        // A swap that left the battle's HP above a smaller max still caps
+        battle.player1_hp = 5;
+        battle.player1_max_hp = 120;
+        heal_player_to_full(&mut battle, 1);
+        assert_eq!(battle.player1_hp, 120);
+    }
+
+    #[test]
+    fn a_reveal_cant_be_replayed_elsewhere() {
+        let (battle, player) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let committed = stance_commitment(BattleStance::Defensive, 0, false, 42, &battle, 3, &player);
+
+        // Another salt, battle, turn or player each give another hash
+        let replays = [
+            stance_commitment(BattleStance::Defensive, 0, false, 43, &battle, 3, &player),
+            stance_commitment(BattleStance::Defensive, 0, false, 42, &Pubkey::new_unique(), 3, &player),
+            stance_commitment(BattleStance::Defensive, 0, false, 42, &battle, 5, &player),
+            stance_commitment(BattleStance::Defensive, 0, false, 42, &battle, 3, &Pubkey::new_unique()),
+        ];
+        for replay in replays {
+            assert_ne!(committed, replay);
+        }
+
+        // And so does another stance under the same salt
+        assert_ne!(committed, stance_commitment(BattleStance::Aggressive, 0, false, 42, &battle, 3, &player));
+    }
+
+    #[test]
+    fn reveals_wait_for_both_commits() {
+        let mut battle = test_battle();
+        let not_committed = |battle: &Battle, is_acting| {
+            matches!(check_can_reveal(battle, is_acting), Err(err) if err == GameError::OpponentNotCommitted.into())
+        };
+
+        // Committing first gives nothing away, even to the acting player
+        assert!(not_committed(&battle, true));
+        battle.player1_stance_committed = 1;
+        assert!(not_committed(&battle, true));
+        assert!(not_committed(&battle, false));
+        battle.player2_stance_committed = 1;
+        assert!(check_can_reveal(&battle, true).is_ok());
+        assert!(check_can_reveal(&battle, false).is_ok());
+
+        // The AI never commits, so only the acting human reveals
+        let mut battle = test_battle();
+        battle.is_vs_ai = 1;
+        assert!(check_can_reveal(&battle, true).is_ok());
+        assert!(matches!(check_can_reveal(&battle, false), Err(err) if err == GameError::NotYourTurn.into()));
+    }
+
+    #[test]
+    fn simulated_range_covers_the_real_turn() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let mut battle = test_battle();
+        battle.player1_stance = BattleStance::Balanced.code();
+        battle.player2_stance = BattleStance::Balanced.code();
+
+        let simulation = simulate_damage(
+            &attacker, &defender, &battle, true, BattleStance::Balanced, BattleStance::Balanced, false,
+        );
+        assert!(simulation.min_damage <= simulation.expected_damage);
+        assert!(simulation.expected_damage <= simulation.max_damage);
+        assert_eq!(simulation.dodge_chance, 0);
+        assert_eq!(simulation.hit_chance, 100);
+
+        // The real turn lands inside the simulated range
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        let dealt = 200 - battle.player2_hp;
+        assert!((simulation.min_damage..=simulation.max_damage).contains(&dealt), "{}", dealt);
+
+        // A sure dodge leaves nothing to hit
+        battle.dodge_chance = [0, 100];
+        let dodged = simulate_damage(
+            &attacker, &defender, &battle, true, BattleStance::Balanced, BattleStance::Balanced, false,
+        );
+        assert_eq!((dodged.hit_chance, dodged.dodge_chance), (0, 100));
+    }
+
+    #[test]
+    fn counter_answers_incoming_attacks_only() {
+        // An aggressive swing into Counter is halved and 60% of it comes back
+        assert_eq!(apply_stance_modifiers(100, BattleStance::Aggressive, BattleStance::Counter), (65, 78));
+        assert_eq!(apply_stance_modifiers(100, BattleStance::Berserker, BattleStance::Counter), (100, 120));
+
+        // Anything else walks into Counter unpunished
+        assert_eq!(apply_stance_modifiers(100, BattleStance::Balanced, BattleStance::Counter), (100, 0));
+        assert_eq!(apply_stance_modifiers(100, BattleStance::Defensive, BattleStance::Counter), (70, 0));
+
+        // Counter's own swing does nothing and provokes nothing, whatever the defender does
+        for defender in [BattleStance::Aggressive, BattleStance::Counter, BattleStance::Defensive] {
+            assert_eq!(apply_stance_modifiers(100, BattleStance::Counter, defender), (0, 0));
+        }
+    }
+
+    #[test]
+    fn berserker_self_damage_ends_the_battle_on_the_same_turn() {
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let berserk = |player1_hp, player2_hp| {
+            let mut battle = test_battle();
+            battle.player1_stance = BattleStance::Berserker.code();
+            battle.player1_hp = player1_hp;
+            battle.player2_hp = player2_hp;
+            execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+            battle
+        };
+
+        // Knocking itself out loses, even though the target still stands
+        let battle = berserk(1, 200);
+        assert_eq!(battle.player1_hp, 0);
+        assert!(battle.is_finished != 0 && battle.winner == 2);
+
+        // Both down on one swing is a draw, not a win for whoever swung
+        let battle = berserk(1, 1);
+        assert!(battle.is_finished != 0 && battle.is_draw != 0 && battle.winner == 0);
+
+        // A dodged swing costs nothing
+        let mut battle = test_battle();
+        battle.player1_stance = BattleStance::Berserker.code();
+        battle.player1_hp = 1;
+        battle.dodge_chance = [0, 100];
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        assert_eq!((battle.player1_hp, battle.is_finished), (1, 0));
+    }
+
+    #[test]
+    fn max_combo_keeps_the_best_battle_peak() {
+        let settings = default_settings();
+        let mut battle = test_battle();
+        for _ in 0..4 {
+            update_attack_combo(&mut battle, true, 10, BattleStance::Balanced, &settings);
+            record_peak_combos(&mut battle);
+        }
+        // The whiff halves the live combo, the peak stays
+        update_attack_combo(&mut battle, true, 0, BattleStance::Balanced, &settings);
+        record_peak_combos(&mut battle);
+        assert_eq!((battle.player1_combo, battle.player1_peak_combo), (2, 4));
+
+        let mut character = test_character(CharacterClass::Warrior);
+        on_account(&mut character, |account| update_combo_stats(account, battle.player1_peak_combo));
+        assert_eq!(character.max_combo, 4);
+
+        // A weaker battle later doesn't lower it
+        on_account(&mut character, |account| update_combo_stats(account, 2));
+        assert_eq!(character.max_combo, 4);
+    }
+
+    #[test]
+    fn turn_seeds_mix_both_salts_and_are_used_once() {
+        let slot_hash = [7u8; 32];
+        let fulfilled = || {
+            let mut battle = test_battle();
+            battle.random_seed = [1u8; 32];
+            battle.seed_turn = battle.turn_number;
+            battle.player1_salt = 11;
+            battle.player2_salt = 22;
+            battle
+        };
+        let seed_with = |change: fn(&mut Battle)| {
+            let mut battle = fulfilled();
+            change(&mut battle);
+            take_turn_seed(&mut battle, &slot_hash).unwrap();
+            battle.random_seed
+        };
 
-// Updated Battle account with all new fields
-#[account]
-#[derive(InitSpace)]
-pub struct Battle {
-    pub player1: Pubkey,
-    pub player2: Pubkey,
-    pub match_type: MatchType,
-    pub stake_amount: u64,
-    pub created_at: i64,
-    pub turn_number: u32,
-    pub current_turn: u8,
-    pub is_finished: bool,
-    pub winner: Option<u8>,
-    pub is_vs_ai: bool,
-    pub abandoned: bool,
-    pub last_action_time: i64,
-    
-    // Battle state
-    pub player1_hp: u64,
-    pub player2_hp: u64,
-    pub player1_combo: u16,
-    pub player2_combo: u16,
-    pub player1_stance: BattleStance,
-    pub player2_stance: BattleStance,
-    
-    // Stance commitment system
-    pub player1_stance_committed: bool,
-    pub player2_stance_committed: bool,
-    pub player1_stance_hash: [u8; 32],
-    pub player2_stance_hash: [u8; 32],
-    
-    // DOT and effects
-    pub player1_dot_damage: u64,
-    pub player2_dot_damage: u64,
-    pub player1_dot_turns: u8,
-    pub player2_dot_turns: u8,
-    pub player1_reflection: u16,
-    pub player2_reflection: u16,
-    pub player1_miss_count: u16,
-    pub player2_miss_count: u16,
-    
-    // Special cooldowns
-    pub player1_special_cooldown: u8,
-    pub player2_special_cooldown: u8,
-    
-    // Wildcard system
-    pub last_damage_roll: u8,
-    pub wildcard_active: bool,
-    pub wildcard_type: Option<WildcardEvent>,
-    pub wildcard_decision_deadline: i64,
-    pub wildcard_player1_decision: Option<bool>,
-    pub wildcard_player2_decision: Option<bool>,
-    
-    // Battle log
-    #[max_len(50)]
-    pub battle_log: Vec<String>,
-}
+        // Either player's salt alone changes every roll of the turn
+        let seed = seed_with(|_| {});
+        assert_ne!(seed, [1u8; 32]);
+        assert_ne!(seed, seed_with(|battle| battle.player1_salt = 12));
+        assert_ne!(seed, seed_with(|battle| battle.player2_salt = 23));
+        assert_ne!(seed, seed_with(|battle| battle.random_seed = [2u8; 32]));
+
+        // A seed is good for one turn resolution
+        let mut battle = fulfilled();
+        take_turn_seed(&mut battle, &slot_hash).unwrap();
+        assert!(matches!(
+            take_turn_seed(&mut battle, &slot_hash),
+            Err(err) if err == GameError::RandomnessNotReady.into()
+        ));
+
+        // The slot hash comes from the newest SlotHashes entry
+        let mut data = vec![0u8; 16];
+        data.extend_from_slice(&slot_hash);
+        let slot_hashes = leaked_info(Pubkey::new_unique(), Pubkey::default(), 1, data, false);
+        assert_eq!(recent_slot_hash(&slot_hashes).unwrap(), slot_hash);
+        let short = leaked_info(Pubkey::new_unique(), Pubkey::default(), 1, vec![0u8; 47], false);
+        assert!(recent_slot_hash(&short).is_err());
+    }
 
-// Existing BettingPool (unchanged)
-#[account]
-#[derive(InitSpace)]
-pub struct BettingPool {
-    pub battle: Pubkey,
-    pub total_pool: u64,
-    pub player1_bets: u64,
-    pub player2_bets: u64,
-    pub player1_odds: u64,
-    pub player2_odds: u64,
-    pub house_edge: u8,
-    pub is_settled: bool,
-    pub winner: Option<u8>,
-    pub created_at: i64,
-}
+    #[test]
+    fn inactive_ranks_decay_once_per_period_down_to_silver() {
+        let at = |unix_timestamp| Clock { unix_timestamp, ..Clock::default() };
+        let mut character = test_character(CharacterClass::Warrior);
+        character.ranked_mmr = 1_100;
+        character.last_battle = 1_000_000;
+        character.last_decay = 0;
+        let decay_start = 1_000_000 + RANK_DECAY_INACTIVITY_SECONDS;
+        let not_due = |result: Result<u64>| matches!(result, Err(err) if err == GameError::NoRankDecayDue.into());
+
+        // Inside the grace period, and the first period after it, nothing is due
+        assert!(not_due(apply_rank_decay(&mut character, &at(decay_start - 1))));
+        assert!(not_due(apply_rank_decay(&mut character, &at(decay_start + RANK_DECAY_PERIOD_SECONDS - 1))));
+
+        // Two full periods at once, then the same periods aren't charged again
+        let now = decay_start + 2 * RANK_DECAY_PERIOD_SECONDS + 5;
+        assert_eq!(apply_rank_decay(&mut character, &at(now)).unwrap(), 2 * RANK_DECAY_AMOUNT);
+        assert_eq!(character.ranked_mmr, 1_050);
+        assert!(not_due(apply_rank_decay(&mut character, &at(now))));
+
+        // Decay stops at the floor, and never lifts anyone below it
+        let later = now + 10 * RANK_DECAY_PERIOD_SECONDS;
+        assert_eq!(apply_rank_decay(&mut character, &at(later)).unwrap(), 50);
+        assert_eq!(character.ranked_mmr, RANK_DECAY_FLOOR_MMR);
+        character.ranked_mmr = 800;
+        assert_eq!(apply_rank_decay(&mut character, &at(later + RANK_DECAY_PERIOD_SECONDS)).unwrap(), 0);
+        assert_eq!(character.ranked_mmr, 800);
+    }
 
-// Existing Bet (unchanged)
-#[account]
-#[derive(InitSpace)]
-pub struct Bet {
-    pub bettor: Pubkey,
-    pub betting_pool: Pubkey,
-    pub amount: u64,
-    pub bet_on_player: u8,
-    pub is_claimed: bool,
-}
+    #[test]
+    fn turn_breakdown_multipliers_match_the_damage_math() {
+        // stance_modifier is the percent a 100 damage swing ends up at
+        let modifier = |attacker, defender| stance_adjusted_damage(100, attacker, defender);
+        assert_eq!(modifier(BattleStance::Balanced, BattleStance::Balanced), 100);
+        assert_eq!(modifier(BattleStance::Aggressive, BattleStance::Balanced), 130);
+        assert_eq!(modifier(BattleStance::Aggressive, BattleStance::Aggressive), 195);
+        assert_eq!(modifier(BattleStance::Berserker, BattleStance::Defensive), 100);
+        assert_eq!(modifier(BattleStance::Venomous, BattleStance::Balanced), 80);
+        assert_eq!(modifier(BattleStance::Counter, BattleStance::Aggressive), 0);
+
+        // combo_multiplier is the percent combo_damage applies
+        let mut battle = test_battle();
+        battle.player1_combo = 4;
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let outcome = calculate_damage(&attacker, &defender, &mut battle, true, false, &default_settings()).unwrap();
+        assert_eq!(outcome.combo_multiplier, 160);
+        assert_eq!(combo_damage(100, 4), 160);
+    }
 
-// Updated CharacterClass with Trickster
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
-pub enum CharacterClass {
-    Warrior,
-    Assassin,
-    Mage,
-    Tank,
-    Trickster, // New class!
-}
+    #[test]
+    fn divine_light_heals_the_paladin_even_when_dodged() {
+        let settings = default_settings();
+        let paladin = test_character(CharacterClass::Paladin);
+        let defender = test_character(CharacterClass::Mage);
+        let heal = |player1_hp, dodge| {
+            let mut battle = test_battle();
+            battle.player1_stance = BattleStance::Balanced.code();
+            battle.player1_hp = player1_hp;
+            battle.dodge_chance = [0, dodge];
+            execute_battle_turn(&mut battle, Pubkey::default(), &paladin, &defender, true, true, &settings).unwrap();
+            (battle.player1_hp, battle.player2_hp, battle.player1_special_cooldown)
+        };
 
-impl CharacterClass {
-    pub fn to_string(&self) -> &str {
-        match self {
-            CharacterClass::Warrior => "Warrior",
-            CharacterClass::Assassin => "Assassin",
-            CharacterClass::Mage => "Mage",
-            CharacterClass::Tank => "Tank",
-            CharacterClass::Trickster => "Trickster",
+        // 30% of max HP, whether or not the attack lands
+        let (hp, target_hp, cooldown) = heal(100, 0);
+        assert_eq!(hp, 160);
+        assert!(target_hp < 200);
+        assert_eq!(cooldown, special_cooldown_for(CharacterClass::Paladin, &settings));
+        assert_eq!(heal(100, 100), (160, 200, cooldown));
+
+        // Capped at max HP
+        assert_eq!(heal(190, 100).0, 200);
+    }
+
+    #[test]
+    fn venom_stacks_to_its_cap_and_refreshes() {
+        let mut battle = test_battle();
+        for stacks in 1..=6 {
+            stack_venom(&mut battle, Pubkey::default(), 2);
+            assert_eq!(battle.player2_dot_damage, (stacks * VENOM_DOT_PER_STACK).min(VENOM_DOT_CAP));
         }
+        assert_eq!(battle.player2_dot_turns, VENOM_DOT_TURNS);
+        assert_eq!((battle.player1_dot_damage, battle.player1_dot_turns), (0, 0));
+
+        // A stronger DOT already running isn't weakened or shortened
+        battle.player1_dot_damage = 25;
+        battle.player1_dot_turns = 5;
+        stack_venom(&mut battle, Pubkey::default(), 1);
+        assert_eq!((battle.player1_dot_damage, battle.player1_dot_turns), (25, 5));
+
+        // A dodged Venomous swing doesn't poison
+        let settings = default_settings();
+        let attacker = test_character(CharacterClass::Warrior);
+        let defender = test_character(CharacterClass::Mage);
+        let mut battle = test_battle();
+        battle.player1_stance = BattleStance::Venomous.code();
+        battle.dodge_chance = [0, 100];
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        assert_eq!(battle.player2_dot_turns, 0);
+        battle.dodge_chance = [0, 0];
+        execute_battle_turn(&mut battle, Pubkey::default(), &attacker, &defender, true, false, &settings).unwrap();
+        assert_eq!((battle.player2_dot_damage, battle.player2_dot_turns), (VENOM_DOT_PER_STACK, VENOM_DOT_TURNS));
     }
-}
 
-// Existing enums (unchanged)
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
-pub enum MatchType {
-    Casual,
-    Ranked,
-    Tournament,
-    Staked,
-}
+    #[test]
+    fn only_the_admin_updates_valid_settings() {
+        let admin = Pubkey::new_unique();
+        let config = config_info(admin);
+        let update = |signer: Pubkey, settings: GameSettings| {
+            let mut infos: &[AccountInfo] =
+                Vec::leak(vec![config.clone(), leaked_info(signer, Pubkey::default(), 1, vec![], true)]);
+            let mut accounts = UpdateConfig::try_accounts(
+                &crate::ID, &mut infos, &[], &mut UpdateConfigBumps::default(), &mut std::collections::BTreeSet::new(),
+            )?;
+            my_program::update_config(Context::new(&crate::ID, &mut accounts, &[], UpdateConfigBumps::default()), settings)?;
+            accounts.exit(&crate::ID)
+        };
+        let stored = || GameConfig::try_deserialize(&mut &config.try_borrow_data().unwrap()[..]).unwrap().settings;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
-pub enum BattleStance {
-    Aggressive,
-    Defensive,
-    Balanced,
-    Berserker,
-    Counter,
-}
+        let tuned = GameSettings { turn_timeout_seconds: 90, ..default_settings() };
+        assert!(matches!(update(Pubkey::new_unique(), tuned), Err(err) if err == ErrorCode::ConstraintHasOne.into()));
+        assert_eq!(stored(), default_settings());
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace, Debug)]
-pub enum WildcardEvent {
-    DoubleOrNothing,
-    ReverseRoles,
-    MysteryBox,
-    DeathRoulette,
-    ComboBreaker,
-    TimeWarp,
-    LuckySeven,
-    GamblersFallacy,
-}
+        update(admin, tuned).unwrap();
+        assert_eq!(stored(), tuned);
 
-// All remaining account contexts
+        // A typo is rejected and the running settings stay
+        let typo = GameSettings { turn_timeout_seconds: 0, ..tuned };
+        assert!(matches!(update(admin, typo), Err(err) if err == GameError::InvalidConfig.into()));
+        assert_eq!(stored(), tuned);
+    }
 
-#[derive(Accounts)]
-#[instruction(character_class: CharacterClass, name: String)]
-pub struct CreateCharacter<'info> {
-    #[account(
-        init,
-        payer = owner,
-        space = 8 + Character::INIT_SPACE,
-        seeds = [b"character", name.as_bytes(), owner.key().as_ref()],
-        bump
-    )]
-    pub character: Account<'info, Character>,
-    #[account(mut)]
-    pub owner: Signer<'info>,
-    pub system_program: Program<'info, System>,
-}
+    #[test]
+    fn the_log_keeps_the_latest_entries_in_order() {
+        let mut battle = test_battle();
+        let total = BATTLE_LOG_CAPACITY + 6;
+        for player in 0..total {
+            log_battle_event(&mut battle, Pubkey::default(), BattleLogEvent::Forfeit { player: player as u8 });
+        }
 
-#[derive(Accounts)]
-pub struct CreateBattle<'info> {
-    #[account(
-        init,
-        payer = player1_owner,
-        space = 8 + Battle::INIT_SPACE,
-        seeds = [b"battle", player1_character.key().as_ref(), player2_character.key().as_ref()],
-        bump
-    )]
-    pub battle: Account<'info, Battle>,
-    #[account(mut)]
-    pub player1_character: Account<'info, Character>,
-    #[account(mut)]
-    pub player2_character: Account<'info, Character>,
-    #[account(mut)]
-    pub player1_owner: Signer<'info>,
-    /// CHECK: Only needed for non-AI battles
-    #[account(mut)]
-    pub player2_owner: AccountInfo<'info>,
-    pub system_program: Program<'info, System>,
-}
+        // Every entry got an index, the account keeps the newest CAPACITY of them
+        assert_eq!(battle.log_offset as usize, total);
+        assert_eq!(battle.battle_log_len as usize, BATTLE_LOG_CAPACITY);
+        let rendered = render_battle_log(&battle);
+        assert_eq!(rendered.len(), BATTLE_LOG_CAPACITY);
+        assert_eq!(rendered[0], BattleLogEvent::Forfeit { player: 6 }.to_string());
+        assert_eq!(rendered[BATTLE_LOG_CAPACITY - 1], BattleLogEvent::Forfeit { player: total as u8 - 1 }.to_string());
+
+        // Before it wraps the log reads from the start
+        let mut battle = test_battle();
+        log_battle_event(&mut battle, Pubkey::default(), BattleLogEvent::Dodged { by: 1 });
+        log_battle_event(&mut battle, Pubkey::default(), BattleLogEvent::Dodged { by: 2 });
+        assert_eq!(
+            render_battle_log(&battle),
+            vec![BattleLogEvent::Dodged { by: 1 }.to_string(), BattleLogEvent::Dodged { by: 2 }.to_string()]
+        );
+    }
 
-#[derive(Accounts)]
-pub struct ExecuteTurn<'info> {
-    #[account(mut)]
-    pub battle: Account<'info, Battle>,
-    #[account(mut)]
-    pub attacker_character: Account<'info, Character>,
-    pub defender_character: Account<'info, Character>,
-    pub attacker: Signer<'info>,
-}
+    #[test]
+    fn heals_are_priced_per_hp_restored() {
+        let settings = default_settings();
+        let price = settings.heal_price_per_hp;
+        let mut character = test_character(CharacterClass::Warrior);
+        character.max_hp = 120;
+        character.current_hp = 70;
+        character.free_heals = 0;
+
+        // Partial, capped at the missing HP, and 0 for a full heal
+        assert_eq!(heal_quote(&character, 20, &settings).unwrap(), (20, 20 * price));
+        assert_eq!(heal_quote(&character, 500, &settings).unwrap(), (50, 50 * price));
+        assert_eq!(heal_quote(&character, 0, &settings).unwrap(), (50, 50 * price));
+
+        // A free heal credit covers the whole heal
+        character.free_heals = 1;
+        assert_eq!(heal_quote(&character, 0, &settings).unwrap(), (50, 0));
+
+        character.current_hp = 120;
+        assert!(matches!(heal_quote(&character, 0, &settings), Err(err) if err == GameError::AlreadyFullHealth.into()));
+        character.current_hp = 0;
+        assert!(matches!(heal_quote(&character, 0, &settings), Err(err) if err == GameError::CharacterDead.into()));
+    }
 
-#[derive(Accounts)]
-pub struct FinalizeBattle<'info> {
-    #[account(mut)]
-    pub battle: Account<'info, Battle>,
-    #[account(mut)]
-    pub player1_character: Account<'info, Character>,
-    #[account(mut)]
-    pub player2_character: Account<'info, Character>,
-    /// CHECK: Owner for stake transfer
-    #[account(mut)]
-    pub player1_owner: AccountInfo<'info>,
-    /// CHECK: Owner for stake transfer
-    #[account(mut)]
-    pub player2_owner: AccountInfo<'info>,
-}
+    #[test]
+    fn heals_wait_until_the_battle_is_finalized() {
+        let settings = default_settings();
+        let battle_key = Pubkey::new_unique();
+        let mut character = test_character(CharacterClass::Tank);
+        character.current_hp = character.max_hp / 2;
+        character.in_battle = Some(battle_key);
 
-#[derive(Accounts)]
-pub struct CreateBettingPool<'info> {
-    #[account(
-        init,
-        payer = creator,
-        space = 8 + BettingPool::INIT_SPACE,
-        seeds = [b"betting_pool", battle.key().as_ref()],
-        bump
-    )]
-    pub betting_pool: Account<'info, BettingPool>,
-    pub battle: Account<'info, Battle>,
-    pub player1_character: Account<'info, Character>,
-    pub player2_character: Account<'info, Character>,
-    #[account(mut)]
-    pub creator: Signer<'info>,
-    pub system_program: Program<'info, System>,
-}
+        let busy = |character: &Character| {
+            matches!(heal_quote(character, 0, &settings), Err(err) if err == GameError::CharacterBusy.into())
+        };
+        assert!(busy(&character));
 
-#[derive(Accounts)]
-pub struct PlaceBet<'info> {
-    #[account(
-        init,
-        payer = bettor,
-        space = 8 + Bet::INIT_SPACE,
-        seeds = [b"bet", betting_pool.key().as_ref(), bettor.key().as_ref()],
-        bump
-    )]
-    pub bet: Account<'info, Bet>,
-    #[account(mut)]
-    pub betting_pool: Account<'info, BettingPool>,
-    pub battle: Account<'info, Battle>,
-    #[account(mut)]
-    pub bettor: Signer<'info>,
-    pub system_program: Program<'info, System>,
-}
+        // A stale battle can't free it, finalizing its own battle does
+        release_character(&mut character, Pubkey::new_unique());
+        assert!(busy(&character));
+        release_character(&mut character, battle_key);
+        assert!(heal_quote(&character, 0, &settings).is_ok());
+    }
 
-#[derive(Accounts)]
-pub struct SettleBettingPool<'info> {
-    #[account(mut)]
-    pub betting_pool: Account<'info, BettingPool>,
-    pub battle: Account<'info, Battle>,
-}
+    #[test]
+    fn the_xp_multiplier_scales_both_sides_of_a_battle() {
+        let mut settings = default_settings();
+        assert_eq!(winner_xp(100, 0, &settings), 100);
+        assert_eq!(winner_xp(100, 3, &settings), 130);
+        assert_eq!(winner_xp(100, 20, &settings), 150);
+
+        // A double XP weekend, and a 1.5x one
+        settings.xp_multiplier = 20_000;
+        assert_eq!(winner_xp(100, 3, &settings), 260);
+        let mut battle = test_battle();
+        battle.winner = 1;
+        assert_eq!(consolation_xp(&battle, 100, &settings), 2 * 100 * LOSER_XP_PERCENT as u64 / 100);
+        settings.xp_multiplier = 15_000;
+        assert_eq!(winner_xp(50, 0, &settings), 75);
+
+        // Bounded so an update can't zero out or explode progression
+        settings.xp_multiplier = 0;
+        assert!(validate_settings(&settings).is_err());
+        settings.xp_multiplier = 50_001;
+        assert!(validate_settings(&settings).is_err());
+    }
 
-#[derive(Accounts)]
-pub struct ClaimBetWinnings<'info> {
-    #[account(mut)]
-    pub betting_pool: Account<'info, BettingPool>,
-    #[account(mut)]
-    pub bet: Account<'info, Bet>,
-    #[account(mut)]
-    pub bettor: Signer<'info>,
-}
+    #[test]
+    fn only_the_dead_revive_after_the_downtime() {
+        let settings = default_settings();
+        let at = |unix_timestamp| Clock { unix_timestamp, ..Clock::default() };
+        let mut character = test_character(CharacterClass::Mage);
+        character.last_battle = 1_000_000;
+        let ready = at(1_000_000 + settings.revive_downtime_seconds);
+
+        // Healing is for the living, reviving for the dead
+        assert!(matches!(check_can_revive(&character, &settings, &ready), Err(err) if err == GameError::CharacterNotDead.into()));
+        character.current_hp = 0;
+        assert!(matches!(heal_quote(&character, 0, &settings), Err(err) if err == GameError::CharacterDead.into()));
+
+        assert!(matches!(
+            check_can_revive(&character, &settings, &at(ready.unix_timestamp - 1)),
+            Err(err) if err == GameError::ReviveCooldownActive.into()
+        ));
+        assert!(check_can_revive(&character, &settings, &ready).is_ok());
+
+        character.in_battle = Some(Pubkey::new_unique());
+        assert!(matches!(check_can_revive(&character, &settings, &ready), Err(err) if err == GameError::CharacterBusy.into()));
+    }
 
-#[derive(Accounts)]
-pub struct HealCharacter<'info> {
-    #[account(mut, has_one = owner)]
-    pub character: Account<'info, Character>,
-    #[account(mut)]
-    pub owner: Signer<'info>,
-    /// CHECK: Game treasury for heal payments
-    #[account(mut)]
-    pub game_treasury: AccountInfo<'info>,
-    pub system_program: Program<'info, System>,
+    #[test]
+    fn prestige_resets_the_level_and_keeps_a_permanent_bonus() {
+        let config = config_info(Pubkey::new_unique());
+        let prestige = |character: &Character| {
+            let mut data = account_data(character);
+            data.resize(8 + Character::INIT_SPACE, 0);
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                leaked_info(Pubkey::new_unique(), crate::ID, 1, data, false),
+                leaked_info(character.owner, Pubkey::default(), 1, vec![], true),
+                config.clone(),
+            ]);
+            let mut accounts = PrestigeCharacter::try_accounts(
+                &crate::ID, &mut infos, &[], &mut PrestigeCharacterBumps::default(), &mut std::collections::BTreeSet::new(),
+            )?;
+            my_program::prestige_character(Context::new(&crate::ID, &mut accounts, &[], PrestigeCharacterBumps::default()))?;
+            Ok::<_, Error>((*accounts.character).clone())
+        };
+
+        let mut character = test_character(CharacterClass::Warrior);
+        character.level = MAX_LEVEL - 1;
+        assert!(matches!(prestige(&character), Err(err) if err == GameError::PrestigeNotReady.into()));
+
+        // Leftover XP carries over, stats restart from the class table plus the bonus
+        character.level = MAX_LEVEL;
+        character.xp = get_required_xp(MAX_LEVEL) + 40;
+        character.max_hp = 900;
+        let prestiged = prestige(&character).unwrap();
+        let base = CLASS_BASE_STATS[CharacterClass::Warrior as usize];
+        assert_eq!((prestiged.prestige, prestiged.level, prestiged.xp), (1, 1, 40));
+        assert_eq!(prestiged.max_hp, base.max_hp + PRESTIGE_HP_BONUS);
+        assert_eq!(prestiged.current_hp, prestiged.max_hp);
+        assert_eq!(prestiged.base_damage_min, base.damage_min + PRESTIGE_DAMAGE_BONUS);
+        assert!(prestiged.achievements.contains(&Achievement::Prestiged));
+
+        // Gear has to come off first
+        character.equipment[0] = Some(Pubkey::new_unique());
+        assert!(matches!(prestige(&character), Err(err) if err == GameError::EquipmentStillEquipped.into()));
+    }
+
+    #[test]
+    fn items_fit_between_commit_and_reveal_once_a_turn() {
+        let error = |result: Result<()>, expected: GameError| matches!(result, Err(err) if err == expected.into());
+        let mut battle = test_battle();
+        battle.player1_hp = 185;
+
+        // Only after committing, and before revealing
+        assert!(error(check_item_use(&battle, true, BattleItem::HealthPotion), GameError::ItemUseWindowClosed));
+        battle.player1_stance_committed = 1;
+        assert!(check_item_use(&battle, true, BattleItem::HealthPotion).is_ok());
+
+        // The potion is capped at max HP and reports what it actually healed
+        assert_eq!(apply_battle_item(&mut battle, true, BattleItem::HealthPotion), 15);
+        assert_eq!(battle.player1_hp, 200);
+        assert!(error(check_item_use(&battle, true, BattleItem::SmokeBomb), GameError::ItemAlreadyUsedThisTurn));
+
+        // Next turn, a full-HP potion is refused but the other items work
+        battle.player1_item_this_turn = 0;
+        assert!(error(check_item_use(&battle, true, BattleItem::HealthPotion), GameError::AlreadyFullHealth));
+        assert_eq!(apply_battle_item(&mut battle, true, BattleItem::SmokeBomb), SMOKE_BOMB_DODGE as u64);
+        assert_eq!(battle.player1_smoke_dodge, SMOKE_BOMB_DODGE);
+        battle.player1_item_this_turn = 0;
+        assert_eq!(apply_battle_item(&mut battle, true, BattleItem::AdrenalineShot), 1);
+        assert_eq!((battle.player1_combo, battle.player1_peak_combo), (1, 1));
+
+        // MAX_ITEMS_PER_BATTLE per battle, counted per player
+        battle.player1_item_this_turn = 0;
+        assert_eq!(battle.player1_items_used, MAX_ITEMS_PER_BATTLE);
+        assert!(error(check_item_use(&battle, true, BattleItem::SmokeBomb), GameError::BattleItemLimitReached));
+        assert_eq!(battle.player2_items_used, 0);
+    }
+
+    #[test]
+    fn daily_streaks_grow_to_the_cap_and_lapse() {
+        let day = DAILY_CLAIM_INTERVAL_SECONDS;
+        let at = |unix_timestamp| Clock { unix_timestamp, ..Clock::default() };
+        let mut character = test_character(CharacterClass::Trickster);
+        let start = 1_700_000_000;
+
+        // Day one, then not again until a full interval has passed
+        assert_eq!(record_daily_claim(&mut character, &at(start)).unwrap(), DAILY_REWARD_BASE_XP);
+        assert!(matches!(
+            record_daily_claim(&mut character, &at(start + day - 1)),
+            Err(err) if err == GameError::DailyAlreadyClaimed.into()
+        ));
+
+        // Each day in a row adds a bonus, up to the cap
+        let mut now = start;
+        let mut rewards = vec![];
+        for _ in 0..8 {
+            now += day;
+            rewards.push(record_daily_claim(&mut character, &at(now)).unwrap());
+        }
+        let bonus = |days: u64| DAILY_REWARD_BASE_XP + days * DAILY_REWARD_XP_PER_STREAK_DAY;
+        assert_eq!(rewards[..6], [bonus(1), bonus(2), bonus(3), bonus(4), bonus(5), bonus(6)]);
+        assert_eq!(rewards[6..], [bonus(6), bonus(6)]);
+
+        // A claim inside the grace period keeps the streak, past it starts over
+        now += DAILY_STREAK_GRACE_SECONDS;
+        assert_eq!(record_daily_claim(&mut character, &at(now)).unwrap(), bonus(6));
+        now += DAILY_STREAK_GRACE_SECONDS + 1;
+        assert_eq!(record_daily_claim(&mut character, &at(now)).unwrap(), DAILY_REWARD_BASE_XP);
+        assert_eq!(character.daily_streak, 1);
+    }
+
+    #[test]
+    fn renaming_keeps_the_address_and_flags_the_metadata() {
+        let mut character = test_character(CharacterClass::Paladin);
+        character.seed_owner = character.owner;
+        let (key, _) = Pubkey::find_program_address(
+            &[b"character", character.seed_name.as_bytes(), character.seed_owner.as_ref()],
+            &crate::ID,
+        );
+        let rename = |signer: Pubkey, new_name: &str| {
+            let mut data = account_data(&character);
+            data.resize(8 + Character::INIT_SPACE, 0);
+            let mut infos: &[AccountInfo] = Vec::leak(vec![
+                leaked_info(key, crate::ID, 1, data, false),
+                leaked_info(signer, Pubkey::default(), 1, vec![], true),
+            ]);
+            let mut accounts = RenameCharacter::try_accounts(
+                &crate::ID, &mut infos, &[], &mut RenameCharacterBumps::default(), &mut std::collections::BTreeSet::new(),
+            )?;
+            my_program::rename_character(
+                Context::new(&crate::ID, &mut accounts, &[], RenameCharacterBumps::default()),
+                new_name.to_string(),
+            )?;
+            accounts.exit(&crate::ID)?;
+            Ok::<_, Error>((*accounts.character).clone())
+        };
+
+        // The PDA still derives from the creation name
+        let renamed = rename(character.owner, "Sir Renamed").unwrap();
+        assert_eq!(renamed.name, "Sir Renamed");
+        assert_eq!(renamed.seed_name, character.name);
+        assert!(renamed.metadata_dirty);
+
+        assert!(matches!(rename(character.owner, &"x".repeat(33)), Err(err) if err == GameError::NameTooLong.into()));
+        assert!(rename(character.owner, &"x".repeat(32)).is_ok());
+        assert!(matches!(rename(Pubkey::new_unique(), "Thief"), Err(err) if err == ErrorCode::ConstraintHasOne.into()));
+    }
+
+    #[test]
+    fn one_grant_can_cross_several_levels() {
+        let mut character = test_character(CharacterClass::Warrior);
+        let base_hp = character.max_hp;
+        character.current_hp = 1;
+        // 100 + 250 + 450 takes level 1 to 4, the 50 left over stays banked
+        on_account(&mut character, |account| grant_xp(account, 850));
+        assert_eq!(character.level, 4);
+        assert_eq!(character.xp, 50);
+        assert_eq!(character.max_hp, base_hp + 15);
+        assert_eq!(character.current_hp, character.max_hp);
+
+        let mut character = test_character(CharacterClass::Warrior);
+        on_account(&mut character, |account| grant_xp(account, u64::MAX / 2));
+        assert_eq!(character.level, MAX_LEVEL);
+        let spent: u64 = (1..MAX_LEVEL).map(get_required_xp).sum();
+        assert_eq!(character.xp, u64::MAX / 2 - spent);
+    }
 }
 
 // ===== IMPLEMENTATION GUIDE =====
@@ -1829,6 +11359,7 @@ pub struct HealCharacter<'info> {
 //    - 5th character class with wildcard manipulation (25% chance vs 10%)
 //    - Special ability: Wild Card (4 random powerful effects)
 //    - Unique crit bonus (+20 flat damage on crits)
+//    - 6th class Paladin: Divine Light special heals 30% of max HP
 //
 // 2. ✅ PVE (Player vs AI)
 //    - is_vs_ai flag in battles
@@ -1836,15 +11367,23 @@ pub struct HealCharacter<'info> {
 //    - AI chooses stances based on HP, opponent behavior
 //
 // 3. ✅ BETTER RANDOMNESS
-//    - Note: Still uses simple_random() - MUST integrate Switchboard/Orao VRF
-//    - Current implementation is placeholder
-//    - TODO: Replace with proper VRF for production
+//    - Switchboard on-demand VRF, one seed per turn resolution
+//    - request_turn_randomness() binds a committed randomness account once
+//      stances are locked, consume_randomness() stores the revealed value
+//    - Turn resolution requires a fresh seed, all rolls use roll_range()
+//    - Seed is mixed with both reveal salts and the latest slot hash
+//    - "localnet" feature swaps in a deterministic seed for tests
 //
 // 4. ✅ STANCE COMMITMENT SYSTEM
-//    - commit_stance() - player commits hash of (stance + salt)
-//    - reveal_and_execute_turn() - verifies hash before executing
+//    - commit_stance() - player commits hash of (stance + item + special + salt +
+//      battle + turn + player)
+//    - Both players commit every turn before either may reveal
+//    - reveal_and_execute_turn() - verifies hash, the second reveal resolves the attack
 //    - Prevents opponent from seeing stance before choosing
-//    - Auto-forfeit if doesn't reveal (via timeout system)
+//    - Venomous stance: -20% damage, each hit stacks +2 DOT (cap 10) for 3 rounds
+//    - Auto-forfeit if doesn't reveal (via timeout system): once both have
+//      committed, check_timeout() forfeits a player who hasn't revealed within
+//      reveal_timeout_seconds of both_committed_at
 //
 // 5. ✅ WILDCARD DECISION MECHANISM
 //    - Risky wildcards (DoubleOrNothing, DeathRoulette) require accept/decline
@@ -1854,83 +11393,222 @@ pub struct HealCharacter<'info> {
 //    - Both players must decide before resolution
 //
 // 6. ✅ SPECIAL MOVE COOLDOWNS
-//    - player1_special_cooldown & player2_special_cooldown fields
-//    - Set to 3 turns after use
-//    - Decremented each turn
+//    - player1_special_cooldown & player2_special_cooldown fields, the only
+//      cooldown state (Character has none, it all ends with the battle)
+//    - Set after use to the class cooldown from GameSettings::special_cooldowns
+//      (Tank 2 up to Trickster 5 by default)
+//    - Ticks down at the start of each of the owner's turns, after the check,
+//      so a cooldown of N sits out the owner's next N turns
 //    - Checked before allowing special use
 //
 // 7. ✅ MATCH STAKING/ESCROW
-//    - Stakes locked in battle account at creation
+//    - Stakes locked in a per-battle BattleEscrow PDA at creation
+//    - finalize_battle() closes the escrow, rent goes back to player 1
 //    - Both players must deposit (except vs AI)
 //    - Winner receives both stakes in finalize_battle()
 //    - Abandoned matches return stakes to non-abandoner
+//    - abandon_threshold PvP forfeits within abandon_window_seconds ban the
+//      character from join_queue and challenges for abandon_ban_seconds
+//      (TooManyAbandons)
+//    - A human losing or timing out against the AI forfeits the stake to the
+//      Treasury, or gets it back when pve_timeout_refunds_stake is set
 //
 // 8. ✅ TOURNAMENT SYSTEM
 //    - Tournament account structure created
 //    - create_tournament() function
 //    - TournamentStatus enum (Registration, InProgress, Completed, Cancelled)
-//    - TODO: Implement bracket logic, round progression, prize distribution
+//    - create_tournament() funds the prize pool from the creator,
+//      join_tournament() collects entry fees
+//    - start_tournament() seeds round one by ranked MMR (highest vs lowest)
+//      and emits a TournamentPairing per match
+//    - record_tournament_result() knocks out the loser of each finished
+//      Tournament battle, complete_tournament() pays the last one standing
+//      the fees and prize pool and grants TournamentWinner
+//    - cancel_tournament() returns the prize pool to the creator, entrants
+//      take their fees back with claim_tournament_refund()
+//    - Character::tournament marks a registered character until it's knocked
+//      out, crowned or refunded; close, transfer and respec wait for it
+//    - TODO: Round progression, split prizes
 //
 // 9. ✅ DYNAMIC NFT UPDATES
-//    - metadata_uri field in Character
+//    - metadata_uri field in Character, metadata_version bumps on every change
 //    - rank_tier field (Bronze → Master)
-//    - achievements vec (FirstWin, TenWins, etc.)
+//    - achievements vec (FirstWin, TenWins, etc.), claim_achievement_reward()
+//      pays a one-time XP, heal credit, crit, title or cosmetic reward
+//    - Win streaks (forfeit wins don't count, draws leave them alone): +10% XP
+//      per win from the 3rd (max +50%), +3 MMR and StreakFive from the 5th,
+//      +6 MMR and StreakMaster from the 10th
 //    - Stats update on level up
-//    - TODO: Integrate with Metaplex for actual NFT minting
+//    - rename_character() changes the display name and transfer_character()
+//      the owner; the PDA stays seeded by the originals kept in seed_name and
+//      seed_owner
+//    - respec_character() switches class for a fee, once a week, stats are
+//      rebuilt from the new class's base plus level-up gains and prestige bonus
+//    - Equipment (weapon/armor/trinket) minted by the config admin, equip_item()
+//      and unequip_item() add and remove its stat bonuses; battles use the
+//      stats snapshotted in create_battle(), with crit capped at 100% and
+//      dodge at 75% however much gear is stacked
+//    - claim_daily_reward(): XP every 24h, growing with the streak up to day 7,
+//      streak resets after 48h without a claim
+//    - prestige_character() at level 50 with full XP: back to level 1 and
+//      class base stats, +1 damage and +3 max HP per prestige
+//    - MetadataShouldUpdate fires when level or rank_tier changes, the art
+//      service answers with set_metadata_uri()
+//    - mint_character_nft() mints a supply-1 Metaplex NFT for the character,
+//      transfer_character() is then disabled and the token holder takes
+//      ownership with sync_character_owner(), which takes the previous
+//      owner's gear off rather than waiting on them
+//    - Level, tier, name, class and URI changes set metadata_dirty, a crank
+//      pushes them with update_character_metadata() ("Shadow [Diamond]")
 //
 // 10. ✅ MATCHMAKING QUEUE
 //     - QueueEntry account
 //     - join_queue() function
 //     - Stores MMR, match_type, stake_amount
-//     - TODO: Off-chain service to match players and call create_battle()
+//     - mmr_bucket and region for the off-chain matcher, priority_boost()
+//       widens the bucket range the longer an entry waits
+//     - expire_queue_entry() refunds entries unmatched after queue_timeout_seconds
+//     - TODO: Off-chain service to match players, who then confirm the match
+//       with send_challenge()/accept_challenge()
 //
 // 11. ✅ SPECTATOR FEATURES
-//     - battle_log vec stores up to 50 events
+//     - battle_log keeps the last 64 BattleLogEvents, packed as fixed-size
+//       BattleLogEntry records (ring buffer)
+//     - render_battle_log() turns them into text for clients
+//     - Every entry is also emitted as BattleLogAppended with a sequence index,
+//       log_offset counts all entries so indexers can page the full history
+//     - turn_records holds a numeric TurnRecord per attack (damage, crit,
+//       dodge, stance, wildcard, combo) for replays without decoding the log.
+//       Each record keeps its turn seed, so the rolls can be re-run off-chain
 //     - Events emitted for all major actions
 //     - TODO: Query programs to fetch battle history
-//     - TODO: Leaderboard requires off-chain indexing
+//     - Leaderboard PDA keeps the top 100 characters by MMR on-chain,
+//       LeaderboardChanged reports a character's new rank when it moves
 //
 // 12. ✅ ANTI-CHEAT / TIMEOUT MECHANISMS
 //     - last_action_time tracked
-//     - TURN_TIMEOUT_SECONDS (30s per turn)
-//     - BATTLE_EXPIRY_SECONDS (1 hour total)
+//     - turn timeout (30s per turn by default)
+//     - battle expiry (1 hour total by default)
+//     - minimum interval between battles (last_battle timestamp)
+//     - in_battle lock: one 1v1 battle per character at a time, no healing or
+//       queueing until finalize_battle() / check_timeout() releases it
+//     - timeouts, wildcard chances, heal price, house edge, Elo K-factors and the
+//       XP multiplier (double-XP events) live in the GameConfig PDA, tunable
+//       by its admin via update_config(); initialize_config() only accepts
+//       the program's upgrade authority as admin
 //     - check_timeout() can be called by anyone to forfeit AFK player
 //     - abandoned flag set, winner determined
+//     - GameSettings::max_battle_turns (50 by default, at most the 50 turn
+//       records a battle holds) ends stalemates on HP percentage, ties are a draw
 //
 // 13. ✅ BATTLE EVENTS/LOGGING
 //     - 8 different events emitted (CharacterCreated, BattleCreated, etc.)
-//     - battle_log stores text descriptions
+//     - battle_log stores typed entries
+//     - Battle is zero-copy (AccountLoader), turns never re-serialize the account.
+//       The account is a fixed 8 + 4080 bytes; a turn only writes the fields
+//       it changes instead of Borsh-decoding and re-encoding all of them
+//     - TODO: Record the CU cost of a full 20-turn battle before and after the
+//       zero-copy change. Needs an SBF build under solana-program-test (the
+//       native processor doesn't meter CU), reading consumed units from the
+//       transaction logs of each reveal_and_execute_turn
 //     - Real-time streaming via Solana event subscriptions
 //
+// 13b. ✅ 2v2 TEAM BATTLES
+//     - TeamBattle account, create_team_battle()
+//     - request_team_turn() locks the target with the randomness request,
+//       team_execute_turn() resolves it with the shared roll_attack() math
+//     - Turns alternate sides and teammates, skipping downed characters
+//     - A side loses once both its characters are at 0 HP
+//     - All four owners sign create_team_battle(), which snapshots the
+//       characters' stats and locks them (in_battle) until
+//       finalize_team_battle()
+//     - check_team_timeout(): the side that stalls past turn_timeout_seconds
+//       forfeits
+//     - TODO: Stances, specials and stakes for team battles
+//
 // 14. ⚠️ ECONOMIC FEATURES (Partial)
 //     - ✅ Entry fees via stake_amount
-//     - ✅ Healing costs (0.001 SOL to game_treasury)
+//     - ✅ Healing costs per HP restored (0.00001 SOL/HP by default, to the
+//       Treasury PDA), partial heals allowed
+//     - ✅ Consumables (Health Potion, Adrenaline Shot, Smoke Bomb) bought with
+//       buy_item() into an ItemInventory PDA, used via use_item() on your own
+//       turn: one per turn, three per battle, covered by the stance commitment
+//     - ✅ revive_character() for characters at 0 HP, pricier than healing
+//       and gated by a downtime after their last battle
+//     - ✅ Betting limits (min bet, per-wallet max via BetTally, pool size),
+//       close_bet_tally() returns the tally's rent once the pool settles
+//       default from GameSettings::bet_limits, overridable per pool
+//     - ✅ Betting house cut swept into the Treasury on settlement
+//     - ✅ finalize_battle() and check_timeout() settle the pool inline when
+//       it's passed in, settle_betting_pool() stays as the permissionless fallback
+//     - ✅ collect_house_edge() sweeps unclaimed funds and closes the pool
+//       once bet_claim_window_seconds (30 days) have passed
+//     - ✅ Proposition markets besides the winner (BetMarket: total turns
+//       over/under, first blood, a wildcard triggers), one pool per market,
+//       a result exactly on the line or that never happened is a push.
+//       A market stops taking bets once its outcome is known, and a total
+//       turns line can't sit below the betting cutoff
+//     - ✅ Betting pools are Parimutuel or FixedAtBet (odds locked per bet at
+//       up to 10x and final once placed, short books pay pro rata up to a max_exposure cap, a fixed-odds
+//       surplus goes to the Treasury). Every bet snapshots its odds (OddsLocked)
+//     - ✅ Bets close at the pool's betting_closes_at_turn (3 by default),
+//       announce_betting_closed() emits BettingClosed once per pool
+//     - ✅ Several bets per wallet per pool, Bet PDAs are indexed per bettor
+//     - ✅ void_betting_pool() refunds bets in full when the battle expires
+//       or never starts (draws, forfeits and pools with no bets on the
+//       winner settle in refund mode)
+//     - ✅ withdraw_treasury() for the config admin
 //     - ❌ Marketplace for stat boosts - NOT IMPLEMENTED
 //     - ❌ Referral system - NOT IMPLEMENTED
 //
 // 15. ✅ SEASON/RANKING SYSTEM
 //     - season_wins & season_losses tracked
-//     - rank_tier auto-updates based on MMR
+//     - rank_tier auto-updates based on ranked_mmr, moved only by ranked,
+//       tournament and staked PvP. Casual PvP moves a hidden casual_mmr, AI
+//       battles move neither. Practice matches record nothing at all
 //     - MMR ranges: Bronze(0-999), Silver(1000-1499), Gold(1500-1999),
 //                   Platinum(2000-2499), Diamond(2500-2999), Master(3000+)
-//     - TODO: Season reset function (requires admin/cron)
+//     - decay_rank() crank: -25 MMR per week after 2 weeks idle, floored at Silver
+//     - start_season() / end_season() by the config admin, the Season PDA
+//       holds a reward pool funded from the Treasury
+//     - claim_season_reward() pays by rank tier once per character per
+//       season, for season_claim_window_seconds (30 days) after it ends.
+//       sweep_season_rewards() then returns what's left of the pool to the
+//       Treasury
+//     - Character::season_index marks which season the W/L belong to. The
+//       first battle of a new season clears them and soft-resets MMR to
+//       (mmr + 1000) / 2, whether or not the character claimed
 //
-// 16. ❌ SOCIAL FEATURES
-//     - NOT IMPLEMENTED
-//     - TODO: Friend list, challenge system, guilds require separate accounts
+// 16. ⚠️ SOCIAL FEATURES (Partial)
+//     - ✅ Direct challenges: send_challenge() locks the challenger's stake in
+//       a Challenge PDA, accept_challenge() turns it into a normal Battle,
+//       decline_challenge() closes it and refunds the stake
+//     - ✅ This is the only way into a PvP battle, both owners have to sign.
+//       create_battle() only starts battles against the AI character the
+//       admin designates with set_ai_character()
+//     - Battle PDAs are seeded by both characters and player 1's battle_nonce,
+//       so the same pair can battle again and every PvE battle gets its own
+//     - ✅ Challenges expire after challenge_window_seconds (GameConfig, 1 day
+//       by default), anyone can clear an expired one
+//     - ✅ Guilds: create_guild(), join_guild(), leave_guild() (up to 50
+//       members), donate_to_guild() funds the guild's treasury
+//     - ✅ The last member leaving clears the leader, the next joiner takes
+//       over the guild and its treasury
+//     - ✅ finalize_battle() credits total_guild_wins for ranked PvP wins when
+//       the winner's guild is passed
+//     - TODO: Friend list, spending from guild treasuries
 //
 // ===== CRITICAL TODO FOR PRODUCTION =====
 //
-// 1. INTEGRATE VRF (HIGHEST PRIORITY)
-//    - Replace simple_random() with Switchboard VRF or Orao VRF
-//    - Add VRF account to ExecuteTurn context
-//    - Request randomness at turn start
-//    - Callback to apply randomness after VRF fulfills
+// 1. VRF OPERATIONS (HIGHEST PRIORITY)
+//    - Client/crank must create the Switchboard randomness account, call
+//      request_turn_randomness, then bundle the oracle reveal with
+//      consume_randomness in one transaction
 //
 // 2. MATCHMAKING SERVICE
 //    - Off-chain service to monitor queue
 //    - Match players with similar MMR (±200 range)
-//    - Call create_battle() when match found
+//    - Have matched players send and accept a challenge
 //
 // 3. TOURNAMENT BRACKET LOGIC
 //    - Round-robin or single elimination
@@ -1943,9 +11621,9 @@ pub struct HealCharacter<'info> {
 //    - Add visual traits based on achievements
 //
 // 5. ADMIN FUNCTIONS
-//    - Season reset (requires privileged signer)
-//    - Emergency pause for bugs
-//    - Treasury withdrawal for team
+//    - ✅ Season start/end with per-tier rewards
+//    - ✅ Emergency pause: set_paused() blocks new gameplay, claims still work
+//    - ✅ Treasury withdrawal for team
 //
 // 6. TESTING
 //    - Unit tests for all damage calculations
@@ -1961,16 +11639,31 @@ pub struct HealCharacter<'info> {
 // // 2. Join queue
 // join_queue(ctx, MatchType::Ranked, 0)
 //
-// // 3. Matchmaking service creates battle
-// create_battle(ctx, MatchType::Ranked, 0, false)
+// // 3. Matched players confirm with a challenge, accepting creates the battle
+// //    (create_battle is only for battles against the AI)
+// send_challenge(ctx, MatchType::Ranked, 0)
+// accept_challenge(ctx)
 //
-// // 4. Players commit stances
-// let salt = 12345_u64;
+// // 4. Players commit stances (random salt, bound to battle/turn/player),
+// //    with the code of the item they'll use this turn or 0 and whether
+// //    they'll use their special
+// let salt = rand::random::<u64>();
 // let stance_bytes = BattleStance::Aggressive.to_bytes();
-// let hash = hash(&[&stance_bytes, &salt.to_le_bytes()].concat()).to_bytes();
+// let item = BattleItem::SmokeBomb.code();
+// let use_special = false;
+// let hash = hash(&[
+//     &stance_bytes[..],
+//     &[item],
+//     &[use_special as u8],
+//     &salt.to_le_bytes(),
+//     battle.as_ref(),
+//     &turn_number.to_le_bytes(),
+//     player.as_ref(),
+// ].concat()).to_bytes();
 // commit_stance(ctx, hash)
+// use_item(ctx, BattleItem::SmokeBomb) // Acting player, before revealing
 //
-// // 5. Execute turn with reveal
+// // 5. Both players reveal, the second reveal executes the turn
 // reveal_and_execute_turn(ctx, BattleStance::Aggressive, salt, false)
 //
 // // 6. If wildcard triggers
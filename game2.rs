@@ -351,8 +351,16 @@ pub mod my_program {
         let player1_score = calculate_betting_score(player1_char, battle.player1_hp);
         let player2_score = calculate_betting_score(player2_char, battle.player2_hp);
 
-        pool.player1_odds = (player2_score * 100) / (player1_score + player2_score);
-        pool.player2_odds = (player1_score * 100) / (player1_score + player2_score);
+        let total_score = player1_score + player2_score;
+
+        if total_score == 0 {
+            // Nothing to tell the sides apart, start at even odds
+            pool.player1_odds = 50;
+            pool.player2_odds = 50;
+        } else {
+            pool.player1_odds = (player2_score * 100) / total_score;
+            pool.player2_odds = (player1_score * 100) / total_score;
+        }
 
         msg!("Betting pool created. Odds - P1: {}%, P2: {}%", 
             pool.player1_odds, pool.player2_odds);